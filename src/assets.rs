@@ -1,5 +1,6 @@
 use ggez::conf::NumSamples;
 
+use ggez::graphics::spritebatch::SpriteBatch;
 use ggez::graphics::{
     Canvas, Color, DrawMode, DrawParam, Drawable, FilterMode, Image, Mesh, MeshBuilder, Rect,
 };
@@ -8,14 +9,17 @@ use ggez::{graphics, Context, GameError, GameResult};
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 
+use crate::core::{TeamSetup, Visibility};
 use crate::data::{self, Animation, EntityType};
 use crate::entities::{Entity, Team};
 use crate::game::{CELL_PIXEL_SIZE, COLOR_FG, WORLD_VIEWPORT};
 use crate::grid::Grid;
+use crate::images;
 use crate::map::TileId;
 use crate::player::HighlightType;
 
 const COLOR_GRID: Color = Color::new(0.3, 0.3, 0.4, 1.0);
+const COLOR_RALLY_POINT: Color = Color::new(0.6, 0.9, 0.6, 1.0);
 
 const TILE_PIXEL_SIZE: [f32; 2] = [CELL_PIXEL_SIZE[0] / 2.0, CELL_PIXEL_SIZE[1] / 2.0];
 
@@ -23,12 +27,17 @@ pub struct Assets {
     grid: Mesh,
     foreground_around_world: Mesh,
     selections: HashMap<([u32; 2], Team), Mesh>,
+    team_setup: TeamSetup,
     construction_outlines: HashMap<[u32; 2], Mesh>,
     entity_animations: HashMap<(EntityType, Team), Animation>,
     movement_command_indicator: Mesh,
+    projectile: Mesh,
+    rally_flag: Mesh,
     tile_map: Image,
     world_background: Image,
     world_size: [f32; 2],
+    unexplored_fog: SpriteBatch,
+    explored_fog: SpriteBatch,
 }
 
 impl Assets {
@@ -36,12 +45,13 @@ impl Assets {
         ctx: &mut Context,
         camera_size: [f32; 2],
         tile_grid: &Grid<TileId>,
+        team_setup: &TeamSetup,
     ) -> GameResult<Assets> {
         let grid = create_grid(ctx, camera_size)?;
 
         let foreground_around_world = create_foreground_around_world(ctx, camera_size)?;
 
-        let entity_animations = data::create_entity_animations(ctx)?;
+        let entity_animations = data::create_entity_animations(ctx, team_setup)?;
 
         let movement_command_indicator = MeshBuilder::new()
             .circle(
@@ -53,6 +63,25 @@ impl Assets {
             )?
             .build(ctx)?;
 
+        let projectile = MeshBuilder::new()
+            .circle(
+                DrawMode::fill(),
+                [0.0, 0.0],
+                3.0,
+                0.1,
+                Color::new(1.0, 0.9, 0.5, 1.0),
+            )?
+            .build(ctx)?;
+
+        // Planted at [0, 0], with the pole going upwards
+        let rally_flag = MeshBuilder::new()
+            .line(&[[0.0, 0.0], [0.0, -18.0]], 2.0, COLOR_RALLY_POINT)?
+            .triangles(
+                &[[1.0, -18.0], [12.0, -14.0], [1.0, -10.0]],
+                COLOR_RALLY_POINT,
+            )?
+            .build(ctx)?;
+
         let mut tile_map = Image::new(ctx, "/images/tile_map.png")?;
         tile_map.set_filter(FilterMode::Nearest); // Make sure our pixels are preserved exactly
 
@@ -63,16 +92,25 @@ impl Assets {
             tile_grid.dimensions()[1] as f32 * TILE_PIXEL_SIZE[1],
         ];
 
+        let cell_rect = Rect::new(0.0, 0.0, CELL_PIXEL_SIZE[0], CELL_PIXEL_SIZE[1]);
+        let unexplored_fog = fog_sprite_batch(ctx, cell_rect, Color::new(0.0, 0.0, 0.0, 1.0))?;
+        let explored_fog = fog_sprite_batch(ctx, cell_rect, Color::new(0.0, 0.0, 0.0, 0.5))?;
+
         let assets = Assets {
             grid,
             foreground_around_world,
             selections: Default::default(),
+            team_setup: team_setup.clone(),
             construction_outlines: Default::default(),
             entity_animations,
             movement_command_indicator,
+            projectile,
+            rally_flag,
             tile_map,
             world_background,
             world_size,
+            unexplored_fog,
+            explored_fog,
         };
         Ok(assets)
     }
@@ -96,7 +134,10 @@ impl Assets {
     ) -> GameResult {
         let mesh = match self.selections.entry((size, team)) {
             Entry::Occupied(o) => o.into_mut(),
-            Entry::Vacant(v) => v.insert(create_selection_mesh(ctx, size, team)?),
+            Entry::Vacant(v) => {
+                let color = self.team_setup.color(team);
+                v.insert(create_selection_mesh(ctx, size, color)?)
+            }
         };
         mesh.draw(ctx, DrawParam::new().dest(screen_coords))
     }
@@ -154,6 +195,44 @@ impl Assets {
         )
     }
 
+    pub fn draw_projectile(&self, ctx: &mut Context, screen_coords: [f32; 2]) -> GameResult {
+        self.projectile
+            .draw(ctx, DrawParam::new().dest(screen_coords))
+    }
+
+    pub fn draw_rally_point(
+        &self,
+        ctx: &mut Context,
+        structure_screen_coords: [f32; 2],
+        rally_point_screen_coords: [f32; 2],
+    ) -> GameResult {
+        if structure_screen_coords != rally_point_screen_coords {
+            let line = Mesh::new_line(
+                ctx,
+                &[structure_screen_coords, rally_point_screen_coords],
+                1.0,
+                Color::new(0.6, 0.9, 0.6, 0.6),
+            )?;
+            line.draw(ctx, DrawParam::new())?;
+        }
+        self.rally_flag
+            .draw(ctx, DrawParam::new().dest(rally_point_screen_coords))
+    }
+
+    /// A line from the unit through each of the positions its queued commands will take it to
+    pub fn draw_waypoints(&self, ctx: &mut Context, screen_coords: &[[f32; 2]]) -> GameResult {
+        if screen_coords.len() < 2 {
+            return Ok(());
+        }
+        let color = Color::new(0.6, 0.9, 0.6, 0.6);
+        let mut builder = MeshBuilder::new();
+        builder.polyline(DrawMode::stroke(1.0), screen_coords, color)?;
+        for waypoint in &screen_coords[1..] {
+            builder.circle(DrawMode::fill(), *waypoint, 3.0, 0.5, color)?;
+        }
+        builder.build(ctx)?.draw(ctx, DrawParam::new())
+    }
+
     pub fn draw_grid(
         &self,
         ctx: &mut Context,
@@ -259,6 +338,40 @@ impl Assets {
         Ok(())
     }
 
+    /// Cover the parts of the world that the team doesn't currently see
+    pub fn draw_fog(
+        &mut self,
+        ctx: &mut Context,
+        screen_coords: [f32; 2],
+        camera_position_in_world: [f32; 2],
+        visibility: &Grid<Visibility>,
+    ) -> GameResult {
+        let [w, h] = visibility.dimensions();
+        let first_x = (camera_position_in_world[0] / CELL_PIXEL_SIZE[0]) as u32;
+        let first_y = (camera_position_in_world[1] / CELL_PIXEL_SIZE[1]) as u32;
+        let last_x = ((camera_position_in_world[0] + WORLD_VIEWPORT.w) / CELL_PIXEL_SIZE[0]) as u32;
+        let last_y = ((camera_position_in_world[1] + WORLD_VIEWPORT.h) / CELL_PIXEL_SIZE[1]) as u32;
+        for x in first_x..(last_x + 1).min(w) {
+            for y in first_y..(last_y + 1).min(h) {
+                let sprite_batch = match visibility.get(&[x, y]).unwrap() {
+                    Visibility::Unexplored => &mut self.unexplored_fog,
+                    Visibility::Explored => &mut self.explored_fog,
+                    Visibility::Visible => continue,
+                };
+                sprite_batch.add(DrawParam::new().dest([
+                    x as f32 * CELL_PIXEL_SIZE[0] - camera_position_in_world[0],
+                    y as f32 * CELL_PIXEL_SIZE[1] - camera_position_in_world[1],
+                ]));
+            }
+        }
+        let param = DrawParam::new().dest(screen_coords);
+        self.unexplored_fog.draw(ctx, param)?;
+        self.explored_fog.draw(ctx, param)?;
+        self.unexplored_fog.clear();
+        self.explored_fog.clear();
+        Ok(())
+    }
+
     pub fn draw_entity(
         &mut self,
         ctx: &mut Context,
@@ -290,12 +403,12 @@ impl Assets {
     }
 }
 
-fn create_selection_mesh(ctx: &mut Context, size: [u32; 2], team: Team) -> GameResult<Mesh> {
-    let color = match team {
-        Team::Player => Color::new(0.6, 0.9, 0.6, 1.0),
-        Team::Enemy1 | Team::Enemy2 => Color::new(0.8, 0.4, 0.4, 1.0),
-        Team::Neutral => Color::new(0.8, 0.8, 0.6, 1.0),
-    };
+fn create_selection_mesh(
+    ctx: &mut Context,
+    size: [u32; 2],
+    [r, g, b]: [u8; 3],
+) -> GameResult<Mesh> {
+    let color = Color::from_rgb(r, g, b);
     let corner_radius = 4.0;
     Mesh::new_rounded_rectangle(
         ctx,
@@ -386,8 +499,8 @@ fn create_grid(ctx: &mut Context, camera_size: [f32; 2]) -> Result<Mesh, GameErr
     let y0 = -CELL_PIXEL_SIZE[1];
     let y1 = y0 + camera_size[1] + CELL_PIXEL_SIZE[1] * 2.0;
 
-    let num_columns = ((x1 - x0) / CELL_PIXEL_SIZE[0] as f32) as u32;
-    let num_rows = ((y1 - y0) / CELL_PIXEL_SIZE[1] as f32) as u32;
+    let num_columns = ((x1 - x0) / CELL_PIXEL_SIZE[0]) as u32;
+    let num_rows = ((y1 - y0) / CELL_PIXEL_SIZE[1]) as u32;
 
     // Horizontal lines
     for i in 0..num_rows {
@@ -403,3 +516,9 @@ fn create_grid(ctx: &mut Context, camera_size: [f32; 2]) -> Result<Mesh, GameErr
 
     builder.build(ctx)
 }
+
+fn fog_sprite_batch(ctx: &mut Context, rect: Rect, color: Color) -> GameResult<SpriteBatch> {
+    let mesh = Mesh::new_rectangle(ctx, DrawMode::fill(), rect, color)?;
+    let image = images::mesh_into_image(ctx, mesh)?;
+    Ok(SpriteBatch::new(image))
+}
@@ -1,7 +1,54 @@
 extern crate rts_rs;
 
+use rts_rs::cli::Usage;
+use rts_rs::definitions;
 use rts_rs::map_editor;
+use rts_rs::map_generator::GeneratorSettings;
+
+const USAGE: Usage = Usage(
+    "Usage: editor [FILE] [--generate] [--seed SEED] [--players N] \
+     [--symmetry mirror|rotational] [--size WIDTHxHEIGHT]",
+);
 
 fn main() {
-    map_editor::run("resources/maps/small.txt".to_owned()).expect("Map editor crashed");
+    let mut filepath = None;
+    let mut generate = false;
+    let mut seed = 0;
+    let mut generator_settings = GeneratorSettings::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--generate" => generate = true,
+            "--seed" => seed = USAGE.parse_value(&arg, args.next()),
+            "--players" => generator_settings.num_players = USAGE.parse_value(&arg, args.next()),
+            "--symmetry" => generator_settings.symmetry = USAGE.parse_value(&arg, args.next()),
+            "--size" => generator_settings.dimensions = USAGE.parse_size(&arg, args.next()),
+            _ if filepath.is_none() && !arg.starts_with("--") => filepath = Some(arg),
+            _ => USAGE.unexpected_argument(&arg),
+        }
+    }
+
+    // A generated map is saved next to the shipped ones, rather than over one of them
+    let filepath = filepath.unwrap_or_else(|| {
+        if generate {
+            format!("resources/maps/generated_{}.ron", seed)
+        } else {
+            "resources/maps/small.ron".to_owned()
+        }
+    });
+    let generator = if generate {
+        if let Err(e) = generator_settings.validate() {
+            USAGE.exit_with_usage(&e);
+        }
+        Some((generator_settings, seed))
+    } else {
+        None
+    };
+
+    definitions::load().expect("Loading entity definitions");
+    if let Err(e) = map_editor::run(filepath, generator) {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    }
 }
@@ -1,25 +1,58 @@
 extern crate rts_rs;
 
-use rts_rs::game;
-use rts_rs::map::{MapConfig, MapType};
+use rts_rs::cli::Usage;
+use rts_rs::definitions;
+use rts_rs::game::{self, GameStart};
+use rts_rs::map::MapConfig;
+use rts_rs::map_generator::GeneratorSettings;
+use rts_rs::simulation::{SimulationSettings, DEFAULT_TICK_DURATION};
+
+const USAGE: Usage = Usage(
+    "Usage: play [MAP] [--seed SEED] [--load FILE] [--replay FILE]
+With MAP \"generated\": [--players N] [--symmetry mirror|rotational] [--size WIDTHxHEIGHT]",
+);
 
 fn main() {
-    let args = std::env::args();
-    let args: Vec<String> = args.collect();
-    let map_config = if args.get(1).map(String::as_str) == Some("loadtest") {
-        MapConfig::Type(MapType::LoadTest)
-    } else if args.get(1).map(String::as_str) == Some("empty") {
-        MapConfig::Type(MapType::Empty)
-    } else if args.get(1).map(String::as_str) == Some("spectator") {
-        MapConfig::Type(MapType::Spectator)
-    } else if args.get(1).map(String::as_str) == Some("small") {
-        MapConfig::Type(MapType::Small)
-    } else if let Some(filename) = args.get(1) {
-        let map_file_path = format!("/maps/{}", filename);
-        MapConfig::FromFile(Box::new(map_file_path))
-    } else {
-        MapConfig::Type(MapType::Medium)
+    let mut map_arg = None;
+    let mut seed = None;
+    let mut save_file = None;
+    let mut replay_file = None;
+    let mut generator_settings = GeneratorSettings::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => seed = Some(USAGE.parse_value(&arg, args.next())),
+            "--load" => save_file = Some(USAGE.parse_value(&arg, args.next())),
+            "--replay" => replay_file = Some(USAGE.parse_value(&arg, args.next())),
+            "--players" => generator_settings.num_players = USAGE.parse_value(&arg, args.next()),
+            "--symmetry" => generator_settings.symmetry = USAGE.parse_value(&arg, args.next()),
+            "--size" => generator_settings.dimensions = USAGE.parse_size(&arg, args.next()),
+            _ if map_arg.is_none() && !arg.starts_with("--") => map_arg = Some(arg),
+            _ => USAGE.unexpected_argument(&arg),
+        }
+    }
+
+    let start = match (save_file, replay_file) {
+        (_, Some(path)) => GameStart::Replay(path),
+        (Some(path), None) => GameStart::SaveFile(path),
+        (None, None) => match MapConfig::from_arg(map_arg.as_deref()) {
+            MapConfig::Generated(_) => {
+                // Checked before the window is opened
+                if let Err(e) = generator_settings.validate() {
+                    USAGE.exit_with_usage(&e);
+                }
+                GameStart::Map(MapConfig::Generated(generator_settings))
+            }
+            map_config => GameStart::Map(map_config),
+        },
     };
+    let settings = SimulationSettings::new(seed, DEFAULT_TICK_DURATION);
+
+    definitions::load().expect("Loading entity definitions");
 
-    game::run(map_config).expect("game crashed");
+    if let Err(e) = game::run(start, settings) {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,84 @@
+extern crate rts_rs;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use rts_rs::cli::Usage;
+use rts_rs::definitions;
+use rts_rs::map::{MapConfig, WorldInitData};
+use rts_rs::simulation::{Simulation, SimulationSettings};
+
+const USAGE: Usage = Usage(
+    "Usage: simulate [MAP] [--seed SEED] [--ticks N] [--tick-ms MS] [--out FILE] \
+     [--record FILE] [--replay FILE]",
+);
+
+fn main() {
+    let mut map_arg = None;
+    let mut seed = None;
+    let mut num_ticks = None;
+    let mut tick_ms = 20;
+    let mut out_path: Option<PathBuf> = None;
+    let mut record_path: Option<PathBuf> = None;
+    let mut replay_path: Option<PathBuf> = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => seed = Some(USAGE.parse_value(&arg, args.next())),
+            "--ticks" => num_ticks = Some(USAGE.parse_value(&arg, args.next())),
+            "--tick-ms" => tick_ms = USAGE.parse_value(&arg, args.next()),
+            "--out" => out_path = Some(USAGE.parse_value(&arg, args.next())),
+            "--record" => record_path = Some(USAGE.parse_value(&arg, args.next())),
+            "--replay" => replay_path = Some(USAGE.parse_value(&arg, args.next())),
+            _ if map_arg.is_none() && !arg.starts_with("--") => map_arg = Some(arg),
+            _ => USAGE.unexpected_argument(&arg),
+        }
+    }
+
+    definitions::load().expect("Loading entity definitions");
+
+    let mut simulation = match replay_path {
+        Some(path) => {
+            eprintln!("Simulating replay {:?}", path);
+            Simulation::from_replay_file(&path).expect("Loading replay")
+        }
+        None => {
+            let map_config = MapConfig::from_arg(map_arg.as_deref());
+            let settings = SimulationSettings::new(seed, Duration::from_millis(tick_ms));
+            eprintln!("Simulating with {:?}", settings);
+            let world =
+                WorldInitData::load_headless(map_config, settings.seed).expect("Loading map");
+            Simulation::new(world, settings)
+        }
+    };
+    let num_ticks = num_ticks
+        .or_else(|| simulation.replay_length())
+        .unwrap_or(3000);
+
+    let mut out: Box<dyn Write> = match out_path {
+        Some(path) => Box::new(BufWriter::new(File::create(path).expect("Creating output"))),
+        None => Box::new(io::stdout()),
+    };
+    let match_result = simulation
+        .run(num_ticks, &mut out)
+        .expect("Writing simulation output");
+    out.flush().expect("Writing simulation output");
+
+    match match_result {
+        Some(result) => {
+            eprintln!("Match over. Winners: {:?}", result.winners);
+            for (team, stats) in &result.stats {
+                eprintln!("  {:?}: {:?}", team, stats);
+            }
+        }
+        None => eprintln!("Match still undecided after {} ticks", num_ticks),
+    }
+
+    if let Some(path) = record_path {
+        simulation.save_replay(&path).expect("Saving replay");
+        eprintln!("Saved replay to {:?}", path);
+    }
+}
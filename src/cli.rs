@@ -0,0 +1,39 @@
+use std::str::FromStr;
+
+/// The usage text of one of the binaries. Any problem with the command line arguments is
+/// reported along with it, and ends the process.
+pub struct Usage(pub &'static str);
+
+impl Usage {
+    /// The value that was given after `flag`
+    pub fn parse_value<T: FromStr>(&self, flag: &str, value: Option<String>) -> T {
+        let value =
+            value.unwrap_or_else(|| self.exit_with_usage(&format!("Missing value for {}", flag)));
+        value.parse().unwrap_or_else(|_| {
+            self.exit_with_usage(&format!("Invalid value for {}: {:?}", flag, value))
+        })
+    }
+
+    /// A size like "40x30", that was given after `flag`
+    pub fn parse_size(&self, flag: &str, value: Option<String>) -> [u32; 2] {
+        let value =
+            value.unwrap_or_else(|| self.exit_with_usage(&format!("Missing value for {}", flag)));
+        let size = value.split_once('x');
+        match size.map(|(w, h)| (w.parse(), h.parse())) {
+            Some((Ok(w), Ok(h))) => [w, h],
+            _ => self.exit_with_usage(&format!(
+                "Invalid value for {}: {:?} (expected WIDTHxHEIGHT, like 40x30)",
+                flag, value
+            )),
+        }
+    }
+
+    pub fn unexpected_argument(&self, arg: &str) -> ! {
+        self.exit_with_usage(&format!("Unexpected argument: {:?}", arg))
+    }
+
+    pub fn exit_with_usage(&self, message: &str) -> ! {
+        eprintln!("{}\n{}", message, self.0);
+        std::process::exit(2);
+    }
+}
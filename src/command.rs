@@ -0,0 +1,440 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::data::EntityType;
+use crate::entities::{ActivityTarget, EntityId, RallyPoint, Stance};
+
+/// An order given to one entity. Commands only refer to entities by their ID, so they can be
+/// stored and sent around freely. It's up to `Core` to check that the IDs are still valid when
+/// the command is issued.
+///
+/// A command is written as a single line of text, for example `move 12 30 7` or
+/// `start-activity 4 train Engineer`. See `Display` and `FromStr` below.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    StartActivity(StartActivityCommand),
+    CancelActivity(CancelActivityCommand),
+    SetRallyPoint(SetRallyPointCommand),
+    SetStance(SetStanceCommand),
+    Construct(ConstructCommand),
+    CancelConstruction(CancelConstructionCommand),
+    Stop(StopCommand),
+    Move(MoveCommand),
+    Attack(AttackCommand),
+    AttackMove(AttackMoveCommand),
+    Patrol(PatrolCommand),
+    GatherResource(GatherResourceCommand),
+    ReturnResource(ReturnResourceCommand),
+    Repair(RepairCommand),
+}
+
+impl Command {
+    /// The entity that is given the command
+    pub fn actor(&self) -> EntityId {
+        match self {
+            Command::StartActivity(StartActivityCommand { structure, .. }) => *structure,
+            Command::CancelActivity(CancelActivityCommand { structure, .. }) => *structure,
+            Command::SetRallyPoint(SetRallyPointCommand { structure, .. }) => *structure,
+            Command::SetStance(SetStanceCommand { unit, .. }) => *unit,
+            Command::Construct(ConstructCommand { builder, .. }) => *builder,
+            Command::CancelConstruction(CancelConstructionCommand { structure }) => *structure,
+            Command::Stop(StopCommand { entity }) => *entity,
+            Command::Move(MoveCommand { unit, .. }) => *unit,
+            Command::Attack(AttackCommand { attacker, .. }) => *attacker,
+            Command::AttackMove(AttackMoveCommand { unit, .. }) => *unit,
+            Command::Patrol(PatrolCommand { unit, .. }) => *unit,
+            Command::GatherResource(GatherResourceCommand { gatherer, .. }) => *gatherer,
+            Command::ReturnResource(ReturnResourceCommand { gatherer, .. }) => *gatherer,
+            Command::Repair(RepairCommand { repairer, .. }) => *repairer,
+        }
+    }
+
+    /// Whether the command takes over from whatever the actor was doing. The other commands
+    /// only change a setting, or add to a structure's queue.
+    pub fn interrupts_actor(&self) -> bool {
+        !matches!(
+            self,
+            Command::StartActivity(..)
+                | Command::CancelActivity(..)
+                | Command::SetRallyPoint(..)
+                | Command::SetStance(..)
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StartActivityCommand {
+    pub structure: EntityId,
+    pub target: ActivityTarget,
+}
+
+/// Cancel the activity at position `slot` in the structure's queue, where 0 is the ongoing one
+#[derive(Debug, Clone, PartialEq)]
+pub struct CancelActivityCommand {
+    pub structure: EntityId,
+    pub slot: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetRallyPointCommand {
+    pub structure: EntityId,
+    pub rally_point: RallyPoint,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetStanceCommand {
+    pub unit: EntityId,
+    pub stance: Stance,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConstructCommand {
+    pub builder: EntityId,
+    pub structure_position: [u32; 2],
+    pub structure_type: EntityType,
+}
+
+/// Tear down a structure that is still under construction. Part of the cost is paid back, and
+/// the builder comes back out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CancelConstructionCommand {
+    pub structure: EntityId,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopCommand {
+    pub entity: EntityId,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoveCommand {
+    pub unit: EntityId,
+    pub destination: [u32; 2],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttackCommand {
+    pub attacker: EntityId,
+    pub victim: EntityId,
+}
+
+/// Move towards the destination, engaging any enemies that are encountered on the way
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttackMoveCommand {
+    pub unit: EntityId,
+    pub destination: [u32; 2],
+}
+
+/// Walk back and forth between the unit's current position and the destination, engaging any
+/// enemies that are encountered on the way. Queueing more of these adds points to the route.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatrolCommand {
+    pub unit: EntityId,
+    pub destination: [u32; 2],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GatherResourceCommand {
+    pub gatherer: EntityId,
+    pub resource: EntityId,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReturnResourceCommand {
+    pub gatherer: EntityId,
+    pub structure: Option<EntityId>,
+}
+
+/// Restore the health of a damaged friendly entity, for a fraction of what it cost to make
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepairCommand {
+    pub repairer: EntityId,
+    pub target: EntityId,
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::StartActivity(StartActivityCommand { structure, target }) => match target {
+                ActivityTarget::Train(entity_type) => {
+                    write!(f, "start-activity {} train {:?}", structure, entity_type)
+                }
+                ActivityTarget::Research(topic) => {
+                    write!(f, "start-activity {} research {:?}", structure, topic)
+                }
+            },
+            Command::CancelActivity(CancelActivityCommand { structure, slot }) => {
+                write!(f, "cancel-activity {} {}", structure, slot)
+            }
+            Command::SetRallyPoint(SetRallyPointCommand {
+                structure,
+                rally_point,
+            }) => match rally_point {
+                RallyPoint::Position([x, y]) => write!(f, "rally {} cell {} {}", structure, x, y),
+                RallyPoint::Unit(unit) => write!(f, "rally {} unit {}", structure, unit),
+                RallyPoint::Resource(resource) => {
+                    write!(f, "rally {} resource {}", structure, resource)
+                }
+            },
+            Command::SetStance(SetStanceCommand { unit, stance }) => {
+                let stance = match stance {
+                    Stance::Aggressive => "aggressive",
+                    Stance::Defensive => "defensive",
+                    Stance::HoldPosition => "hold",
+                    Stance::Passive => "passive",
+                };
+                write!(f, "stance {} {}", unit, stance)
+            }
+            Command::Construct(ConstructCommand {
+                builder,
+                structure_position: [x, y],
+                structure_type,
+            }) => write!(f, "construct {} {:?} {} {}", builder, structure_type, x, y),
+            Command::CancelConstruction(CancelConstructionCommand { structure }) => {
+                write!(f, "cancel-construction {}", structure)
+            }
+            Command::Stop(StopCommand { entity }) => write!(f, "stop {}", entity),
+            Command::Move(MoveCommand {
+                unit,
+                destination: [x, y],
+            }) => write!(f, "move {} {} {}", unit, x, y),
+            Command::Attack(AttackCommand { attacker, victim }) => {
+                write!(f, "attack {} {}", attacker, victim)
+            }
+            Command::AttackMove(AttackMoveCommand {
+                unit,
+                destination: [x, y],
+            }) => write!(f, "attack-move {} {} {}", unit, x, y),
+            Command::Patrol(PatrolCommand {
+                unit,
+                destination: [x, y],
+            }) => write!(f, "patrol {} {} {}", unit, x, y),
+            Command::GatherResource(GatherResourceCommand { gatherer, resource }) => {
+                write!(f, "gather {} {}", gatherer, resource)
+            }
+            Command::ReturnResource(ReturnResourceCommand {
+                gatherer,
+                structure,
+            }) => match structure {
+                Some(structure) => write!(f, "return {} {}", gatherer, structure),
+                None => write!(f, "return {}", gatherer),
+            },
+            Command::Repair(RepairCommand { repairer, target }) => {
+                write!(f, "repair {} {}", repairer, target)
+            }
+        }
+    }
+}
+
+impl FromStr for Command {
+    type Err = ParseCommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Tokens(s.split_whitespace());
+        let name = tokens.next("command name")?;
+        let actor = tokens.parse("entity id")?;
+        let command = match name {
+            "start-activity" => {
+                let target = match tokens.next("activity")? {
+                    "train" => ActivityTarget::Train(tokens.parse("entity type")?),
+                    "research" => ActivityTarget::Research(tokens.parse("research topic")?),
+                    other => return Err(ParseCommandError::new("Unknown activity", other)),
+                };
+                Command::StartActivity(StartActivityCommand {
+                    structure: actor,
+                    target,
+                })
+            }
+            "cancel-activity" => Command::CancelActivity(CancelActivityCommand {
+                structure: actor,
+                slot: tokens.parse("queue slot")?,
+            }),
+            "rally" => {
+                let rally_point = match tokens.next("rally point")? {
+                    "cell" => RallyPoint::Position([tokens.parse("x")?, tokens.parse("y")?]),
+                    "unit" => RallyPoint::Unit(tokens.parse("entity id")?),
+                    "resource" => RallyPoint::Resource(tokens.parse("entity id")?),
+                    other => return Err(ParseCommandError::new("Unknown rally point", other)),
+                };
+                Command::SetRallyPoint(SetRallyPointCommand {
+                    structure: actor,
+                    rally_point,
+                })
+            }
+            "stance" => {
+                let stance = match tokens.next("stance")? {
+                    "aggressive" => Stance::Aggressive,
+                    "defensive" => Stance::Defensive,
+                    "hold" => Stance::HoldPosition,
+                    "passive" => Stance::Passive,
+                    other => return Err(ParseCommandError::new("Unknown stance", other)),
+                };
+                Command::SetStance(SetStanceCommand {
+                    unit: actor,
+                    stance,
+                })
+            }
+            "construct" => Command::Construct(ConstructCommand {
+                builder: actor,
+                structure_type: tokens.parse("entity type")?,
+                structure_position: [tokens.parse("x")?, tokens.parse("y")?],
+            }),
+            "cancel-construction" => {
+                Command::CancelConstruction(CancelConstructionCommand { structure: actor })
+            }
+            "stop" => Command::Stop(StopCommand { entity: actor }),
+            "move" => Command::Move(MoveCommand {
+                unit: actor,
+                destination: [tokens.parse("x")?, tokens.parse("y")?],
+            }),
+            "attack" => Command::Attack(AttackCommand {
+                attacker: actor,
+                victim: tokens.parse("entity id")?,
+            }),
+            "attack-move" => Command::AttackMove(AttackMoveCommand {
+                unit: actor,
+                destination: [tokens.parse("x")?, tokens.parse("y")?],
+            }),
+            "patrol" => Command::Patrol(PatrolCommand {
+                unit: actor,
+                destination: [tokens.parse("x")?, tokens.parse("y")?],
+            }),
+            "gather" => Command::GatherResource(GatherResourceCommand {
+                gatherer: actor,
+                resource: tokens.parse("entity id")?,
+            }),
+            "return" => Command::ReturnResource(ReturnResourceCommand {
+                gatherer: actor,
+                structure: tokens.parse_optional("entity id")?,
+            }),
+            "repair" => Command::Repair(RepairCommand {
+                repairer: actor,
+                target: tokens.parse("entity id")?,
+            }),
+            other => return Err(ParseCommandError::new("Unknown command", other)),
+        };
+        if let Some(extra) = tokens.0.next() {
+            return Err(ParseCommandError::new("Unexpected trailing input", extra));
+        }
+        Ok(command)
+    }
+}
+
+// Commands are stored in their text form, so that recorded commands stay readable
+impl Serialize for Command {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Command {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(D::Error::custom)
+    }
+}
+
+struct Tokens<'a>(std::str::SplitWhitespace<'a>);
+
+impl<'a> Tokens<'a> {
+    fn next(&mut self, expected: &str) -> Result<&'a str, ParseCommandError> {
+        self.0
+            .next()
+            .ok_or_else(|| ParseCommandError::new("Missing", expected))
+    }
+
+    fn parse<T: FromStr>(&mut self, expected: &str) -> Result<T, ParseCommandError> {
+        let token = self.next(expected)?;
+        token
+            .parse()
+            .map_err(|_| ParseCommandError::new(&format!("Invalid {}", expected), token))
+    }
+
+    fn parse_optional<T: FromStr>(
+        &mut self,
+        expected: &str,
+    ) -> Result<Option<T>, ParseCommandError> {
+        match self.0.next() {
+            Some(token) => token
+                .parse()
+                .map(Some)
+                .map_err(|_| ParseCommandError::new(&format!("Invalid {}", expected), token)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseCommandError(String);
+
+impl ParseCommandError {
+    fn new(problem: &str, detail: &str) -> Self {
+        Self(format!("{}: {:?}", problem, detail))
+    }
+}
+
+impl Display for ParseCommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ParseCommandError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn commands_survive_text_round_trip() {
+        let lines = [
+            "start-activity 4 train Engineer",
+            "start-activity 4 research Weapons",
+            "cancel-activity 4 2",
+            "rally 4 cell 10 3",
+            "rally 4 unit 9",
+            "rally 4 resource 1",
+            "stance 9 aggressive",
+            "stance 9 defensive",
+            "stance 9 hold",
+            "stance 9 passive",
+            "construct 7 BattleAcademy 12 30",
+            "cancel-construction 12",
+            "stop 7",
+            "move 7 0 19",
+            "attack 9 2",
+            "attack-move 9 14 3",
+            "patrol 9 7 12",
+            "gather 7 1",
+            "return 7",
+            "return 7 4",
+            "repair 7 4",
+        ];
+        for line in lines {
+            let command: Command = line.parse().unwrap();
+            assert_eq!(command.to_string(), line);
+        }
+    }
+
+    #[test]
+    fn malformed_commands_are_rejected() {
+        for line in [
+            "",
+            "jump 3",
+            "move 3 1",
+            "move x 1 2",
+            "attack-move 3 1",
+            "patrol 3",
+            "repair 3",
+            "stop 3 4",
+            "construct 3 Castle 1 1",
+            "rally 3 flag 1",
+            "stance 3 brave",
+        ] {
+            assert!(line.parse::<Command>().is_err(), "{:?}", line);
+        }
+    }
+}
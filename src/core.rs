@@ -1,23 +1,68 @@
 use std::cell::{Ref, RefCell, RefMut};
-use std::cmp::min;
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::ops::Deref;
+use std::cmp::{max, min};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
-use crate::data::{self, EntityType};
+use serde::{Deserialize, Serialize};
+
+use crate::command::{
+    AttackCommand, AttackMoveCommand, CancelActivityCommand, CancelConstructionCommand, Command,
+    ConstructCommand, GatherResourceCommand, MoveCommand, PatrolCommand, RepairCommand,
+    ReturnResourceCommand, SetRallyPointCommand, SetStanceCommand, StartActivityCommand,
+    StopCommand,
+};
+use crate::data::{self, EntityType, ResearchTopic, ResourceType};
+use crate::definitions::{self, EntityRegistry};
 use crate::entities::{
-    Action, ActionSlot, ActivityStatus, ActivityTarget, ActivityUpdateStatus, Direction, Entity,
-    EntityCategory, EntityId, EntityState, GatheringProgress, Team,
+    Action, ActionSlot, ActivityStatus, ActivityTarget, ActivityUpdateStatus, ConstructionModel,
+    ConstructionSite, Cost, Direction, Entity, EntityCategory, EntityId, EntityIdAllocator,
+    EntityState, GatheringProgress, HeldResource, Prerequisite, Projectile, RallyPoint,
+    ResearchConfig, Stance, Team, UnitComponent,
 };
-use crate::grid::{CellRect, ObstacleGrid};
+use crate::grid::{CellRect, Grid, ObstacleGrid};
 use crate::pathfind::{self, Destination};
+use crate::trigger::{Trigger, TriggerAction, TriggerCondition};
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Core {
-    teams: HashMap<Team, RefCell<TeamState>>,
+    teams: BTreeMap<Team, RefCell<TeamState>>,
     entities: Vec<(EntityId, RefCell<Entity>)>,
     obstacle_grid: ObstacleGrid,
+    // The definitions that this match is played with, which can be rebalanced without affecting
+    // any other match. Saved games store them separately, see `refresh_definitions()`.
+    #[serde(skip, default = "definitions::registry")]
+    definitions: Arc<EntityRegistry>,
+    // Looked up from the definitions
+    #[serde(skip)]
     structure_sizes: HashMap<EntityType, [u32; 2]>,
+    #[serde(skip)]
+    costs: HashMap<EntityType, Cost>,
+    #[serde(skip)]
+    entity_prerequisites: HashMap<EntityType, Vec<Prerequisite>>,
+    #[serde(skip)]
+    research_configs: HashMap<ResearchTopic, ResearchConfig>,
+    #[serde(skip)]
+    drop_off_structures: HashMap<ResourceType, Vec<EntityType>>,
+    #[serde(skip)]
+    supply_costs: HashMap<EntityType, u32>,
+    #[serde(skip)]
+    supply_provided: HashMap<EntityType, u32>,
+    entity_ids: EntityIdAllocator,
+    victory_condition: VictoryCondition,
+    team_setup: TeamSetup,
+    match_result: Option<MatchResult>,
+    visibility: BTreeMap<Team, Grid<Visibility>>,
+    projectiles: Vec<Projectile>,
+    // Triggers that haven't fired yet
+    triggers: Vec<Trigger>,
+    elapsed: Duration,
+    // Decides whether projectiles hit. Seeded from the match seed and kept here rather than
+    // passed in, so that the outcome of a match only depends on the seed and the issued commands.
+    random_state: u64,
 }
 
 impl Core {
@@ -25,13 +70,21 @@ impl Core {
         entities: Vec<Entity>,
         world_dimensions: [u32; 2],
         water_cells: Vec<[u32; 2]>,
+        victory_condition: VictoryCondition,
+        team_setup: TeamSetup,
+        seed: u64,
     ) -> Self {
-        let mut teams: HashMap<Team, RefCell<TeamState>> = HashMap::new();
+        let mut teams: BTreeMap<Team, RefCell<TeamState>> = BTreeMap::new();
         for entity in &entities {
             if let Entry::Vacant(entry) = teams.entry(entity.team) {
                 entry.insert(RefCell::new(TeamState {
-                    resources: 15,
-                    research_state: TeamResearchState::NotStarted,
+                    resources: BTreeMap::from([
+                        (ResourceType::Fuel, 15),
+                        (ResourceType::Minerals, 4),
+                    ]),
+                    tech: TechState::default(),
+                    stats: MatchStats::default(),
+                    is_eliminated: false,
                 }));
             }
         }
@@ -45,26 +98,61 @@ impl Core {
             //      (although entity_id->entity is still not constant currently)
             obstacle_grid.set_area(entity.cell_rect(), ObstacleType::Entity(entity.team));
         }
+        let entity_ids = EntityIdAllocator::continuing_after(&entities);
         let entities = entities
             .into_iter()
             .map(|entity| (entity.id, RefCell::new(entity)))
             .collect();
-        let structure_sizes = data::structure_sizes();
-        Self {
+        let definitions = definitions::registry();
+        let structure_sizes = data::structure_sizes(&definitions);
+        let costs = data::costs(&definitions);
+        let entity_prerequisites = data::entity_prerequisites(&definitions);
+        let research_configs = data::research_configs(&definitions);
+        let drop_off_structures = data::drop_off_structures(&definitions);
+        let supply_costs = data::supply_costs(&definitions);
+        let supply_provided = data::supply_provided(&definitions);
+        let visibility = teams
+            .keys()
+            .filter(|team| **team != Team::Neutral)
+            .map(|team| (*team, Grid::new(world_dimensions)))
+            .collect();
+        let mut core = Self {
             teams,
             entities,
             obstacle_grid,
+            definitions,
             structure_sizes,
-        }
+            costs,
+            entity_prerequisites,
+            research_configs,
+            drop_off_structures,
+            supply_costs,
+            supply_provided,
+            entity_ids,
+            victory_condition,
+            team_setup,
+            match_result: None,
+            visibility,
+            projectiles: vec![],
+            triggers: vec![],
+            elapsed: Duration::ZERO,
+            random_state: initial_random_state(seed),
+        };
+        core.update_action_availability();
+        core.update_visibility();
+        core
     }
 
     pub fn update(&mut self, dt: Duration) -> UpdateOutcome {
+        self.elapsed += dt;
+
         //-------------------------------
         //          MOVEMENT
         //-------------------------------
         for (_id, entity) in &self.entities {
             let mut entity = entity.borrow_mut();
             let pos = entity.position;
+            let state = entity.state;
             if let EntityCategory::Unit(unit) = &mut entity.category {
                 unit.sub_cell_movement.update(dt, pos);
                 if !unit.sub_cell_movement.is_between_cells() {
@@ -97,8 +185,15 @@ impl Core {
                                 }
                             }
                         }
-                    } else if entity.state == EntityState::Moving {
+                    } else if matches!(state, EntityState::Moving | EntityState::AttackMoving) {
                         // Unit reached its destination
+                        if let Some(combat) = &mut unit.combat {
+                            combat.set_attack_move_destination(None);
+                        }
+                        entity.state = EntityState::Idle;
+                    } else if state == EntityState::Patrolling {
+                        // The unit heads for the next waypoint once it's found idle below
+                        unit.combat.as_mut().unwrap().advance_patrol();
                         entity.state = EntityState::Idle;
                     }
                 }
@@ -110,6 +205,103 @@ impl Core {
             }
         }
 
+        //-------------------------------
+        //      ACQUIRING TARGETS
+        //-------------------------------
+        for (entity_id, entity) in &self.entities {
+            let mut entity = entity.borrow_mut();
+            let state = entity.state;
+            let position = entity.position;
+            let team = entity.team;
+            let unit = match &mut entity.category {
+                EntityCategory::Unit(unit) if unit.combat.is_some() => unit,
+                _ => continue,
+            };
+            let stance = unit.stance;
+            let combat = unit.combat.as_mut().unwrap();
+            // Taken even if unused, so that a busy unit doesn't retaliate once it's done
+            let attacker = combat.take_attacker();
+            let is_attack_moving = state == EntityState::AttackMoving;
+            let is_patrolling = state == EntityState::Patrolling;
+            if state != EntityState::Idle && !is_attack_moving && !is_patrolling {
+                continue;
+            }
+            let guard_point = combat.guard_point();
+            let attack_move_destination = combat.attack_move_destination();
+            let patrol_waypoint = combat.patrol_waypoint();
+            let is_away_from_guard_point = guard_point.is_some_and(|point| point != position);
+
+            // How far away the unit engages whoever attacked it, and anyone else
+            let engagement_ranges = match stance {
+                // Never attacks on its own, not even while attack-moving or patrolling
+                Stance::Passive => None,
+                // Attack-moving and patrolling units engage what they meet on the way, regardless
+                // of any other stance
+                _ if is_attack_moving || is_patrolling => {
+                    Some((u32::MAX, combat.acquisition_range()))
+                }
+                Stance::Aggressive => Some((u32::MAX, combat.acquisition_range())),
+                Stance::Defensive if is_away_from_guard_point => None,
+                Stance::Defensive => Some((u32::MAX, combat.acquisition_range())),
+                Stance::HoldPosition => Some((combat.range(), combat.range())),
+            };
+            let target = engagement_ranges.and_then(|(retaliation_range, acquisition_range)| {
+                attacker
+                    .filter(|attacker| {
+                        self.is_target_within_range(team, position, *attacker, retaliation_range)
+                    })
+                    .or_else(|| {
+                        self.find_target_within_range(*entity_id, team, position, acquisition_range)
+                    })
+            });
+
+            if let Some(target) = target {
+                if !is_attack_moving && !is_patrolling && guard_point.is_none() {
+                    combat.set_guard_point(Some(position));
+                }
+                entity.state = EntityState::MovingToAttackTarget(target);
+                entity.unit_mut().movement_plan.clear();
+            } else if state == EntityState::Idle {
+                if let Some(destination) = attack_move_destination {
+                    // Done fighting, so the attack-move continues
+                    if let Some(plan) = pathfind::find_path(
+                        position,
+                        Destination::Point(destination),
+                        &self.obstacle_grid,
+                    ) {
+                        entity.state = EntityState::AttackMoving;
+                        entity.unit_mut().movement_plan.set(plan);
+                    } else {
+                        combat.set_attack_move_destination(None);
+                    }
+                } else if let Some(waypoint) = patrol_waypoint {
+                    // Done fighting, or done with the last leg, so the patrol continues
+                    if let Some(plan) = pathfind::find_path(
+                        position,
+                        Destination::Point(waypoint),
+                        &self.obstacle_grid,
+                    ) {
+                        entity.state = EntityState::Patrolling;
+                        entity.unit_mut().movement_plan.set(plan);
+                    } else {
+                        combat.set_patrol_route(vec![]);
+                    }
+                } else if is_away_from_guard_point && stance != Stance::Aggressive {
+                    // Done fighting, so it heads back to where it was standing guard
+                    if let Some(plan) = pathfind::find_path(
+                        position,
+                        Destination::Point(guard_point.unwrap()),
+                        &self.obstacle_grid,
+                    ) {
+                        entity.state = EntityState::Moving;
+                        entity.unit_mut().movement_plan.set(plan);
+                    } else {
+                        combat.set_guard_point(None);
+                    }
+                }
+            }
+        }
+
         //-------------------------------
         //      MOVING TO COMBAT
         //-------------------------------
@@ -120,14 +312,21 @@ impl Core {
                 let mut attacker = entity;
                 if let Some(victim) = self.find_entity(victim_id) {
                     let victim = victim.borrow_mut();
+                    let range = attacker.unit().combat.as_ref().unwrap().range();
                     if let Some(direction) =
-                        unit_melee_direction(attacker.position, victim.cell_rect())
+                        unit_direction_within_range(attacker.position, victim.cell_rect(), range)
                     {
                         attacker.state = EntityState::Attacking(victim_id);
                         let unit = attacker.unit_mut();
+                        // No need to walk all the way up to a target that is already in range
+                        unit.movement_plan.clear();
                         if !unit.sub_cell_movement.is_between_cells() {
-                            attacker.unit_mut().direction = direction;
+                            unit.direction = direction;
                         }
+                    } else if should_abandon_chase(attacker.unit(), attacker.position) {
+                        println!("Abandoning the chase. Idling.");
+                        attacker.state = EntityState::Idle;
+                        attacker.unit_mut().movement_plan.clear();
                     } else if attacker.unit_mut().movement_plan.peek().is_none() {
                         if let Some(plan) = pathfind::find_path(
                             attacker.position,
@@ -135,6 +334,9 @@ impl Core {
                             &self.obstacle_grid,
                         ) {
                             attacker.unit_mut().movement_plan.set(plan);
+                        } else {
+                            println!("Can't reach attack target. Idling.");
+                            attacker.state = EntityState::Idle;
                         }
                     }
                 } else {
@@ -148,6 +350,7 @@ impl Core {
         //-------------------------------
         //        ATTACKING
         //-------------------------------
+        let mut fired_projectiles = vec![];
         for (_entity_id, entity) in &self.entities {
             let mut entity = entity.borrow_mut();
 
@@ -165,30 +368,46 @@ impl Core {
                     .as_mut()
                     .expect("non-combat attacker");
                 if combat.is_attack_ready() {
+                    let range = combat.range();
+                    let projectile = combat.projectile();
                     if let Some(victim) = self.find_entity(victim_id) {
                         let mut victim = victim.borrow_mut();
+                        let victim_rect = victim.cell_rect();
                         if let Some(direction) =
-                            unit_melee_direction(attacker.position, victim.cell_rect())
+                            unit_direction_within_range(attacker.position, victim_rect, range)
                         {
-                            let victim_health =
-                                victim.health.as_mut().expect("victim without health");
                             let attacker_id = attacker.id;
-                            let has_completed_research = self
-                                .team_state_unchecked(&attacker.team)
-                                .borrow()
-                                .research_state
-                                == TeamResearchState::Done;
+                            let attacker_team = attacker.team;
+                            let attacker_position = attacker.position;
                             let attacker_unit = attacker.unit_mut();
-                            let bonus_damage = if has_completed_research { 1 } else { 0 };
-                            let damage = attacker_unit.combat.as_mut().unwrap().damage_amount()
-                                + bonus_damage;
-                            victim_health.receive_damage(damage);
-                            println!("{:?} --[{} dmg]--> {:?}", attacker_id, damage, victim_id);
+                            let damage = attacker_unit.combat.as_mut().unwrap().damage_amount();
+                            if let Some(projectile) = projectile {
+                                // Aimed at where the victim is now. If it moves away before the
+                                // projectile arrives, it's a miss.
+                                let destination = closest_cell(attacker_position, &victim_rect);
+                                fired_projectiles.push(Projectile::new(
+                                    attacker_id,
+                                    attacker_team,
+                                    victim_id,
+                                    damage,
+                                    attacker_position,
+                                    destination,
+                                    projectile,
+                                ));
+                                println!("{:?} --[projectile]--> {:?}", attacker_id, victim_id);
+                            } else {
+                                self.damage_entity(attacker_id, attacker_team, &mut victim, damage);
+                                println!("{:?} --[{} dmg]--> {:?}", attacker_id, damage, victim_id);
+                            }
 
+                            attacker_unit.movement_plan.clear();
                             if !attacker_unit.sub_cell_movement.is_between_cells() {
                                 attacker_unit.direction = direction;
                             }
                             attacker_unit.combat.as_mut().unwrap().start_cooldown();
+                        } else if should_abandon_chase(attacker.unit(), attacker.position) {
+                            println!("Target got out of range. Abandoning it.");
+                            attacker.state = EntityState::Idle;
                         } else {
                             // Attacked target is not in range
                             attacker.state = EntityState::MovingToAttackTarget(victim_id);
@@ -209,6 +428,43 @@ impl Core {
             }
         }
 
+        self.projectiles.extend(fired_projectiles);
+
+        //-------------------------------
+        //        PROJECTILES
+        //-------------------------------
+        let mut landed_projectiles = vec![];
+        self.projectiles.retain_mut(|projectile| {
+            let has_landed = projectile.advance(dt);
+            if has_landed {
+                landed_projectiles.push(projectile.clone());
+            }
+            !has_landed
+        });
+        for projectile in landed_projectiles {
+            let roll = next_random(&mut self.random_state);
+            let victim = self
+                .find_entity(projectile.victim)
+                .filter(|victim| victim.borrow().cell_rect().contains(projectile.destination));
+            match victim {
+                Some(victim) if roll < projectile.accuracy => {
+                    let mut victim = victim.borrow_mut();
+                    self.damage_entity(
+                        projectile.attacker,
+                        projectile.team,
+                        &mut victim,
+                        projectile.damage,
+                    );
+                    println!(
+                        "Projectile --[{} dmg]--> {:?}",
+                        projectile.damage, victim.id
+                    );
+                }
+                Some(_) => println!("Projectile missed {:?}", projectile.victim),
+                None => println!("{:?} dodged a projectile", projectile.victim),
+            }
+        }
+
         //-------------------------------
         //     MOVING TO RESOURCE
         //-------------------------------
@@ -226,7 +482,7 @@ impl Core {
                             let unit = gatherer.unit_mut();
                             unit.direction = direction;
                             let gathering = unit.gathering.as_mut().unwrap();
-                            gathering.start_gathering();
+                            gathering.start_gathering(resource.resource_config().gather_time);
                         }
                     } else {
                         println!("Arrived at resource, but it's gone");
@@ -246,16 +502,21 @@ impl Core {
                 let mut gatherer = entity;
                 if let Some(resource) = self.find_entity(resource_id) {
                     let mut resource = resource.borrow_mut();
+                    let config = *resource.resource_config();
                     let remaining = resource.resource_remaining_mut();
                     if *remaining > 0 {
                         let gathering = gatherer.unit_mut().gathering.as_mut().unwrap();
-                        if let GatheringProgress::Done =
-                            gathering.make_progress_on_gathering(dt, resource_id)
-                        {
-                            *remaining = remaining.saturating_sub(1);
+                        if let GatheringProgress::Done = gathering.make_progress_on_gathering(dt) {
+                            let amount = config.carry_amount.min(*remaining);
+                            *remaining -= amount;
                             if *remaining == 0 {
                                 used_up_resources.push(resource_id);
                             }
+                            gathering.pick_up_resource(HeldResource {
+                                source: resource_id,
+                                resource_type: config.resource_type,
+                                amount,
+                            });
                             self.unit_return_resource(gatherer, None);
                         }
                     }
@@ -279,14 +540,15 @@ impl Core {
                         if let Some(direction) =
                             unit_melee_direction(returner.position, structure.cell_rect())
                         {
-                            self.team_state_unchecked(&returner.team)
-                                .borrow_mut()
-                                .resources += 1;
-
+                            let team = returner.team;
                             let unit = returner.unit_mut();
                             unit.direction = direction;
                             let gathering = unit.gathering.as_mut().unwrap();
-                            let resource_id = gathering.drop_resource();
+                            let held = gathering.drop_resource();
+                            self.team_state_unchecked(&team)
+                                .borrow_mut()
+                                .receive_gathered(held.resource_type, held.amount);
+                            let resource_id = held.source;
                             // Unit goes back out to gather more
                             if let Some(resource) = self.find_entity(resource_id) {
                                 if let Some(plan) = pathfind::find_path(
@@ -314,6 +576,159 @@ impl Core {
             }
         }
 
+        //-------------------------------
+        //          FOLLOWING
+        //-------------------------------
+        for (_entity_id, entity) in &self.entities {
+            let entity = entity.borrow_mut();
+            if let EntityState::Following(followed_id) = entity.state {
+                let mut follower = entity;
+                if follower.unit_mut().sub_cell_movement.is_between_cells() {
+                    continue;
+                }
+                let followed_rect = match self.find_entity(followed_id) {
+                    Some(followed) => followed.borrow().cell_rect(),
+                    None => {
+                        println!("Followed unit is gone");
+                        follower.state = EntityState::Idle;
+                        follower.unit_mut().movement_plan.clear();
+                        continue;
+                    }
+                };
+                let position = follower.position;
+                let unit = follower.unit_mut();
+                if unit_melee_direction(position, followed_rect).is_some() {
+                    // Caught up. Stays here until the followed unit moves on.
+                    unit.movement_plan.clear();
+                    continue;
+                }
+                // The followed unit may have moved on since the path was planned
+                let is_plan_outdated = unit.movement_plan.peek().is_none()
+                    || unit_melee_direction(unit.movement_plan.destination(), followed_rect)
+                        .is_none();
+                if is_plan_outdated {
+                    if let Some(plan) = pathfind::find_path(
+                        position,
+                        Destination::AdjacentToEntity(followed_rect),
+                        &self.obstacle_grid,
+                    ) {
+                        unit.movement_plan.set(plan);
+                    } else {
+                        println!("Can't reach followed unit. Idling.");
+                        unit.movement_plan.clear();
+                        follower.state = EntityState::Idle;
+                    }
+                }
+            }
+        }
+
+        //-------------------------------
+        //      MOVING TO REPAIR
+        //-------------------------------
+        for (_entity_id, entity) in &self.entities {
+            let entity = entity.borrow_mut();
+            if let EntityState::MovingToRepair(target_id) = entity.state {
+                let mut repairer = entity;
+                if !repairer.unit_mut().sub_cell_movement.is_between_cells() {
+                    if let Some(target) = self.find_entity(target_id) {
+                        let target = target.borrow();
+                        if let Some(direction) =
+                            unit_melee_direction(repairer.position, target.cell_rect())
+                        {
+                            let is_under_construction =
+                                matches!(target.state, EntityState::UnderConstruction(..));
+                            let unit = repairer.unit_mut();
+                            unit.direction = direction;
+                            unit.movement_plan.clear();
+                            if is_under_construction {
+                                repairer.state = EntityState::Constructing(target_id);
+                            } else {
+                                unit.repairing.as_mut().unwrap().start_repairing();
+                                repairer.state = EntityState::Repairing(target_id);
+                            }
+                        } else if repairer.unit_mut().movement_plan.peek().is_none() {
+                            // The target may be a unit that moved away
+                            if let Some(plan) = pathfind::find_path(
+                                repairer.position,
+                                Destination::AdjacentToEntity(target.cell_rect()),
+                                &self.obstacle_grid,
+                            ) {
+                                repairer.unit_mut().movement_plan.set(plan);
+                            } else {
+                                println!("Can't reach repair target. Idling.");
+                                repairer.state = EntityState::Idle;
+                            }
+                        }
+                    } else {
+                        println!("Repair target is gone");
+                        repairer.state = EntityState::Idle;
+                    }
+                }
+            }
+        }
+
+        //-------------------------------
+        //         REPAIRING
+        //-------------------------------
+        for (_entity_id, entity) in &self.entities {
+            let entity = entity.borrow_mut();
+            if let EntityState::Repairing(target_id) = entity.state {
+                let mut repairer = entity;
+                let target = match self.find_entity(target_id) {
+                    Some(target) => target,
+                    None => {
+                        println!("Repair target is gone");
+                        repairer.state = EntityState::Idle;
+                        continue;
+                    }
+                };
+                let mut target = target.borrow_mut();
+                if unit_melee_direction(repairer.position, target.cell_rect()).is_none() {
+                    repairer.state = EntityState::MovingToRepair(target_id);
+                    continue;
+                }
+                let team = repairer.team;
+                let cost = self
+                    .costs
+                    .get(&target.entity_type)
+                    .copied()
+                    .unwrap_or_default();
+                let repairing = repairer.unit_mut().repairing.as_mut().unwrap();
+                if repairing.make_progress_on_repair(dt) {
+                    let health = target.health.as_mut().unwrap();
+                    // Each resource that the target cost is paid for separately
+                    let mut missing_resource = None;
+                    for resource_type in ResourceType::ALL {
+                        let resource_cost = cost.get(resource_type);
+                        if resource_cost == 0 || repairing.has_paid_health(resource_type) {
+                            continue;
+                        }
+                        let mut team_state = self.team_state_unchecked(&team).borrow_mut();
+                        if team_state.resource(resource_type) > 0 {
+                            team_state.pay(&Cost::single(resource_type, 1));
+                            repairing.pay_for_health(
+                                resource_type,
+                                repair_health_per_resource(health.max, resource_cost),
+                            );
+                        } else {
+                            missing_resource = Some(resource_type);
+                            break;
+                        }
+                    }
+                    if let Some(resource_type) = missing_resource {
+                        println!("Out of {}. Stopping repair.", resource_type);
+                        repairer.state = EntityState::Idle;
+                    } else {
+                        repairing.take_paid_health(&cost);
+                        health.receive_healing(1);
+                    }
+                }
+                if !target.health.as_ref().unwrap().is_damaged() {
+                    repairer.state = EntityState::Idle;
+                }
+            }
+        }
+
         //-------------------------------
         //     PREPARE CONSTRUCTION
         //-------------------------------
@@ -328,77 +743,107 @@ impl Core {
                 let has_arrived = entity.unit_mut().movement_plan.peek().is_none()
                     && !entity.unit_mut().sub_cell_movement.is_between_cells();
                 if has_arrived {
-                    let structure_size = *self.structure_sizes.get(&structure_type).unwrap();
-                    if self.can_structure_fit(entity.position, structure_position, structure_size) {
-                        let constructions_options =
-                            entity.unit().construction_options.as_ref().unwrap();
-                        let construction_time = constructions_options
-                            .get(&structure_type)
-                            .unwrap()
-                            .construction_time;
-
-                        // Mark worker for removal and free occupied grid cell
-                        builders_to_remove.push(*entity_id);
-                        self.obstacle_grid
-                            .set_area(entity.cell_rect(), ObstacleType::None);
+                    let structure_rect = CellRect {
+                        position: structure_position,
+                        size: *self.structure_sizes.get(&structure_type).unwrap(),
+                    };
+                    let constructions_options =
+                        entity.unit().construction_options.as_ref().unwrap();
+                    let config = *constructions_options.get(&structure_type).unwrap();
+                    let is_builder_in_the_way = config.model == ConstructionModel::Assisted
+                        && structure_rect.contains(entity.position);
+                    if is_builder_in_the_way {
+                        // The builder will stay outside of the structure, so it has to step out
+                        // of the way first
+                        if let Some(plan) = self.find_path_out_of(entity.position, structure_rect) {
+                            entity.unit_mut().movement_plan.set(plan);
+                            continue;
+                        }
+                    }
+                    if !is_builder_in_the_way
+                        && self.can_structure_fit(
+                            entity.position,
+                            structure_position,
+                            structure_rect.size,
+                        )
+                    {
+                        if config.model == ConstructionModel::ConsumeBuilder {
+                            // Mark worker for removal and free occupied grid cell
+                            builders_to_remove.push(*entity_id);
+                            self.obstacle_grid
+                                .set_area(entity.cell_rect(), ObstacleType::None);
+                        }
 
                         // Plan for structure creation and claim occupied grid cells
                         structures_to_add.push((
                             entity.team,
                             structure_position,
                             structure_type,
-                            construction_time,
+                            config,
+                            *entity_id,
                         ));
-                        let structure_rect = CellRect {
-                            position: structure_position,
-                            size: structure_size,
-                        };
                         self.obstacle_grid
                             .set_area(structure_rect, ObstacleType::Entity(entity.team));
                     } else {
                         println!("There's not enough space for the structure, so builder goes back to idling");
                         let construction_options =
                             entity.unit_mut().construction_options.as_ref().unwrap();
-                        let config = construction_options.get(&structure_type).unwrap();
+                        let cost = construction_options.get(&structure_type).unwrap().cost;
                         self.team_state_unchecked(&entity.team)
                             .borrow_mut()
-                            .resources += config.cost;
+                            .refund(&cost);
                         entity.state = EntityState::Idle;
                     }
                 }
             }
         }
 
-        let mut did_research_state_change = false;
+        let mut did_tech_state_change = false;
 
         //-------------------------------
         //       ENTITY REMOVAL
         //-------------------------------
         let mut removed_entities = vec![];
+        let mut builders_at_work = vec![];
+        let mut returning_builders = vec![];
         self.entities.retain(|(entity_id, entity)| {
             let entity = entity.borrow();
-            let is_dead = entity
-                .health
-                .as_ref()
-                .map(|health| health.current == 0)
-                .unwrap_or(false);
+            let is_dead = is_dead(&entity);
             if is_dead {
+                if let EntityCategory::Unit(_) = entity.category {
+                    self.teams[&entity.team].borrow_mut().stats.units_lost += 1;
+                }
                 let was_research_interrupted =
                     Core::maybe_handle_interrupted_construction_or_research(&entity, &self.teams);
                 if was_research_interrupted {
-                    did_research_state_change = true;
+                    did_tech_state_change = true;
                 }
             }
             let is_transforming_into_structure = builders_to_remove.contains(entity_id);
+            if is_transforming_into_structure {
+                builders_at_work.push(entity.clone());
+            }
             let is_used_up_resource = used_up_resources.contains(entity_id);
+            let cancelled_construction = entity
+                .construction_site
+                .as_ref()
+                .filter(|site| site.is_cancelled);
+            if let Some(builder) = cancelled_construction.and_then(|site| site.builder.clone()) {
+                returning_builders.push((builder, entity.cell_rect()));
+            }
+            let is_cancelled_construction = cancelled_construction.is_some();
 
             // worker transforming into structure has already cleared its grid cell
-            if is_dead || is_used_up_resource {
+            if is_dead || is_used_up_resource || is_cancelled_construction {
                 let cell_rect = entity.cell_rect();
                 self.obstacle_grid.set_area(cell_rect, ObstacleType::None);
             }
 
-            if is_dead || is_transforming_into_structure || is_used_up_resource {
+            if is_dead
+                || is_transforming_into_structure
+                || is_used_up_resource
+                || is_cancelled_construction
+            {
                 removed_entities.push(*entity_id);
                 false
             } else {
@@ -406,13 +851,67 @@ impl Core {
             }
         });
 
+        //-------------------------------
+        //     RETURNING BUILDERS
+        //-------------------------------
+        for (mut builder, freed_rect) in returning_builders {
+            let position = if self.obstacle_grid.get(&builder.position) == Some(ObstacleType::None)
+            {
+                Some(builder.position)
+            } else {
+                self.free_cell_near(freed_rect)
+            };
+            if let Some(position) = position {
+                builder.position = position;
+                builder.state = EntityState::Idle;
+                builder.unit_mut().movement_plan.clear();
+                self.obstacle_grid
+                    .set_area(builder.cell_rect(), ObstacleType::Entity(builder.team));
+                self.entities.push((builder.id, RefCell::new(*builder)));
+            } else {
+                println!("No room for the builder to come back out. It's lost.");
+            }
+        }
+
         //-------------------------------
         //     START CONSTRUCTION
         //-------------------------------
-        for (team, position, structure_type, construction_time) in structures_to_add {
+        for (team, position, structure_type, config, builder_id) in structures_to_add {
             let mut new_structure = self.create_entity(structure_type, position, team);
             new_structure.state =
-                EntityState::UnderConstruction(construction_time, construction_time);
+                EntityState::UnderConstruction(config.construction_time, config.construction_time);
+            let builder = match config.model {
+                ConstructionModel::ConsumeBuilder => {
+                    let builder_index = builders_at_work
+                        .iter()
+                        .position(|builder| builder.id == builder_id)
+                        .unwrap();
+                    Some(Box::new(builders_at_work.swap_remove(builder_index)))
+                }
+                ConstructionModel::Assisted => {
+                    let health = new_structure.health.as_mut().unwrap();
+                    health.current = construction_health(
+                        health.max,
+                        config.construction_time,
+                        config.construction_time,
+                    );
+                    if let Some(builder) = self.find_entity(builder_id) {
+                        let mut builder = builder.borrow_mut();
+                        builder.state = EntityState::Constructing(new_structure.id);
+                        if let Some(direction) =
+                            unit_melee_direction(builder.position, new_structure.cell_rect())
+                        {
+                            builder.unit_mut().direction = direction;
+                        }
+                    }
+                    None
+                }
+            };
+            new_structure.construction_site = Some(ConstructionSite {
+                builder,
+                config,
+                is_cancelled: false,
+            });
             self.entities
                 .push((new_structure.id, RefCell::new(new_structure)));
         }
@@ -420,14 +919,53 @@ impl Core {
         //-------------------------------
         //     CONSTRUCTION
         //-------------------------------
+        let mut structures_being_worked_on = vec![];
+        for (_id, entity) in &self.entities {
+            let mut entity = entity.borrow_mut();
+            if let EntityState::Constructing(structure_id) = entity.state {
+                let is_under_construction =
+                    self.find_entity(structure_id).is_some_and(|structure| {
+                        matches!(structure.borrow().state, EntityState::UnderConstruction(..))
+                    });
+                if is_under_construction {
+                    structures_being_worked_on.push(structure_id);
+                } else {
+                    entity.state = EntityState::Idle;
+                }
+            }
+        }
         let mut finished_structures = vec![];
         for (id, entity) in &self.entities {
             let mut entity = entity.borrow_mut();
-            if let EntityState::UnderConstruction(remaining, total) = entity.state {
-                let remaining = remaining.saturating_sub(dt);
+            if let EntityState::UnderConstruction(previously_remaining, total) = entity.state {
+                let is_assisted = entity
+                    .construction_site
+                    .as_ref()
+                    .is_some_and(|site| site.config.model == ConstructionModel::Assisted);
+                let remaining = if is_assisted {
+                    let num_builders = structures_being_worked_on
+                        .iter()
+                        .filter(|structure_id| *structure_id == id)
+                        .count() as u32;
+                    let remaining = previously_remaining.saturating_sub(dt * num_builders);
+                    let health = entity.health.as_mut().unwrap();
+                    health.receive_healing(
+                        construction_health(health.max, remaining, total)
+                            - construction_health(health.max, previously_remaining, total),
+                    );
+                    remaining
+                } else {
+                    previously_remaining.saturating_sub(dt)
+                };
                 if remaining.is_zero() {
                     entity.state = EntityState::Idle;
+                    // The builder has become part of the structure
+                    entity.construction_site = None;
                     finished_structures.push(*id);
+                    self.team_state_unchecked(&entity.team)
+                        .borrow_mut()
+                        .stats
+                        .structures_built += 1;
                 } else {
                     entity.state = EntityState::UnderConstruction(remaining, total);
                 }
@@ -438,192 +976,833 @@ impl Core {
         //     STRUCTURE ACTIVITY
         //-------------------------------
         let mut completed_trainings = Vec::new();
+        let mut completed_research = Vec::new();
         for (_id, entity) in &self.entities {
             let mut entity = entity.borrow_mut();
             if let EntityState::DoingActivity(activity_target) = entity.state {
-                let status = entity.activity.as_mut().map(|training| training.update(dt));
-                if let Some(ActivityUpdateStatus::Done) = status {
-                    entity.state = EntityState::Idle;
+                let activity = entity.activity.as_mut().unwrap();
+                let rally_point = activity.rally_point();
+                if activity.update(dt) == ActivityUpdateStatus::Done {
+                    entity.state = match activity.current() {
+                        Some(next_target) => EntityState::DoingActivity(next_target),
+                        None => EntityState::Idle,
+                    };
                     match activity_target {
                         ActivityTarget::Train(trained_entity_type) => {
                             completed_trainings.push((
                                 trained_entity_type,
                                 entity.team,
                                 entity.cell_rect(),
+                                rally_point,
                             ));
                         }
-                        ActivityTarget::Research => {
+                        ActivityTarget::Research(topic) => {
                             self.team_state_unchecked(&entity.team)
                                 .borrow_mut()
-                                .research_state = TeamResearchState::Done;
-                            did_research_state_change = true;
+                                .tech
+                                .finish(topic);
+                            completed_research.push((entity.team, topic));
+                            did_tech_state_change = true;
                         }
                     }
                 }
             }
         }
-        for (entity_type, team, source_rect) in completed_trainings {
-            if self
-                .try_add_trained_entity(entity_type, team, source_rect)
-                .is_none()
-            {
+        for (entity_type, team, source_rect, rally_point) in completed_trainings {
+            if let Some(new_unit_id) = self.try_add_trained_entity(entity_type, team, source_rect) {
+                self.team_state_unchecked(&team)
+                    .borrow_mut()
+                    .stats
+                    .units_trained += 1;
+                if let Some(rally_point) = rally_point {
+                    self.send_to_rally_point(new_unit_id, team, rally_point);
+                }
+            } else {
                 eprintln!("Failed to create entity around {:?}", source_rect);
             }
         }
-        if did_research_state_change {
-            self.on_research_state_changed();
+        for (team, topic) in completed_research {
+            let effects = &self.research_configs.get(&topic).unwrap().effects;
+            for (_id, entity) in &self.entities {
+                let mut entity = entity.borrow_mut();
+                if entity.team == team {
+                    for effect in effects {
+                        entity.apply_research_effect(*effect);
+                    }
+                }
+            }
         }
 
-        UpdateOutcome {
-            removed_entities,
-            finished_structures,
-            did_research_state_change,
+        //-------------------------------
+        //      ACTION AVAILABILITY
+        //-------------------------------
+        // Finished or destroyed structures may also have changed what is available
+        if self.update_action_availability() {
+            did_tech_state_change = true;
         }
-    }
 
-    fn on_research_state_changed(&self) {
-        // Research action should only be enabled if research hasn't been started for
-        // the team yet
+        //-------------------------------
+        //        COMMAND QUEUES
+        //-------------------------------
+        let mut queued_commands = vec![];
         for (_id, entity) in &self.entities {
             let mut entity = entity.borrow_mut();
             let team = entity.team;
-            for action in entity.action_slots.iter_mut().flatten() {
-                if let ActionSlot {
-                    action: Action::StartActivity(ActivityTarget::Research, _),
-                    enabled,
-                } = action
-                {
-                    let team_research_state =
-                        self.team_state_unchecked(&team).borrow().research_state;
-                    *enabled = team_research_state == TeamResearchState::NotStarted;
+            let is_idle = entity.state == EntityState::Idle;
+            if let EntityCategory::Unit(unit) = &mut entity.category {
+                if is_idle {
+                    if let Some(command) = unit.command_queue.pop_front() {
+                        queued_commands.push((command, team));
+                    }
                 }
             }
         }
+        for (command, team) in queued_commands {
+            // Things may have changed since the command was queued
+            let result = self
+                .validate_command(&command, team)
+                .and_then(|()| self.carry_out_command(command.clone(), team));
+            if let Err(e) = result {
+                println!("Skipping queued command '{}': {:?}", command, e);
+            }
+        }
+
+        //-------------------------------
+        //           TRIGGERS
+        //-------------------------------
+        let messages = if self.match_result.is_none() {
+            self.run_triggers()
+        } else {
+            vec![]
+        };
+
+        //-------------------------------
+        //         VISIBILITY
+        //-------------------------------
+        self.update_visibility();
+
+        //-------------------------------
+        //     VICTORY CONDITIONS
+        //-------------------------------
+        let (eliminated_teams, match_result) = self.check_victory_condition();
+
+        UpdateOutcome {
+            removed_entities,
+            finished_structures,
+            did_tech_state_change,
+            eliminated_teams,
+            match_result,
+            messages,
+        }
     }
 
-    pub fn can_structure_fit(
-        &self,
-        worker_position: [u32; 2],
-        structure_position: [u32; 2],
-        structure_size: [u32; 2],
-    ) -> bool {
-        let mut can_fit = true;
-        for x in structure_position[0]..structure_position[0] + structure_size[0] {
-            for y in structure_position[1]..structure_position[1] + structure_size[1] {
-                if [x, y] != worker_position {
-                    // Don't check for collision on the cell that the builder stands on,
-                    // since it will be removed when structure is added.
-                    let is_occupied = self
-                        .obstacle_grid
-                        .get(&[x, y])
-                        .map_or(true, |obstacle| obstacle != ObstacleType::None);
-                    if is_occupied {
-                        can_fit = false;
+    // Fire the triggers whose conditions are met, and return the messages that they show
+    fn run_triggers(&mut self) -> Vec<String> {
+        let (fired, pending): (Vec<Trigger>, Vec<Trigger>) = std::mem::take(&mut self.triggers)
+            .into_iter()
+            .partition(|trigger| self.is_trigger_condition_met(&trigger.condition));
+        self.triggers = pending;
+
+        let mut messages = vec![];
+        for action in fired.into_iter().flat_map(|trigger| trigger.actions) {
+            match action {
+                TriggerAction::Message(message) => messages.push(message),
+                TriggerAction::GiveResources {
+                    team,
+                    fuel,
+                    minerals,
+                } => match self.team_state(&team) {
+                    Some(team_state) => {
+                        let resources = &mut team_state.borrow_mut().resources;
+                        *resources.entry(ResourceType::Fuel).or_default() += fuel;
+                        *resources.entry(ResourceType::Minerals).or_default() += minerals;
+                    }
+                    None => println!("WARN: Can't give resources to {}. Not in the match.", team),
+                },
+                TriggerAction::SpawnUnit {
+                    unit_type,
+                    team,
+                    position,
+                } => {
+                    let rect = CellRect {
+                        position,
+                        size: [1, 1],
+                    };
+                    if self.team_state(&team).is_none()
+                        || self.try_add_trained_entity(unit_type, team, rect).is_none()
+                    {
+                        println!("WARN: Couldn't spawn {} for {}", unit_type, team);
                     }
                 }
             }
         }
-        can_fit
+        messages
     }
 
-    fn maybe_handle_interrupted_construction_or_research(
-        entity: &Entity,
-        teams: &HashMap<Team, RefCell<TeamState>>,
-    ) -> bool {
-        match entity.state {
-            EntityState::MovingToConstruction(structure_type, _) => {
-                let construction_options = entity.unit().construction_options.as_ref().unwrap();
-                let config = construction_options.get(&structure_type).unwrap();
-                let mut team_state = teams.get(&entity.team).unwrap().borrow_mut();
-                team_state.resources += config.cost;
-                println!(
-                    "Repaying {} to {:?} due to cancelled construction",
-                    config.cost, entity.team
-                );
+    fn is_trigger_condition_met(&self, condition: &TriggerCondition) -> bool {
+        match condition {
+            TriggerCondition::Time(seconds) => self.elapsed.as_secs_f32() >= *seconds,
+            TriggerCondition::UnitInArea {
+                team,
+                position,
+                size,
+            } => {
+                let area = CellRect {
+                    position: *position,
+                    size: *size,
+                };
+                self.entities.iter().any(|(_id, entity)| {
+                    let entity = entity.borrow();
+                    entity.team == *team
+                        && matches!(entity.category, EntityCategory::Unit(_))
+                        && area.contains(entity.position)
+                })
             }
-            EntityState::DoingActivity(target @ ActivityTarget::Research) => {
-                let config = entity.activity.as_ref().unwrap().config(&target);
-                let mut team_state = teams.get(&entity.team).unwrap().borrow_mut();
-                team_state.resources += config.cost;
-                team_state.research_state = TeamResearchState::NotStarted;
-                println!(
-                    "Repaying {} to {:?} due to cancelled research",
-                    config.cost, entity.team
-                );
-                return true;
+            TriggerCondition::NoneLeft { team, entity_type } => {
+                !self.entities.iter().any(|(_id, entity)| {
+                    let entity = entity.borrow();
+                    entity.team == *team && entity.entity_type == *entity_type
+                })
             }
-            _ => {}
         }
-        false
     }
 
-    pub fn issue_command(
-        &self,
-        command: Command,
-        issuing_team: Team,
-    ) -> Result<CommandSuccess, CommandError> {
-        Core::maybe_handle_interrupted_construction_or_research(
-            command.actor().deref(),
-            &self.teams,
-        );
-
-        match command {
-            Command::StartActivity(StartActivityCommand {
-                mut structure,
-                target: activity_target,
-            }) => {
-                assert_eq!(structure.team, issuing_team);
-                let mut team_state = self.teams.get(&issuing_team).unwrap().borrow_mut();
-                let activity = structure
-                    .activity
-                    .as_mut()
-                    .expect("activity command was issued for incompatible entity");
-
-                let cost = activity.config(&activity_target).cost;
+    // Returns the teams that were eliminated just now, and the result of the match if it ended
+    // just now. Once the match is over, nothing changes anymore.
+    fn check_victory_condition(&mut self) -> (Vec<Team>, Option<MatchResult>) {
+        if self.match_result.is_some() {
+            return (vec![], None);
+        }
 
-                if team_state.resources >= cost {
-                    match activity.try_start(activity_target) {
-                        ActivityStatus::NewActivityStarted => {
-                            structure.state = EntityState::DoingActivity(activity_target);
-                            team_state.resources -= cost;
-
-                            if matches!(activity_target, ActivityTarget::Research) {
-                                team_state.research_state = TeamResearchState::InProgress;
-                                drop(structure);
-                                drop(team_state);
-                                // References must be freed before calling the below
-                                self.on_research_state_changed();
-                                return Ok(CommandSuccess {
-                                    did_research_state_change: true,
-                                });
-                            }
-                        }
-                        ActivityStatus::AlreadyOngoing => return Err(CommandError::EntityIsBusy),
-                    }
-                } else {
-                    return Err(CommandError::NotEnoughResources);
+        let mut resource_target_winner = None;
+        let mut eliminated_teams = vec![];
+        for (team, team_state) in &self.teams {
+            let mut team_state = team_state.borrow_mut();
+            if *team == Team::Neutral || team_state.is_eliminated {
+                continue;
+            }
+            if let VictoryCondition::ResourceTarget(resource_type, target) = self.victory_condition
+            {
+                if team_state.stats.gathered(resource_type) >= target
+                    && resource_target_winner.is_none()
+                {
+                    resource_target_winner = Some(*team);
                 }
             }
+            if !self.is_team_still_in_game(*team) {
+                team_state.is_eliminated = true;
+                eliminated_teams.push(*team);
+            }
+        }
 
-            Command::Construct(ConstructCommand {
-                mut builder,
-                structure_position,
-                structure_type,
-            }) => {
-                assert_eq!(builder.team, issuing_team);
-                let unit = builder.unit_mut();
-                let cost = unit
-                    .construction_options
-                    .as_mut()
-                    .unwrap()
-                    .get_mut(&structure_type)
-                    .unwrap()
-                    .cost;
-                let mut team_state = self.teams.get(&issuing_team).unwrap().borrow_mut();
-
-                if team_state.resources < cost {
-                    return Err(CommandError::NotEnoughResources);
-                }
+        let mut remaining_teams = vec![];
+        for (team, team_state) in &self.teams {
+            let mut team_state = team_state.borrow_mut();
+            if *team == Team::Neutral || team_state.is_eliminated {
+                continue;
+            }
+            let is_beaten_to_target = resource_target_winner
+                .is_some_and(|winner| !self.team_setup.are_allied(winner, *team));
+            if is_beaten_to_target {
+                // Everyone else loses as soon as one team (or one of their allies) reaches it
+                team_state.is_eliminated = true;
+                eliminated_teams.push(*team);
+            } else {
+                remaining_teams.push(*team);
+            }
+        }
+        for team in &eliminated_teams {
+            println!("{} has been eliminated", team);
+        }
+
+        // A match where no one was hostile to begin with (for example on a map with only one
+        // team) never ends by elimination
+        let playing_teams: Vec<Team> = self
+            .teams
+            .keys()
+            .copied()
+            .filter(|t| *t != Team::Neutral)
+            .collect();
+        let is_over = resource_target_winner.is_some()
+            || (self.any_hostile(&playing_teams) && !self.any_hostile(&remaining_teams));
+        if !is_over {
+            return (eliminated_teams, None);
+        }
+
+        let match_result = MatchResult {
+            winners: remaining_teams,
+            stats: self
+                .teams
+                .iter()
+                .filter(|(team, _)| **team != Team::Neutral)
+                .map(|(team, team_state)| (*team, team_state.borrow().stats))
+                .collect(),
+        };
+        println!("Match over. Winners: {:?}", match_result.winners);
+        self.match_result = Some(match_result.clone());
+        (eliminated_teams, Some(match_result))
+    }
+
+    fn any_hostile(&self, teams: &[Team]) -> bool {
+        teams.iter().any(|team| {
+            teams
+                .iter()
+                .any(|other_team| self.team_setup.are_hostile(*team, *other_team))
+        })
+    }
+
+    fn is_team_still_in_game(&self, team: Team) -> bool {
+        self.entities.iter().any(|(_id, entity)| {
+            let entity = entity.borrow();
+            if entity.team != team {
+                return false;
+            }
+            match self.victory_condition {
+                VictoryCondition::Annihilation | VictoryCondition::ResourceTarget(..) => true,
+                // Units that can build a new base keep the team alive
+                VictoryCondition::DestroyAllStructures => match &entity.category {
+                    EntityCategory::Structure { .. } => true,
+                    EntityCategory::Unit(unit) => unit.construction_options.is_some(),
+                    EntityCategory::Resource { .. } => false,
+                },
+            }
+        })
+    }
+
+    fn damage_entity(
+        &self,
+        attacker: EntityId,
+        attacker_team: Team,
+        victim: &mut Entity,
+        damage: u32,
+    ) {
+        let victim_health = victim.health.as_mut().expect("victim without health");
+        let was_alive = victim_health.current > 0;
+        victim_health.receive_damage(damage);
+        if let EntityCategory::Unit(UnitComponent {
+            combat: Some(combat),
+            ..
+        }) = &mut victim.category
+        {
+            combat.on_attacked_by(attacker);
+        }
+        let is_unit = matches!(victim.category, EntityCategory::Unit(_));
+        if was_alive && is_dead(victim) && is_unit {
+            self.team_state_unchecked(&attacker_team)
+                .borrow_mut()
+                .stats
+                .units_killed += 1;
+        }
+    }
+
+    fn is_target_within_range(
+        &self,
+        team: Team,
+        position: [u32; 2],
+        target_id: EntityId,
+        range: u32,
+    ) -> bool {
+        self.find_entity(target_id).is_some_and(|target| {
+            let target = target.borrow();
+            self.team_setup.are_hostile(team, target.team)
+                && !is_dead(&target)
+                && distance_to_rect(position, &target.cell_rect()) <= range
+        })
+    }
+
+    // The closest hostile entity that is at most `range` cells away. Units are preferred over
+    // structures.
+    fn find_target_within_range(
+        &self,
+        seeker_id: EntityId,
+        team: Team,
+        position: [u32; 2],
+        range: u32,
+    ) -> Option<EntityId> {
+        self.entities
+            .iter()
+            .filter(|(id, _entity)| *id != seeker_id)
+            .filter_map(|(id, entity)| {
+                let entity = entity.borrow();
+                if !self.team_setup.are_hostile(team, entity.team)
+                    || entity.health.is_none()
+                    || is_dead(&entity)
+                {
+                    return None;
+                }
+                let distance = distance_to_rect(position, &entity.cell_rect());
+                let is_structure = !matches!(entity.category, EntityCategory::Unit(_));
+                (distance <= range).then_some((*id, (is_structure, distance)))
+            })
+            .min_by_key(|(_id, priority)| *priority)
+            .map(|(id, _priority)| id)
+    }
+
+    fn update_visibility(&mut self) {
+        for (team, visibility) in &mut self.visibility {
+            let [w, h] = visibility.dimensions();
+            for x in 0..w {
+                for y in 0..h {
+                    if visibility.get(&[x, y]) == Some(Visibility::Visible) {
+                        visibility.set([x, y], Visibility::Explored);
+                    }
+                }
+            }
+
+            // Allies share what they see
+            for (_id, entity) in &self.entities {
+                let entity = entity.borrow();
+                if !self.team_setup.are_allied(entity.team, *team) {
+                    continue;
+                }
+                let rect = entity.cell_rect();
+                let radius = entity.sight_radius;
+                let left = rect.position[0].saturating_sub(radius);
+                let top = rect.position[1].saturating_sub(radius);
+                let right = min(rect.position[0] + rect.size[0] + radius, w);
+                let bot = min(rect.position[1] + rect.size[1] + radius, h);
+                for x in left..right {
+                    for y in top..bot {
+                        if square_distance_to_rect([x, y], &rect) <= radius * radius {
+                            visibility.set([x, y], Visibility::Visible);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Actions are enabled once their prerequisites are met, except for research that has
+    // already been started. Returns true if any action was enabled or disabled.
+    fn update_action_availability(&self) -> bool {
+        let finished_structures = self.finished_structures();
+        let mut did_change = false;
+        for (_id, entity) in &self.entities {
+            let mut entity = entity.borrow_mut();
+            let team = entity.team;
+            for ActionSlot { action, enabled } in entity.action_slots.iter_mut().flatten() {
+                let is_available = match action {
+                    Action::StartActivity(ActivityTarget::Research(topic), _) => {
+                        self.team_state_unchecked(&team)
+                            .borrow()
+                            .tech
+                            .can_start(*topic)
+                            && self.are_prerequisites_met(
+                                team,
+                                self.research_prerequisites(*topic),
+                                &finished_structures,
+                            )
+                    }
+                    Action::StartActivity(ActivityTarget::Train(entity_type), _)
+                    | Action::Construct(entity_type, _) => self.are_prerequisites_met(
+                        team,
+                        self.entity_prerequisites(*entity_type),
+                        &finished_structures,
+                    ),
+                    _ => continue,
+                };
+                if *enabled != is_available {
+                    *enabled = is_available;
+                    did_change = true;
+                }
+            }
+        }
+        did_change
+    }
+
+    /// Apply the balance numbers of reloaded entity definitions to the ongoing match. Entities
+    /// are compared to freshly created ones, so that research bonuses are kept.
+    pub fn rebalance(&mut self, definitions: Arc<EntityRegistry>) {
+        self.refresh_definitions(definitions);
+        for (_id, entity) in &self.entities {
+            let mut entity = entity.borrow_mut();
+            let team_state = self.team_state_unchecked(&entity.team).borrow();
+            let balanced = data::create_entity_with(
+                &self.definitions,
+                entity.entity_type,
+                entity.id,
+                entity.position,
+                entity.team,
+                &team_state.tech,
+            );
+            entity.rebalance(&balanced);
+        }
+        self.update_action_availability();
+    }
+
+    /// Put the given entity definitions in use, and look up everything that is derived from
+    /// them again, without touching the entities themselves. Needed before a restored core can
+    /// be used, as saved games store the definitions separately.
+    pub fn refresh_definitions(&mut self, definitions: Arc<EntityRegistry>) {
+        self.structure_sizes = data::structure_sizes(&definitions);
+        self.costs = data::costs(&definitions);
+        self.entity_prerequisites = data::entity_prerequisites(&definitions);
+        self.research_configs = data::research_configs(&definitions);
+        self.drop_off_structures = data::drop_off_structures(&definitions);
+        self.supply_costs = data::supply_costs(&definitions);
+        self.supply_provided = data::supply_provided(&definitions);
+        self.definitions = definitions;
+    }
+
+    /// The entity definitions that this match is played with
+    pub fn definitions(&self) -> &Arc<EntityRegistry> {
+        &self.definitions
+    }
+
+    /// Whether the team has what it takes to train or construct this type of entity
+    pub fn is_unlocked(&self, team: &Team, entity_type: EntityType) -> bool {
+        self.are_prerequisites_met(
+            *team,
+            self.entity_prerequisites(entity_type),
+            &self.finished_structures(),
+        )
+    }
+
+    /// Units count towards the supply that is used from the moment they are queued for
+    /// training. Only finished structures provide supply.
+    pub fn supply(&self, team: &Team) -> Supply {
+        let mut used = 0;
+        let mut provided = 0;
+        for (_id, entity) in &self.entities {
+            let entity = entity.borrow();
+            if entity.team != *team {
+                continue;
+            }
+            used += self.supply_cost(entity.entity_type);
+            if let Some(activity) = entity.activity.as_ref() {
+                for target in activity.queue() {
+                    if let ActivityTarget::Train(entity_type) = target {
+                        used += self.supply_cost(entity_type);
+                    }
+                }
+            }
+            match &entity.construction_site {
+                // The builder comes back if the construction is cancelled
+                Some(site) => {
+                    if let Some(builder) = &site.builder {
+                        used += self.supply_cost(builder.entity_type);
+                    }
+                }
+                None => {
+                    provided += self
+                        .supply_provided
+                        .get(&entity.entity_type)
+                        .copied()
+                        .unwrap_or(0);
+                }
+            }
+        }
+        Supply {
+            used,
+            provided: provided.min(self.team_setup.max_supply()),
+        }
+    }
+
+    pub fn supply_cost(&self, entity_type: EntityType) -> u32 {
+        self.supply_costs.get(&entity_type).copied().unwrap_or(0)
+    }
+
+    fn entity_prerequisites(&self, entity_type: EntityType) -> &[Prerequisite] {
+        self.entity_prerequisites
+            .get(&entity_type)
+            .map_or(&[], |prerequisites| &prerequisites[..])
+    }
+
+    fn research_prerequisites(&self, topic: ResearchTopic) -> &[Prerequisite] {
+        &self.research_configs.get(&topic).unwrap().prerequisites
+    }
+
+    // Structures that are still under construction don't count towards prerequisites
+    fn finished_structures(&self) -> Vec<(Team, EntityType)> {
+        self.entities
+            .iter()
+            .filter_map(|(_id, entity)| {
+                let entity = entity.borrow();
+                let is_finished_structure =
+                    matches!(entity.category, EntityCategory::Structure { .. })
+                        && entity.construction_site.is_none();
+                is_finished_structure.then_some((entity.team, entity.entity_type))
+            })
+            .collect()
+    }
+
+    fn are_prerequisites_met(
+        &self,
+        team: Team,
+        prerequisites: &[Prerequisite],
+        finished_structures: &[(Team, EntityType)],
+    ) -> bool {
+        let team_state = self.team_state_unchecked(&team).borrow();
+        prerequisites.iter().all(|prerequisite| match prerequisite {
+            Prerequisite::Research(topic) => team_state.tech.has_researched(*topic),
+            Prerequisite::Structure(structure_type) => {
+                finished_structures.contains(&(team, *structure_type))
+            }
+        })
+    }
+
+    pub fn can_structure_fit(
+        &self,
+        worker_position: [u32; 2],
+        structure_position: [u32; 2],
+        structure_size: [u32; 2],
+    ) -> bool {
+        let mut can_fit = true;
+        for x in structure_position[0]..structure_position[0] + structure_size[0] {
+            for y in structure_position[1]..structure_position[1] + structure_size[1] {
+                if [x, y] != worker_position {
+                    // Don't check for collision on the cell that the builder stands on,
+                    // since it will be removed when structure is added.
+                    let is_occupied = self
+                        .obstacle_grid
+                        .get(&[x, y])
+                        .is_none_or(|obstacle| obstacle != ObstacleType::None);
+                    if is_occupied {
+                        can_fit = false;
+                    }
+                }
+            }
+        }
+        can_fit
+    }
+
+    fn maybe_handle_interrupted_construction_or_research(
+        entity: &Entity,
+        teams: &BTreeMap<Team, RefCell<TeamState>>,
+    ) -> bool {
+        match entity.state {
+            EntityState::MovingToConstruction(structure_type, _) => {
+                let construction_options = entity.unit().construction_options.as_ref().unwrap();
+                let config = construction_options.get(&structure_type).unwrap();
+                let mut team_state = teams.get(&entity.team).unwrap().borrow_mut();
+                team_state.refund(&config.cost);
+                println!(
+                    "Repaying {} to {:?} due to cancelled construction",
+                    config.cost, entity.team
+                );
+            }
+            EntityState::DoingActivity(_) => {
+                let activity = entity.activity.as_ref().unwrap();
+                let mut was_research_interrupted = false;
+                for target in activity.queue() {
+                    if let ActivityTarget::Research(topic) = target {
+                        let config = activity.config(&target);
+                        let mut team_state = teams.get(&entity.team).unwrap().borrow_mut();
+                        team_state.refund(&config.cost);
+                        team_state.tech.cancel(topic);
+                        println!(
+                            "Repaying {} to {:?} due to cancelled research",
+                            config.cost, entity.team
+                        );
+                        was_research_interrupted = true;
+                    }
+                }
+                return was_research_interrupted;
+            }
+            _ => {}
+        }
+        false
+    }
+
+    /// Carry out the command right away. For a unit, this replaces whatever it was doing, along
+    /// with any commands that were queued up for it.
+    pub fn issue_command(
+        &self,
+        command: Command,
+        issuing_team: Team,
+    ) -> Result<CommandSuccess, CommandError> {
+        self.validate_command(&command, issuing_team)?;
+        if command.interrupts_actor() {
+            if let EntityCategory::Unit(unit) =
+                &mut self.entity(command.actor()).borrow_mut().category
+            {
+                unit.command_queue.clear();
+            }
+        }
+        self.carry_out_command(command, issuing_team)
+    }
+
+    /// Like `issue_command()`, except that a busy unit puts the command at the end of its queue
+    /// and gets to it once it's done with everything before it. Commands that don't interrupt
+    /// the unit, like changing its stance, are carried out right away.
+    pub fn queue_command(
+        &self,
+        command: Command,
+        issuing_team: Team,
+    ) -> Result<CommandSuccess, CommandError> {
+        if let Command::Stop(..) = command {
+            return self.issue_command(command, issuing_team);
+        }
+        self.validate_command(&command, issuing_team)?;
+        let mut actor = self.entity(command.actor()).borrow_mut();
+        let is_idle = actor.state == EntityState::Idle;
+        match &mut actor.category {
+            EntityCategory::Unit(UnitComponent {
+                command_queue,
+                combat: Some(combat),
+                ..
+            }) if matches!(command, Command::Patrol(..))
+                && command_queue.is_empty()
+                && combat.patrol_waypoint().is_some() =>
+            {
+                // Extends the ongoing patrol
+                if let Command::Patrol(PatrolCommand { destination, .. }) = command {
+                    combat.add_patrol_waypoint(destination);
+                }
+                Ok(CommandSuccess {
+                    did_tech_state_change: false,
+                })
+            }
+            EntityCategory::Unit(unit)
+                if command.interrupts_actor() && (!is_idle || !unit.command_queue.is_empty()) =>
+            {
+                unit.command_queue.push_back(command);
+                Ok(CommandSuccess {
+                    did_tech_state_change: false,
+                })
+            }
+            _ => {
+                drop(actor);
+                self.carry_out_command(command, issuing_team)
+            }
+        }
+    }
+
+    // The command must have been validated
+    fn carry_out_command(
+        &self,
+        command: Command,
+        issuing_team: Team,
+    ) -> Result<CommandSuccess, CommandError> {
+        if command.interrupts_actor() {
+            Core::maybe_handle_interrupted_construction_or_research(
+                &self.entity(command.actor()).borrow(),
+                &self.teams,
+            );
+            if let EntityCategory::Unit(UnitComponent {
+                combat: Some(combat),
+                ..
+            }) = &mut self.entity(command.actor()).borrow_mut().category
+            {
+                // A new order takes over from any ongoing attack-move, and from fights that the
+                // unit got into on its own
+                combat.set_attack_move_destination(None);
+                combat.set_guard_point(None);
+                combat.set_patrol_route(vec![]);
+            }
+        }
+
+        match command {
+            Command::StartActivity(StartActivityCommand {
+                structure,
+                target: activity_target,
+            }) => {
+                if let ActivityTarget::Train(entity_type) = activity_target {
+                    // Must be checked before the structure is borrowed, as all entities are
+                    // looked at
+                    if !self
+                        .supply(&issuing_team)
+                        .has_room_for(self.supply_cost(entity_type))
+                    {
+                        return Err(CommandError::SupplyBlocked);
+                    }
+                }
+                let mut structure = self.entity(structure).borrow_mut();
+                let mut team_state = self.teams.get(&issuing_team).unwrap().borrow_mut();
+                let activity = structure.activity.as_mut().unwrap();
+
+                let cost = activity.config(&activity_target).cost;
+
+                if let Some(missing) = team_state.missing_resource(&cost) {
+                    return Err(CommandError::NotEnoughResources(missing));
+                } else {
+                    match activity.try_enqueue(activity_target) {
+                        ActivityStatus::NewActivityStarted => {
+                            structure.state = EntityState::DoingActivity(activity_target);
+                        }
+                        ActivityStatus::Queued => {}
+                        ActivityStatus::QueueIsFull => return Err(CommandError::QueueIsFull),
+                    }
+                    team_state.pay(&cost);
+
+                    if let ActivityTarget::Research(topic) = activity_target {
+                        team_state.tech.start(topic);
+                        drop(structure);
+                        drop(team_state);
+                        // References must be freed before calling the below
+                        self.update_action_availability();
+                        return Ok(CommandSuccess {
+                            did_tech_state_change: true,
+                        });
+                    }
+                }
+            }
+
+            Command::CancelActivity(CancelActivityCommand { structure, slot }) => {
+                let mut structure = self.entity(structure).borrow_mut();
+                let mut team_state = self.teams.get(&issuing_team).unwrap().borrow_mut();
+                let activity = structure.activity.as_mut().unwrap();
+
+                let cancelled_target =
+                    activity.cancel(slot).ok_or(CommandError::NothingToCancel)?;
+                let cost = activity.config(&cancelled_target).cost;
+                team_state.refund(&cost);
+                println!(
+                    "Repaying {} to {:?} due to cancelled {:?}",
+                    cost, issuing_team, cancelled_target
+                );
+                structure.state = match activity.current() {
+                    Some(target) => EntityState::DoingActivity(target),
+                    None => EntityState::Idle,
+                };
+
+                if let ActivityTarget::Research(topic) = cancelled_target {
+                    team_state.tech.cancel(topic);
+                    drop(structure);
+                    drop(team_state);
+                    self.update_action_availability();
+                    return Ok(CommandSuccess {
+                        did_tech_state_change: true,
+                    });
+                }
+            }
+
+            Command::SetRallyPoint(SetRallyPointCommand {
+                structure,
+                rally_point,
+            }) => {
+                let mut structure = self.entity(structure).borrow_mut();
+                let activity = structure.activity.as_mut().unwrap();
+                activity.set_rally_point(rally_point);
+            }
+
+            Command::SetStance(SetStanceCommand { unit, stance }) => {
+                let mut unit = self.entity(unit).borrow_mut();
+                let unit = unit.unit_mut();
+                unit.stance = stance;
+                // The unit stands guard wherever it is when the new stance is taken
+                unit.combat.as_mut().unwrap().set_guard_point(None);
+            }
+
+            Command::Construct(ConstructCommand {
+                builder,
+                structure_position,
+                structure_type,
+            }) => {
+                let mut builder = self.entity(builder).borrow_mut();
+                let unit = builder.unit_mut();
+                let cost = unit
+                    .construction_options
+                    .as_mut()
+                    .unwrap()
+                    .get_mut(&structure_type)
+                    .unwrap()
+                    .cost;
+                let mut team_state = self.teams.get(&issuing_team).unwrap().borrow_mut();
+
+                if let Some(missing) = team_state.missing_resource(&cost) {
+                    return Err(CommandError::NotEnoughResources(missing));
+                }
 
                 let structure_size = self.structure_sizes.get(&structure_type).unwrap();
                 if !self.can_structure_fit(builder.position, structure_position, *structure_size) {
@@ -639,7 +1818,7 @@ impl Core {
                     Destination::AdjacentToEntity(structure_rect),
                     &self.obstacle_grid,
                 ) {
-                    team_state.resources -= cost;
+                    team_state.pay(&cost);
                     builder.unit_mut().movement_plan.set(plan);
                     builder.state =
                         EntityState::MovingToConstruction(structure_type, structure_position);
@@ -648,19 +1827,28 @@ impl Core {
                 }
             }
 
-            Command::Stop(StopCommand {
-                entity: mut stopper,
-            }) => {
-                assert_eq!(stopper.team, issuing_team);
+            Command::CancelConstruction(CancelConstructionCommand { structure }) => {
+                let mut structure = self.entity(structure).borrow_mut();
+                let site = structure.construction_site.as_mut().unwrap();
+                let refund = site.config.cost.percent(site.config.refund_percent);
+                site.is_cancelled = true;
+                self.team_state_unchecked(&issuing_team)
+                    .borrow_mut()
+                    .refund(&refund);
+                println!(
+                    "Repaying {} to {:?} due to cancelled construction",
+                    refund, issuing_team
+                );
+            }
+
+            Command::Stop(StopCommand { entity }) => {
+                let mut stopper = self.entity(entity).borrow_mut();
                 stopper.state = EntityState::Idle;
                 stopper.unit_mut().movement_plan.clear();
             }
 
-            Command::Move(MoveCommand {
-                unit: mut mover,
-                destination,
-            }) => {
-                assert_eq!(mover.team, issuing_team);
+            Command::Move(MoveCommand { unit, destination }) => {
+                let mut mover = self.entity(unit).borrow_mut();
                 if let Some(plan) = pathfind::find_path(
                     mover.position,
                     Destination::Point(destination),
@@ -673,13 +1861,16 @@ impl Core {
                 }
             }
 
-            Command::Attack(AttackCommand {
-                mut attacker,
-                victim,
-            }) => {
-                assert_eq!(attacker.team, issuing_team);
-                assert_ne!(victim.team, issuing_team);
-                if let Some(plan) = pathfind::find_path(
+            Command::Attack(AttackCommand { attacker, victim }) => {
+                let mut attacker = self.entity(attacker).borrow_mut();
+                let victim = self.entity(victim).borrow();
+                let range = attacker.unit().combat.as_ref().unwrap().range();
+                if unit_direction_within_range(attacker.position, victim.cell_rect(), range)
+                    .is_some()
+                {
+                    attacker.state = EntityState::Attacking(victim.id);
+                    attacker.unit_mut().movement_plan.clear();
+                } else if let Some(plan) = pathfind::find_path(
                     attacker.position,
                     Destination::AdjacentToEntity(victim.cell_rect()),
                     &self.obstacle_grid,
@@ -691,19 +1882,59 @@ impl Core {
                 }
             }
 
-            Command::GatherResource(GatherResourceCommand {
-                mut gatherer,
-                resource,
-            }) => {
-                assert_eq!(gatherer.team, issuing_team);
-                assert_eq!(resource.team, Team::Neutral);
-                let is_carrying_resource = gatherer
-                    .unit_mut()
-                    .gathering
-                    .as_ref()
-                    .unwrap()
-                    .is_carrying();
-                if is_carrying_resource {
+            Command::AttackMove(AttackMoveCommand { unit, destination }) => {
+                let mut mover = self.entity(unit).borrow_mut();
+                if let Some(plan) = pathfind::find_path(
+                    mover.position,
+                    Destination::Point(destination),
+                    &self.obstacle_grid,
+                ) {
+                    mover.state = EntityState::AttackMoving;
+                    let unit = mover.unit_mut();
+                    unit.movement_plan.set(plan);
+                    let combat = unit.combat.as_mut().unwrap();
+                    combat.set_attack_move_destination(Some(destination));
+                } else {
+                    return Err(CommandError::NoPathFound);
+                }
+            }
+
+            Command::Patrol(PatrolCommand { unit, destination }) => {
+                let mut patroller = self.entity(unit).borrow_mut();
+                let mut route = vec![patroller.position, destination];
+                let unit = patroller.unit_mut();
+                // A patrol never ends, so any patrol points that were queued after this one are
+                // made part of the route
+                while let Some(Command::Patrol(PatrolCommand { destination, .. })) =
+                    unit.command_queue.front()
+                {
+                    route.push(*destination);
+                    unit.command_queue.pop_front();
+                }
+                if let Some(plan) = pathfind::find_path(
+                    patroller.position,
+                    Destination::Point(destination),
+                    &self.obstacle_grid,
+                ) {
+                    patroller.state = EntityState::Patrolling;
+                    let unit = patroller.unit_mut();
+                    unit.movement_plan.set(plan);
+                    unit.combat.as_mut().unwrap().set_patrol_route(route);
+                } else {
+                    return Err(CommandError::NoPathFound);
+                }
+            }
+
+            Command::GatherResource(GatherResourceCommand { gatherer, resource }) => {
+                let mut gatherer = self.entity(gatherer).borrow_mut();
+                let resource = self.entity(resource).borrow();
+                let is_carrying_resource = gatherer
+                    .unit_mut()
+                    .gathering
+                    .as_ref()
+                    .unwrap()
+                    .is_carrying();
+                if is_carrying_resource {
                     println!("Unit is already carrying resources, reinterpreting gather command as return command.");
                     self.unit_return_resource(gatherer, None);
                 } else if let Some(plan) = pathfind::find_path(
@@ -718,283 +1949,2025 @@ impl Core {
                 }
             }
 
-            Command::ReturnResource(ReturnResourceCommand {
-                mut gatherer,
-                structure,
-            }) => {
-                assert_eq!(gatherer.team, issuing_team);
-                let is_carrying_resource = gatherer
-                    .unit_mut()
-                    .gathering
-                    .as_ref()
-                    .unwrap()
-                    .is_carrying();
-                if is_carrying_resource {
-                    self.unit_return_resource(gatherer, structure);
-                } else {
-                    // TODO improve UI so that no player input leads to this situation
-                    return Err(CommandError::NotCarryingResource);
+            Command::Repair(RepairCommand { repairer, target }) => {
+                let mut repairer = self.entity(repairer).borrow_mut();
+                let target = self.entity(target).borrow();
+                if unit_melee_direction(repairer.position, target.cell_rect()).is_some() {
+                    repairer.state = EntityState::MovingToRepair(target.id);
+                    repairer.unit_mut().movement_plan.clear();
+                } else if let Some(plan) = pathfind::find_path(
+                    repairer.position,
+                    Destination::AdjacentToEntity(target.cell_rect()),
+                    &self.obstacle_grid,
+                ) {
+                    repairer.state = EntityState::MovingToRepair(target.id);
+                    repairer.unit_mut().movement_plan.set(plan);
+                } else {
+                    return Err(CommandError::NoPathFound);
+                }
+            }
+
+            Command::ReturnResource(ReturnResourceCommand {
+                gatherer,
+                structure,
+            }) => {
+                let mut gatherer = self.entity(gatherer).borrow_mut();
+                let structure = structure.map(|structure| self.entity(structure).borrow());
+                let is_carrying_resource = gatherer
+                    .unit_mut()
+                    .gathering
+                    .as_ref()
+                    .unwrap()
+                    .is_carrying();
+                if is_carrying_resource {
+                    self.unit_return_resource(gatherer, structure);
+                } else {
+                    // TODO improve UI so that no player input leads to this situation
+                    return Err(CommandError::NotCarryingResource);
+                }
+            }
+        }
+        Ok(CommandSuccess {
+            did_tech_state_change: false,
+        })
+    }
+
+    /// Make sure that all entities referred to by the command exist and are fit for their
+    /// role in it, so that the command can then be carried out without further checks.
+    fn validate_command(&self, command: &Command, issuing_team: Team) -> Result<(), CommandError> {
+        let actor_id = command.actor();
+        let actor = self.live_entity(actor_id)?.borrow();
+        if actor.team != issuing_team {
+            return Err(CommandError::NotOwnedByTeam(actor_id));
+        }
+
+        let is_unit = matches!(actor.category, EntityCategory::Unit(_));
+        let is_compatible = match command {
+            Command::StartActivity(StartActivityCommand { target, .. }) => actor
+                .activity
+                .as_ref()
+                .is_some_and(|activity| activity.has_option(target)),
+            Command::CancelActivity(..) => actor.activity.is_some(),
+            Command::SetRallyPoint(..) => actor
+                .activity
+                .as_ref()
+                .is_some_and(|activity| activity.trains_units()),
+            Command::Construct(ConstructCommand { structure_type, .. }) => {
+                is_unit
+                    && actor
+                        .unit()
+                        .construction_options
+                        .as_ref()
+                        .is_some_and(|options| options.contains_key(structure_type))
+            }
+            // The structure is removed at the end of the next update
+            Command::CancelConstruction(..) => actor
+                .construction_site
+                .as_ref()
+                .is_some_and(|site| !site.is_cancelled),
+            Command::Stop(..) | Command::Move(..) => is_unit,
+            Command::Attack(..)
+            | Command::AttackMove(..)
+            | Command::Patrol(..)
+            | Command::SetStance(..) => is_unit && actor.unit().combat.is_some(),
+            Command::GatherResource(..) | Command::ReturnResource(..) => {
+                is_unit && actor.unit().gathering.is_some()
+            }
+            Command::Repair(..) => is_unit && actor.unit().repairing.is_some(),
+        };
+        if !is_compatible {
+            return Err(CommandError::IncompatibleEntity(actor_id));
+        }
+
+        let prerequisites = match command {
+            Command::StartActivity(StartActivityCommand {
+                target: ActivityTarget::Research(topic),
+                ..
+            }) => {
+                let tech = &self.team_state_unchecked(&issuing_team).borrow().tech;
+                if !tech.can_start(*topic) {
+                    return Err(CommandError::AlreadyResearched);
+                }
+                self.research_prerequisites(*topic)
+            }
+            Command::StartActivity(StartActivityCommand {
+                target: ActivityTarget::Train(entity_type),
+                ..
+            })
+            | Command::Construct(ConstructCommand {
+                structure_type: entity_type,
+                ..
+            }) => self.entity_prerequisites(*entity_type),
+            _ => &[],
+        };
+        if !self.are_prerequisites_met(issuing_team, prerequisites, &self.finished_structures()) {
+            return Err(CommandError::MissingPrerequisites);
+        }
+
+        let (target_id, is_valid_target): (EntityId, fn(&Entity, Team) -> bool) = match command {
+            Command::Attack(AttackCommand { victim, .. }) => {
+                (*victim, |victim, _team| victim.health.is_some())
+            }
+            Command::GatherResource(GatherResourceCommand { resource, .. }) => {
+                (*resource, |resource, _team| {
+                    matches!(resource.category, EntityCategory::Resource { .. })
+                })
+            }
+            Command::SetRallyPoint(SetRallyPointCommand {
+                rally_point: RallyPoint::Unit(unit),
+                ..
+            }) => (*unit, |unit, team| {
+                unit.team == team && matches!(unit.category, EntityCategory::Unit(_))
+            }),
+            Command::SetRallyPoint(SetRallyPointCommand {
+                rally_point: RallyPoint::Resource(resource),
+                ..
+            }) => (*resource, |resource, _team| {
+                matches!(resource.category, EntityCategory::Resource { .. })
+            }),
+            Command::Repair(RepairCommand { target, .. }) => (*target, |target, team| {
+                target.team == team
+                    && match &target.construction_site {
+                        // Helping out with construction is done the same way as repairing
+                        Some(site) => site.config.model == ConstructionModel::Assisted,
+                        None => target
+                            .health
+                            .as_ref()
+                            .is_some_and(|health| health.is_damaged()),
+                    }
+            }),
+            Command::ReturnResource(ReturnResourceCommand {
+                structure: Some(structure),
+                ..
+            }) => (*structure, |structure, team| {
+                structure.team == team
+                    && matches!(structure.category, EntityCategory::Structure { .. })
+            }),
+            _ => return Ok(()),
+        };
+        if target_id == actor_id {
+            return Err(CommandError::InvalidTarget(target_id));
+        }
+        let target = self.live_entity(target_id)?.borrow();
+        if !is_valid_target(&target, issuing_team) {
+            return Err(CommandError::InvalidTarget(target_id));
+        }
+        if let Command::Attack(..) = command {
+            if !self.team_setup.are_hostile(issuing_team, target.team) {
+                return Err(CommandError::InvalidTarget(target_id));
+            }
+        }
+        if let Command::ReturnResource(..) = command {
+            let held_resource = actor.unit().gathering.as_ref().unwrap().held_resource();
+            if let Some(held_resource) = held_resource {
+                if !self.is_drop_off_for(&target, held_resource.resource_type) {
+                    return Err(CommandError::InvalidTarget(target_id));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether gatherers can bring this type of resource back to the structure
+    fn is_drop_off_for(&self, structure: &Entity, resource_type: ResourceType) -> bool {
+        structure.construction_site.is_none()
+            && self
+                .drop_off_structures
+                .get(&resource_type)
+                .is_some_and(|structure_types| structure_types.contains(&structure.entity_type))
+    }
+
+    fn live_entity(&self, id: EntityId) -> Result<&RefCell<Entity>, CommandError> {
+        let entity = self
+            .find_entity(id)
+            .ok_or(CommandError::UnknownEntity(id))?;
+        if is_dead(&entity.borrow()) {
+            return Err(CommandError::EntityIsDead(id));
+        }
+        Ok(entity)
+    }
+
+    /// Look up an entity that is known to exist, for example because it was part of a
+    /// command that passed validation
+    fn entity(&self, id: EntityId) -> &RefCell<Entity> {
+        self.find_entity(id)
+            .unwrap_or_else(|| panic!("Unknown entity: {:?}", id))
+    }
+
+    fn unit_return_resource(&self, mut gatherer: RefMut<Entity>, structure: Option<Ref<Entity>>) {
+        let held_resource_type = gatherer
+            .unit()
+            .gathering
+            .as_ref()
+            .and_then(|gathering| gathering.held_resource())
+            .expect("Unit must be carrying a resource")
+            .resource_type;
+        let structure = structure.or_else(|| {
+            // No specific structure was selected as the destination, so we pick one
+            for (_entity_id, entity) in &self.entities {
+                match entity.try_borrow() {
+                    Ok(entity)
+                        if entity.team == gatherer.team
+                            && self.is_drop_off_for(&entity, held_resource_type) =>
+                    {
+                        //TODO find the closest structure
+                        return Some(entity);
+                    }
+                    _ => {}
+                };
+            }
+            None
+        });
+
+        if let Some(structure) = structure {
+            if let Some(plan) = pathfind::find_path(
+                gatherer.position,
+                Destination::AdjacentToEntity(structure.cell_rect()),
+                &self.obstacle_grid,
+            ) {
+                gatherer.state = EntityState::ReturningResource(structure.id);
+                gatherer.unit_mut().movement_plan.set(plan);
+            } else {
+                gatherer.state = EntityState::Idle;
+            }
+        } else {
+            gatherer.state = EntityState::Idle;
+            eprintln!("WARN: Couldn't return resource. No structure found?");
+        }
+    }
+
+    pub fn team_state_unchecked(&self, team: &Team) -> &RefCell<TeamState> {
+        self.teams
+            .get(team)
+            .unwrap_or_else(|| panic!("Unknown team: {:?}", team))
+    }
+
+    pub fn team_state(&self, team: &Team) -> Option<&RefCell<TeamState>> {
+        self.teams.get(team)
+    }
+
+    /// Set once the victory condition has been met. The simulation may still be stepped
+    /// afterwards, but the result doesn't change.
+    pub fn match_result(&self) -> Option<&MatchResult> {
+        self.match_result.as_ref()
+    }
+
+    /// What the team has seen of the map. Neutral has no visibility grid.
+    pub fn visibility(&self, team: &Team) -> Option<&Grid<Visibility>> {
+        self.visibility.get(team)
+    }
+
+    /// Entities are always visible to their own team and its allies. Other entities are visible
+    /// when any cell they occupy is currently in sight. Teams without a visibility grid see
+    /// everything.
+    pub fn is_visible_to(&self, entity: &Entity, team: &Team) -> bool {
+        let visibility = match self.visibility.get(team) {
+            Some(visibility) if !self.team_setup.are_allied(entity.team, *team) => visibility,
+            _ => return true,
+        };
+        let rect = entity.cell_rect();
+        (rect.position[0]..rect.position[0] + rect.size[0]).any(|x| {
+            (rect.position[1]..rect.position[1] + rect.size[1])
+                .any(|y| visibility.get(&[x, y]) == Some(Visibility::Visible))
+        })
+    }
+
+    /// The entities that the team can currently see. Use this rather than `entities()` when
+    /// acting on behalf of a team, so that nothing is revealed through the fog of war.
+    pub fn visible_entities<'a>(
+        &'a self,
+        team: &'a Team,
+    ) -> impl Iterator<Item = &'a (EntityId, RefCell<Entity>)> {
+        self.entities
+            .iter()
+            .filter(move |(_id, entity)| self.is_visible_to(&entity.borrow(), team))
+    }
+
+    pub fn teams(&self) -> Vec<Team> {
+        self.teams.keys().copied().collect()
+    }
+
+    pub fn team_setup(&self) -> &TeamSetup {
+        &self.team_setup
+    }
+
+    pub fn set_triggers(&mut self, triggers: Vec<Trigger>) {
+        self.triggers = triggers;
+    }
+
+    pub fn entities(&self) -> &[(EntityId, RefCell<Entity>)] {
+        &self.entities
+    }
+
+    pub fn projectiles(&self) -> &[Projectile] {
+        &self.projectiles
+    }
+
+    pub fn dimensions(&self) -> [u32; 2] {
+        self.obstacle_grid.dimensions()
+    }
+
+    pub fn structure_size(&self, structure_type: &EntityType) -> &[u32; 2] {
+        self.structure_sizes
+            .get(structure_type)
+            .expect("Unknown structure type")
+    }
+
+    pub fn obstacle_grid(&self) -> &ObstacleGrid {
+        &self.obstacle_grid
+    }
+
+    fn send_to_rally_point(&self, unit_id: EntityId, team: Team, rally_point: RallyPoint) {
+        let command = match rally_point {
+            RallyPoint::Position(destination) => Command::Move(MoveCommand {
+                unit: unit_id,
+                destination,
+            }),
+            RallyPoint::Unit(followed_id) => {
+                // The path is planned once the unit is updated, and again whenever the followed
+                // unit moves on
+                if self.find_entity(followed_id).is_some() {
+                    self.entity(unit_id).borrow_mut().state = EntityState::Following(followed_id);
+                }
+                return;
+            }
+            RallyPoint::Resource(resource) => {
+                let can_gather = self.entity(unit_id).borrow().unit().gathering.is_some();
+                if can_gather {
+                    Command::GatherResource(GatherResourceCommand {
+                        gatherer: unit_id,
+                        resource,
+                    })
+                } else {
+                    let resource_position = match self.find_entity(resource) {
+                        Some(resource) => resource.borrow().position,
+                        None => return,
+                    };
+                    Command::Move(MoveCommand {
+                        unit: unit_id,
+                        destination: resource_position,
+                    })
+                }
+            }
+        };
+        if let Err(e) = self.issue_command(command, team) {
+            println!("Trained unit couldn't head for the rally point: {:?}", e);
+        }
+    }
+
+    fn try_add_trained_entity(
+        &mut self,
+        entity_type: EntityType,
+        team: Team,
+        source_rect: CellRect,
+    ) -> Option<EntityId> {
+        let position = self.free_cell_near(source_rect)?;
+        let new_unit = self.create_entity(entity_type, position, team);
+        let id = new_unit.id;
+        let rect = new_unit.cell_rect();
+        self.entities.push((id, RefCell::new(new_unit)));
+        self.obstacle_grid
+            .set_area(rect, ObstacleType::Entity(team));
+        Some(id)
+    }
+
+    // A path for a unit standing within the rect, to a free cell right next to it
+    fn find_path_out_of(&self, position: [u32; 2], rect: CellRect) -> Option<Vec<[u32; 2]>> {
+        let left = rect.position[0].saturating_sub(1);
+        let top = rect.position[1].saturating_sub(1);
+        let right = rect.position[0] + rect.size[0];
+        let bot = rect.position[1] + rect.size[1];
+        for x in left..right + 1 {
+            for y in top..bot + 1 {
+                let is_free = !rect.contains([x, y])
+                    && self
+                        .obstacle_grid
+                        .get(&[x, y])
+                        .is_some_and(|obstacle| obstacle == ObstacleType::None);
+                if is_free {
+                    if let Some(plan) = pathfind::find_path(
+                        position,
+                        Destination::Point([x, y]),
+                        &self.obstacle_grid,
+                    ) {
+                        return Some(plan);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    // A free cell within the rect, or right next to it
+    fn free_cell_near(&self, rect: CellRect) -> Option<[u32; 2]> {
+        let left = rect.position[0].saturating_sub(1);
+        let top = rect.position[1].saturating_sub(1);
+        let right = min(
+            rect.position[0] + rect.size[0],
+            self.obstacle_grid.dimensions()[0] - 1,
+        );
+        let bot = min(
+            rect.position[1] + rect.size[1],
+            self.obstacle_grid.dimensions()[1] - 1,
+        );
+        for x in left..right + 1 {
+            for y in top..bot + 1 {
+                let is_free = self
+                    .obstacle_grid
+                    .get(&[x, y])
+                    .is_some_and(|obstacle| obstacle == ObstacleType::None);
+                if is_free {
+                    return Some([x, y]);
+                }
+            }
+        }
+        None
+    }
+
+    fn create_entity(&mut self, entity_type: EntityType, position: [u32; 2], team: Team) -> Entity {
+        let id = self.entity_ids.next();
+        let team_state = self.team_state_unchecked(&team).borrow();
+        data::create_entity_with(
+            &self.definitions,
+            entity_type,
+            id,
+            position,
+            team,
+            &team_state.tech,
+        )
+    }
+
+    fn find_entity(&self, id: EntityId) -> Option<&RefCell<Entity>> {
+        //println!("find_entity({:?})", id);
+        self.entities.iter().find_map(
+            |(entity_id, entity)| {
+                if entity_id == &id {
+                    Some(entity)
+                } else {
+                    None
+                }
+            },
+        )
+    }
+}
+
+// Fully repairing something costs this many times less of each resource than it took to make
+// it. Things that didn't cost anything are repaired for free.
+const REPAIR_COST_DIVISOR: u32 = 2;
+
+// How much health one unit of a resource pays for, given what the entity cost of that resource
+fn repair_health_per_resource(max_health: u32, resource_cost: u32) -> u32 {
+    (max_health * REPAIR_COST_DIVISOR / resource_cost).max(1)
+}
+
+// Structures that grow during construction start out with a tenth of their health. One that takes
+// no time at all is done right away.
+fn construction_health(max_health: u32, remaining: Duration, total: Duration) -> u32 {
+    let initial = (max_health / 10).max(1);
+    if total.is_zero() {
+        return max_health;
+    }
+    let progress = 1.0 - remaining.as_secs_f32() / total.as_secs_f32();
+    initial + ((max_health - initial) as f32 * progress) as u32
+}
+
+fn unit_melee_direction(unit_position: [u32; 2], rect: CellRect) -> Option<Direction> {
+    unit_direction_within_range(unit_position, rect, 1)
+}
+
+// The direction that the unit should face to reach the rect, if the closest cell of the rect is
+// at most `range` cells away (diagonals included)
+fn unit_direction_within_range(
+    unit_position: [u32; 2],
+    rect: CellRect,
+    range: u32,
+) -> Option<Direction> {
+    let [x, y] = closest_cell(unit_position, &rect);
+    let dx = x as i32 - unit_position[0] as i32;
+    let dy = y as i32 - unit_position[1] as i32;
+    if dx.unsigned_abs().max(dy.unsigned_abs()) > range {
+        return None;
+    }
+    match [dx.signum(), dy.signum()] {
+        [0, -1] => Some(Direction::North),
+        [1, -1] => Some(Direction::NorthEast),
+        [1, 0] => Some(Direction::East),
+        [1, 1] => Some(Direction::SouthEast),
+        [0, 1] => Some(Direction::South),
+        [-1, 1] => Some(Direction::SouthWest),
+        [-1, 0] => Some(Direction::West),
+        [-1, -1] => Some(Direction::NorthWest),
+        // The unit is inside the rect
+        _ => None,
+    }
+}
+
+// How far (in cells, diagonals included) a unit that engaged an enemy on its own may stray from
+// its guard point, when it's in the defensive stance
+const DEFENSIVE_LEASH_DISTANCE: u32 = 6;
+
+// Whether a unit that engaged an enemy on its own should stop chasing it, rather than move any
+// further from its guard point
+fn should_abandon_chase(unit: &UnitComponent, position: [u32; 2]) -> bool {
+    let guard_point = match unit.combat.as_ref().and_then(|combat| combat.guard_point()) {
+        Some(guard_point) => guard_point,
+        None => return false,
+    };
+    match unit.stance {
+        Stance::Aggressive => false,
+        Stance::Defensive => distance(position, guard_point) >= DEFENSIVE_LEASH_DISTANCE,
+        Stance::HoldPosition | Stance::Passive => true,
+    }
+}
+
+fn closest_cell(point: [u32; 2], rect: &CellRect) -> [u32; 2] {
+    [
+        point[0].clamp(rect.position[0], rect.position[0] + rect.size[0] - 1),
+        point[1].clamp(rect.position[1], rect.position[1] + rect.size[1] - 1),
+    ]
+}
+
+// Scrambles the match seed with SplitMix64, so that similar seeds still give unrelated hit rolls.
+// The xorshift state below must never be zero.
+fn initial_random_state(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (z ^ (z >> 31)).max(1)
+}
+
+// A small xorshift generator, which (unlike StdRng) can be saved along with the rest of the
+// state. Returns a number in [0, 1).
+fn next_random(state: &mut u64) -> f32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    (*state >> 40) as f32 / (1u64 << 24) as f32
+}
+
+fn is_dead(entity: &Entity) -> bool {
+    entity
+        .health
+        .as_ref()
+        .map(|health| health.current == 0)
+        .unwrap_or(false)
+}
+
+// Diagonal steps count as one, so this is the number of steps needed to reach the rect
+fn distance_to_rect(point: [u32; 2], rect: &CellRect) -> u32 {
+    distance(point, closest_cell(point, rect))
+}
+
+fn distance(a: [u32; 2], b: [u32; 2]) -> u32 {
+    a[0].abs_diff(b[0]).max(a[1].abs_diff(b[1]))
+}
+
+fn square_distance_to_rect(point: [u32; 2], rect: &CellRect) -> u32 {
+    square_distance(point, closest_cell(point, rect))
+}
+
+fn square_distance(a: [u32; 2], b: [u32; 2]) -> u32 {
+    ((a[0] as i32 - b[0] as i32).pow(2) + (a[1] as i32 - b[1] as i32).pow(2)) as u32
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TeamState {
+    pub resources: BTreeMap<ResourceType, u32>,
+    pub tech: TechState,
+    pub stats: MatchStats,
+    pub is_eliminated: bool,
+}
+
+impl TeamState {
+    pub fn resource(&self, resource_type: ResourceType) -> u32 {
+        self.resources.get(&resource_type).copied().unwrap_or(0)
+    }
+
+    /// The first resource that there isn't enough of to pay the cost, if any
+    pub fn missing_resource(&self, cost: &Cost) -> Option<ResourceType> {
+        ResourceType::ALL
+            .into_iter()
+            .find(|resource_type| self.resource(*resource_type) < cost.get(*resource_type))
+    }
+
+    pub fn can_afford(&self, cost: &Cost) -> bool {
+        self.missing_resource(cost).is_none()
+    }
+
+    fn pay(&mut self, cost: &Cost) {
+        for resource_type in ResourceType::ALL {
+            *self.resources.entry(resource_type).or_default() -= cost.get(resource_type);
+        }
+    }
+
+    fn refund(&mut self, cost: &Cost) {
+        for resource_type in ResourceType::ALL {
+            *self.resources.entry(resource_type).or_default() += cost.get(resource_type);
+        }
+    }
+
+    fn receive_gathered(&mut self, resource_type: ResourceType, amount: u32) {
+        *self.resources.entry(resource_type).or_default() += amount;
+        match resource_type {
+            ResourceType::Fuel => self.stats.fuel_gathered += amount,
+            ResourceType::Minerals => self.stats.minerals_gathered += amount,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Supply {
+    pub used: u32,
+    pub provided: u32,
+}
+
+impl Supply {
+    pub fn has_room_for(&self, cost: u32) -> bool {
+        self.used + cost <= self.provided
+    }
+}
+
+/// What a team has accomplished so far in the match, shown when the match is over
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MatchStats {
+    pub units_trained: u32,
+    pub units_lost: u32,
+    pub units_killed: u32,
+    pub fuel_gathered: u32,
+    pub minerals_gathered: u32,
+    pub structures_built: u32,
+}
+
+impl MatchStats {
+    pub fn gathered(&self, resource_type: ResourceType) -> u32 {
+        match resource_type {
+            ResourceType::Fuel => self.fuel_gathered,
+            ResourceType::Minerals => self.minerals_gathered,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MatchResult {
+    /// The remaining teams, none of which are hostile to each other. Empty if the last
+    /// remaining teams were eliminated at the same time.
+    pub winners: Vec<Team>,
+    pub stats: BTreeMap<Team, MatchStats>,
+}
+
+/// How a match is won. A team that has no entities left is always eliminated.
+#[derive(Debug, Copy, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub enum VictoryCondition {
+    /// The last team with any entities left wins
+    #[default]
+    Annihilation,
+    /// A team is eliminated once it has no structures left, and no units that could build one
+    DestroyAllStructures,
+    /// The first team to gather this much of the resource wins
+    ResourceTarget(ResourceType, u32),
+}
+
+impl Display for VictoryCondition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            VictoryCondition::Annihilation => write!(f, "annihilation"),
+            VictoryCondition::DestroyAllStructures => write!(f, "structures"),
+            VictoryCondition::ResourceTarget(resource_type, target) => {
+                write!(f, "{} {}", resource_type, target)
+            }
+        }
+    }
+}
+
+impl FromStr for VictoryCondition {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens: Vec<&str> = s.split_whitespace().collect();
+        match tokens[..] {
+            ["annihilation"] => Ok(VictoryCondition::Annihilation),
+            ["structures"] => Ok(VictoryCondition::DestroyAllStructures),
+            [resource, target] => {
+                let resource_type = ResourceType::ALL
+                    .into_iter()
+                    .find(|resource_type| resource_type.to_string() == resource)
+                    .ok_or(())?;
+                let target = target.parse().map_err(|_| ())?;
+                Ok(VictoryCondition::ResourceTarget(resource_type, target))
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+/// How two teams treat each other. Only hostile teams attack each other, and allied teams
+/// share vision.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Relation {
+    Allied,
+    Neutral,
+    Hostile,
+}
+
+impl Display for Relation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Relation::Allied => write!(f, "allied"),
+            Relation::Neutral => write!(f, "neutral"),
+            Relation::Hostile => write!(f, "hostile"),
+        }
+    }
+}
+
+impl FromStr for Relation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allied" => Ok(Relation::Allied),
+            "neutral" => Ok(Relation::Neutral),
+            "hostile" => Ok(Relation::Hostile),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The colors and diplomatic relations of the teams in a match, and how much supply each team
+/// can have. Unless stated otherwise, players are hostile to each other, and everyone is neutral
+/// towards the neutral team.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TeamSetup {
+    colors: BTreeMap<Team, [u8; 3]>,
+    // Relations go both ways, and are stored with the lower team first
+    relations: BTreeMap<(Team, Team), Relation>,
+    max_supply: Option<u32>,
+}
+
+impl TeamSetup {
+    pub fn max_supply(&self) -> u32 {
+        self.max_supply.unwrap_or(data::DEFAULT_MAX_SUPPLY)
+    }
+
+    pub fn set_max_supply(&mut self, max_supply: u32) {
+        self.max_supply = Some(max_supply);
+    }
+
+    pub fn color(&self, team: Team) -> [u8; 3] {
+        self.colors
+            .get(&team)
+            .copied()
+            .unwrap_or_else(|| data::default_team_color(team))
+    }
+
+    pub fn set_color(&mut self, team: Team, color: [u8; 3]) {
+        self.colors.insert(team, color);
+    }
+
+    /// Colors that have been set explicitly, rather than falling back to the defaults
+    pub fn custom_colors(&self) -> impl Iterator<Item = (Team, [u8; 3])> + '_ {
+        self.colors.iter().map(|(team, color)| (*team, *color))
+    }
+
+    /// Relations that have been set explicitly, rather than falling back to the defaults
+    pub fn custom_relations(&self) -> impl Iterator<Item = (Team, Team, Relation)> + '_ {
+        self.relations
+            .iter()
+            .map(|((team, other_team), relation)| (*team, *other_team, *relation))
+    }
+
+    pub fn relation(&self, team: Team, other_team: Team) -> Relation {
+        if team == other_team {
+            return Relation::Allied;
+        }
+        if team == Team::Neutral || other_team == Team::Neutral {
+            return Relation::Neutral;
+        }
+        let key = (min(team, other_team), max(team, other_team));
+        self.relations
+            .get(&key)
+            .copied()
+            .unwrap_or(Relation::Hostile)
+    }
+
+    pub fn set_relation(&mut self, team: Team, other_team: Team, relation: Relation) {
+        let key = (min(team, other_team), max(team, other_team));
+        self.relations.insert(key, relation);
+    }
+
+    pub fn are_hostile(&self, team: Team, other_team: Team) -> bool {
+        self.relation(team, other_team) == Relation::Hostile
+    }
+
+    pub fn are_allied(&self, team: Team, other_team: Team) -> bool {
+        self.relation(team, other_team) == Relation::Allied
+    }
+}
+
+/// What a team has researched, and what it's in the middle of researching
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TechState {
+    researched: Vec<ResearchTopic>,
+    in_progress: Vec<ResearchTopic>,
+}
+
+impl TechState {
+    pub fn researched(&self) -> impl Iterator<Item = ResearchTopic> + '_ {
+        self.researched.iter().copied()
+    }
+
+    pub fn has_researched(&self, topic: ResearchTopic) -> bool {
+        self.researched.contains(&topic)
+    }
+
+    /// A topic can only be researched once
+    pub fn can_start(&self, topic: ResearchTopic) -> bool {
+        !self.researched.contains(&topic) && !self.in_progress.contains(&topic)
+    }
+
+    fn start(&mut self, topic: ResearchTopic) {
+        self.in_progress.push(topic);
+    }
+
+    fn cancel(&mut self, topic: ResearchTopic) {
+        self.in_progress.retain(|t| *t != topic);
+    }
+
+    fn finish(&mut self, topic: ResearchTopic) {
+        self.cancel(topic);
+        self.researched.push(topic);
+    }
+}
+
+pub struct CommandSuccess {
+    pub did_tech_state_change: bool,
+}
+
+pub struct UpdateOutcome {
+    pub removed_entities: Vec<EntityId>,
+    pub finished_structures: Vec<EntityId>,
+    pub did_tech_state_change: bool,
+    pub eliminated_teams: Vec<Team>,
+    /// Only set on the update where the match ended
+    pub match_result: Option<MatchResult>,
+    /// Shown by map triggers
+    pub messages: Vec<String>,
+}
+
+/// How much a team knows about a cell of the map
+#[derive(Debug, Copy, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub enum Visibility {
+    #[default]
+    Unexplored,
+    /// Has been seen before, but isn't in sight right now
+    Explored,
+    Visible,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub enum ObstacleType {
+    Entity(Team),
+    Water,
+    #[default]
+    None,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CommandError {
+    NotEnoughResources(ResourceType),
+    NoPathFound,
+    NotCarryingResource,
+    NotEnoughSpaceForStructure,
+    QueueIsFull,
+    NothingToCancel,
+    UnknownEntity(EntityId),
+    EntityIsDead(EntityId),
+    NotOwnedByTeam(EntityId),
+    IncompatibleEntity(EntityId),
+    InvalidTarget(EntityId),
+    MissingPrerequisites,
+    AlreadyResearched,
+    SupplyBlocked,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::entities::MAX_ACTIVITY_QUEUE_LENGTH;
+    use crate::simulation::DEFAULT_TICK_DURATION;
+
+    /// Entities of the given types, at the given positions. The ids are returned in the same order.
+    fn create_entities(entities: &[(&str, [u32; 2], Team)]) -> (Vec<Entity>, Vec<EntityId>) {
+        let mut entity_ids = EntityIdAllocator::new();
+        let tech = TechState::default();
+        entities
+            .iter()
+            .map(|(name, position, team)| {
+                let id = entity_ids.next();
+                let entity =
+                    data::create_entity(EntityType::named(name), id, *position, *team, &tech);
+                (entity, id)
+            })
+            .unzip()
+    }
+
+    /// A core with the given entities on a map without water, where all teams are enemies
+    fn create_core(
+        dimensions: [u32; 2],
+        entities: &[(&str, [u32; 2], Team)],
+    ) -> (Core, Vec<EntityId>) {
+        let (entities, ids) = create_entities(entities);
+        let core = Core::new(
+            entities,
+            dimensions,
+            vec![],
+            VictoryCondition::default(),
+            TeamSetup::default(),
+            0,
+        );
+        (core, ids)
+    }
+
+    #[test]
+    fn teams_only_see_what_is_in_sight() {
+        let (core, _ids) = create_core(
+            [30, 20],
+            &[
+                ("Engineer", [3, 8], Team::Player(1)),
+                ("Engineer", [27, 8], Team::Player(2)),
+            ],
+        );
+
+        let visible_teams: Vec<Team> = core
+            .visible_entities(&Team::Player(1))
+            .map(|(_id, entity)| entity.borrow().team)
+            .collect();
+        assert!(visible_teams.contains(&Team::Player(1)));
+        assert!(!visible_teams.contains(&Team::Player(2)));
+
+        let visibility = core.visibility(&Team::Player(1)).unwrap();
+        assert_eq!(visibility.get(&[3, 8]), Some(Visibility::Visible));
+        assert_eq!(visibility.get(&[24, 0]), Some(Visibility::Unexplored));
+    }
+
+    #[test]
+    fn rangers_attack_from_a_distance() {
+        let (mut core, ids) = create_core(
+            [10, 10],
+            &[
+                ("Ranger", [2, 2], Team::Player(0)),
+                ("Engineer", [5, 2], Team::Player(1)),
+            ],
+        );
+        let (ranger_id, victim_id) = (ids[0], ids[1]);
+
+        let attack = Command::Attack(AttackCommand {
+            attacker: ranger_id,
+            victim: victim_id,
+        });
+        core.issue_command(attack, Team::Player(0)).unwrap();
+        core.update(DEFAULT_TICK_DURATION);
+        assert_eq!(core.projectiles().len(), 1);
+
+        for _ in 0..250 {
+            core.update(DEFAULT_TICK_DURATION);
+        }
+        let entity_ids: Vec<EntityId> = core.entities().iter().map(|(id, _)| *id).collect();
+        assert_eq!(
+            entity_ids,
+            vec![ranger_id],
+            "victim should have been shot down"
+        );
+        let ranger = core.entities()[0].1.borrow();
+        assert_eq!(ranger.position, [2, 2]);
+    }
+
+    #[test]
+    fn commands_must_name_a_live_entity_of_the_issuing_team() {
+        let (core, ids) = create_core(
+            [10, 10],
+            &[
+                ("Enforcer", [2, 2], Team::Player(0)),
+                ("Enforcer", [6, 2], Team::Player(1)),
+                ("Enforcer", [6, 5], Team::Player(1)),
+            ],
+        );
+        let (enforcer_id, enemy_id, dead_id) = (ids[0], ids[1], ids[2]);
+        core.entities()[2]
+            .1
+            .borrow_mut()
+            .health
+            .as_mut()
+            .unwrap()
+            .current = 0;
+        // The ids are handed out in order, so the next one isn't used by any entity
+        let mut entity_ids = EntityIdAllocator::new();
+        let unknown_id = (0..=ids.len()).map(|_| entity_ids.next()).last().unwrap();
+
+        let snapshot = || -> Vec<(EntityId, EntityState, [u32; 2])> {
+            core.entities()
+                .iter()
+                .map(|(id, entity)| {
+                    let entity = entity.borrow();
+                    (*id, entity.state, entity.position)
+                })
+                .collect()
+        };
+        let before = snapshot();
+        let move_command = |unit| {
+            Command::Move(MoveCommand {
+                unit,
+                destination: [4, 8],
+            })
+        };
+        let attack_command = |victim| {
+            Command::Attack(AttackCommand {
+                attacker: enforcer_id,
+                victim,
+            })
+        };
+
+        assert_eq!(
+            core.issue_command(move_command(unknown_id), Team::Player(0))
+                .err(),
+            Some(CommandError::UnknownEntity(unknown_id))
+        );
+        assert_eq!(
+            core.issue_command(move_command(enemy_id), Team::Player(0))
+                .err(),
+            Some(CommandError::NotOwnedByTeam(enemy_id))
+        );
+        assert_eq!(
+            core.issue_command(move_command(dead_id), Team::Player(1))
+                .err(),
+            Some(CommandError::EntityIsDead(dead_id))
+        );
+        assert_eq!(
+            core.issue_command(attack_command(unknown_id), Team::Player(0))
+                .err(),
+            Some(CommandError::UnknownEntity(unknown_id))
+        );
+        assert_eq!(
+            core.issue_command(attack_command(dead_id), Team::Player(0))
+                .err(),
+            Some(CommandError::EntityIsDead(dead_id))
+        );
+        assert_eq!(snapshot(), before);
+    }
+
+    #[test]
+    fn hit_rolls_depend_on_the_match_seed() {
+        // The health of the target each time a projectile lands, hit or miss
+        let shoot = |seed| {
+            let (entities, ids) = create_entities(&[
+                ("Ranger", [2, 2], Team::Player(0)),
+                ("TechLab", [5, 2], Team::Player(1)),
+            ]);
+            let mut core = Core::new(
+                entities,
+                [10, 10],
+                vec![],
+                VictoryCondition::default(),
+                TeamSetup::default(),
+                seed,
+            );
+            let attack = Command::Attack(AttackCommand {
+                attacker: ids[0],
+                victim: ids[1],
+            });
+            core.issue_command(attack, Team::Player(0)).unwrap();
+            let mut healths = vec![];
+            for _ in 0..500 {
+                let num_projectiles = core.projectiles().len();
+                core.update(DEFAULT_TICK_DURATION);
+                if core.projectiles().len() < num_projectiles {
+                    let target = core.entities()[1].1.borrow();
+                    healths.push(target.health.as_ref().unwrap().current);
                 }
             }
-        }
-        Ok(CommandSuccess {
-            did_research_state_change: false,
-        })
+            healths
+        };
+
+        assert_eq!(shoot(1), shoot(1));
+        let outcomes: Vec<Vec<u32>> = (0..4).map(shoot).collect();
+        assert!(outcomes.iter().any(|outcome| *outcome != outcomes[0]));
     }
 
-    fn unit_return_resource(&self, mut gatherer: RefMut<Entity>, structure: Option<Ref<Entity>>) {
-        let structure = structure.or_else(|| {
-            // No specific structure was selected as the destination, so we pick one
-            for (_entity_id, entity) in &self.entities {
-                match entity.try_borrow() {
-                    Ok(entity) if entity.team == gatherer.team => {
-                        // For now, resources can be returned to any friendly structure
-                        if let EntityCategory::Structure { .. } = entity.category {
-                            //TODO find the closest structure
-                            return Some(entity);
-                        }
-                    }
-                    _ => {}
-                };
-            }
-            None
+    #[test]
+    fn structures_queue_activities_and_refund_cancelled_ones() {
+        let (core, ids) = create_core([10, 10], &[("TechLab", [2, 2], Team::Player(0))]);
+        let tech_lab_id = ids[0];
+        let resources = || {
+            core.team_state_unchecked(&Team::Player(0))
+                .borrow()
+                .resource(ResourceType::Fuel)
+        };
+        let train = Command::StartActivity(StartActivityCommand {
+            structure: tech_lab_id,
+            target: ActivityTarget::Train(EntityType::named("Engineer")),
         });
 
-        if let Some(structure) = structure {
-            if let Some(plan) = pathfind::find_path(
-                gatherer.position,
-                Destination::AdjacentToEntity(structure.cell_rect()),
-                &self.obstacle_grid,
-            ) {
-                gatherer.state = EntityState::ReturningResource(structure.id);
-                gatherer.unit_mut().movement_plan.set(plan);
-            } else {
-                gatherer.state = EntityState::Idle;
-            }
-        } else {
-            gatherer.state = EntityState::Idle;
-            eprintln!("WARN: Couldn't return resource. No structure found?");
+        for _ in 0..MAX_ACTIVITY_QUEUE_LENGTH {
+            core.issue_command(train.clone(), Team::Player(0)).unwrap();
         }
-    }
+        assert_eq!(resources(), 10);
+        assert_eq!(
+            core.issue_command(train, Team::Player(0)).err(),
+            Some(CommandError::QueueIsFull)
+        );
 
-    pub fn team_state_unchecked(&self, team: &Team) -> &RefCell<TeamState> {
-        self.teams
-            .get(team)
-            .unwrap_or_else(|| panic!("Unknown team: {:?}", team))
+        let cancel = Command::CancelActivity(CancelActivityCommand {
+            structure: tech_lab_id,
+            slot: 0,
+        });
+        core.issue_command(cancel, Team::Player(0)).unwrap();
+        assert_eq!(resources(), 11);
+        let tech_lab = core.entities()[0].1.borrow();
+        assert_eq!(tech_lab.activity.as_ref().unwrap().queue_length(), 4);
+        assert_eq!(
+            tech_lab.state,
+            EntityState::DoingActivity(ActivityTarget::Train(EntityType::named("Engineer")))
+        );
     }
 
-    pub fn team_state(&self, team: &Team) -> Option<&RefCell<TeamState>> {
-        self.teams.get(team)
+    #[test]
+    fn trained_units_head_for_the_rally_point() {
+        let (mut core, ids) = create_core(
+            [12, 12],
+            &[
+                ("TechLab", [2, 2], Team::Player(0)),
+                ("FuelRift", [8, 8], Team::Neutral),
+            ],
+        );
+        let (tech_lab_id, resource_id) = (ids[0], ids[1]);
+
+        let commands = [
+            Command::SetRallyPoint(SetRallyPointCommand {
+                structure: tech_lab_id,
+                rally_point: RallyPoint::Resource(resource_id),
+            }),
+            Command::StartActivity(StartActivityCommand {
+                structure: tech_lab_id,
+                target: ActivityTarget::Train(EntityType::named("Engineer")),
+            }),
+        ];
+        for command in commands {
+            core.issue_command(command, Team::Player(0)).unwrap();
+        }
+        for _ in 0..500 {
+            core.update(DEFAULT_TICK_DURATION);
+        }
+
+        let (_id, engineer) = core
+            .entities()
+            .iter()
+            .find(|(_id, e)| e.borrow().entity_type == EntityType::named("Engineer"))
+            .unwrap();
+        assert!(
+            matches!(
+                engineer.borrow().state,
+                EntityState::MovingToResource(id) | EntityState::GatheringResource(id)
+                    if id == resource_id
+            ),
+            "{:?}",
+            engineer.borrow().state
+        );
     }
 
-    pub fn entities(&self) -> &[(EntityId, RefCell<Entity>)] {
-        &self.entities
+    #[test]
+    fn trained_units_keep_following_the_rally_unit() {
+        let (mut core, ids) = create_core(
+            [16, 12],
+            &[
+                ("TechLab", [2, 2], Team::Player(0)),
+                ("Enforcer", [8, 2], Team::Player(0)),
+            ],
+        );
+        let (tech_lab_id, enforcer_id) = (ids[0], ids[1]);
+
+        let commands = [
+            Command::SetRallyPoint(SetRallyPointCommand {
+                structure: tech_lab_id,
+                rally_point: RallyPoint::Unit(enforcer_id),
+            }),
+            Command::StartActivity(StartActivityCommand {
+                structure: tech_lab_id,
+                target: ActivityTarget::Train(EntityType::named("Engineer")),
+            }),
+        ];
+        for command in commands {
+            core.issue_command(command, Team::Player(0)).unwrap();
+        }
+        let follower = |core: &Core| {
+            let (_id, engineer) = core
+                .entities()
+                .iter()
+                .find(|(_id, e)| e.borrow().entity_type == EntityType::named("Engineer"))
+                .unwrap();
+            let engineer = engineer.borrow();
+            (engineer.state, engineer.position)
+        };
+        let is_next_to = |[x, y]: [u32; 2], [other_x, other_y]: [u32; 2]| {
+            x.abs_diff(other_x) <= 1 && y.abs_diff(other_y) <= 1
+        };
+        for _ in 0..700 {
+            core.update(DEFAULT_TICK_DURATION);
+        }
+        let (state, position) = follower(&core);
+        assert_eq!(state, EntityState::Following(enforcer_id));
+        assert!(is_next_to(position, [8, 2]), "{:?}", position);
+
+        // Rather than going to where the enforcer was, it goes after it
+        let move_away = Command::Move(MoveCommand {
+            unit: enforcer_id,
+            destination: [14, 10],
+        });
+        core.issue_command(move_away, Team::Player(0)).unwrap();
+        for _ in 0..1000 {
+            core.update(DEFAULT_TICK_DURATION);
+        }
+        let (state, position) = follower(&core);
+        assert_eq!(state, EntityState::Following(enforcer_id));
+        assert!(is_next_to(position, [14, 10]), "{:?}", position);
     }
 
-    pub fn dimensions(&self) -> [u32; 2] {
-        self.obstacle_grid.dimensions()
+    #[test]
+    fn units_carry_out_queued_commands_in_order() {
+        let (mut core, ids) = create_core([8, 8], &[("Engineer", [1, 1], Team::Player(0))]);
+        let engineer_id = ids[0];
+        let engineer = |core: &Core| core.entities()[0].1.clone().into_inner();
+        let move_to = |destination| {
+            Command::Move(MoveCommand {
+                unit: engineer_id,
+                destination,
+            })
+        };
+
+        core.queue_command(move_to([5, 1]), Team::Player(0))
+            .unwrap();
+        core.queue_command(move_to([5, 5]), Team::Player(0))
+            .unwrap();
+        core.queue_command(move_to([1, 5]), Team::Player(0))
+            .unwrap();
+        assert_eq!(engineer(&core).unit().command_queue.len(), 2);
+
+        let mut visited = vec![];
+        for _ in 0..1000 {
+            core.update(DEFAULT_TICK_DURATION);
+            let position = engineer(&core).position;
+            if visited.last() != Some(&position) {
+                visited.push(position);
+            }
+        }
+        let index_of = |cell| visited.iter().position(|visited| *visited == cell).unwrap();
+        assert!(index_of([5, 1]) < index_of([5, 5]));
+        assert!(index_of([5, 5]) < index_of([1, 5]));
+        assert_eq!(visited.last(), Some(&[1, 5]));
+
+        // A plain command replaces the queue, and so does Stop
+        core.queue_command(move_to([1, 1]), Team::Player(0))
+            .unwrap();
+        core.queue_command(move_to([5, 5]), Team::Player(0))
+            .unwrap();
+        core.issue_command(move_to([3, 3]), Team::Player(0))
+            .unwrap();
+        assert!(engineer(&core).unit().command_queue.is_empty());
+        core.queue_command(move_to([5, 5]), Team::Player(0))
+            .unwrap();
+        core.queue_command(
+            Command::Stop(StopCommand {
+                entity: engineer_id,
+            }),
+            Team::Player(0),
+        )
+        .unwrap();
+        assert!(engineer(&core).unit().command_queue.is_empty());
     }
 
-    pub fn structure_size(&self, structure_type: &EntityType) -> &[u32; 2] {
-        self.structure_sizes
-            .get(structure_type)
-            .expect("Unknown structure type")
+    #[test]
+    fn units_engage_enemies_that_come_near() {
+        let (mut core, ids) = create_core(
+            [12, 12],
+            &[
+                ("Enforcer", [1, 1], Team::Player(0)),
+                ("Enforcer", [1, 10], Team::Player(0)),
+                // Close to where the attack-mover passes by
+                ("Engineer", [6, 3], Team::Player(1)),
+                // Close to the idle guard
+                ("Engineer", [4, 10], Team::Player(1)),
+            ],
+        );
+        let (attack_mover_id, guard_id) = (ids[0], ids[1]);
+
+        let attack_move = Command::AttackMove(AttackMoveCommand {
+            unit: attack_mover_id,
+            destination: [10, 1],
+        });
+        core.issue_command(attack_move, Team::Player(0)).unwrap();
+        for _ in 0..1500 {
+            core.update(DEFAULT_TICK_DURATION);
+        }
+
+        let remaining: Vec<EntityId> = core.entities().iter().map(|(id, _)| *id).collect();
+        assert_eq!(remaining, vec![attack_mover_id, guard_id]);
+        let attack_mover = core.entities()[0].1.borrow();
+        assert_eq!(attack_mover.position, [10, 1]);
+        assert_eq!(attack_mover.state, EntityState::Idle);
     }
 
-    pub fn obstacle_grid(&self) -> &ObstacleGrid {
-        &self.obstacle_grid
+    #[test]
+    fn stances_control_how_units_engage_on_their_own() {
+        // Each guard has an enemy nearby, at a given distance
+        let setups = [
+            (Stance::Aggressive, 4),
+            (Stance::Defensive, 4),
+            (Stance::HoldPosition, 2),
+            (Stance::Passive, 1),
+        ];
+        let mut entities = vec![];
+        for (i, (_stance, distance)) in setups.iter().enumerate() {
+            let y = 1 + i as u32 * 7;
+            entities.push(("Enforcer", [1, y], Team::Player(0)));
+            entities.push(("Engineer", [1 + distance, y], Team::Player(1)));
+        }
+        let (mut core, ids) = create_core([12, 28], &entities);
+        let guards: Vec<(EntityId, EntityId, Stance)> = ids
+            .chunks(2)
+            .zip(setups)
+            .map(|(ids, (stance, _distance))| (ids[0], ids[1], stance))
+            .collect();
+        for (guard_id, _enemy_id, stance) in &guards {
+            let command = Command::SetStance(SetStanceCommand {
+                unit: *guard_id,
+                stance: *stance,
+            });
+            core.issue_command(command, Team::Player(0)).unwrap();
+        }
+
+        for _ in 0..1000 {
+            core.update(DEFAULT_TICK_DURATION);
+        }
+
+        let find = |id: EntityId| {
+            core.entities()
+                .iter()
+                .find(|(entity_id, _)| *entity_id == id)
+                .map(|(_, entity)| entity.borrow().position)
+        };
+        let outcomes: Vec<(Stance, Option<[u32; 2]>, bool)> = guards
+            .iter()
+            .map(|(guard_id, enemy_id, stance)| {
+                (*stance, find(*guard_id), find(*enemy_id).is_some())
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                // Stays where the fight ended
+                (Stance::Aggressive, Some([4, 1]), false),
+                // Heads back after the fight
+                (Stance::Defensive, Some([1, 8]), false),
+                // The enemy is out of reach
+                (Stance::HoldPosition, Some([1, 15]), true),
+                (Stance::Passive, Some([1, 22]), true),
+            ]
+        );
     }
 
-    fn try_add_trained_entity(
-        &mut self,
-        entity_type: EntityType,
-        team: Team,
-        source_rect: CellRect,
-    ) -> Option<[u32; 2]> {
-        let left = source_rect.position[0].saturating_sub(1);
-        let top = source_rect.position[1].saturating_sub(1);
-        let right = min(
-            source_rect.position[0] + source_rect.size[0],
-            self.obstacle_grid.dimensions()[0] - 1,
+    #[test]
+    fn passive_units_dont_fight_while_patrolling() {
+        let (mut core, ids) = create_core(
+            [12, 12],
+            &[
+                ("Enforcer", [1, 1], Team::Player(0)),
+                // Right next to the route
+                ("Engineer", [5, 2], Team::Player(1)),
+            ],
         );
-        let bot = min(
-            source_rect.position[1] + source_rect.size[1],
-            self.obstacle_grid.dimensions()[1] - 1,
+        let (patroller_id, enemy_id) = (ids[0], ids[1]);
+        let commands = [
+            Command::SetStance(SetStanceCommand {
+                unit: patroller_id,
+                stance: Stance::Passive,
+            }),
+            Command::Patrol(PatrolCommand {
+                unit: patroller_id,
+                destination: [10, 1],
+            }),
+        ];
+        for command in commands {
+            core.issue_command(command, Team::Player(0)).unwrap();
+        }
+
+        let mut visited = vec![];
+        for _ in 0..2000 {
+            core.update(DEFAULT_TICK_DURATION);
+            let patroller = core.entities()[0].1.borrow();
+            assert_ne!(patroller.state, EntityState::MovingToAttackTarget(enemy_id));
+            assert_ne!(patroller.state, EntityState::Attacking(enemy_id));
+            if [[1, 1], [10, 1]].contains(&patroller.position)
+                && visited.last() != Some(&patroller.position)
+            {
+                visited.push(patroller.position);
+            }
+        }
+
+        let enemy = core.entities()[1].1.borrow();
+        let health = enemy.health.as_ref().unwrap();
+        assert_eq!(health.current, health.max);
+        assert_eq!(visited[..3], [[10, 1], [1, 1], [10, 1]]);
+    }
+
+    #[test]
+    fn patrolling_units_fight_their_way_along_the_route() {
+        let (mut core, ids) = create_core(
+            [12, 12],
+            &[
+                ("Enforcer", [1, 1], Team::Player(0)),
+                // Close to the first leg of the route
+                ("Engineer", [6, 3], Team::Player(1)),
+            ],
         );
-        for x in left..right + 1 {
-            for y in top..bot + 1 {
-                let is_free = self
-                    .obstacle_grid
-                    .get(&[x, y])
-                    .map_or(false, |obstacle| obstacle == ObstacleType::None);
-                if is_free {
-                    let new_unit = self.create_entity(entity_type, [x, y], team);
-                    let rect = new_unit.cell_rect();
-                    self.entities.push((new_unit.id, RefCell::new(new_unit)));
-                    self.obstacle_grid
-                        .set_area(rect, ObstacleType::Entity(team));
-                    return Some([x, y]);
-                }
+        let patroller_id = ids[0];
+
+        let patrol_to = |destination| {
+            Command::Patrol(PatrolCommand {
+                unit: patroller_id,
+                destination,
+            })
+        };
+        core.queue_command(patrol_to([10, 1]), Team::Player(0))
+            .unwrap();
+        // Adds a point to the route, rather than waiting for the first patrol to end
+        core.queue_command(patrol_to([10, 8]), Team::Player(0))
+            .unwrap();
+        let mut visited = vec![];
+        for _ in 0..6000 {
+            core.update(DEFAULT_TICK_DURATION);
+            let position = core.entities()[0].1.borrow().position;
+            if [[1, 1], [10, 1], [10, 8]].contains(&position) && visited.last() != Some(&position) {
+                visited.push(position);
             }
         }
-        None
+
+        let remaining: Vec<EntityId> = core.entities().iter().map(|(id, _)| *id).collect();
+        assert_eq!(remaining, vec![patroller_id]);
+        assert_eq!(visited[..5], [[10, 1], [10, 8], [10, 1], [1, 1], [10, 1]]);
     }
 
-    fn create_entity(&self, entity_type: EntityType, position: [u32; 2], team: Team) -> Entity {
-        let research_state = self.team_state_unchecked(&team).borrow().research_state;
-        data::create_entity(entity_type, position, team, research_state)
+    #[test]
+    fn engineers_repair_structures_for_fuel() {
+        let (mut core, ids) = create_core(
+            [10, 10],
+            &[
+                ("Engineer", [1, 1], Team::Player(0)),
+                ("TechLab", [4, 1], Team::Player(0)),
+            ],
+        );
+        let (engineer_id, tech_lab_id) = (ids[0], ids[1]);
+        let health = |core: &Core| {
+            core.entities()[1]
+                .1
+                .borrow()
+                .health
+                .as_ref()
+                .unwrap()
+                .current
+        };
+        let set_resources = |core: &Core, resources| {
+            core.team_state_unchecked(&Team::Player(0))
+                .borrow_mut()
+                .resources
+                .insert(ResourceType::Fuel, resources);
+        };
+        let repair = Command::Repair(RepairCommand {
+            repairer: engineer_id,
+            target: tech_lab_id,
+        });
+        assert_eq!(
+            core.issue_command(repair.clone(), Team::Player(0)).err(),
+            Some(CommandError::InvalidTarget(tech_lab_id))
+        );
+
+        // Each fuel pays for half of the tech lab's health, so the repair stops halfway
+        core.entities()[1]
+            .1
+            .borrow_mut()
+            .health
+            .as_mut()
+            .unwrap()
+            .current = 2;
+        set_resources(&core, 1);
+        core.issue_command(repair.clone(), Team::Player(0)).unwrap();
+        for _ in 0..1000 {
+            core.update(DEFAULT_TICK_DURATION);
+        }
+        assert_eq!(health(&core), 17);
+        assert_eq!(core.entities()[0].1.borrow().state, EntityState::Idle);
+
+        // With more fuel, it goes on until the tech lab is fully repaired
+        set_resources(&core, 5);
+        core.issue_command(repair, Team::Player(0)).unwrap();
+        for _ in 0..1000 {
+            core.update(DEFAULT_TICK_DURATION);
+        }
+        assert_eq!(health(&core), 30);
+        assert_eq!(core.entities()[0].1.borrow().state, EntityState::Idle);
+        let resources = core
+            .team_state_unchecked(&Team::Player(0))
+            .borrow()
+            .resource(ResourceType::Fuel);
+        assert_eq!(resources, 4);
     }
 
-    fn find_entity(&self, id: EntityId) -> Option<&RefCell<Entity>> {
-        //println!("find_entity({:?})", id);
-        self.entities.iter().find_map(
-            |(entity_id, entity)| {
-                if entity_id == &id {
-                    Some(entity)
-                } else {
-                    None
-                }
-            },
-        )
+    #[test]
+    fn repairs_cost_every_resource_that_the_structure_cost() {
+        let (mut core, ids) = create_core(
+            [10, 10],
+            &[
+                ("Engineer", [1, 1], Team::Player(0)),
+                ("BattleAcademy", [4, 1], Team::Player(0)),
+            ],
+        );
+        let repair = Command::Repair(RepairCommand {
+            repairer: ids[0],
+            target: ids[1],
+        });
+        let health = |core: &Core| {
+            core.entities()[1]
+                .1
+                .borrow()
+                .health
+                .as_ref()
+                .unwrap()
+                .current
+        };
+        let resources = |core: &Core| {
+            let team_state = core.team_state_unchecked(&Team::Player(0)).borrow();
+            [
+                team_state.resource(ResourceType::Fuel),
+                team_state.resource(ResourceType::Minerals),
+            ]
+        };
+        let set_resources = |core: &Core, resource_type, amount| {
+            core.team_state_unchecked(&Team::Player(0))
+                .borrow_mut()
+                .resources
+                .insert(resource_type, amount);
+        };
+        core.entities()[1]
+            .1
+            .borrow_mut()
+            .health
+            .as_mut()
+            .unwrap()
+            .current = 2;
+
+        // The battle academy cost minerals too, so fuel alone isn't enough
+        set_resources(&core, ResourceType::Fuel, 10);
+        set_resources(&core, ResourceType::Minerals, 0);
+        core.issue_command(repair.clone(), Team::Player(0)).unwrap();
+        for _ in 0..1000 {
+            core.update(DEFAULT_TICK_DURATION);
+        }
+        assert_eq!(health(&core), 2);
+        assert_eq!(core.entities()[0].1.borrow().state, EntityState::Idle);
+
+        // Each fuel pays for 13 points of health and each mineral for 20 (the full cost of
+        // 3 fuel and 2 minerals would pay for 40)
+        set_resources(&core, ResourceType::Minerals, 1);
+        core.issue_command(repair, Team::Player(0)).unwrap();
+        for _ in 0..1000 {
+            core.update(DEFAULT_TICK_DURATION);
+        }
+        assert_eq!(health(&core), 20);
+        assert_eq!(resources(&core), [8, 0]);
     }
-}
 
-fn unit_melee_direction(unit_position: [u32; 2], rect: CellRect) -> Option<Direction> {
-    for x in rect.position[0]..rect.position[0] + rect.size[0] {
-        for y in rect.position[1]..rect.position[1] + rect.size[1] {
-            if square_distance(unit_position, [x, y]) <= 2 {
-                let unit_x = unit_position[0] as i32;
-                let unit_y = unit_position[1] as i32;
-
-                match [x as i32 - unit_x, y as i32 - unit_y] {
-                    [0, -1] => return Some(Direction::North),
-                    [1, -1] => return Some(Direction::NorthEast),
-                    [1, 0] => return Some(Direction::East),
-                    [1, 1] => return Some(Direction::SouthEast),
-                    [0, 1] => return Some(Direction::South),
-                    [-1, 1] => return Some(Direction::SouthWest),
-                    [-1, 0] => return Some(Direction::West),
-                    [-1, -1] => return Some(Direction::NorthWest),
-                    _ => {}
-                }
+    #[test]
+    fn cancelled_construction_gives_back_builder_and_part_of_the_cost() {
+        let (mut core, ids) = create_core([10, 10], &[("Engineer", [1, 1], Team::Player(0))]);
+        let engineer_id = ids[0];
+        let resources = |core: &Core| {
+            core.team_state_unchecked(&Team::Player(0))
+                .borrow()
+                .resource(ResourceType::Fuel)
+        };
+        let construct = Command::Construct(ConstructCommand {
+            builder: engineer_id,
+            structure_position: [4, 4],
+            structure_type: EntityType::named("TechLab"),
+        });
+        core.issue_command(construct, Team::Player(0)).unwrap();
+        assert_eq!(resources(&core), 11);
+
+        let mut structure_id = None;
+        let mut builder_position = [1, 1];
+        for _ in 0..500 {
+            builder_position = core.entities()[0].1.borrow().position;
+            let outcome = core.update(DEFAULT_TICK_DURATION);
+            if outcome.removed_entities.contains(&engineer_id) {
+                structure_id = Some(core.entities()[0].0);
+                break;
             }
         }
+        let structure_id = structure_id.expect("Construction never started");
+        let cancel = Command::CancelConstruction(CancelConstructionCommand {
+            structure: structure_id,
+        });
+        core.issue_command(cancel.clone(), Team::Player(0)).unwrap();
+        assert_eq!(
+            core.issue_command(cancel, Team::Player(0)).err(),
+            Some(CommandError::IncompatibleEntity(structure_id))
+        );
+        assert_eq!(resources(&core), 14);
+
+        let outcome = core.update(DEFAULT_TICK_DURATION);
+        assert_eq!(outcome.removed_entities, vec![structure_id]);
+        let remaining: Vec<EntityId> = core.entities().iter().map(|(id, _)| *id).collect();
+        assert_eq!(remaining, vec![engineer_id]);
+        let engineer = core.entities()[0].1.borrow();
+        assert_eq!(engineer.state, EntityState::Idle);
+        assert_eq!(engineer.position, builder_position);
     }
-    None
-}
 
-fn square_distance(a: [u32; 2], b: [u32; 2]) -> u32 {
-    ((a[0] as i32 - b[0] as i32).pow(2) + (a[1] as i32 - b[1] as i32).pow(2)) as u32
-}
+    #[test]
+    fn assisted_construction_grows_while_builders_work_on_it() {
+        // A tech lab is needed to unlock the battle academy
+        let (mut core, ids) = create_core(
+            [10, 10],
+            &[
+                ("Engineer", [1, 1], Team::Player(0)),
+                ("Engineer", [1, 8], Team::Player(0)),
+                ("TechLab", [7, 0], Team::Player(0)),
+            ],
+        );
+        let (builder_id, helper_id) = (ids[0], ids[1]);
 
-#[derive(Debug)]
-pub enum Command<'a> {
-    StartActivity(StartActivityCommand<'a>),
-    Construct(ConstructCommand<'a>),
-    Stop(StopCommand<'a>),
-    Move(MoveCommand<'a>),
-    Attack(AttackCommand<'a>),
-    GatherResource(GatherResourceCommand<'a>),
-    ReturnResource(ReturnResourceCommand<'a>),
-}
+        let construct = Command::Construct(ConstructCommand {
+            builder: builder_id,
+            structure_position: [4, 4],
+            structure_type: EntityType::named("BattleAcademy"),
+        });
+        core.issue_command(construct, Team::Player(0)).unwrap();
+
+        let mut structure_id = None;
+        for _ in 0..500 {
+            core.update(DEFAULT_TICK_DURATION);
+            if core.entities().len() == 4 {
+                structure_id = Some(core.entities()[3].0);
+                break;
+            }
+        }
+        let structure_id = structure_id.expect("Construction never started");
+        let structure = |core: &Core| {
+            let structure = core.entities()[3].1.borrow();
+            let remaining = match structure.state {
+                EntityState::UnderConstruction(remaining, _) => remaining,
+                _ => Duration::ZERO,
+            };
+            (structure.health.as_ref().unwrap().current, remaining)
+        };
+        let (health, remaining) = structure(&core);
+        assert_eq!(health, 2);
+        assert_eq!(
+            core.entities()[0].1.borrow().state,
+            EntityState::Constructing(structure_id)
+        );
 
-impl<'a> Command<'a> {
-    fn actor(&self) -> &RefMut<'a, Entity> {
-        match self {
-            Command::StartActivity(StartActivityCommand { structure, .. }) => structure,
-            Command::Construct(ConstructCommand { builder, .. }) => builder,
-            Command::Stop(StopCommand { entity }) => entity,
-            Command::Move(MoveCommand { unit, .. }) => unit,
-            Command::Attack(AttackCommand { attacker, .. }) => attacker,
-            Command::GatherResource(GatherResourceCommand { gatherer, .. }) => gatherer,
-            Command::ReturnResource(ReturnResourceCommand { gatherer, .. }) => gatherer,
+        // Nothing happens while no one is working on it
+        let stop = Command::Stop(StopCommand { entity: builder_id });
+        core.issue_command(stop, Team::Player(0)).unwrap();
+        for _ in 0..100 {
+            core.update(DEFAULT_TICK_DURATION);
+        }
+        assert_eq!(structure(&core), (2, remaining));
+
+        for repairer in [builder_id, helper_id] {
+            let repair = Command::Repair(RepairCommand {
+                repairer,
+                target: structure_id,
+            });
+            core.issue_command(repair, Team::Player(0)).unwrap();
+        }
+        for _ in 0..300 {
+            core.update(DEFAULT_TICK_DURATION);
+        }
+        let (health, remaining) = structure(&core);
+        assert!(health > 2 && health < 20);
+        assert!(remaining > Duration::ZERO && remaining < Duration::from_secs(9));
+
+        // Two builders finish it in half the time it takes one
+        for _ in 0..200 {
+            core.update(DEFAULT_TICK_DURATION);
+        }
+        assert_eq!(structure(&core), (20, Duration::ZERO));
+        for (_id, engineer) in &core.entities()[..2] {
+            assert_eq!(engineer.borrow().state, EntityState::Idle);
         }
     }
-}
 
-#[derive(Debug)]
-pub struct StartActivityCommand<'a> {
-    pub structure: RefMut<'a, Entity>,
-    pub target: ActivityTarget,
-}
+    #[test]
+    fn research_unlocks_more_and_improves_existing_units() {
+        let (mut core, ids) = create_core(
+            [10, 10],
+            &[
+                ("TechLab", [0, 0], Team::Player(0)),
+                ("BattleAcademy", [5, 0], Team::Player(0)),
+                ("Enforcer", [1, 6], Team::Player(0)),
+            ],
+        );
+        let (tech_lab_id, academy_id) = (ids[0], ids[1]);
 
-#[derive(Debug)]
-pub struct ConstructCommand<'a> {
-    pub builder: RefMut<'a, Entity>,
-    pub structure_position: [u32; 2],
-    pub structure_type: EntityType,
-}
+        let start =
+            |structure, target| Command::StartActivity(StartActivityCommand { structure, target });
+        let train_ranger = start(
+            academy_id,
+            ActivityTarget::Train(EntityType::named("Ranger")),
+        );
+        let research = |topic| ActivityTarget::Research(ResearchTopic::named(topic));
+        let weapons = start(tech_lab_id, research("Weapons"));
+        let armor = start(tech_lab_id, research("Armor"));
+        let is_enabled = |core: &Core, command: &Command| {
+            let Command::StartActivity(StartActivityCommand { structure, target }) = command else {
+                unreachable!()
+            };
+            let (_id, structure) = core
+                .entities()
+                .iter()
+                .find(|(id, _)| id == structure)
+                .unwrap();
+            structure
+                .borrow()
+                .action_slots
+                .iter()
+                .flatten()
+                .any(|slot| {
+                    slot.enabled
+                        && matches!(slot.action, Action::StartActivity(t, _) if t == *target)
+                })
+        };
+        let damage = |core: &Core| {
+            let enforcer = core.entities()[2].1.borrow();
+            enforcer.unit().combat.as_ref().unwrap().damage_amount()
+        };
+
+        for locked in [&train_ranger, &armor] {
+            assert!(!is_enabled(&core, locked));
+            assert_eq!(
+                core.issue_command(locked.clone(), Team::Player(0)).err(),
+                Some(CommandError::MissingPrerequisites)
+            );
+        }
+        core.issue_command(weapons.clone(), Team::Player(0))
+            .unwrap();
+        assert!(!is_enabled(&core, &weapons));
+        assert_eq!(
+            core.issue_command(weapons.clone(), Team::Player(0)).err(),
+            Some(CommandError::AlreadyResearched)
+        );
+        assert_eq!(damage(&core), 2);
 
-#[derive(Debug)]
-pub struct StopCommand<'a> {
-    pub entity: RefMut<'a, Entity>,
-}
+        for _ in 0..250 {
+            core.update(DEFAULT_TICK_DURATION);
+        }
+        assert_eq!(damage(&core), 3);
+        assert!(is_enabled(&core, &train_ranger));
+        assert!(is_enabled(&core, &armor));
+        assert!(!is_enabled(&core, &weapons));
+        core.issue_command(train_ranger, Team::Player(0)).unwrap();
+    }
 
-#[derive(Debug)]
-pub struct MoveCommand<'a> {
-    pub unit: RefMut<'a, Entity>,
-    pub destination: [u32; 2],
-}
+    #[test]
+    fn gathered_minerals_are_returned_to_a_drop_off_structure() {
+        let (mut core, ids) = create_core(
+            [10, 10],
+            &[
+                ("Engineer", [4, 4], Team::Player(0)),
+                ("TechLab", [0, 0], Team::Player(0)),
+                ("BattleAcademy", [6, 0], Team::Player(0)),
+                ("MineralDeposit", [8, 8], Team::Neutral),
+            ],
+        );
+        let (engineer_id, tech_lab_id, academy_id, deposit_id) = (ids[0], ids[1], ids[2], ids[3]);
+
+        let minerals = |core: &Core| {
+            core.team_state_unchecked(&Team::Player(0))
+                .borrow()
+                .resource(ResourceType::Minerals)
+        };
+        core.team_state_unchecked(&Team::Player(0))
+            .borrow_mut()
+            .resources
+            .insert(ResourceType::Minerals, 0);
+        let construct = Command::Construct(ConstructCommand {
+            builder: engineer_id,
+            structure_position: [4, 6],
+            structure_type: EntityType::named("BattleAcademy"),
+        });
+        assert_eq!(
+            core.issue_command(construct, Team::Player(0)).err(),
+            Some(CommandError::NotEnoughResources(ResourceType::Minerals))
+        );
 
-#[derive(Debug)]
-pub struct AttackCommand<'a> {
-    pub attacker: RefMut<'a, Entity>,
-    pub victim: Ref<'a, Entity>,
-}
+        let gather = Command::GatherResource(GatherResourceCommand {
+            gatherer: engineer_id,
+            resource: deposit_id,
+        });
+        core.issue_command(gather, Team::Player(0)).unwrap();
+        let is_carrying = |core: &Core| {
+            let engineer = core.entities()[0].1.borrow();
+            engineer.unit().gathering.as_ref().unwrap().is_carrying()
+        };
+        for _ in 0..500 {
+            core.update(DEFAULT_TICK_DURATION);
+            if is_carrying(&core) {
+                break;
+            }
+        }
+        assert!(is_carrying(&core));
+        assert_eq!(*core.entities()[3].1.borrow().resource_remaining(), 38);
 
-#[derive(Debug)]
-pub struct GatherResourceCommand<'a> {
-    pub gatherer: RefMut<'a, Entity>,
-    pub resource: Ref<'a, Entity>,
-}
+        // Only the tech lab takes resources
+        let return_to = |structure| {
+            Command::ReturnResource(ReturnResourceCommand {
+                gatherer: engineer_id,
+                structure: Some(structure),
+            })
+        };
+        assert_eq!(
+            core.issue_command(return_to(academy_id), Team::Player(0))
+                .err(),
+            Some(CommandError::InvalidTarget(academy_id))
+        );
+        core.issue_command(return_to(tech_lab_id), Team::Player(0))
+            .unwrap();
+        for _ in 0..500 {
+            core.update(DEFAULT_TICK_DURATION);
+            if minerals(&core) > 0 {
+                break;
+            }
+        }
+        assert_eq!(minerals(&core), 2);
+        assert_eq!(
+            core.entities()[0].1.borrow().state,
+            EntityState::MovingToResource(deposit_id)
+        );
+    }
 
-#[derive(Debug)]
-pub struct ReturnResourceCommand<'a> {
-    pub gatherer: RefMut<'a, Entity>,
-    pub structure: Option<Ref<'a, Entity>>,
-}
+    #[test]
+    fn training_is_limited_by_supply() {
+        let mut entities = vec![("TechLab", [0, 0], Team::Player(0))];
+        for x in 0..4 {
+            entities.push(("Enforcer", [x, 5], Team::Player(0)));
+        }
+        let (core, ids) = create_core([20, 10], &entities);
+        let train = Command::StartActivity(StartActivityCommand {
+            structure: ids[0],
+            target: ActivityTarget::Train(EntityType::named("Engineer")),
+        });
 
-pub struct TeamState {
-    pub resources: u32,
-    // TODO: different kinds of research
-    research_state: TeamResearchState,
-}
+        // Queued units take up supply right away
+        core.issue_command(train.clone(), Team::Player(0)).unwrap();
+        core.issue_command(train.clone(), Team::Player(0)).unwrap();
+        let supply = core.supply(&Team::Player(0));
+        assert_eq!((supply.used, supply.provided), (10, 10));
+        assert_eq!(
+            core.issue_command(train, Team::Player(0)).err(),
+            Some(CommandError::SupplyBlocked)
+        );
 
-#[derive(PartialEq, Debug, Copy, Clone)]
-pub enum TeamResearchState {
-    NotStarted,
-    InProgress,
-    Done,
-}
+        // Each structure adds to the supply, up to the maximum
+        let tech_labs: Vec<_> = [0, 3, 6, 9]
+            .into_iter()
+            .map(|x| ("TechLab", [x, 0], Team::Player(0)))
+            .collect();
+        let (core, _ids) = create_core([20, 10], &tech_labs);
+        assert_eq!(
+            core.supply(&Team::Player(0)).provided,
+            data::DEFAULT_MAX_SUPPLY
+        );
 
-pub struct CommandSuccess {
-    pub did_research_state_change: bool,
-}
+        // The maximum can be set for the match
+        let (entities, _ids) = create_entities(&tech_labs);
+        let mut team_setup = TeamSetup::default();
+        team_setup.set_max_supply(12);
+        let core = Core::new(
+            entities,
+            [20, 10],
+            vec![],
+            VictoryCondition::default(),
+            team_setup,
+            0,
+        );
+        assert_eq!(core.supply(&Team::Player(0)).provided, 12);
+    }
 
-pub struct UpdateOutcome {
-    pub removed_entities: Vec<EntityId>,
-    pub finished_structures: Vec<EntityId>,
-    pub did_research_state_change: bool,
-}
+    #[test]
+    fn allied_teams_share_vision_and_dont_fight() {
+        let (entities, ids) = create_entities(&[
+            ("Enforcer", [1, 1], Team::Player(0)),
+            ("Enforcer", [2, 1], Team::Player(1)),
+            ("Engineer", [15, 15], Team::Player(1)),
+        ]);
+        let (enforcer_id, ally_id) = (ids[0], ids[1]);
+        let mut team_setup = TeamSetup::default();
+        team_setup.set_relation(Team::Player(0), Team::Player(1), Relation::Allied);
+        let mut core = Core::new(
+            entities,
+            [20, 20],
+            vec![],
+            VictoryCondition::default(),
+            team_setup,
+            0,
+        );
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-pub enum ObstacleType {
-    Entity(Team),
-    Water,
-    None,
-}
+        let attack = Command::Attack(AttackCommand {
+            attacker: enforcer_id,
+            victim: ally_id,
+        });
+        assert_eq!(
+            core.issue_command(attack, Team::Player(0)).err(),
+            Some(CommandError::InvalidTarget(ally_id))
+        );
+        for _ in 0..100 {
+            core.update(DEFAULT_TICK_DURATION);
+        }
+
+        for (_id, entity) in core.entities() {
+            let entity = entity.borrow();
+            let health = entity.health.as_ref().unwrap();
+            assert_eq!(health.current, health.max, "{:?}", entity);
+        }
+        let visibility = core.visibility(&Team::Player(0)).unwrap();
+        assert_eq!(visibility.get(&[15, 15]), Some(Visibility::Visible));
+    }
 
-impl Default for ObstacleType {
-    fn default() -> Self {
-        ObstacleType::None
+    #[test]
+    fn map_triggers_fire_once_their_condition_is_met() {
+        let (mut core, _ids) = create_core([10, 10], &[("TechLab", [1, 1], Team::Player(0))]);
+        core.set_triggers(vec![Trigger {
+            condition: TriggerCondition::Time(1.0),
+            actions: vec![
+                TriggerAction::Message("Reinforcements".to_owned()),
+                TriggerAction::GiveResources {
+                    team: Team::Player(0),
+                    fuel: 5,
+                    minerals: 0,
+                },
+                TriggerAction::SpawnUnit {
+                    unit_type: EntityType::named("Enforcer"),
+                    team: Team::Player(0),
+                    position: [5, 5],
+                },
+            ],
+        }]);
+        let fuel = |core: &Core| {
+            core.team_state_unchecked(&Team::Player(0))
+                .borrow()
+                .resource(ResourceType::Fuel)
+        };
+        let fuel_before = fuel(&core);
+
+        let mut messages = vec![];
+        for _ in 0..100 {
+            messages.extend(core.update(DEFAULT_TICK_DURATION).messages);
+        }
+        assert_eq!(messages, vec!["Reinforcements".to_owned()]);
+        assert_eq!(fuel(&core), fuel_before + 5);
+        assert_eq!(core.entities().len(), 2);
     }
-}
 
-#[derive(Debug, Copy, Clone)]
-pub enum CommandError {
-    NotEnoughResources,
-    NoPathFound,
-    NotCarryingResource,
-    NotEnoughSpaceForStructure,
-    EntityIsBusy,
+    #[test]
+    fn resource_target_counts_only_its_own_resource() {
+        let victory_condition: VictoryCondition = "minerals 5".parse().unwrap();
+        assert_eq!(
+            victory_condition,
+            VictoryCondition::ResourceTarget(ResourceType::Minerals, 5)
+        );
+        assert_eq!(victory_condition.to_string(), "minerals 5");
+
+        let (entities, _ids) = create_entities(&[
+            ("TechLab", [0, 0], Team::Player(0)),
+            ("TechLab", [10, 0], Team::Player(1)),
+        ]);
+        let mut core = Core::new(
+            entities,
+            [20, 10],
+            vec![],
+            victory_condition,
+            TeamSetup::default(),
+            0,
+        );
+        let gather = |core: &Core, team, resource_type| {
+            let mut team_state = core.team_state_unchecked(&team).borrow_mut();
+            team_state.receive_gathered(resource_type, 5);
+        };
+
+        gather(&core, Team::Player(0), ResourceType::Fuel);
+        assert_eq!(core.update(DEFAULT_TICK_DURATION).match_result, None);
+
+        gather(&core, Team::Player(1), ResourceType::Minerals);
+        let result = core.update(DEFAULT_TICK_DURATION).match_result.unwrap();
+        assert_eq!(result.winners, vec![Team::Player(1)]);
+    }
 }
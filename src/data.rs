@@ -1,151 +1,282 @@
 use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
 use ggez::graphics::{DrawParam, Drawable, Image, Rect};
 use ggez::input::keyboard::KeyCode;
 use ggez::{Context, GameResult};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::entities::{
-    Action, ActionConfig, ActivityConfig, ActivityTarget, AnimationState, CategoryConfig,
-    ConstructionConfig, Direction, Entity, EntityCategory, EntityConfig, EntityState, Team,
-    NUM_ENTITY_ACTIONS,
+    Action, ActionConfig, ActivityTarget, AnimationState, CategoryConfig, Cost, Direction, Entity,
+    EntityCategory, EntityId, EntityState, Prerequisite, ResearchConfig, ResearchEffect, Stance,
+    Team, MAX_PLAYERS,
 };
 
-use crate::core::TeamResearchState;
+use crate::core::{TeamSetup, TechState};
+use crate::definitions::{registry, EntityRegistry, SpriteDefinition};
 
-#[derive(Debug, PartialEq, Copy, Clone, Eq, Hash)]
-pub enum EntityType {
-    FuelRift,
-    Enforcer,
-    Engineer,
-    BattleAcademy,
-    TechLab,
+/// Refers to one of the definitions in `resources/entities.ron`
+#[derive(PartialEq, Copy, Clone, Eq, Hash, PartialOrd, Ord)]
+pub struct EntityType(u16);
+
+impl EntityType {
+    /// Look up one of the types that the game refers to by name. These are guaranteed to exist
+    /// when the definitions are loaded.
+    pub fn named(name: &str) -> Self {
+        name.parse()
+            .unwrap_or_else(|_| panic!("No entity type named {:?}", name))
+    }
+
+    pub(crate) fn from_index(index: usize) -> Self {
+        Self(index as u16)
+    }
+
+    pub(crate) fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    pub fn name(self) -> String {
+        registry().get(self).name.clone()
+    }
+}
+
+impl FromStr for EntityType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        registry().type_named(s).ok_or(())
+    }
+}
+
+impl Debug for EntityType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl Display for EntityType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// Saves and replays refer to types by name, so that they don't depend on the order of the
+// definitions
+impl Serialize for EntityType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for EntityType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse()
+            .map_err(|_| D::Error::custom(format!("Unknown entity type: {}", name)))
+    }
+}
+
+/// Refers to one of the research topics in `resources/entities.ron`, each of which is defined by
+/// the action that researches it
+#[derive(PartialEq, Copy, Clone, Eq, Hash, PartialOrd, Ord)]
+pub struct ResearchTopic(u16);
+
+impl ResearchTopic {
+    /// Look up one of the topics that the game refers to by name. These are guaranteed to exist
+    /// when the definitions are loaded.
+    pub fn named(name: &str) -> Self {
+        name.parse()
+            .unwrap_or_else(|_| panic!("No research topic named {:?}", name))
+    }
+
+    pub(crate) fn from_index(index: usize) -> Self {
+        Self(index as u16)
+    }
+
+    pub(crate) fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    pub fn name(self) -> String {
+        registry().research_definition(self).name.clone()
+    }
+}
+
+impl FromStr for ResearchTopic {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        registry().topic_named(s).ok_or(())
+    }
+}
+
+impl Debug for ResearchTopic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl Display for ResearchTopic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
 }
 
+impl Serialize for ResearchTopic {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for ResearchTopic {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse()
+            .map_err(|_| D::Error::custom(format!("Unknown research topic: {}", name)))
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ResourceType {
+    Fuel,
+    Minerals,
+}
+
+impl ResourceType {
+    pub const ALL: [ResourceType; 2] = [ResourceType::Fuel, ResourceType::Minerals];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResourceType::Fuel => "Fuel",
+            ResourceType::Minerals => "Minerals",
+        }
+    }
+}
+
+impl Display for ResourceType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name().to_lowercase())
+    }
+}
+
+/// Create an entity with the numbers of the definitions that were read at startup, for example
+/// when setting up a map
 pub fn create_entity(
     entity_type: EntityType,
+    id: EntityId,
     position: [u32; 2],
     team: Team,
-    research_state: TeamResearchState,
+    tech: &TechState,
 ) -> Entity {
-    let mut config = entity_config(entity_type);
-    // If team has finished research, it should not be available as an action
-    if research_state == TeamResearchState::Done {
-        for action in &mut config.actions {
-            if matches!(
-                action,
-                Some(ActionConfig::StartActivity(ActivityTarget::Research, _))
-            ) {
-                *action = None;
-            }
+    create_entity_with(&registry(), entity_type, id, position, team, tech)
+}
+
+/// Like `create_entity()`, with the numbers of the given definitions, which a match may have
+/// rebalanced
+pub fn create_entity_with(
+    definitions: &EntityRegistry,
+    entity_type: EntityType,
+    id: EntityId,
+    position: [u32; 2],
+    team: Team,
+    tech: &TechState,
+) -> Entity {
+    let config = definitions.get(entity_type).config.clone();
+    let mut entity = Entity::new(entity_type, id, config, position, team);
+    // New units benefit from what the team has already researched
+    for topic in tech.researched() {
+        for &effect in &definitions.research(topic).effects {
+            entity.apply_research_effect(effect);
         }
     }
+    entity
+}
 
-    Entity::new(entity_type, config, position, team)
+pub fn structure_sizes(definitions: &EntityRegistry) -> HashMap<EntityType, [u32; 2]> {
+    definitions
+        .iter()
+        .filter_map(
+            |(entity_type, definition)| match definition.config.category {
+                CategoryConfig::StructureSize(size) => Some((entity_type, size)),
+                _ => None,
+            },
+        )
+        .collect()
 }
 
-pub fn structure_sizes() -> HashMap<EntityType, [u32; 2]> {
-    let mut map: HashMap<EntityType, [u32; 2]> = Default::default();
-    let structure_types = [EntityType::BattleAcademy, EntityType::TechLab];
-    for structure_type in structure_types {
-        let config = entity_config(structure_type);
-        let size = match config.category {
-            CategoryConfig::StructureSize(size) => size,
-            _ => {
-                panic!("{:?} is not a structure", structure_type)
+/// What it takes to train or construct each type of entity
+pub fn costs(definitions: &EntityRegistry) -> HashMap<EntityType, Cost> {
+    let mut map: HashMap<EntityType, Cost> = Default::default();
+    for (_, definition) in definitions.iter() {
+        for action in definition.config.actions.into_iter().flatten() {
+            match action {
+                ActionConfig::StartActivity(ActivityTarget::Train(trained_type), config) => {
+                    map.insert(trained_type, config.cost);
+                }
+                ActionConfig::Construct(structure_type, config) => {
+                    map.insert(structure_type, config.cost);
+                }
+                _ => {}
             }
-        };
-        map.insert(structure_type, size);
+        }
     }
     map
 }
 
-fn entity_config(entity_type: EntityType) -> EntityConfig {
-    match entity_type {
-        EntityType::Enforcer => EntityConfig {
-            max_health: Some(10),
-            category: CategoryConfig::Unit,
-            actions: [
-                Some(ActionConfig::Move(Duration::from_millis(700))),
-                Some(ActionConfig::Stop),
-                Some(ActionConfig::Attack(2)),
-                None,
-                None,
-                None,
-            ],
-        },
-        EntityType::Engineer => EntityConfig {
-            max_health: Some(5),
-            category: CategoryConfig::Unit,
-            actions: [
-                Some(ActionConfig::Move(Duration::from_millis(900))),
-                Some(ActionConfig::Stop),
-                Some(ActionConfig::GatherResource),
-                Some(ActionConfig::ReturnResource),
-                Some(ActionConfig::Construct(
-                    EntityType::BattleAcademy,
-                    ConstructionConfig {
-                        construction_time: Duration::from_secs_f32(12.0),
-                        cost: 4,
-                    },
-                )),
-                Some(ActionConfig::Construct(
-                    EntityType::TechLab,
-                    ConstructionConfig {
-                        construction_time: Duration::from_secs_f32(6.0),
-                        cost: 4,
-                    },
-                )),
-            ],
-        },
-        EntityType::BattleAcademy => EntityConfig {
-            max_health: Some(20),
-            category: CategoryConfig::StructureSize([3, 3]),
-            actions: [
-                Some(ActionConfig::StartActivity(
-                    ActivityTarget::Train(EntityType::Enforcer),
-                    ActivityConfig {
-                        duration: Duration::from_secs(12),
-                        cost: 2,
-                    },
-                )),
-                None,
-                None,
-                None,
-                None,
-                None,
-            ],
-        },
-        EntityType::TechLab => EntityConfig {
-            max_health: Some(30),
-            category: CategoryConfig::StructureSize([3, 3]),
-            actions: [
-                Some(ActionConfig::StartActivity(
-                    ActivityTarget::Train(EntityType::Engineer),
-                    ActivityConfig {
-                        duration: Duration::from_secs(8),
-                        cost: 1,
-                    },
-                )),
-                Some(ActionConfig::StartActivity(
-                    ActivityTarget::Research,
-                    ActivityConfig {
-                        duration: Duration::from_secs(4),
-                        cost: 3,
-                    },
-                )),
-                None,
-                None,
-                None,
-                None,
-            ],
-        },
-        EntityType::FuelRift => EntityConfig {
-            max_health: None,
-            category: CategoryConfig::ResourceCapacity(30),
-            actions: [None; NUM_ENTITY_ACTIONS],
-        },
+/// No matter how many structures a team has, it can never have more supply than this, unless the
+/// map says otherwise
+pub const DEFAULT_MAX_SUPPLY: u32 = 30;
+
+/// How much supply each type of unit takes up
+pub fn supply_costs(definitions: &EntityRegistry) -> HashMap<EntityType, u32> {
+    definitions
+        .iter()
+        .filter(|(_, definition)| definition.supply_cost > 0)
+        .map(|(entity_type, definition)| (entity_type, definition.supply_cost))
+        .collect()
+}
+
+/// How much supply each type of structure provides once it's finished
+pub fn supply_provided(definitions: &EntityRegistry) -> HashMap<EntityType, u32> {
+    definitions
+        .iter()
+        .filter(|(_, definition)| definition.supply_provided > 0)
+        .map(|(entity_type, definition)| (entity_type, definition.supply_provided))
+        .collect()
+}
+
+/// What a team needs before it can train or construct each type of entity
+pub fn entity_prerequisites(
+    definitions: &EntityRegistry,
+) -> HashMap<EntityType, Vec<Prerequisite>> {
+    definitions
+        .iter()
+        .filter(|(_, definition)| !definition.prerequisites.is_empty())
+        .map(|(entity_type, definition)| (entity_type, definition.prerequisites.clone()))
+        .collect()
+}
+
+/// Which structures gatherers can bring each type of resource back to
+pub fn drop_off_structures(definitions: &EntityRegistry) -> HashMap<ResourceType, Vec<EntityType>> {
+    let mut map: HashMap<ResourceType, Vec<EntityType>> = Default::default();
+    for (entity_type, definition) in definitions.iter() {
+        for &resource_type in &definition.drop_off_for {
+            map.entry(resource_type).or_default().push(entity_type);
+        }
     }
+    map
+}
+
+/// What each research topic costs, what it requires and what it improves
+pub fn research_configs(definitions: &EntityRegistry) -> HashMap<ResearchTopic, ResearchConfig> {
+    definitions
+        .research_topics()
+        .map(|(topic, config)| (topic, config.clone()))
+        .collect()
 }
 
 pub struct EntityHudConfig {
@@ -174,41 +305,66 @@ pub struct ActionHudConfig {
 }
 
 pub struct HudAssets {
-    enforcer: EntityHudConfig,
-    engineer: EntityHudConfig,
-    battle_academy: EntityHudConfig,
-    tech_lab: EntityHudConfig,
-    fuel_rift: EntityHudConfig,
+    // The definitions of the match, which research effects are described with
+    definitions: Arc<EntityRegistry>,
+    entities: HashMap<EntityType, EntityHudConfig>,
+    research_icons: HashMap<ResearchTopic, Image>,
     stop_icon: Image,
     move_icon: Image,
     attack_icon: Image,
+    patrol_icon: Image,
     gather_icon: Image,
     return_icon: Image,
+    repair_icon: Image,
+    cancel_icon: Image,
+    aggressive_icon: Image,
+    defensive_icon: Image,
+    hold_position_icon: Image,
+    passive_icon: Image,
 }
 
 impl HudAssets {
-    pub fn new(ctx: &mut Context) -> GameResult<Self> {
+    pub fn new(ctx: &mut Context, definitions: Arc<EntityRegistry>) -> GameResult<Self> {
+        let mut entities = HashMap::new();
+        for (entity_type, definition) in registry().iter() {
+            let config = EntityHudConfig::new(ctx, &definition.display_name, &definition.portrait)?;
+            entities.insert(entity_type, config);
+        }
+        let mut research_icons = HashMap::new();
+        for (topic, definition) in registry().research_definitions() {
+            research_icons.insert(topic, load_icon(ctx, &definition.icon)?);
+        }
         Ok(Self {
-            enforcer: EntityHudConfig::new(ctx, "Enforcer", "enforcer.png")?,
-            engineer: EntityHudConfig::new(ctx, "Engineer", "engineer.png")?,
-            battle_academy: EntityHudConfig::new(ctx, "Battle Academy", "battle_academy.png")?,
-            tech_lab: EntityHudConfig::new(ctx, "Tech Lab", "tech_lab.png")?,
-            fuel_rift: EntityHudConfig::new(ctx, "Fuel rift", "resource.png")?,
+            definitions,
+            entities,
+            research_icons,
             stop_icon: load_icon(ctx, "stop.png")?,
             move_icon: load_icon(ctx, "move.png")?,
             attack_icon: load_icon(ctx, "attack.png")?,
+            patrol_icon: load_icon(ctx, "patrol.png")?,
             gather_icon: load_icon(ctx, "gather.png")?,
             return_icon: load_icon(ctx, "return.png")?,
+            repair_icon: load_icon(ctx, "repair.png")?,
+            cancel_icon: load_icon(ctx, "cancel.png")?,
+            aggressive_icon: load_icon(ctx, "stance_aggressive.png")?,
+            defensive_icon: load_icon(ctx, "stance_defensive.png")?,
+            hold_position_icon: load_icon(ctx, "stance_hold.png")?,
+            passive_icon: load_icon(ctx, "stance_passive.png")?,
         })
     }
 
+    pub fn set_definitions(&mut self, definitions: Arc<EntityRegistry>) {
+        self.definitions = definitions;
+    }
+
     pub fn entity(&self, entity_type: EntityType) -> &EntityHudConfig {
-        match entity_type {
-            EntityType::Enforcer => &self.enforcer,
-            EntityType::Engineer => &self.engineer,
-            EntityType::BattleAcademy => &self.battle_academy,
-            EntityType::TechLab => &self.tech_lab,
-            EntityType::FuelRift => &self.fuel_rift,
+        &self.entities[&entity_type]
+    }
+
+    pub fn activity_icon(&self, target: ActivityTarget) -> &Image {
+        match target {
+            ActivityTarget::Train(entity_type) => &self.entity(entity_type).portrait,
+            ActivityTarget::Research(topic) => &self.research_icons[&topic],
         }
     }
 
@@ -216,14 +372,10 @@ impl HudAssets {
         match action {
             Action::StartActivity(ActivityTarget::Train(entity_type), activity_config) => {
                 let unit_config = self.entity(entity_type);
-                let keycode = match entity_type {
-                    EntityType::Engineer => KeyCode::E,
-                    EntityType::Enforcer => KeyCode::F,
-                    _ => panic!("No keycode for training: {:?}", entity_type),
-                };
+                let keycode = hotkey(entity_type);
                 ActionHudConfig {
                     text: format!(
-                        "Train {} ({} fuel, {}s)",
+                        "Train {} ({}, {}s)",
                         &unit_config.name,
                         activity_config.cost,
                         activity_config.duration.as_secs()
@@ -232,28 +384,32 @@ impl HudAssets {
                     keycode,
                 }
             }
-            Action::StartActivity(ActivityTarget::Research, activity_config) => {
-                let attack_config = self.action(Action::Attack);
+            Action::StartActivity(ActivityTarget::Research(topic), activity_config) => {
+                let definition = self.definitions.research_definition(topic);
+                let effects: Vec<String> = definition
+                    .config
+                    .effects
+                    .iter()
+                    .map(ResearchEffect::to_string)
+                    .collect();
                 ActionHudConfig {
                     text: format!(
-                        "Research ({} fuel, {}s)",
+                        "Research {} ({}, {}s): {}",
+                        definition.name,
                         activity_config.cost,
-                        activity_config.duration.as_secs()
+                        activity_config.duration.as_secs(),
+                        effects.join(", ")
                     ),
-                    icon: attack_config.icon,
-                    keycode: KeyCode::R,
+                    icon: self.research_icons[&topic].clone(),
+                    keycode: definition.hotkey,
                 }
             }
             Action::Construct(structure_type, construction_config) => {
-                let keycode = match structure_type {
-                    EntityType::BattleAcademy => KeyCode::B,
-                    EntityType::TechLab => KeyCode::T,
-                    _ => panic!("No keycode for constructing: {:?}", structure_type),
-                };
+                let keycode = hotkey(structure_type);
                 let structure_config = self.entity(structure_type);
                 ActionHudConfig {
                     text: format!(
-                        "Construct {} ({} fuel, {}s)",
+                        "Construct {} ({}, {}s)",
                         &structure_config.name,
                         construction_config.cost,
                         construction_config.construction_time.as_secs()
@@ -277,6 +433,17 @@ impl HudAssets {
                 icon: self.attack_icon.clone(),
                 keycode: KeyCode::A,
             },
+            Action::Patrol => ActionHudConfig {
+                text: "Patrol".to_owned(),
+                icon: self.patrol_icon.clone(),
+                keycode: KeyCode::P,
+            },
+            // The button shows the current stance instead, see `stance()`
+            Action::ChangeStance => ActionHudConfig {
+                text: "Change stance".to_owned(),
+                icon: self.aggressive_icon.clone(),
+                keycode: KeyCode::C,
+            },
             Action::GatherResource => ActionHudConfig {
                 text: "Gather resource".to_owned(),
                 icon: self.gather_icon.clone(),
@@ -287,84 +454,139 @@ impl HudAssets {
                 icon: self.return_icon.clone(),
                 keycode: KeyCode::R,
             },
+            Action::Repair => ActionHudConfig {
+                text: "Repair".to_owned(),
+                icon: self.repair_icon.clone(),
+                keycode: KeyCode::E,
+            },
+            Action::CancelConstruction => ActionHudConfig {
+                text: "Cancel construction (partial refund)".to_owned(),
+                icon: self.cancel_icon.clone(),
+                keycode: KeyCode::X,
+            },
         }
     }
+
+    pub fn stance(&self, stance: Stance) -> ActionHudConfig {
+        let (description, icon) = match stance {
+            Stance::Aggressive => ("Aggressive (chases enemies)", &self.aggressive_icon),
+            Stance::Defensive => ("Defensive (doesn't stray far)", &self.defensive_icon),
+            Stance::HoldPosition => ("Hold position (never moves)", &self.hold_position_icon),
+            Stance::Passive => ("Passive (never attacks)", &self.passive_icon),
+        };
+        ActionHudConfig {
+            text: format!("Stance: {}", description),
+            icon: icon.clone(),
+            keycode: self.action(Action::ChangeStance).keycode,
+        }
+    }
+}
+
+// Trained and constructed types are required to have a hotkey
+fn hotkey(entity_type: EntityType) -> KeyCode {
+    registry()
+        .get(entity_type)
+        .hotkey
+        .unwrap_or_else(|| panic!("No hotkey for {:?}", entity_type))
 }
 
 pub fn create_entity_animations(
     ctx: &mut Context,
+    team_setup: &TeamSetup,
 ) -> GameResult<HashMap<(EntityType, Team), Animation>> {
+    let color_families = player_color_families(team_setup);
     let mut animations = Default::default();
-    create_enforcer(ctx, &mut animations)?;
-    create_engineer(ctx, &mut animations)?;
-    create_battle_academy(ctx, &mut animations)?;
-    create_tech_lab(ctx, &mut animations)?;
-    create_fuel_rift(ctx, &mut animations)?;
+    for (entity_type, definition) in registry().iter() {
+        match &definition.sprite {
+            SpriteDefinition::UnitSheets { moving, attacking } => {
+                let moving = Image::new(ctx, format!("/images/{}", moving))?;
+                let attacking = attacking
+                    .as_ref()
+                    .map(|attacking| Image::new(ctx, format!("/images/{}", attacking)))
+                    .transpose()?;
+                create_unit_tilesheets(
+                    ctx,
+                    &mut animations,
+                    &color_families,
+                    entity_type,
+                    moving,
+                    attacking,
+                )?;
+            }
+            SpriteDefinition::Static { image, origin } => {
+                let image = Image::new(ctx, format!("/images/{}", image))?;
+                static_sprites(
+                    ctx,
+                    entity_type,
+                    &mut animations,
+                    &color_families,
+                    image,
+                    *origin,
+                )?;
+            }
+        }
+    }
 
     Ok(animations)
 }
 
-fn create_enforcer(
-    ctx: &mut Context,
-    animations: &mut HashMap<(EntityType, Team), Animation>,
-) -> GameResult {
-    let moving = Image::new(ctx, "/images/enforcer_sheet.png")?;
-    let attacking = Image::new(ctx, "/images/enforcer_attacking_sheet.png")?;
-    create_unit_tilesheets(
-        ctx,
-        animations,
-        EntityType::Enforcer,
-        moving,
-        Some(attacking),
-    )
-}
-
 // Sprites must be designed with these reserved colors in mind.
 // Pixels that use these exact color are changed to an appropriate team color.
 const TEMPLATE_COLOR_LIGHT: [u8; 4] = [122, 171, 255, 255];
 const TEMPLATE_COLOR_DARK: [u8; 4] = [99, 155, 255, 255];
 
-const TEAM_COLOR_FAMILIES: [(Team, EntityColorFamily); 3] = [
-    (
-        Team::Player,
-        EntityColorFamily {
-            light: [120, 200, 120, 255],
-            dark: [100, 180, 100, 255],
-        },
-    ),
-    (
-        Team::Enemy1,
-        EntityColorFamily {
-            light: [240, 100, 100, 255],
-            dark: [220, 80, 80, 255],
-        },
-    ),
-    (
-        Team::Enemy2,
-        EntityColorFamily {
-            light: [200, 60, 200, 255],
-            dark: [180, 40, 180, 255],
-        },
-    ),
+// Players are told apart by these colors, unless the map says otherwise
+const PLAYER_COLORS: [[u8; 3]; MAX_PLAYERS as usize] = [
+    [120, 200, 120],
+    [240, 100, 100],
+    [200, 60, 200],
+    [230, 200, 70],
+    [70, 200, 220],
+    [240, 150, 60],
+    [150, 110, 240],
+    [220, 220, 220],
 ];
 
+const NEUTRAL_COLOR: [u8; 3] = [200, 200, 150];
+
+pub fn default_team_color(team: Team) -> [u8; 3] {
+    match team {
+        Team::Player(i) => PLAYER_COLORS[i as usize % PLAYER_COLORS.len()],
+        Team::Neutral => NEUTRAL_COLOR,
+    }
+}
+
 #[derive(Copy, Clone)]
 struct EntityColorFamily {
     light: [u8; 4],
     dark: [u8; 4],
 }
 
-fn create_engineer(
-    ctx: &mut Context,
-    animations: &mut HashMap<(EntityType, Team), Animation>,
-) -> GameResult {
-    let moving = Image::new(ctx, "/images/engineer_sheet.png")?;
-    create_unit_tilesheets(ctx, animations, EntityType::Engineer, moving, None)
+impl EntityColorFamily {
+    fn new([r, g, b]: [u8; 3]) -> Self {
+        let shade = 20;
+        Self {
+            light: [r, g, b, 255],
+            dark: [
+                r.saturating_sub(shade),
+                g.saturating_sub(shade),
+                b.saturating_sub(shade),
+                255,
+            ],
+        }
+    }
+}
+
+fn player_color_families(team_setup: &TeamSetup) -> Vec<(Team, EntityColorFamily)> {
+    Team::players()
+        .map(|team| (team, EntityColorFamily::new(team_setup.color(team))))
+        .collect()
 }
 
 fn create_unit_tilesheets(
     ctx: &mut Context,
     animations: &mut HashMap<(EntityType, Team), Animation>,
+    color_families: &[(Team, EntityColorFamily)],
     entity_type: EntityType,
     moving_image: Image,
     attacking_image: Option<Image>,
@@ -372,7 +594,7 @@ fn create_unit_tilesheets(
     let moving_size = [moving_image.width(), moving_image.height()];
     let moving_rgba = moving_image.to_rgba8(ctx)?;
 
-    for (team, color_family) in TEAM_COLOR_FAMILIES {
+    for &(team, color_family) in color_families {
         let moving_tilesheet = tilesheet(
             ctx,
             moving_size,
@@ -527,7 +749,7 @@ impl UnitTilesheets {
             is_between_cells = unit.sub_cell_movement.is_between_cells();
         }
         let tilesheet = match entity.state {
-            EntityState::Idle => {
+            EntityState::Idle | EntityState::Following(_) => {
                 if is_between_cells {
                     &self.moving
                 } else {
@@ -535,13 +757,18 @@ impl UnitTilesheets {
                 }
             }
             EntityState::Moving => &self.moving,
+            EntityState::AttackMoving => &self.moving,
+            EntityState::Patrolling => &self.moving,
             EntityState::Attacking(_) => self.attacking.as_ref().unwrap(),
             EntityState::MovingToResource(_) => &self.moving,
             EntityState::ReturningResource(_) => &self.moving,
             EntityState::MovingToAttackTarget(_) => &self.moving,
             EntityState::MovingToConstruction(..) => &self.moving,
-            // TODO gathering animation
+            EntityState::MovingToRepair(_) => &self.moving,
+            // TODO gathering and repairing animations
             EntityState::GatheringResource(_) => &self.idle,
+            EntityState::Repairing(_) => &self.idle,
+            EntityState::Constructing(_) => &self.idle,
 
             state @ EntityState::DoingActivity(..) | state @ EntityState::UnderConstruction(..) => {
                 panic!("No animation for state: {:?}", state)
@@ -604,54 +831,29 @@ impl Frame {
     }
 }
 
-fn create_battle_academy(
-    ctx: &mut Context,
-    animations: &mut HashMap<(EntityType, Team), Animation>,
-) -> GameResult {
-    let image = Image::new(ctx, "/images/battle_academy.png")?;
-    structure_sprite(ctx, EntityType::BattleAcademy, animations, image)
-}
-
-fn structure_sprite(
+// Structures are recolored for each team, while resources keep their original colors
+fn static_sprites(
     ctx: &mut Context,
     entity_type: EntityType,
     animations: &mut HashMap<(EntityType, Team), Animation>,
+    color_families: &[(Team, EntityColorFamily)],
     image: Image,
+    origin: [f32; 2],
 ) -> GameResult {
     let rgba = image.to_rgba8(ctx)?;
-    for (team, color_family) in TEAM_COLOR_FAMILIES {
+    for &(team, color_family) in color_families {
         let team_image = recolor(ctx, [image.width(), image.height()], &rgba, &color_family)?;
         animations.insert(
             (entity_type, team),
             Animation::Static(StaticImage {
                 image: team_image,
-                origin: [0.0, 0.0],
+                origin,
             }),
         );
     }
-    Ok(())
-}
-
-fn create_tech_lab(
-    ctx: &mut Context,
-    animations: &mut HashMap<(EntityType, Team), Animation>,
-) -> GameResult {
-    let image = Image::new(ctx, "/images/tech_lab.png")?;
-    structure_sprite(ctx, EntityType::TechLab, animations, image)
-}
-
-fn create_fuel_rift(
-    ctx: &mut Context,
-    animations: &mut HashMap<(EntityType, Team), Animation>,
-) -> GameResult {
-    let image = Image::new(ctx, "/images/fuel_rift.png")?;
-
     animations.insert(
-        (EntityType::FuelRift, Team::Neutral),
-        Animation::Static(StaticImage {
-            image,
-            origin: [8.0, 8.0],
-        }),
+        (entity_type, Team::Neutral),
+        Animation::Static(StaticImage { image, origin }),
     );
     Ok(())
 }
@@ -0,0 +1,1105 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use ggez::input::keyboard::KeyCode;
+use serde::Deserialize;
+
+use crate::data::{EntityType, ResearchTopic, ResourceType};
+use crate::entities::{
+    ActionConfig, ActivityConfig, ActivityTarget, AttackConfig, CategoryConfig, ConstructionConfig,
+    ConstructionModel, Cost, EntityConfig, Prerequisite, ResearchConfig, ResearchEffect,
+    ResourceConfig, NUM_ENTITY_ACTIONS,
+};
+
+pub const DEFINITIONS_FILE: &str = "resources/entities.ron";
+const IMAGES_DIR: &str = "resources/images";
+
+/// Types that the game refers to by name, for example in the AI and when creating maps
+const REQUIRED_TYPES: [&str; 7] = [
+    "Enforcer",
+    "Ranger",
+    "Engineer",
+    "BattleAcademy",
+    "TechLab",
+    "FuelRift",
+    "MineralDeposit",
+];
+
+/// Research topics that the game refers to by name, for example in the AI
+const REQUIRED_TOPICS: [&str; 1] = ["Weapons"];
+
+static REGISTRY: RwLock<Option<Arc<EntityRegistry>>> = RwLock::new(None);
+
+/// The entity definitions that were read from disk, the first time they were needed or when
+/// `load()` was called. A match that has been rebalanced since then has its own definitions (see
+/// `Core::definitions()`), but they only differ in their balance numbers.
+pub fn registry() -> Arc<EntityRegistry> {
+    if let Some(registry) = REGISTRY.read().unwrap().as_ref() {
+        return Arc::clone(registry);
+    }
+    let mut registry = REGISTRY.write().unwrap();
+    let registry = registry.get_or_insert_with(|| {
+        let loaded = EntityRegistry::load_from_file(Path::new(DEFINITIONS_FILE));
+        Arc::new(loaded.unwrap_or_else(|e| panic!("{}", e)))
+    });
+    Arc::clone(registry)
+}
+
+/// Read the definitions up front, so that any problem with the file is reported before the
+/// game starts rather than whenever they are first needed
+pub fn load() -> io::Result<()> {
+    let registry = EntityRegistry::load_from_file(Path::new(DEFINITIONS_FILE))?;
+    *REGISTRY.write().unwrap() = Some(Arc::new(registry));
+    Ok(())
+}
+
+/// Read the definitions again, while the game is running. Only balance numbers can differ from
+/// `current`, as anything else (like new types or different action slots) requires a restart.
+/// The returned list describes each number that changed. Existing entities are not affected
+/// until they are rebalanced.
+pub fn reload(current: &EntityRegistry) -> io::Result<(Arc<EntityRegistry>, Vec<String>)> {
+    let contents = fs::read_to_string(DEFINITIONS_FILE)?;
+    rebalance(current, &contents)
+}
+
+/// Like `reload()`, but with definitions that were read before. This is how saved games and
+/// replays bring back the balance numbers that their match was played with.
+pub fn rebalance(
+    current: &EntityRegistry,
+    contents: &str,
+) -> io::Result<(Arc<EntityRegistry>, Vec<String>)> {
+    let invalid_data = |e| io::Error::new(ErrorKind::InvalidData, e);
+    let rebalanced = EntityRegistry::parse(contents, Path::new(IMAGES_DIR))
+        .map_err(|e| invalid_data(format!("Invalid entity definitions: {}", e)))?;
+    let changes = current.balance_changes(&rebalanced).map_err(invalid_data)?;
+    Ok((Arc::new(rebalanced), changes))
+}
+
+/// Notices when the definitions file has been saved, so that it can be reloaded
+pub(crate) struct DefinitionsWatcher {
+    last_modified: Option<SystemTime>,
+    timer: Duration,
+}
+
+impl DefinitionsWatcher {
+    const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new() -> Self {
+        Self {
+            last_modified: last_modified(),
+            timer: Self::CHECK_INTERVAL,
+        }
+    }
+
+    pub fn has_changed(&mut self, dt: Duration) -> bool {
+        self.timer = self.timer.saturating_sub(dt);
+        if !self.timer.is_zero() {
+            return false;
+        }
+        self.timer = Self::CHECK_INTERVAL;
+        let modified = last_modified();
+        let has_changed = modified != self.last_modified;
+        self.last_modified = modified;
+        has_changed
+    }
+}
+
+fn last_modified() -> Option<SystemTime> {
+    fs::metadata(DEFINITIONS_FILE)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+pub struct EntityRegistry {
+    // Indexed by EntityType
+    definitions: Vec<EntityDefinition>,
+    // Indexed by ResearchTopic. Each topic is defined by the action that researches it.
+    research: Vec<ResearchDefinition>,
+    // What the definitions were parsed from, so that they can be stored with a match
+    source: String,
+}
+
+pub struct EntityDefinition {
+    /// What the type is called in code, map files and commands
+    pub name: String,
+    /// What the type is called in the HUD
+    pub display_name: String,
+    pub portrait: String,
+    pub sprite: SpriteDefinition,
+    /// Used to train or construct this type of entity
+    pub hotkey: Option<KeyCode>,
+    pub config: EntityConfig,
+    pub prerequisites: Vec<Prerequisite>,
+    pub supply_cost: u32,
+    pub supply_provided: u32,
+    /// Which types of resources gatherers can bring back to this structure
+    pub drop_off_for: Vec<ResourceType>,
+}
+
+pub struct ResearchDefinition {
+    /// What the topic is called in code, commands and the HUD
+    pub name: String,
+    pub icon: String,
+    pub hotkey: KeyCode,
+    pub config: ResearchConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub enum SpriteDefinition {
+    /// Team-colored sheets with a row per direction. Idling uses the middle column of the
+    /// moving sheet.
+    UnitSheets {
+        moving: String,
+        attacking: Option<String>,
+    },
+    /// A single team-colored image. The origin is how far up and to the left of the entity's
+    /// cell it is drawn.
+    Static { image: String, origin: [f32; 2] },
+}
+
+impl EntityRegistry {
+    pub fn load_from_file(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Self::parse(&contents, Path::new(IMAGES_DIR)).map_err(|e| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid entity definitions in {:?}: {}", path, e),
+            )
+        })
+    }
+
+    fn parse(contents: &str, images_dir: &Path) -> Result<Self, String> {
+        let raw_definitions: Vec<RawDefinition> =
+            ron::from_str(contents).map_err(|e| e.to_string())?;
+
+        let mut types_by_name: HashMap<&str, EntityType> = HashMap::new();
+        for (i, raw) in raw_definitions.iter().enumerate() {
+            if types_by_name
+                .insert(&raw.name, EntityType::from_index(i))
+                .is_some()
+            {
+                return Err(format!("{} is defined more than once", raw.name));
+            }
+        }
+        for name in REQUIRED_TYPES {
+            if !types_by_name.contains_key(name) {
+                return Err(format!(
+                    "{} must be defined, as the game refers to it",
+                    name
+                ));
+            }
+        }
+
+        let mut topics_by_name: HashMap<&str, ResearchTopic> = HashMap::new();
+        for (_, topic, _) in raw_research(&raw_definitions) {
+            let index = topics_by_name.len();
+            if topics_by_name
+                .insert(topic, ResearchTopic::from_index(index))
+                .is_some()
+            {
+                return Err(format!("{} research is defined more than once", topic));
+            }
+        }
+        for name in REQUIRED_TOPICS {
+            if !topics_by_name.contains_key(name) {
+                return Err(format!(
+                    "{} research must be defined, as the game refers to it",
+                    name
+                ));
+            }
+        }
+
+        let resolver = Resolver {
+            raw_definitions: &raw_definitions,
+            types_by_name,
+            topics_by_name,
+            images_dir,
+        };
+        let definitions = raw_definitions
+            .iter()
+            .map(|raw| {
+                resolver
+                    .resolve(raw)
+                    .map_err(|e| format!("{}: {}", raw.name, e))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let research = raw_research(&raw_definitions)
+            .map(|(raw, _, action)| {
+                resolver
+                    .resolve_research(action)
+                    .map_err(|e| format!("{}: {}", raw.name, e))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            definitions,
+            research,
+            source: contents.to_owned(),
+        })
+    }
+
+    /// The contents of the definitions file, as it was when these definitions were read
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn get(&self, entity_type: EntityType) -> &EntityDefinition {
+        &self.definitions[entity_type.index()]
+    }
+
+    pub fn type_named(&self, name: &str) -> Option<EntityType> {
+        self.definitions
+            .iter()
+            .position(|definition| definition.name == name)
+            .map(EntityType::from_index)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (EntityType, &EntityDefinition)> {
+        self.definitions
+            .iter()
+            .enumerate()
+            .map(|(i, definition)| (EntityType::from_index(i), definition))
+    }
+
+    pub fn research(&self, topic: ResearchTopic) -> &ResearchConfig {
+        &self.research_definition(topic).config
+    }
+
+    pub fn research_definition(&self, topic: ResearchTopic) -> &ResearchDefinition {
+        &self.research[topic.index()]
+    }
+
+    pub fn topic_named(&self, name: &str) -> Option<ResearchTopic> {
+        self.research
+            .iter()
+            .position(|definition| definition.name == name)
+            .map(ResearchTopic::from_index)
+    }
+
+    pub fn research_definitions(
+        &self,
+    ) -> impl Iterator<Item = (ResearchTopic, &ResearchDefinition)> {
+        self.research
+            .iter()
+            .enumerate()
+            .map(|(i, definition)| (ResearchTopic::from_index(i), definition))
+    }
+
+    pub fn research_topics(&self) -> impl Iterator<Item = (ResearchTopic, &ResearchConfig)> {
+        self.research_definitions()
+            .map(|(topic, definition)| (topic, &definition.config))
+    }
+
+    fn balance_changes(&self, reloaded: &EntityRegistry) -> Result<Vec<String>, String> {
+        if self.definitions.len() != reloaded.definitions.len() {
+            return Err("types can't be added or removed while the game is running".to_owned());
+        }
+        if self.research.len() != reloaded.research.len() {
+            return Err(
+                "research topics can't be added or removed while the game is running".to_owned(),
+            );
+        }
+        let mut changes = vec![];
+        for (old, new) in self.definitions.iter().zip(&reloaded.definitions) {
+            let fields = fields_needing_restart(old, new);
+            if !fields.is_empty() {
+                return Err(format!(
+                    "{}: {} can't be changed while the game is running",
+                    old.name,
+                    fields.join(", ")
+                ));
+            }
+            let old_numbers = balance_numbers(old);
+            let new_numbers = balance_numbers(new);
+            for ((label, old_value), (_, new_value)) in old_numbers.iter().zip(new_numbers) {
+                if *old_value != new_value {
+                    changes.push(format!(
+                        "{} {} {} -> {}",
+                        old.name, label, old_value, new_value
+                    ));
+                }
+            }
+        }
+        // Research time and cost are numbers of the action, and were compared above
+        for (old_definition, new_definition) in self.research.iter().zip(&reloaded.research) {
+            let topic = &old_definition.name;
+            let (old, new) = (&old_definition.config, &new_definition.config);
+            let mut fields = vec![];
+            if old_definition.name != new_definition.name {
+                fields.push("name");
+            }
+            if old_definition.icon != new_definition.icon {
+                fields.push("icon");
+            }
+            if old_definition.hotkey != new_definition.hotkey {
+                fields.push("hotkey");
+            }
+            if old.prerequisites != new.prerequisites {
+                fields.push("prerequisites");
+            }
+            let is_same_kind = |(a, b): (&ResearchEffect, &ResearchEffect)| {
+                std::mem::discriminant(a) == std::mem::discriminant(b)
+            };
+            if old.effects.len() != new.effects.len()
+                || !old.effects.iter().zip(&new.effects).all(is_same_kind)
+            {
+                fields.push("kinds of effects");
+            }
+            if !fields.is_empty() {
+                return Err(format!(
+                    "{} research: {} can't be changed while the game is running",
+                    topic,
+                    fields.join(", ")
+                ));
+            }
+            if old.effects != new.effects {
+                changes.push(format!(
+                    "{} research effects {} -> {}",
+                    topic,
+                    describe_effects(&old.effects),
+                    describe_effects(&new.effects)
+                ));
+            }
+        }
+        Ok(changes)
+    }
+}
+
+// Each research action, along with the definition it belongs to and its topic, in the order that
+// they appear in the file
+fn raw_research(
+    raw_definitions: &[RawDefinition],
+) -> impl Iterator<Item = (&RawDefinition, &str, &RawAction)> {
+    raw_definitions.iter().flat_map(|raw| {
+        raw.actions.values().filter_map(move |action| match action {
+            RawAction::Research { topic, .. } => Some((raw, topic.as_str(), action)),
+            _ => None,
+        })
+    })
+}
+
+// Anything that differs between the definitions, other than balance numbers
+fn fields_needing_restart(old: &EntityDefinition, new: &EntityDefinition) -> Vec<String> {
+    let mut fields = vec![];
+    let mut check = |name: &str, is_same: bool| {
+        if !is_same {
+            fields.push(name.to_owned());
+        }
+    };
+    check("name", old.name == new.name);
+    check("display name", old.display_name == new.display_name);
+    check("portrait", old.portrait == new.portrait);
+    check("sprite", old.sprite == new.sprite);
+    check("hotkey", old.hotkey == new.hotkey);
+    check("prerequisites", old.prerequisites == new.prerequisites);
+    check("drop-off resources", old.drop_off_for == new.drop_off_for);
+    let rebalanced = rebalanced(&old.config, &new.config);
+    check("max health", rebalanced.max_health == new.config.max_health);
+    check("category", rebalanced.category == new.config.category);
+    for (slot, (action, new_action)) in rebalanced
+        .actions
+        .iter()
+        .zip(&new.config.actions)
+        .enumerate()
+    {
+        check(&format!("action slot {}", slot), action == new_action);
+    }
+    fields
+}
+
+// The old config, with the balance numbers of the new one
+fn rebalanced(old: &EntityConfig, new: &EntityConfig) -> EntityConfig {
+    let mut config = old.clone();
+    // Whether an entity has health at all can't change
+    if let (Some(max_health), Some(new_max_health)) = (config.max_health.as_mut(), new.max_health) {
+        *max_health = new_max_health;
+    }
+    config.sight_radius = new.sight_radius;
+    if let (CategoryConfig::Resource(resource), CategoryConfig::Resource(new_resource)) =
+        (&mut config.category, &new.category)
+    {
+        resource.gather_time = new_resource.gather_time;
+        resource.carry_amount = new_resource.carry_amount;
+    }
+    for (action, new_action) in config.actions.iter_mut().zip(new.actions) {
+        if let (Some(action), Some(new_action)) = (action.as_mut(), new_action) {
+            match (action, new_action) {
+                (ActionConfig::Move(cooldown), ActionConfig::Move(new_cooldown)) => {
+                    *cooldown = new_cooldown;
+                }
+                (ActionConfig::Attack(attack), ActionConfig::Attack(new_attack)) => {
+                    attack.damage = new_attack.damage;
+                    attack.range = new_attack.range;
+                    attack.acquisition_range = new_attack.acquisition_range;
+                    // Melee attacks can't become ranged, or the other way around
+                    if let (Some(projectile), Some(new_projectile)) =
+                        (attack.projectile.as_mut(), new_attack.projectile)
+                    {
+                        *projectile = new_projectile;
+                    }
+                }
+                (
+                    ActionConfig::StartActivity(target, activity),
+                    ActionConfig::StartActivity(new_target, new_activity),
+                ) if *target == new_target => *activity = new_activity,
+                (
+                    ActionConfig::Construct(structure_type, construction),
+                    ActionConfig::Construct(new_structure_type, new_construction),
+                ) if *structure_type == new_structure_type => {
+                    construction.construction_time = new_construction.construction_time;
+                    construction.cost = new_construction.cost;
+                    construction.refund_percent = new_construction.refund_percent;
+                }
+                _ => {}
+            }
+        }
+    }
+    config
+}
+
+// Labelled numbers that can be compared between two definitions of the same shape
+fn balance_numbers(definition: &EntityDefinition) -> Vec<(String, String)> {
+    let config = &definition.config;
+    let mut numbers = vec![
+        ("sight radius".to_owned(), config.sight_radius.to_string()),
+        ("supply cost".to_owned(), definition.supply_cost.to_string()),
+        (
+            "supply provided".to_owned(),
+            definition.supply_provided.to_string(),
+        ),
+    ];
+    if let Some(max_health) = config.max_health {
+        numbers.push(("max health".to_owned(), max_health.to_string()));
+    }
+    if let CategoryConfig::Resource(resource) = &config.category {
+        numbers.push((
+            "gather time".to_owned(),
+            format_seconds(resource.gather_time),
+        ));
+        numbers.push(("carry amount".to_owned(), resource.carry_amount.to_string()));
+    }
+    for action in config.actions.iter().flatten() {
+        match action {
+            ActionConfig::Move(cooldown) => {
+                numbers.push(("move cooldown".to_owned(), format_seconds(*cooldown)));
+            }
+            ActionConfig::Attack(attack) => {
+                numbers.push(("damage".to_owned(), attack.damage.to_string()));
+                numbers.push(("range".to_owned(), attack.range.to_string()));
+                numbers.push((
+                    "acquisition range".to_owned(),
+                    attack.acquisition_range.to_string(),
+                ));
+                if let Some(projectile) = &attack.projectile {
+                    numbers.push(("projectile speed".to_owned(), projectile.speed.to_string()));
+                    numbers.push(("accuracy".to_owned(), projectile.accuracy.to_string()));
+                }
+            }
+            ActionConfig::StartActivity(target, activity) => {
+                let name = match target {
+                    ActivityTarget::Train(entity_type) => format!("{} training", entity_type),
+                    ActivityTarget::Research(topic) => format!("{} research", topic),
+                };
+                numbers.push((format!("{} time", name), format_seconds(activity.duration)));
+                numbers.push((format!("{} cost", name), activity.cost.to_string()));
+            }
+            ActionConfig::Construct(structure_type, construction) => {
+                numbers.push((
+                    format!("{} construction time", structure_type),
+                    format_seconds(construction.construction_time),
+                ));
+                numbers.push((
+                    format!("{} construction cost", structure_type),
+                    construction.cost.to_string(),
+                ));
+                numbers.push((
+                    format!("{} refund percent", structure_type),
+                    construction.refund_percent.to_string(),
+                ));
+            }
+            _ => {}
+        }
+    }
+    numbers
+}
+
+fn describe_effects(effects: &[ResearchEffect]) -> String {
+    let effects: Vec<String> = effects.iter().map(ResearchEffect::to_string).collect();
+    effects.join(", ")
+}
+
+fn format_seconds(duration: Duration) -> String {
+    format!("{}s", duration.as_secs_f32())
+}
+
+// The layout of a definition in the file, where other types are referred to by name
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawDefinition {
+    name: String,
+    display_name: String,
+    portrait: String,
+    sprite: SpriteDefinition,
+    #[serde(default)]
+    hotkey: Option<char>,
+    #[serde(default)]
+    max_health: Option<u32>,
+    sight_radius: u32,
+    category: RawCategory,
+    #[serde(default)]
+    actions: BTreeMap<usize, RawAction>,
+    #[serde(default)]
+    prerequisites: Vec<RawPrerequisite>,
+    #[serde(default)]
+    supply_cost: u32,
+    #[serde(default)]
+    supply_provided: u32,
+    #[serde(default)]
+    drop_off_for: Vec<ResourceType>,
+}
+
+#[derive(Deserialize)]
+enum RawCategory {
+    Unit,
+    Structure {
+        size: [u32; 2],
+    },
+    Resource {
+        resource_type: ResourceType,
+        capacity: u32,
+        gather_time: f32,
+        carry_amount: u32,
+    },
+}
+
+#[derive(Deserialize)]
+enum RawAction {
+    Train {
+        unit: String,
+        duration: f32,
+        cost: Cost,
+    },
+    Research {
+        topic: String,
+        icon: String,
+        hotkey: char,
+        duration: f32,
+        cost: Cost,
+        #[serde(default)]
+        prerequisites: Vec<RawPrerequisite>,
+        effects: Vec<ResearchEffect>,
+    },
+    Construct {
+        structure: String,
+        duration: f32,
+        cost: Cost,
+        refund_percent: u32,
+        model: ConstructionModel,
+    },
+    Stop,
+    Move {
+        cooldown: f32,
+    },
+    Attack(AttackConfig),
+    Patrol,
+    ChangeStance,
+    GatherResource,
+    ReturnResource,
+    Repair,
+}
+
+#[derive(Deserialize)]
+enum RawPrerequisite {
+    Research(String),
+    Structure(String),
+}
+
+struct Resolver<'a> {
+    raw_definitions: &'a [RawDefinition],
+    types_by_name: HashMap<&'a str, EntityType>,
+    topics_by_name: HashMap<&'a str, ResearchTopic>,
+    images_dir: &'a Path,
+}
+
+impl Resolver<'_> {
+    fn resolve(&self, raw: &RawDefinition) -> Result<EntityDefinition, String> {
+        self.check_image_exists(&Path::new("icons").join(&raw.portrait))?;
+        match &raw.sprite {
+            SpriteDefinition::UnitSheets { moving, attacking } => {
+                self.check_image_exists(Path::new(moving))?;
+                if let Some(attacking) = attacking {
+                    self.check_image_exists(Path::new(attacking))?;
+                }
+            }
+            SpriteDefinition::Static { image, .. } => self.check_image_exists(Path::new(image))?,
+        }
+        let hotkey = raw.hotkey.map(keycode).transpose()?;
+
+        let category = match raw.category {
+            RawCategory::Unit => CategoryConfig::Unit,
+            RawCategory::Structure { size } => CategoryConfig::StructureSize(size),
+            RawCategory::Resource {
+                resource_type,
+                capacity,
+                gather_time,
+                carry_amount,
+            } => CategoryConfig::Resource(ResourceConfig {
+                resource_type,
+                capacity,
+                gather_time: seconds(gather_time)?,
+                carry_amount,
+            }),
+        };
+
+        let mut actions = [None; NUM_ENTITY_ACTIONS];
+        for (&slot, action) in &raw.actions {
+            if slot >= NUM_ENTITY_ACTIONS {
+                return Err(format!(
+                    "action slot {} is out of range (there are {} slots)",
+                    slot, NUM_ENTITY_ACTIONS
+                ));
+            }
+            actions[slot] = Some(self.resolve_action(action)?);
+        }
+        let has_movement = actions
+            .iter()
+            .any(|action| matches!(action, Some(ActionConfig::Move(_))));
+        if matches!(category, CategoryConfig::Unit) != has_movement {
+            return Err("units, and only units, must have a Move action".to_owned());
+        }
+        let has_attack = actions
+            .iter()
+            .any(|action| matches!(action, Some(ActionConfig::Attack(_))));
+        if has_attack
+            && matches!(
+                raw.sprite,
+                SpriteDefinition::UnitSheets {
+                    attacking: None,
+                    ..
+                }
+            )
+        {
+            return Err("has an Attack action but no attacking sheet".to_owned());
+        }
+
+        let prerequisites = self.resolve_prerequisites(&raw.prerequisites)?;
+
+        if !raw.drop_off_for.is_empty() && !matches!(category, CategoryConfig::StructureSize(_)) {
+            return Err("only structures can be resource drop-offs".to_owned());
+        }
+
+        Ok(EntityDefinition {
+            name: raw.name.clone(),
+            display_name: raw.display_name.clone(),
+            portrait: raw.portrait.clone(),
+            sprite: raw.sprite.clone(),
+            hotkey,
+            config: EntityConfig {
+                max_health: raw.max_health,
+                sight_radius: raw.sight_radius,
+                category,
+                actions,
+            },
+            prerequisites,
+            supply_cost: raw.supply_cost,
+            supply_provided: raw.supply_provided,
+            drop_off_for: raw.drop_off_for.clone(),
+        })
+    }
+
+    fn resolve_prerequisites(
+        &self,
+        prerequisites: &[RawPrerequisite],
+    ) -> Result<Vec<Prerequisite>, String> {
+        prerequisites
+            .iter()
+            .map(|prerequisite| match prerequisite {
+                RawPrerequisite::Research(topic) => {
+                    self.find_topic(topic).map(Prerequisite::Research)
+                }
+                RawPrerequisite::Structure(name) => self
+                    .find(name, Kind::Structure)
+                    .map(Prerequisite::Structure),
+            })
+            .collect()
+    }
+
+    fn resolve_action(&self, action: &RawAction) -> Result<ActionConfig, String> {
+        let action = match action {
+            RawAction::Train {
+                unit,
+                duration,
+                cost,
+            } => ActionConfig::StartActivity(
+                ActivityTarget::Train(self.find(unit, Kind::Unit)?),
+                ActivityConfig {
+                    duration: positive_seconds(*duration)?,
+                    cost: *cost,
+                },
+            ),
+            RawAction::Research {
+                topic,
+                duration,
+                cost,
+                ..
+            } => ActionConfig::StartActivity(
+                ActivityTarget::Research(self.find_topic(topic)?),
+                ActivityConfig {
+                    duration: positive_seconds(*duration)?,
+                    cost: *cost,
+                },
+            ),
+            RawAction::Construct {
+                structure,
+                duration,
+                cost,
+                refund_percent,
+                model,
+            } => ActionConfig::Construct(
+                self.find(structure, Kind::Structure)?,
+                ConstructionConfig {
+                    construction_time: positive_seconds(*duration)?,
+                    cost: *cost,
+                    refund_percent: *refund_percent,
+                    model: *model,
+                },
+            ),
+            RawAction::Stop => ActionConfig::Stop,
+            RawAction::Move { cooldown } => ActionConfig::Move(seconds(*cooldown)?),
+            RawAction::Attack(config) => ActionConfig::Attack(*config),
+            RawAction::Patrol => ActionConfig::Patrol,
+            RawAction::ChangeStance => ActionConfig::ChangeStance,
+            RawAction::GatherResource => ActionConfig::GatherResource,
+            RawAction::ReturnResource => ActionConfig::ReturnResource,
+            RawAction::Repair => ActionConfig::Repair,
+        };
+        Ok(action)
+    }
+
+    fn resolve_research(&self, action: &RawAction) -> Result<ResearchDefinition, String> {
+        let RawAction::Research {
+            topic,
+            icon,
+            hotkey,
+            duration,
+            cost,
+            prerequisites,
+            effects,
+        } = action
+        else {
+            unreachable!("not a research action");
+        };
+        self.check_image_exists(&Path::new("icons").join(icon))?;
+        Ok(ResearchDefinition {
+            name: topic.clone(),
+            icon: icon.clone(),
+            hotkey: keycode(*hotkey)?,
+            config: ResearchConfig {
+                cost: *cost,
+                duration: positive_seconds(*duration)?,
+                prerequisites: self.resolve_prerequisites(prerequisites)?,
+                effects: effects.clone(),
+            },
+        })
+    }
+
+    fn find_topic(&self, name: &str) -> Result<ResearchTopic, String> {
+        self.topics_by_name
+            .get(name)
+            .copied()
+            .ok_or_else(|| format!("unknown research topic {:?}", name))
+    }
+
+    // Trained and constructed types need a hotkey, as they show up as buttons in the HUD
+    fn find(&self, name: &str, kind: Kind) -> Result<EntityType, String> {
+        let entity_type = *self
+            .types_by_name
+            .get(name)
+            .ok_or_else(|| format!("unknown {} type {:?}", kind.description(), name))?;
+        let raw = &self.raw_definitions[entity_type.index()];
+        let is_expected_kind = match kind {
+            Kind::Unit => matches!(raw.category, RawCategory::Unit),
+            Kind::Structure => matches!(raw.category, RawCategory::Structure { .. }),
+        };
+        if !is_expected_kind {
+            return Err(format!("{} is not a {}", name, kind.description()));
+        }
+        if raw.hotkey.is_none() {
+            return Err(format!("{} must have a hotkey", name));
+        }
+        Ok(entity_type)
+    }
+
+    fn check_image_exists(&self, relative_path: &Path) -> Result<(), String> {
+        if self.images_dir.join(relative_path).is_file() {
+            Ok(())
+        } else {
+            Err(format!("missing image {:?}", relative_path))
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Kind {
+    Unit,
+    Structure,
+}
+
+impl Kind {
+    fn description(&self) -> &'static str {
+        match self {
+            Kind::Unit => "unit",
+            Kind::Structure => "structure",
+        }
+    }
+}
+
+fn seconds(value: f32) -> Result<Duration, String> {
+    Duration::try_from_secs_f32(value).map_err(|_| format!("invalid duration: {}", value))
+}
+
+// For things that progress over time, which would otherwise be done before they have started
+fn positive_seconds(value: f32) -> Result<Duration, String> {
+    let duration = seconds(value)?;
+    if duration.is_zero() {
+        return Err(format!("duration must be more than zero: {}", value));
+    }
+    Ok(duration)
+}
+
+fn keycode(hotkey: char) -> Result<KeyCode, String> {
+    let keycode = match hotkey.to_ascii_uppercase() {
+        'A' => KeyCode::A,
+        'B' => KeyCode::B,
+        'C' => KeyCode::C,
+        'D' => KeyCode::D,
+        'E' => KeyCode::E,
+        'F' => KeyCode::F,
+        'G' => KeyCode::G,
+        'H' => KeyCode::H,
+        'I' => KeyCode::I,
+        'J' => KeyCode::J,
+        'K' => KeyCode::K,
+        'L' => KeyCode::L,
+        'M' => KeyCode::M,
+        'N' => KeyCode::N,
+        'O' => KeyCode::O,
+        'P' => KeyCode::P,
+        'Q' => KeyCode::Q,
+        'R' => KeyCode::R,
+        'S' => KeyCode::S,
+        'T' => KeyCode::T,
+        'U' => KeyCode::U,
+        'V' => KeyCode::V,
+        'W' => KeyCode::W,
+        'X' => KeyCode::X,
+        'Y' => KeyCode::Y,
+        'Z' => KeyCode::Z,
+        _ => return Err(format!("hotkey must be a letter, not {:?}", hotkey)),
+    };
+    Ok(keycode)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_modified(original: &str, replacement: &str) -> Result<EntityRegistry, String> {
+        let contents = fs::read_to_string(DEFINITIONS_FILE).unwrap();
+        assert!(contents.contains(original));
+        let contents = contents.replacen(original, replacement, 1);
+        EntityRegistry::parse(&contents, Path::new(IMAGES_DIR))
+    }
+
+    #[test]
+    fn definitions_file_is_valid() {
+        let registry = EntityRegistry::load_from_file(Path::new(DEFINITIONS_FILE)).unwrap();
+        for name in REQUIRED_TYPES {
+            let entity_type = registry.type_named(name).unwrap();
+            assert_eq!(registry.get(entity_type).name, name);
+        }
+    }
+
+    #[test]
+    fn invalid_definitions_are_rejected_with_clear_errors() {
+        let error = parse_modified(r#"unit: "Enforcer""#, r#"unit: "Paladin""#).err();
+        assert_eq!(
+            error.as_deref(),
+            Some(r#"BattleAcademy: unknown unit type "Paladin""#)
+        );
+
+        let error = parse_modified("5: Research(", "8: Research(").err();
+        assert_eq!(
+            error.as_deref(),
+            Some("TechLab: action slot 8 is out of range (there are 8 slots)")
+        );
+
+        let error = parse_modified(r#"portrait: "tech_lab.png""#, r#"portrait: "lab.png""#).err();
+        assert_eq!(
+            error.as_deref(),
+            Some(r#"TechLab: missing image "icons/lab.png""#)
+        );
+
+        let error = parse_modified("duration: 6.0", "duration: 0.0").err();
+        assert_eq!(
+            error.as_deref(),
+            Some("Engineer: duration must be more than zero: 0")
+        );
+
+        let error = parse_modified(r#"topic: "Extraction""#, r#"topic: "Weapons""#).err();
+        assert_eq!(
+            error.as_deref(),
+            Some("Weapons research is defined more than once")
+        );
+
+        let error = parse_modified(r#"Research("Propulsion")"#, r#"Research("Stealth")"#).err();
+        assert_eq!(
+            error.as_deref(),
+            Some(r#"TechLab: unknown research topic "Stealth""#)
+        );
+
+        let error = parse_modified(r#"icon: "research_armor.png""#, r#"icon: "armor.png""#).err();
+        assert_eq!(
+            error.as_deref(),
+            Some(r#"TechLab: missing image "icons/armor.png""#)
+        );
+
+        let error = parse_modified("hotkey: 'H'", "hotkey: '7'").err();
+        assert_eq!(
+            error.as_deref(),
+            Some("TechLab: hotkey must be a letter, not '7'")
+        );
+
+        let error = parse_modified(
+            r#"attacking: Some("enforcer_attacking_sheet.png")"#,
+            "attacking: None",
+        )
+        .err();
+        assert_eq!(
+            error.as_deref(),
+            Some("Enforcer: has an Attack action but no attacking sheet")
+        );
+    }
+
+    #[test]
+    fn reloading_only_accepts_balance_changes() {
+        let registry = EntityRegistry::load_from_file(Path::new(DEFINITIONS_FILE)).unwrap();
+        let changes = |original, replacement| {
+            let reloaded = parse_modified(original, replacement).unwrap();
+            registry.balance_changes(&reloaded)
+        };
+
+        // Numbers of the entity itself
+        assert_eq!(
+            changes(
+                "sight_radius: 4,\n        category: Unit,\n        supply_cost: 1,",
+                "sight_radius: 5,\n        category: Unit,\n        supply_cost: 2,",
+            ),
+            Ok(vec![
+                "Engineer sight radius 4 -> 5".to_owned(),
+                "Engineer supply cost 1 -> 2".to_owned(),
+            ])
+        );
+
+        // Attacks, melee and ranged
+        assert_eq!(
+            changes(
+                "Attack((damage: 2, range: 1,",
+                "Attack((damage: 3, range: 1,"
+            ),
+            Ok(vec!["Enforcer damage 2 -> 3".to_owned()])
+        );
+        assert_eq!(
+            changes(
+                "range: 4,\n                acquisition_range: 5,\n                \
+                 projectile: Some((speed: 8.0, accuracy: 0.8)),",
+                "range: 5,\n                acquisition_range: 6,\n                \
+                 projectile: Some((speed: 10.0, accuracy: 0.9)),",
+            ),
+            Ok(vec![
+                "Ranger range 4 -> 5".to_owned(),
+                "Ranger acquisition range 5 -> 6".to_owned(),
+                "Ranger projectile speed 8 -> 10".to_owned(),
+                "Ranger accuracy 0.8 -> 0.9".to_owned(),
+            ])
+        );
+
+        // Construction
+        assert_eq!(
+            changes(
+                "refund_percent: 75,\n                model: Assisted,",
+                "refund_percent: 50,\n                model: Assisted,",
+            ),
+            Ok(vec![
+                "Engineer BattleAcademy refund percent 75 -> 50".to_owned()
+            ])
+        );
+
+        // Resources
+        assert_eq!(
+            changes(
+                "gather_time: 1.5, carry_amount: 1",
+                "gather_time: 1.0, carry_amount: 2"
+            ),
+            Ok(vec![
+                "FuelRift gather time 1.5s -> 1s".to_owned(),
+                "FuelRift carry amount 1 -> 2".to_owned(),
+            ])
+        );
+
+        // Research
+        assert_eq!(
+            changes(
+                "duration: 4.0,\n                cost: (fuel: 3),\n                effects: [Damage(1)]",
+                "duration: 5.0,\n                cost: (fuel: 3),\n                effects: [Damage(2)]"
+            ),
+            Ok(vec![
+                "TechLab Weapons research time 4s -> 5s".to_owned(),
+                "Weapons research effects +1 damage -> +2 damage".to_owned(),
+            ])
+        );
+
+        // Anything else needs a restart
+        assert_eq!(
+            changes("hotkey: Some('F')", "hotkey: Some('Q')"),
+            Err("Enforcer: hotkey can't be changed while the game is running".to_owned())
+        );
+        assert_eq!(
+            changes(
+                "range: 1, acquisition_range: 4, projectile: None",
+                "range: 1, acquisition_range: 4, projectile: Some((speed: 8.0, accuracy: 0.8))"
+            ),
+            Err("Enforcer: action slot 2 can't be changed while the game is running".to_owned())
+        );
+        assert_eq!(
+            changes("model: ConsumeBuilder", "model: Assisted"),
+            Err("Engineer: action slot 6 can't be changed while the game is running".to_owned())
+        );
+        assert_eq!(
+            changes("capacity: 30,", "capacity: 20,"),
+            Err("FuelRift: category can't be changed while the game is running".to_owned())
+        );
+        assert_eq!(
+            changes(
+                "prerequisites: [Research(\"Weapons\")],\n                effects",
+                "prerequisites: [],\n                effects"
+            ),
+            Err(
+                "Armor research: prerequisites can't be changed while the game is running"
+                    .to_owned()
+            )
+        );
+        assert_eq!(
+            changes("effects: [Sight(1)]", "effects: [Damage(1)]"),
+            Err(
+                "Optics research: kinds of effects can't be changed while the game is running"
+                    .to_owned()
+            )
+        );
+    }
+}
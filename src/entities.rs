@@ -1,36 +1,85 @@
 use std::cmp::min;
-use std::collections::HashMap;
-use std::sync::atomic::{self, AtomicUsize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{self, Display, Formatter};
+use std::num::ParseIntError;
+use std::str::FromStr;
 use std::time::Duration;
 
 use ggez::graphics::Rect;
+use serde::{Deserialize, Serialize};
 
-use crate::data::EntityType;
+use crate::command::Command;
+use crate::data::{EntityType, ResearchTopic, ResourceType};
 use crate::game::{self, CELL_PIXEL_SIZE};
 use crate::grid::CellRect;
 
-static NEXT_ENTITY_ID: AtomicUsize = AtomicUsize::new(1);
+pub const NUM_ENTITY_ACTIONS: usize = 8;
 
-pub const NUM_ENTITY_ACTIONS: usize = 6;
-
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub struct EntityId(usize);
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+impl Display for EntityId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for EntityId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(EntityId)
+    }
+}
+
+/// Hands out entity IDs. Each game world has its own allocator, so that the IDs of a match
+/// don't depend on anything else that has happened in the process.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntityIdAllocator {
+    next: usize,
+}
+
+impl EntityIdAllocator {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self { next: 1 }
+    }
+
+    pub fn continuing_after<'a>(entities: impl IntoIterator<Item = &'a Entity>) -> Self {
+        let max_id = entities.into_iter().map(|entity| entity.id.0).max();
+        Self {
+            next: max_id.map_or(1, |id| id + 1),
+        }
+    }
+
+    pub fn next(&mut self) -> EntityId {
+        let id = EntityId(self.next);
+        self.next += 1;
+        id
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub enum EntityState {
     Idle,
     DoingActivity(ActivityTarget),
     MovingToConstruction(EntityType, [u32; 2]),
     Moving,
+    AttackMoving,
+    Patrolling,
+    Following(EntityId),
     MovingToAttackTarget(EntityId),
     Attacking(EntityId),
     MovingToResource(EntityId),
     GatheringResource(EntityId),
     ReturningResource(EntityId),
+    MovingToRepair(EntityId),
+    Repairing(EntityId),
+    Constructing(EntityId),
     UnderConstruction(Duration, Duration),
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Hash, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Hash, Eq, Serialize, Deserialize)]
 pub enum Direction {
     North,
     NorthEast,
@@ -42,7 +91,7 @@ pub enum Direction {
     NorthWest,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Entity {
     pub entity_type: EntityType,
     pub id: EntityId,
@@ -54,22 +103,34 @@ pub struct Entity {
     pub activity: Option<ActivityComponent>,
     pub action_slots: [Option<ActionSlot>; NUM_ENTITY_ACTIONS],
     pub state: EntityState,
+    /// How far (in cells) the entity reveals the map for its team
+    pub sight_radius: u32,
+    /// Only set for a structure that is under construction
+    pub construction_site: Option<ConstructionSite>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AnimationState {
     pub ms_counter: u16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)]
 pub enum EntityCategory {
     Unit(UnitComponent),
-    Structure { size: [u32; 2] },
-    Resource { remaining: u32 },
+    Structure {
+        size: [u32; 2],
+    },
+    Resource {
+        remaining: u32,
+        config: ResourceConfig,
+    },
 }
 
+#[derive(Clone, PartialEq)]
 pub struct EntityConfig {
     pub max_health: Option<u32>,
+    pub sight_radius: u32,
     pub category: CategoryConfig,
     pub actions: [Option<ActionConfig>; NUM_ENTITY_ACTIONS],
 }
@@ -80,32 +141,45 @@ pub enum ActionConfig {
     Construct(EntityType, ConstructionConfig),
     Stop,
     Move(Duration),
-    Attack(u32),
+    Attack(AttackConfig),
+    Patrol,
+    ChangeStance,
     GatherResource,
     ReturnResource,
+    Repair,
 }
 
+#[derive(Clone, PartialEq)]
 pub enum CategoryConfig {
     Unit,
     StructureSize([u32; 2]),
-    ResourceCapacity(u32),
+    Resource(ResourceConfig),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResourceConfig {
+    pub resource_type: ResourceType,
+    pub capacity: u32,
+    /// How long it takes a unit to gather one load
+    pub gather_time: Duration,
+    /// How much one load holds
+    pub carry_amount: u32,
 }
 
 impl Entity {
     pub fn new(
         entity_type: EntityType,
+        id: EntityId,
         config: EntityConfig,
         position: [u32; 2],
         team: Team,
     ) -> Self {
-        // Make sure all entities have unique IDs
-        let id = EntityId(NEXT_ENTITY_ID.fetch_add(1, atomic::Ordering::Relaxed));
-
         let health = config.max_health.map(HealthComponent::new);
-        let mut activity_options: HashMap<ActivityTarget, ActivityConfig> = Default::default();
-        let mut construction_options: HashMap<EntityType, ConstructionConfig> = Default::default();
-        let mut attack_damage = None;
+        let mut activity_options: BTreeMap<ActivityTarget, ActivityConfig> = Default::default();
+        let mut construction_options: BTreeMap<EntityType, ConstructionConfig> = Default::default();
+        let mut attack_config = None;
         let mut can_gather = false;
+        let mut can_repair = false;
         let mut movement_cooldown = None;
         let mut action_slots = [None; NUM_ENTITY_ACTIONS];
         for (i, action) in config.actions.into_iter().enumerate() {
@@ -119,8 +193,8 @@ impl Entity {
                         construction_options.insert(structure_type, config);
                         Action::Construct(structure_type, config)
                     }
-                    ActionConfig::Attack(damage) => {
-                        attack_damage = Some(damage);
+                    ActionConfig::Attack(config) => {
+                        attack_config = Some(config);
                         Action::Attack
                     }
                     ActionConfig::GatherResource => {
@@ -132,29 +206,39 @@ impl Entity {
                         Action::Move
                     }
                     ActionConfig::Stop => Action::Stop,
+                    ActionConfig::Patrol => Action::Patrol,
+                    ActionConfig::ChangeStance => Action::ChangeStance,
                     ActionConfig::ReturnResource => Action::ReturnResource,
+                    ActionConfig::Repair => {
+                        can_repair = true;
+                        Action::Repair
+                    }
                 })
                 .map(ActionSlot::new);
         }
         let activity =
-            (!activity_options.is_empty()).then(|| ActivityComponent::new(activity_options));
-        let construction_options = (!construction_options.is_empty()).then(|| construction_options);
+            (!activity_options.is_empty()).then_some(ActivityComponent::new(activity_options));
+        let construction_options =
+            (!construction_options.is_empty()).then_some(construction_options);
         let category = match config.category {
             CategoryConfig::Unit => {
-                let combat = attack_damage.map(Combat::new);
+                let combat = attack_config.map(Combat::new);
                 let gathering = can_gather.then(Gathering::new);
+                let repairing = can_repair.then(Repairing::new);
                 let cooldown = movement_cooldown.expect("Unit must have movement");
                 EntityCategory::Unit(UnitComponent::new(
                     position,
                     cooldown,
                     combat,
                     gathering,
+                    repairing,
                     construction_options,
                 ))
             }
             CategoryConfig::StructureSize(size) => EntityCategory::Structure { size },
-            CategoryConfig::ResourceCapacity(capacity) => EntityCategory::Resource {
-                remaining: capacity,
+            CategoryConfig::Resource(config) => EntityCategory::Resource {
+                remaining: config.capacity,
+                config,
             },
         };
         let animation = AnimationState { ms_counter: 0 };
@@ -170,6 +254,8 @@ impl Entity {
             activity,
             action_slots,
             state: EntityState::Idle,
+            sight_radius: config.sight_radius,
+            construction_site: None,
         }
     }
 
@@ -209,14 +295,21 @@ impl Entity {
 
     pub fn resource_remaining(&self) -> &u32 {
         match &self.category {
-            EntityCategory::Resource { remaining } => remaining,
+            EntityCategory::Resource { remaining, .. } => remaining,
             _ => panic!("Not a resource"),
         }
     }
 
     pub fn resource_remaining_mut(&mut self) -> &mut u32 {
         match &mut self.category {
-            EntityCategory::Resource { remaining } => remaining,
+            EntityCategory::Resource { remaining, .. } => remaining,
+            _ => panic!("Not a resource"),
+        }
+    }
+
+    pub fn resource_config(&self) -> &ResourceConfig {
+        match &self.category {
+            EntityCategory::Resource { config, .. } => config,
             _ => panic!("Not a resource"),
         }
     }
@@ -242,15 +335,114 @@ impl Entity {
         }
     }
 
+    /// Only combat units have a stance
+    pub fn stance(&self) -> Option<Stance> {
+        match &self.category {
+            EntityCategory::Unit(unit) if unit.combat.is_some() => Some(unit.stance),
+            _ => None,
+        }
+    }
+
     pub fn has_enabled_action(&self, action: Action) -> bool {
         self.action_slots
             .iter()
             .flatten()
             .any(|action_slot| action_slot.action == action && action_slot.enabled)
     }
+
+    /// Take on the balance numbers of an entity of the same type that was just created from
+    /// reloaded definitions. Whatever the entity is doing carries on, and its health stays at the
+    /// same fraction of its max health. A structure that is under construction keeps the
+    /// construction numbers it was started with.
+    pub fn rebalance(&mut self, balanced: &Entity) {
+        if let (Some(health), Some(balanced_health)) = (self.health.as_mut(), &balanced.health) {
+            health.rescale(balanced_health.max);
+        }
+        self.sight_radius = balanced.sight_radius;
+        for (slot, balanced_slot) in self.action_slots.iter_mut().zip(&balanced.action_slots) {
+            if let (Some(slot), Some(balanced_slot)) = (slot, balanced_slot) {
+                // Other actions have no numbers to update
+                match (slot.action, balanced_slot.action) {
+                    (
+                        Action::StartActivity(target, _),
+                        Action::StartActivity(balanced_target, _),
+                    ) if target == balanced_target => {
+                        slot.action = balanced_slot.action;
+                    }
+                    (Action::Construct(structure, _), Action::Construct(balanced_structure, _))
+                        if structure == balanced_structure =>
+                    {
+                        slot.action = balanced_slot.action;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if let (Some(activity), Some(balanced_activity)) =
+            (self.activity.as_mut(), &balanced.activity)
+        {
+            activity.options = balanced_activity.options.clone();
+        }
+        match (&mut self.category, &balanced.category) {
+            (EntityCategory::Unit(unit), EntityCategory::Unit(balanced_unit)) => {
+                if let (Some(combat), Some(balanced_combat)) =
+                    (unit.combat.as_mut(), &balanced_unit.combat)
+                {
+                    combat.config = balanced_combat.config;
+                }
+                unit.sub_cell_movement
+                    .rebalance(&balanced_unit.sub_cell_movement);
+                if let (Some(gathering), Some(balanced_gathering)) =
+                    (unit.gathering.as_mut(), &balanced_unit.gathering)
+                {
+                    gathering.speed_bonus = balanced_gathering.speed_bonus;
+                }
+                unit.construction_options = balanced_unit.construction_options.clone();
+            }
+            (
+                EntityCategory::Resource { config, .. },
+                EntityCategory::Resource {
+                    config: balanced_config,
+                    ..
+                },
+            ) => *config = *balanced_config,
+            _ => {}
+        }
+    }
+
+    /// Effects only apply to units. Those that lack the affected ability are left as they are.
+    pub fn apply_research_effect(&mut self, effect: ResearchEffect) {
+        if !matches!(self.category, EntityCategory::Unit(..)) {
+            return;
+        }
+        match effect {
+            ResearchEffect::Damage(amount) => {
+                if let Some(combat) = self.unit_mut().combat.as_mut() {
+                    combat.config.damage += amount;
+                }
+            }
+            ResearchEffect::MaxHealth(amount) => {
+                if let Some(health) = self.health.as_mut() {
+                    health.max += amount;
+                    health.current += amount;
+                }
+            }
+            ResearchEffect::MoveSpeed(percent) => {
+                self.unit_mut().sub_cell_movement.speed_up(percent);
+            }
+            ResearchEffect::GatherRate(percent) => {
+                if let Some(gathering) = self.unit_mut().gathering.as_mut() {
+                    gathering.speed_up(percent);
+                }
+            }
+            ResearchEffect::Sight(amount) => {
+                self.sight_radius += amount;
+            }
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HealthComponent {
     pub max: u32,
     pub current: u32,
@@ -264,6 +456,19 @@ impl HealthComponent {
         }
     }
 
+    pub fn is_damaged(&self) -> bool {
+        self.current < self.max
+    }
+
+    /// Current health is scaled along with max health (rounded, but never down to zero)
+    fn rescale(&mut self, new_max: u32) {
+        if self.current > 0 {
+            let scaled = (self.current * new_max + self.max / 2) / self.max;
+            self.current = scaled.clamp(1, new_max);
+        }
+        self.max = new_max;
+    }
+
     pub fn receive_healing(&mut self, amount: u32) {
         self.current = min(self.current + amount, self.max);
     }
@@ -275,22 +480,47 @@ impl HealthComponent {
     }
 }
 
-#[derive(Debug, PartialEq, Copy, Clone, Eq, Hash)]
+/// How many players (human or AI) a match can have at most
+pub const MAX_PLAYERS: u8 = 8;
+
+#[derive(Debug, PartialEq, Copy, Clone, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Team {
-    Player,
-    Enemy1,
-    Enemy2,
+    /// Numbered from 0, up to MAX_PLAYERS
+    Player(u8),
+    /// Resources, and anything else that no one plays as
     Neutral,
 }
 
-#[derive(Debug)]
+impl Team {
+    /// The team that is controlled with mouse and keyboard, unless the match is only watched
+    pub const HUMAN: Team = Team::Player(0);
+
+    pub fn players() -> impl Iterator<Item = Team> {
+        (0..MAX_PLAYERS).map(Team::Player)
+    }
+}
+
+impl Display for Team {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Team::Player(i) => write!(f, "Player {}", i + 1),
+            Team::Neutral => write!(f, "Neutral"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UnitComponent {
     pub sub_cell_movement: SubCellMovement,
     pub movement_plan: MovementPlan,
     pub direction: Direction,
     pub combat: Option<Combat>,
     pub gathering: Option<Gathering>,
-    pub construction_options: Option<HashMap<EntityType, ConstructionConfig>>,
+    pub repairing: Option<Repairing>,
+    pub construction_options: Option<BTreeMap<EntityType, ConstructionConfig>>,
+    /// Commands that were queued up (with Shift) to be carried out after the current one
+    pub command_queue: VecDeque<Command>,
+    pub stance: Stance,
 }
 
 impl UnitComponent {
@@ -299,7 +529,8 @@ impl UnitComponent {
         movement_cooldown: Duration,
         combat: Option<Combat>,
         gathering: Option<Gathering>,
-        construction_options: Option<HashMap<EntityType, ConstructionConfig>>,
+        repairing: Option<Repairing>,
+        construction_options: Option<BTreeMap<EntityType, ConstructionConfig>>,
     ) -> Self {
         Self {
             sub_cell_movement: SubCellMovement::new(position, movement_cooldown),
@@ -307,7 +538,10 @@ impl UnitComponent {
             direction: Direction::South,
             combat,
             gathering,
+            repairing,
             construction_options,
+            command_queue: VecDeque::new(),
+            stance: Stance::Aggressive,
         }
     }
 
@@ -330,7 +564,33 @@ impl UnitComponent {
     }
 }
 
-#[derive(Debug)]
+/// How a combat unit deals with enemies that it hasn't been told to attack
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Stance {
+    /// Engages enemies that come near, and chases them for as long as it takes
+    Aggressive,
+    /// Engages enemies that come near, but heads back to its guard point if the chase
+    /// takes it too far away
+    Defensive,
+    /// Attacks enemies that are within range, but never moves on its own
+    HoldPosition,
+    /// Never attacks on its own, not even when attacked
+    Passive,
+}
+
+impl Stance {
+    /// The stance that follows this one, when cycling through them
+    pub fn next(self) -> Self {
+        match self {
+            Stance::Aggressive => Stance::Defensive,
+            Stance::Defensive => Stance::HoldPosition,
+            Stance::HoldPosition => Stance::Passive,
+            Stance::Passive => Stance::Aggressive,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MovementPlan {
     cell_positions: Vec<[u32; 2]>,
     blocked_counter: u32,
@@ -375,7 +635,7 @@ impl MovementPlan {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SubCellMovement {
     previous_position: [u32; 2],
     remaining: Duration,
@@ -393,6 +653,18 @@ impl SubCellMovement {
         }
     }
 
+    // Any ongoing step between two cells finishes at its original pace
+    fn rebalance(&mut self, balanced: &SubCellMovement) {
+        self.straight_movement_cooldown = balanced.straight_movement_cooldown;
+        self.diagonal_movement_cooldown = balanced.diagonal_movement_cooldown;
+    }
+
+    fn speed_up(&mut self, percent: u32) {
+        let factor = 100.0 / (100 + percent) as f32;
+        self.straight_movement_cooldown = self.straight_movement_cooldown.mul_f32(factor);
+        self.diagonal_movement_cooldown = self.diagonal_movement_cooldown.mul_f32(factor);
+    }
+
     pub fn update(&mut self, dt: Duration, position: [u32; 2]) {
         self.remaining = self.remaining.saturating_sub(dt);
         if self.remaining.is_zero() {
@@ -449,69 +721,187 @@ enum MovementDirection {
     None,
 }
 
-#[derive(Debug)]
+pub const MAX_ACTIVITY_QUEUE_LENGTH: usize = 5;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ActivityComponent {
-    ongoing: Option<OngoingActivity>,
-    options: HashMap<ActivityTarget, ActivityConfig>,
+    // The first activity in the queue is the ongoing one. All of them have already been paid for.
+    queue: VecDeque<ActivityTarget>,
+    remaining: Duration,
+    options: BTreeMap<ActivityTarget, ActivityConfig>,
+    rally_point: Option<RallyPoint>,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+/// Where units go once they have been trained
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RallyPoint {
+    Position([u32; 2]),
+    /// A friendly unit, which trained units keep following until they are told otherwise
+    Unit(EntityId),
+    Resource(EntityId),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ActivityConfig {
     pub duration: Duration,
-    pub cost: u32,
+    pub cost: Cost,
 }
 
-#[derive(Debug)]
-struct OngoingActivity {
-    remaining: Duration,
+/// An amount of each type of resource
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Cost {
+    pub fuel: u32,
+    pub minerals: u32,
+}
+
+impl Cost {
+    pub fn fuel(amount: u32) -> Self {
+        Self {
+            fuel: amount,
+            minerals: 0,
+        }
+    }
+
+    pub fn single(resource_type: ResourceType, amount: u32) -> Self {
+        match resource_type {
+            ResourceType::Fuel => Self::fuel(amount),
+            ResourceType::Minerals => Self {
+                fuel: 0,
+                minerals: amount,
+            },
+        }
+    }
+
+    pub fn get(&self, resource_type: ResourceType) -> u32 {
+        match resource_type {
+            ResourceType::Fuel => self.fuel,
+            ResourceType::Minerals => self.minerals,
+        }
+    }
+
+    /// Rounded down, per resource
+    pub fn percent(&self, percent: u32) -> Self {
+        Self {
+            fuel: self.fuel * percent / 100,
+            minerals: self.minerals * percent / 100,
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Copy, Clone, Eq, Hash)]
+impl Display for Cost {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = ResourceType::ALL
+            .iter()
+            .filter(|resource_type| self.get(**resource_type) > 0)
+            .map(|resource_type| format!("{} {}", self.get(*resource_type), resource_type))
+            .collect();
+        if parts.is_empty() {
+            write!(f, "free")
+        } else {
+            write!(f, "{}", parts.join(", "))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum ActivityTarget {
     Train(EntityType),
-    Research,
+    Research(ResearchTopic),
 }
 
 impl ActivityComponent {
-    fn new(options: HashMap<ActivityTarget, ActivityConfig>) -> Self {
+    fn new(options: BTreeMap<ActivityTarget, ActivityConfig>) -> Self {
         Self {
-            ongoing: None,
+            queue: VecDeque::new(),
+            remaining: Duration::ZERO,
             options,
+            rally_point: None,
         }
     }
 
+    pub fn has_option(&self, target: &ActivityTarget) -> bool {
+        self.options.contains_key(target)
+    }
+
+    pub fn trains_units(&self) -> bool {
+        self.options
+            .keys()
+            .any(|target| matches!(target, ActivityTarget::Train(..)))
+    }
+
+    pub fn rally_point(&self) -> Option<RallyPoint> {
+        self.rally_point
+    }
+
+    pub fn set_rally_point(&mut self, rally_point: RallyPoint) {
+        self.rally_point = Some(rally_point);
+    }
+
     #[must_use]
-    pub fn try_start(&mut self, target: ActivityTarget) -> ActivityStatus {
-        if self.ongoing.is_some() {
-            ActivityStatus::AlreadyOngoing
+    pub fn try_enqueue(&mut self, target: ActivityTarget) -> ActivityStatus {
+        if self.queue.len() >= MAX_ACTIVITY_QUEUE_LENGTH {
+            ActivityStatus::QueueIsFull
         } else {
-            self.ongoing = Some(OngoingActivity {
-                remaining: self.options.get(&target).unwrap().duration,
-            });
-            ActivityStatus::NewActivityStarted
+            self.queue.push_back(target);
+            if self.queue.len() == 1 {
+                self.remaining = self.config(&target).duration;
+                ActivityStatus::NewActivityStarted
+            } else {
+                ActivityStatus::Queued
+            }
         }
     }
 
+    /// Remove the activity at the given position in the queue. If it was the ongoing one, the
+    /// next activity in the queue is started.
+    pub fn cancel(&mut self, index: usize) -> Option<ActivityTarget> {
+        let cancelled = self.queue.remove(index)?;
+        if index == 0 {
+            self.start_next();
+        }
+        Some(cancelled)
+    }
+
     pub fn update(&mut self, dt: Duration) -> ActivityUpdateStatus {
-        match self.ongoing.take() {
-            Some(mut ongoing) => {
-                ongoing.remaining = ongoing.remaining.saturating_sub(dt);
-                if ongoing.remaining.is_zero() {
-                    println!("Activity done!");
-                    ActivityUpdateStatus::Done
-                } else {
-                    self.ongoing = Some(ongoing);
-                    ActivityUpdateStatus::Ongoing
-                }
-            }
-            None => ActivityUpdateStatus::NothingOngoing,
+        if self.queue.is_empty() {
+            return ActivityUpdateStatus::NothingOngoing;
+        }
+        self.remaining = self.remaining.saturating_sub(dt);
+        if self.remaining.is_zero() {
+            println!("Activity done!");
+            self.queue.pop_front();
+            self.start_next();
+            ActivityUpdateStatus::Done
+        } else {
+            ActivityUpdateStatus::Ongoing
         }
     }
 
-    pub fn progress(&self, target: ActivityTarget) -> Option<f32> {
-        self.ongoing.as_ref().map(|ongoing_training| {
-            let total = self.options.get(&target).unwrap().duration;
-            1.0 - ongoing_training.remaining.as_secs_f32() / total.as_secs_f32()
+    fn start_next(&mut self) {
+        self.remaining = match self.queue.front() {
+            Some(next) => self.config(next).duration,
+            None => Duration::ZERO,
+        };
+    }
+
+    pub fn current(&self) -> Option<ActivityTarget> {
+        self.queue.front().copied()
+    }
+
+    pub fn queue(&self) -> impl Iterator<Item = ActivityTarget> + '_ {
+        self.queue.iter().copied()
+    }
+
+    pub fn queue_length(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// How far the ongoing activity has come, from 0 to 1
+    pub fn progress(&self) -> Option<f32> {
+        self.current().map(|target| {
+            let total = self.config(&target).duration;
+            1.0 - self.remaining.as_secs_f32() / total.as_secs_f32()
         })
     }
 
@@ -535,20 +925,32 @@ pub enum ActivityUpdateStatus {
 #[derive(PartialEq)]
 pub enum ActivityStatus {
     NewActivityStarted,
-    AlreadyOngoing,
+    Queued,
+    QueueIsFull,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Combat {
     cooldown: Duration,
-    damage: u32,
+    config: AttackConfig,
+    attack_move_destination: Option<[u32; 2]>,
+    attacked_by: Option<EntityId>,
+    guard_point: Option<[u32; 2]>,
+    patrol_route: Vec<[u32; 2]>,
+    // Counts up through the route and back down again
+    patrol_step: usize,
 }
 
 impl Combat {
-    fn new(damage: u32) -> Self {
+    fn new(config: AttackConfig) -> Self {
         Self {
             cooldown: Duration::ZERO,
-            damage,
+            config,
+            attack_move_destination: None,
+            attacked_by: None,
+            guard_point: None,
+            patrol_route: vec![],
+            patrol_step: 0,
         }
     }
 
@@ -566,14 +968,200 @@ impl Combat {
     }
 
     pub fn damage_amount(&self) -> u32 {
-        self.damage
+        self.config.damage
+    }
+
+    /// Targets that are at most this many cells away (diagonals included) can be attacked
+    pub fn range(&self) -> u32 {
+        self.config.range
+    }
+
+    /// Ranged attacks fire a projectile instead of dealing damage right away
+    pub fn projectile(&self) -> Option<ProjectileConfig> {
+        self.config.projectile
+    }
+
+    /// Enemies that come at most this many cells close are engaged without being told to
+    pub fn acquisition_range(&self) -> u32 {
+        self.config.acquisition_range
+    }
+
+    /// Where the unit is headed when it's attack-moving. It's kept while the unit fights
+    /// along the way, so that it can continue afterwards.
+    pub fn attack_move_destination(&self) -> Option<[u32; 2]> {
+        self.attack_move_destination
+    }
+
+    pub fn set_attack_move_destination(&mut self, destination: Option<[u32; 2]>) {
+        self.attack_move_destination = destination;
+    }
+
+    pub fn on_attacked_by(&mut self, attacker: EntityId) {
+        self.attacked_by = Some(attacker);
+    }
+
+    /// The most recent attacker since this was last called
+    pub fn take_attacker(&mut self) -> Option<EntityId> {
+        self.attacked_by.take()
+    }
+
+    /// Where the unit was standing when it engaged an enemy on its own. It's not set while the
+    /// unit follows orders.
+    pub fn guard_point(&self) -> Option<[u32; 2]> {
+        self.guard_point
+    }
+
+    pub fn set_guard_point(&mut self, guard_point: Option<[u32; 2]>) {
+        self.guard_point = guard_point;
+    }
+
+    /// The points that the unit walks back and forth between. Like the attack-move destination,
+    /// it's kept while the unit fights along the way. Empty if the unit isn't patrolling.
+    pub fn patrol_route(&self) -> &[[u32; 2]] {
+        &self.patrol_route
+    }
+
+    /// The unit starts out heading for the second point of the route
+    pub fn set_patrol_route(&mut self, route: Vec<[u32; 2]>) {
+        self.patrol_route = route;
+        self.patrol_step = 1;
+    }
+
+    pub fn add_patrol_waypoint(&mut self, waypoint: [u32; 2]) {
+        if self.patrol_route.len() < 2 {
+            self.patrol_route.push(waypoint);
+            self.patrol_step = 1;
+            return;
+        }
+        let index = self.patrol_index();
+        let is_heading_back = self.patrol_step != index;
+        self.patrol_route.push(waypoint);
+        if is_heading_back {
+            // Keep heading for the same waypoint
+            self.patrol_step = 2 * (self.patrol_route.len() - 1) - index;
+        }
+    }
+
+    pub fn patrol_waypoint(&self) -> Option<[u32; 2]> {
+        (self.patrol_route.len() > 1).then(|| self.patrol_route[self.patrol_index()])
+    }
+
+    /// Called when the current waypoint has been reached
+    pub fn advance_patrol(&mut self) {
+        if self.patrol_route.len() > 1 {
+            self.patrol_step = (self.patrol_step + 1) % (2 * (self.patrol_route.len() - 1));
+        }
+    }
+
+    fn patrol_index(&self) -> usize {
+        let len = self.patrol_route.len();
+        if self.patrol_step < len {
+            self.patrol_step
+        } else {
+            2 * (len - 1) - self.patrol_step
+        }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AttackConfig {
+    pub damage: u32,
+    pub range: u32,
+    pub acquisition_range: u32,
+    pub projectile: Option<ProjectileConfig>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProjectileConfig {
+    /// Cells per second
+    pub speed: f32,
+    /// The chance of hitting a target that stays where it was when the projectile was fired
+    pub accuracy: f32,
+}
+
+/// Fired by a ranged attack. It flies towards the cell where the victim was standing, and only
+/// hits if the victim is still there when it arrives.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Projectile {
+    pub attacker: EntityId,
+    pub team: Team,
+    pub victim: EntityId,
+    pub damage: u32,
+    pub destination: [u32; 2],
+    pub accuracy: f32,
+    // In cells, rather than pixels like the rest of the sub-cell movement
+    position: [f32; 2],
+    speed: f32,
+}
+
+impl Projectile {
+    pub fn new(
+        attacker: EntityId,
+        team: Team,
+        victim: EntityId,
+        damage: u32,
+        origin: [u32; 2],
+        destination: [u32; 2],
+        config: ProjectileConfig,
+    ) -> Self {
+        Self {
+            attacker,
+            team,
+            victim,
+            damage,
+            destination,
+            accuracy: config.accuracy,
+            position: [origin[0] as f32, origin[1] as f32],
+            speed: config.speed,
+        }
+    }
+
+    /// Returns true once the projectile has reached its destination
+    pub fn advance(&mut self, dt: Duration) -> bool {
+        let dx = self.destination[0] as f32 - self.position[0];
+        let dy = self.destination[1] as f32 - self.position[1];
+        let remaining = (dx * dx + dy * dy).sqrt();
+        let step = self.speed * dt.as_secs_f32();
+        if step >= remaining {
+            self.position = [self.destination[0] as f32, self.destination[1] as f32];
+            true
+        } else {
+            self.position[0] += dx / remaining * step;
+            self.position[1] += dy / remaining * step;
+            false
+        }
+    }
+
+    pub fn cell(&self) -> [u32; 2] {
+        [
+            self.position[0].round() as u32,
+            self.position[1].round() as u32,
+        ]
+    }
+
+    pub fn world_pixel_position(&self) -> [f32; 2] {
+        [
+            (self.position[0] + 0.5) * CELL_PIXEL_SIZE[0],
+            (self.position[1] + 0.5) * CELL_PIXEL_SIZE[1],
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Gathering {
-    held_resource: Option<EntityId>,
+    held_resource: Option<HeldResource>,
     countdown: Duration,
+    /// How much faster than normal this unit gathers, in percent
+    speed_bonus: u32,
+}
+
+/// A load that a unit has gathered and is carrying back
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeldResource {
+    /// Where the unit goes back to once the load has been returned
+    pub source: EntityId,
+    pub resource_type: ResourceType,
+    pub amount: u32,
 }
 
 impl Gathering {
@@ -581,36 +1169,44 @@ impl Gathering {
         Self {
             held_resource: None,
             countdown: Duration::ZERO,
+            speed_bonus: 0,
         }
     }
 
-    pub fn start_gathering(&mut self) {
-        self.countdown = Duration::from_secs_f32(1.5);
+    pub fn start_gathering(&mut self, gather_time: Duration) {
+        self.countdown = gather_time.mul_f32(100.0 / (100 + self.speed_bonus) as f32);
+    }
+
+    fn speed_up(&mut self, percent: u32) {
+        self.speed_bonus += percent;
     }
 
-    pub fn make_progress_on_gathering(
-        &mut self,
-        dt: Duration,
-        resource_id: EntityId,
-    ) -> GatheringProgress {
+    pub fn make_progress_on_gathering(&mut self, dt: Duration) -> GatheringProgress {
         self.countdown = self.countdown.saturating_sub(dt);
         if self.countdown.is_zero() {
-            assert!(
-                self.held_resource.is_none(),
-                "Can only hold one resource at a time"
-            );
-            self.held_resource = Some(resource_id);
             GatheringProgress::Done
         } else {
             GatheringProgress::InProgress
         }
     }
 
+    pub fn pick_up_resource(&mut self, resource: HeldResource) {
+        assert!(
+            self.held_resource.is_none(),
+            "Can only hold one resource at a time"
+        );
+        self.held_resource = Some(resource);
+    }
+
     pub fn is_carrying(&self) -> bool {
         self.held_resource.is_some()
     }
 
-    pub fn drop_resource(&mut self) -> EntityId {
+    pub fn held_resource(&self) -> Option<&HeldResource> {
+        self.held_resource.as_ref()
+    }
+
+    pub fn drop_resource(&mut self) -> HeldResource {
         self.held_resource
             .take()
             .expect("Can't drop a resource that's not being held")
@@ -623,18 +1219,132 @@ pub enum GatheringProgress {
     InProgress,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+/// Restores the health of friendly entities, one point at a time. Each resource that the
+/// entity cost is paid up front for a batch of points.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Repairing {
+    countdown: Duration,
+    paid_health: BTreeMap<ResourceType, u32>,
+}
+
+impl Repairing {
+    fn new() -> Self {
+        Self {
+            countdown: Duration::ZERO,
+            paid_health: BTreeMap::new(),
+        }
+    }
+
+    pub fn start_repairing(&mut self) {
+        self.countdown = Duration::from_millis(500);
+    }
+
+    /// Whether it's time to restore another point of health
+    pub fn make_progress_on_repair(&mut self, dt: Duration) -> bool {
+        self.countdown = self.countdown.saturating_sub(dt);
+        if self.countdown.is_zero() {
+            self.start_repairing();
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn pay_for_health(&mut self, resource_type: ResourceType, amount: u32) {
+        let paid = self.paid_health.entry(resource_type).or_default();
+        *paid = paid.saturating_add(amount);
+    }
+
+    pub fn has_paid_health(&self, resource_type: ResourceType) -> bool {
+        self.paid_health.get(&resource_type).copied().unwrap_or(0) > 0
+    }
+
+    /// Uses up one point of health that has been paid for, in each resource that the repaired
+    /// entity cost
+    pub fn take_paid_health(&mut self, cost: &Cost) {
+        for (resource_type, paid) in &mut self.paid_health {
+            if cost.get(*resource_type) > 0 {
+                *paid = paid.saturating_sub(1);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ConstructionConfig {
     pub construction_time: Duration,
-    pub cost: u32,
+    pub cost: Cost,
+    /// How much of the cost is paid back if the construction is cancelled
+    pub refund_percent: u32,
+    pub model: ConstructionModel,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ConstructionModel {
+    /// The builder becomes part of the structure, which has full health from the start and
+    /// finishes on its own.
+    ConsumeBuilder,
+    /// Builders stay outside and work on the structure, which grows in health as it progresses.
+    /// Construction pauses while no one is working on it, and goes faster with more builders.
+    Assisted,
+}
+
+/// With the `ConsumeBuilder` model, the builder is taken out of the world while it works on a
+/// structure. It comes back if the construction is cancelled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConstructionSite {
+    pub builder: Option<Box<Entity>>,
+    pub config: ConstructionConfig,
+    pub is_cancelled: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResearchConfig {
+    pub cost: Cost,
+    pub duration: Duration,
+    pub prerequisites: Vec<Prerequisite>,
+    pub effects: Vec<ResearchEffect>,
+}
+
+/// Something that a team must have before it can research a topic, or train or construct an
+/// entity
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Prerequisite {
+    Research(ResearchTopic),
+    /// A finished structure of this type
+    Structure(EntityType),
+}
+
+/// A lasting improvement to all of the team's units, current and future
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ResearchEffect {
+    Damage(u32),
+    MaxHealth(u32),
+    /// In percent
+    MoveSpeed(u32),
+    /// In percent
+    GatherRate(u32),
+    Sight(u32),
+}
+
+impl Display for ResearchEffect {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ResearchEffect::Damage(amount) => write!(f, "+{} damage", amount),
+            ResearchEffect::MaxHealth(amount) => write!(f, "+{} health", amount),
+            ResearchEffect::MoveSpeed(percent) => write!(f, "+{}% speed", percent),
+            ResearchEffect::GatherRate(percent) => write!(f, "+{}% gathering", percent),
+            ResearchEffect::Sight(amount) => write!(f, "+{} sight", amount),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ActionSlot {
     pub action: Action,
     // An action can be disabled on an entity but then be
     // re-enabled later (e.g. if research is in progress but
-    // gets interrupted)
+    // gets interrupted, or once its prerequisites are met)
     pub enabled: bool,
 }
 
@@ -647,13 +1357,86 @@ impl ActionSlot {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Action {
     StartActivity(ActivityTarget, ActivityConfig),
     Construct(EntityType, ConstructionConfig),
     Stop,
     Move,
     Attack,
+    Patrol,
+    ChangeStance,
     GatherResource,
     ReturnResource,
+    Repair,
+    CancelConstruction,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::TechState;
+    use crate::data;
+
+    #[test]
+    fn rebalanced_entities_keep_their_share_of_health() {
+        let mut enforcer = data::create_entity(
+            EntityType::named("Enforcer"),
+            EntityIdAllocator::new().next(),
+            [0, 0],
+            Team::Player(0),
+            &TechState::default(),
+        );
+        enforcer.health.as_mut().unwrap().receive_damage(4);
+
+        let mut balanced = enforcer.clone();
+        balanced.health = Some(HealthComponent::new(15));
+        enforcer.rebalance(&balanced);
+
+        let health = enforcer.health.as_ref().unwrap();
+        assert_eq!((health.current, health.max), (9, 15));
+    }
+
+    #[test]
+    fn rebalanced_entities_take_on_attack_sight_and_construction_numbers() {
+        let tech = TechState::default();
+        let mut entity_ids = EntityIdAllocator::new();
+        let create = |name, entity_ids: &mut EntityIdAllocator| {
+            data::create_entity(
+                EntityType::named(name),
+                entity_ids.next(),
+                [0, 0],
+                Team::Player(0),
+                &tech,
+            )
+        };
+
+        let mut ranger = create("Ranger", &mut entity_ids);
+        let mut balanced = ranger.clone();
+        balanced.sight_radius = 8;
+        let attack = &mut balanced.unit_mut().combat.as_mut().unwrap().config;
+        attack.range = 5;
+        attack.acquisition_range = 6;
+        attack.projectile = Some(ProjectileConfig {
+            speed: 10.0,
+            accuracy: 0.9,
+        });
+        let balanced_attack = *attack;
+        ranger.rebalance(&balanced);
+        assert_eq!(ranger.sight_radius, 8);
+        assert_eq!(
+            ranger.unit().combat.as_ref().unwrap().config,
+            balanced_attack
+        );
+
+        let mut engineer = create("Engineer", &mut entity_ids);
+        let mut balanced = engineer.clone();
+        let options = balanced.unit_mut().construction_options.as_mut().unwrap();
+        for config in options.values_mut() {
+            config.refund_percent = 50;
+        }
+        engineer.rebalance(&balanced);
+        let options = engineer.unit().construction_options.as_ref().unwrap();
+        assert!(options.values().all(|config| config.refund_percent == 50));
+    }
 }
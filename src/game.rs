@@ -2,28 +2,38 @@ use ggez;
 use ggez::conf::{NumSamples, WindowMode, WindowSetup};
 use ggez::event::EventHandler;
 use ggez::graphics::{Color, DrawMode, DrawParam, Drawable, FilterMode, Font, MeshBuilder, Rect};
-use ggez::input::keyboard::{KeyCode, KeyMods};
+use ggez::input::keyboard::{self, KeyCode, KeyMods};
 use ggez::input::mouse::{self, CursorIcon, MouseButton};
 use ggez::{graphics, Context, ContextBuilder, GameError, GameResult};
 
-use rand::rngs::ThreadRng;
-use std::cell::{Ref, RefCell, RefMut};
+use std::cell::{Ref, RefCell};
 use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use crate::assets::Assets;
 use crate::camera::Camera;
-use crate::core::{
-    AttackCommand, Command, CommandError, ConstructCommand, Core, GatherResourceCommand,
-    MoveCommand, ReturnResourceCommand, StartActivityCommand, StopCommand, UpdateOutcome,
+use crate::command::{
+    AttackCommand, AttackMoveCommand, CancelActivityCommand, CancelConstructionCommand, Command,
+    ConstructCommand, GatherResourceCommand, MoveCommand, PatrolCommand, RepairCommand,
+    ReturnResourceCommand, SetRallyPointCommand, SetStanceCommand, StartActivityCommand,
+    StopCommand,
 };
+use crate::core::{CommandError, Core, UpdateOutcome, Visibility};
 use crate::data::EntityType;
+use crate::definitions::{self, DefinitionsWatcher};
 use crate::entities::{
-    Action, Entity, EntityCategory, EntityId, EntityState, Team, NUM_ENTITY_ACTIONS,
+    Action, Entity, EntityCategory, EntityId, EntityState, RallyPoint, Team, NUM_ENTITY_ACTIONS,
 };
+use crate::grid::Grid;
 use crate::hud_graphics::{HudGraphics, PlayerInput};
-use crate::map::{MapConfig, WorldInitData};
+use crate::map::{self, MapConfig, WorldInitData};
 use crate::player::{CursorState, EntityHighlight, HighlightType, PlayerState};
-use crate::team_ai::TeamAi;
+use crate::replay::{self, Replay, ReplayPlayback};
+use crate::save;
+use crate::simulation::SimulationSettings;
+use crate::team_ai::{self, AiRng, TeamAi};
 use crate::text::SharpFont;
 
 pub const COLOR_FG: Color = Color::new(0.3, 0.3, 0.4, 1.0);
@@ -52,7 +62,18 @@ pub const MAX_NUM_SELECTED_ENTITIES: usize = 8;
 
 const TITLE: &str = "RTS";
 
-pub fn run(map_config: MapConfig) -> GameResult {
+// If the game falls behind by more than this, the simulation is slowed down rather than
+// trying to catch up with a burst of ticks.
+const MAX_TICKS_PER_FRAME: u32 = 10;
+
+/// How a match is started: on a fresh map, by resuming a saved game, or by watching a replay
+pub enum GameStart {
+    Map(MapConfig),
+    SaveFile(PathBuf),
+    Replay(PathBuf),
+}
+
+pub fn run(start: GameStart, settings: SimulationSettings) -> GameResult {
     const GAME_SCALE: f32 = 3.0;
     let window_setup = WindowSetup::default().title(TITLE).samples(NumSamples::One);
     let window_mode =
@@ -68,7 +89,8 @@ pub fn run(map_config: MapConfig) -> GameResult {
     graphics::set_screen_coordinates(&mut ctx, Rect::new(0.0, 0.0, GAME_SIZE[0], GAME_SIZE[1]))
         .unwrap();
 
-    let game = Game::new(&mut ctx, map_config)?;
+    println!("Starting game with {:?}", settings);
+    let game = Game::new(&mut ctx, start, settings)?;
     ggez::event::run(ctx, event_loop, game)
 }
 
@@ -77,46 +99,115 @@ struct Game {
     hud: RefCell<HudGraphics>,
     player_state: PlayerState,
     enemy_team_ais: Vec<TeamAi>,
-    rng: ThreadRng,
+    rng: AiRng,
+    tick: u64,
+    tick_duration: Duration,
+    unsimulated_time: Duration,
+    settings: SimulationSettings,
+    water_grid: Grid<bool>,
+    // Every issued command is recorded, unless we're watching a replay
+    recording: Option<RefCell<Replay>>,
+    replay_viewer: Option<ReplayViewer>,
+    // Balance numbers can be tweaked in the definitions file while the game is running
+    definitions_watcher: DefinitionsWatcher,
     core: Core,
 }
 
-impl Game {
-    fn new(ctx: &mut Context, map_config: MapConfig) -> Result<Self, GameError> {
-        let WorldInitData {
-            dimensions: world_dimensions,
-            entities,
-            water_grid,
-            tile_grid,
-        } = WorldInitData::load(ctx, map_config);
+struct ReplayViewer {
+    playback: ReplayPlayback,
+    is_paused: bool,
+    speed: u32,
+}
 
-        println!("Created {} entities", entities.len());
+const MAX_REPLAY_SPEED: u32 = 16;
+const REPLAY_SEEK_STEP: Duration = Duration::from_secs(10);
 
-        let assets = Assets::new(ctx, [WORLD_VIEWPORT.w, WORLD_VIEWPORT.h], &tile_grid)?;
+impl Game {
+    fn new(
+        ctx: &mut Context,
+        start: GameStart,
+        settings: SimulationSettings,
+    ) -> Result<Self, GameError> {
+        let mut tick_duration = settings.tick_duration;
+        let mut replay_viewer = None;
+        let (core, water_grid, start_location, saved_ais) = match start {
+            GameStart::Map(map_config) => {
+                let WorldInitData {
+                    dimensions,
+                    entities,
+                    water_grid,
+                    victory_condition,
+                    team_setup,
+                    start_locations,
+                    triggers,
+                    ..
+                } = WorldInitData::load(ctx, map_config, settings.seed)?;
+                println!("Created {} entities", entities.len());
+                let water_cells = map::water_cells(&water_grid);
+                let mut core = Core::new(
+                    entities,
+                    dimensions,
+                    water_cells,
+                    victory_condition,
+                    team_setup,
+                    settings.seed,
+                );
+                core.set_triggers(triggers);
+                let start_location = start_locations.get(&Team::HUMAN).copied();
+                (core, water_grid, start_location, None)
+            }
+            GameStart::SaveFile(path) => {
+                let saved_game = save::load_from_file(&path).map_err(|e| {
+                    GameError::ResourceLoadError(format!("Loading {:?}: {}", path, e))
+                })?;
+                let core = saved_game.restore().map_err(|e| {
+                    GameError::ResourceLoadError(format!("Loading {:?}: {}", path, e))
+                })?;
+                println!("Loaded saved game from {:?}", path);
+                let saved_ais = (saved_game.team_ais, saved_game.ai_rng);
+                (core, saved_game.water_grid, None, Some(saved_ais))
+            }
+            GameStart::Replay(path) => {
+                let replay = Replay::load_from_file(&path).map_err(|e| {
+                    GameError::ResourceLoadError(format!("Loading {:?}: {}", path, e))
+                })?;
+                println!(
+                    "Watching replay {:?} ({} ticks, {} commands)",
+                    path,
+                    replay.num_ticks,
+                    replay.commands.len()
+                );
+                tick_duration = replay.tick_duration;
+                let core = replay.initial_state.restore().map_err(|e| {
+                    GameError::ResourceLoadError(format!("Loading {:?}: {}", path, e))
+                })?;
+                let water_grid = replay.initial_state.water_grid.clone();
+                let saved_ais = (
+                    replay.initial_state.team_ais.clone(),
+                    replay.initial_state.ai_rng.clone(),
+                );
+                replay_viewer = Some(ReplayViewer {
+                    playback: ReplayPlayback::new(replay),
+                    is_paused: false,
+                    speed: 1,
+                });
+                (core, water_grid, None, Some(saved_ais))
+            }
+        };
+        let world_dimensions = core.dimensions();
+        let tile_grid = map::create_tile_grid(&water_grid);
 
-        let rng = rand::thread_rng();
+        let assets = Assets::new(
+            ctx,
+            [WORLD_VIEWPORT.w, WORLD_VIEWPORT.h],
+            &tile_grid,
+            core.team_setup(),
+        )?;
 
-        let mut teams = HashSet::new();
-        for entity in &entities {
-            teams.insert(entity.team);
-        }
-        let mut enemy_team_ais = vec![];
-        if teams.contains(&Team::Enemy1) {
-            let opponent = if teams.contains(&Team::Player) {
-                Team::Player
-            } else {
-                Team::Enemy2
-            };
-            enemy_team_ais.push(TeamAi::new(Team::Enemy1, opponent));
-        }
-        if teams.contains(&Team::Enemy2) {
-            let opponent = if teams.contains(&Team::Player) {
-                Team::Player
-            } else {
-                Team::Enemy1
-            };
-            enemy_team_ais.push(TeamAi::new(Team::Enemy2, opponent));
-        }
+        let (enemy_team_ais, rng) = saved_ais.unwrap_or_else(|| {
+            let teams: HashSet<Team> = core.teams().into_iter().collect();
+            (team_ai::create_team_ais(&teams, false), settings.rng())
+        });
 
         let font = Font::new(ctx, "/fonts/Merchant Copy.ttf")?;
         // let font = Font::new(ctx, "/fonts/Retro Gaming.ttf")?;
@@ -126,24 +217,45 @@ impl Game {
             world_dimensions[0] as f32 * CELL_PIXEL_SIZE[0] - WORLD_VIEWPORT.w,
             world_dimensions[1] as f32 * CELL_PIXEL_SIZE[1] - WORLD_VIEWPORT.h,
         ];
-        let camera = Camera::new([0.0, 0.0], max_camera_position);
-        let player_state = PlayerState::new(camera);
+        // Centered on the player's base, if the map says where it is
+        let camera_position = match start_location {
+            Some([x, y]) => [
+                (x as f32 * CELL_PIXEL_SIZE[0] - WORLD_VIEWPORT.w / 2.0)
+                    .min(max_camera_position[0])
+                    .max(0.0),
+                (y as f32 * CELL_PIXEL_SIZE[1] - WORLD_VIEWPORT.h / 2.0)
+                    .min(max_camera_position[1])
+                    .max(0.0),
+            ],
+            None => [0.0, 0.0],
+        };
+        let camera = Camera::new(camera_position, max_camera_position);
+        let player_state = PlayerState::new(camera, Team::HUMAN);
 
         let hud_pos = [12.5, 12.5];
         let tooltip_pos = [WORLD_VIEWPORT.x, GAME_SIZE[1] - 25.0];
-        let hud = HudGraphics::new(ctx, hud_pos, font, world_dimensions, tooltip_pos)?;
+        let hud = HudGraphics::new(
+            ctx,
+            hud_pos,
+            font,
+            world_dimensions,
+            tooltip_pos,
+            core.team_setup(),
+            Arc::clone(core.definitions()),
+        )?;
         let hud = RefCell::new(hud);
 
-        let mut water_cells = vec![];
-        for x in 0..world_dimensions[0] {
-            for y in 0..world_dimensions[1] {
-                if water_grid.get(&[x, y]).unwrap() {
-                    water_cells.push([x, y]);
-                }
-            }
-        }
-
-        let core = Core::new(entities, world_dimensions, water_cells);
+        let recording = if replay_viewer.is_none() {
+            Some(RefCell::new(Replay::new(
+                &core,
+                &water_grid,
+                &enemy_team_ais,
+                &rng,
+                tick_duration,
+            )))
+        } else {
+            None
+        };
 
         Ok(Self {
             assets,
@@ -151,10 +263,94 @@ impl Game {
             player_state,
             enemy_team_ais,
             rng,
+            tick: 0,
+            tick_duration,
+            unsimulated_time: Duration::ZERO,
+            settings,
+            water_grid,
+            recording,
+            replay_viewer,
+            definitions_watcher: DefinitionsWatcher::new(),
             core,
         })
     }
 
+    fn save_recording(&self) {
+        if let Some(recording) = &self.recording {
+            let path = Path::new(replay::LAST_REPLAY_FILE);
+            let mut recording = recording.borrow_mut();
+            recording.num_ticks = self.tick;
+            match recording.save_to_file(path) {
+                Ok(()) => println!("Saved replay to {:?}", path),
+                Err(e) => eprintln!("ERROR: Failed to save replay to {:?}: {}", path, e),
+            }
+        }
+    }
+
+    fn quicksave(&self) {
+        let path = Path::new(save::QUICKSAVE_FILE);
+        match save::save_to_file(
+            &self.core,
+            &self.water_grid,
+            &self.enemy_team_ais,
+            &self.rng,
+            path,
+        ) {
+            Ok(()) => println!("Saved game to {:?}", path),
+            Err(e) => {
+                eprintln!("ERROR: Failed to save game to {:?}: {}", path, e);
+                self.hud
+                    .borrow_mut()
+                    .set_error_message("Failed to save game".to_owned());
+            }
+        }
+    }
+
+    fn quickload(&mut self, ctx: &mut Context) {
+        let start = GameStart::SaveFile(PathBuf::from(save::QUICKSAVE_FILE));
+        match Game::new(ctx, start, self.settings) {
+            Ok(game) => {
+                // The match that was being played ends here
+                self.save_recording();
+                *self = game;
+            }
+            Err(e) => {
+                eprintln!("ERROR: {}", e);
+                self.hud
+                    .borrow_mut()
+                    .set_error_message("Failed to load game".to_owned());
+            }
+        }
+    }
+
+    // Watching a replay with different numbers would make it play out differently. Recorded
+    // rebalances are applied ahead of the commands for the next tick, so this must happen after
+    // the last tick of a frame and before any player input.
+    fn reload_definitions_if_changed(&mut self, dt: Duration) {
+        if !self.definitions_watcher.has_changed(dt) || self.replay_viewer.is_some() {
+            return;
+        }
+        match definitions::reload(self.core.definitions()) {
+            Ok((_, changes)) if changes.is_empty() => {
+                println!("Reloaded entity definitions. No balance numbers changed.")
+            }
+            Ok((definitions, changes)) => {
+                self.hud
+                    .borrow_mut()
+                    .set_definitions(Arc::clone(&definitions));
+                self.core.rebalance(definitions);
+                self.update_hud_for_selection();
+                println!("Rebalanced: {}", changes.join(", "));
+                if let Some(recording) = &self.recording {
+                    recording
+                        .borrow_mut()
+                        .record_rebalance(self.tick, self.core.definitions().source());
+                }
+            }
+            Err(e) => eprintln!("ERROR: Failed to reload entity definitions: {}", e),
+        }
+    }
+
     fn selected_entities(&self) -> impl Iterator<Item = &RefCell<Entity>> {
         self.player_state.selected_entity_ids.iter().map(|id| {
             self.core
@@ -167,13 +363,19 @@ impl Game {
 
     fn selected_player_entities(&self) -> impl Iterator<Item = &RefCell<Entity>> {
         self.selected_entities()
-            .filter(|entity| RefCell::borrow(entity).team == Team::Player)
+            .filter(|entity| RefCell::borrow(entity).team == self.player_state.team)
+    }
+
+    // Entities hidden by the fog of war can't be seen or interacted with
+    fn is_visible(&self, entity: &Entity) -> bool {
+        self.core.is_visible_to(entity, &self.player_state.team)
     }
 
     fn resource_at_position(&self, world_pixel_coords: [f32; 2]) -> Option<&RefCell<Entity>> {
         self.core.entities().iter().find_map(|(_id, entity)| {
-            if entity.borrow().entity_type == EntityType::FuelRift
+            if matches!(entity.borrow().category, EntityCategory::Resource { .. })
                 && entity.borrow().pixel_rect().contains(world_pixel_coords)
+                && self.is_visible(&entity.borrow())
             {
                 Some(entity)
             } else {
@@ -185,8 +387,12 @@ impl Game {
     fn enemy_at_position(&self, world_pixel_coords: [f32; 2]) -> Option<&RefCell<Entity>> {
         self.core.entities().iter().find_map(|(_id, entity)| {
             let entity_ref = entity.borrow();
-            if (entity_ref.team == Team::Enemy1 || entity_ref.team == Team::Enemy2)
+            if self
+                .core
+                .team_setup()
+                .are_hostile(self.player_state.team, entity_ref.team)
                 && entity_ref.pixel_rect().contains(world_pixel_coords)
+                && self.is_visible(&entity_ref)
             {
                 drop(entity_ref);
                 Some(entity)
@@ -196,6 +402,108 @@ impl Game {
         })
     }
 
+    fn damaged_player_entity_at_position(
+        &self,
+        world_pixel_coords: [f32; 2],
+    ) -> Option<&RefCell<Entity>> {
+        self.core.entities().iter().find_map(|(_id, entity)| {
+            let entity_ref = entity.borrow();
+            let is_damaged = entity_ref
+                .health
+                .as_ref()
+                .is_some_and(|health| health.is_damaged());
+            if entity_ref.team == self.player_state.team
+                && is_damaged
+                && entity_ref.pixel_rect().contains(world_pixel_coords)
+            {
+                drop(entity_ref);
+                Some(entity)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn rally_point_world_position(&self, rally_point: RallyPoint) -> Option<[f32; 2]> {
+        match rally_point {
+            RallyPoint::Position(cell) => Some(cell_center(cell)),
+            RallyPoint::Unit(id) | RallyPoint::Resource(id) => self.visible_entity_center(id),
+        }
+    }
+
+    fn visible_entity_center(&self, target_id: EntityId) -> Option<[f32; 2]> {
+        self.core.entities().iter().find_map(|(id, entity)| {
+            let entity = entity.borrow();
+            (*id == target_id && self.is_visible(&entity)).then(|| rect_center(entity.pixel_rect()))
+        })
+    }
+
+    // Where the unit is currently heading, followed by its patrol route and where each of its
+    // queued commands will take it
+    fn waypoints(&self, unit_entity: &Entity) -> Vec<[f32; 2]> {
+        let unit = unit_entity.unit();
+        let mut waypoints = vec![rect_center(unit_entity.pixel_rect())];
+        if unit.movement_plan.peek().is_some() {
+            waypoints.push(cell_center(unit.movement_plan.destination()));
+        }
+        if let Some(combat) = &unit.combat {
+            waypoints.extend(
+                combat
+                    .patrol_route()
+                    .iter()
+                    .map(|point| cell_center(*point)),
+            );
+        }
+        for command in &unit.command_queue {
+            let waypoint = match command {
+                Command::Move(MoveCommand { destination, .. })
+                | Command::AttackMove(AttackMoveCommand { destination, .. })
+                | Command::Patrol(PatrolCommand { destination, .. }) => {
+                    Some(cell_center(*destination))
+                }
+                Command::Attack(AttackCommand { victim: id, .. })
+                | Command::GatherResource(GatherResourceCommand { resource: id, .. })
+                | Command::Repair(RepairCommand { target: id, .. })
+                | Command::ReturnResource(ReturnResourceCommand {
+                    structure: Some(id),
+                    ..
+                }) => self.visible_entity_center(*id),
+                Command::Construct(ConstructCommand {
+                    structure_position,
+                    structure_type,
+                    ..
+                }) => {
+                    let [x, y] = grid_to_world(*structure_position);
+                    let [w, h] = *self.core.structure_size(structure_type);
+                    Some(rect_center(Rect::new(
+                        x,
+                        y,
+                        w as f32 * CELL_PIXEL_SIZE[0],
+                        h as f32 * CELL_PIXEL_SIZE[1],
+                    )))
+                }
+                _ => None,
+            };
+            waypoints.extend(waypoint);
+        }
+        waypoints
+    }
+
+    fn player_unit_at_position(&self, world_pixel_coords: [f32; 2]) -> Option<&RefCell<Entity>> {
+        self.core.entities().iter().find_map(|(_id, entity)| {
+            let entity_ref = entity.borrow();
+            if let EntityCategory::Unit(_) = &entity_ref.category {
+                if entity_ref.pixel_rect().contains(world_pixel_coords)
+                    && entity_ref.team == self.player_state.team
+                {
+                    drop(entity_ref);
+                    return Some(entity);
+                }
+            }
+            None
+        })
+    }
+
     fn player_structure_at_position(
         &self,
         clicked_world_pos: [u32; 2],
@@ -204,7 +512,7 @@ impl Game {
             let entity_ref = entity.borrow();
             if let EntityCategory::Structure { .. } = &entity_ref.category {
                 if entity_ref.cell_rect().contains(clicked_world_pos)
-                    && entity_ref.team == Team::Player
+                    && entity_ref.team == self.player_state.team
                 {
                     drop(entity_ref);
                     return Some(entity);
@@ -233,31 +541,16 @@ impl Game {
 
         let mut player_entities = self.selected_player_entities();
         if let Some(first) = player_entities.next() {
-            let first = first.borrow();
-            // TODO standardize how this sort of thing should work.
-            //      There are many situations where certain actions shouldn't be shown:
-            //      building under construction, research already complete / in progress,
-            //      having a cursor action tied to some selected action (?), etc.
-            let is_under_construction = matches!(first.state, EntityState::UnderConstruction(..));
-            if !is_under_construction {
-                for (i, action_slot) in first.action_slots.iter().enumerate() {
-                    actions[i] = action_slot
-                        .filter(|slot| slot.enabled)
-                        .map(|slot| slot.action);
-                }
-            }
+            actions = entity_actions(&first.borrow());
         }
 
         for additional in player_entities {
-            let additional = additional.borrow();
-
-            let is_under_construction =
-                matches!(additional.state, EntityState::UnderConstruction(..));
-            for (i, action_slot) in additional.action_slots.iter().enumerate() {
-                if is_under_construction || actions[i] != action_slot.map(|slot| slot.action) {
+            let additional_actions = entity_actions(&additional.borrow());
+            for (action, additional_action) in actions.iter_mut().zip(additional_actions) {
+                if *action != additional_action {
                     // Since not all selected entities have this action, it should not
                     // be shown in HUD.
-                    actions[i] = None;
+                    *action = None;
                 }
             }
         }
@@ -269,9 +562,12 @@ impl Game {
 
     fn handle_player_input(&mut self, ctx: &mut Context, player_input: PlayerInput) {
         match player_input {
+            PlayerInput::UseEntityAction(Action::ChangeStance) => {
+                self.player_issue_all_selected_change_stance();
+            }
             PlayerInput::UseEntityAction(action) => {
                 for entity in self.selected_player_entities() {
-                    let entity = entity.borrow_mut();
+                    let entity = entity.borrow();
                     if entity.has_enabled_action(action) {
                         self.handle_player_use_entity_action(ctx, entity, action);
                     }
@@ -283,43 +579,59 @@ impl Game {
             PlayerInput::LimitSelectionToIndex(i) => {
                 self.set_selected_entities(vec![self.player_state.selected_entity_ids[i]])
             }
+            PlayerInput::CancelActivity(slot) => {
+                // The queue is only shown when a single structure is selected
+                let structure = self
+                    .selected_player_entities()
+                    .next()
+                    .map(|e| e.borrow().id);
+                if let Some(structure) = structure {
+                    self.player_issue_command(Command::CancelActivity(CancelActivityCommand {
+                        structure,
+                        slot,
+                    }));
+                }
+            }
         }
     }
 
     fn handle_player_use_entity_action(
         &self,
         ctx: &mut Context,
-        actor: RefMut<Entity>,
+        actor: Ref<Entity>,
         action: Action,
     ) {
+        let actor_id = actor.id;
         match action {
             Action::StartActivity(target, _config) => {
+                drop(actor);
                 self.player_issue_command(Command::StartActivity(StartActivityCommand {
-                    structure: actor,
+                    structure: actor_id,
                     target,
                 }));
             }
             Action::Construct(structure_type, _) => {
-                let resources = self
-                    .core
-                    .team_state_unchecked(&Team::Player)
-                    .borrow()
-                    .resources;
                 let construction_options = actor.unit().construction_options.as_ref().unwrap();
                 let cost = construction_options.get(&structure_type).unwrap().cost;
-                if resources >= cost {
+                let missing_resource = self
+                    .core
+                    .team_state_unchecked(&self.player_state.team)
+                    .borrow()
+                    .missing_resource(&cost);
+                if let Some(missing_resource) = missing_resource {
+                    self.hud
+                        .borrow_mut()
+                        .set_error_message(format!("Not enough {}", missing_resource));
+                } else {
                     self.set_player_cursor_state(
                         ctx,
                         CursorState::PlacingStructure(structure_type),
                     );
-                } else {
-                    self.hud
-                        .borrow_mut()
-                        .set_error_message("Not enough resources".to_owned());
                 }
             }
             Action::Stop => {
-                self.player_issue_command(Command::Stop(StopCommand { entity: actor }));
+                drop(actor);
+                self.player_issue_command(Command::Stop(StopCommand { entity: actor_id }));
             }
             Action::Move => {
                 self.set_player_cursor_state(ctx, CursorState::SelectingMovementDestination);
@@ -327,34 +639,86 @@ impl Game {
             Action::Attack => {
                 self.set_player_cursor_state(ctx, CursorState::SelectingAttackTarget);
             }
+            Action::Patrol => {
+                self.set_player_cursor_state(ctx, CursorState::SelectingPatrolDestination);
+            }
+            Action::ChangeStance => {
+                unreachable!("Stance is changed for the whole selection at once");
+            }
             Action::GatherResource => {
                 self.set_player_cursor_state(ctx, CursorState::SelectingResourceTarget);
             }
             Action::ReturnResource => {
-                self.player_issue_return_resource(actor, None);
+                drop(actor);
+                self.player_issue_return_resource(actor_id, None);
+            }
+            Action::Repair => {
+                self.set_player_cursor_state(ctx, CursorState::SelectingRepairTarget);
+            }
+            Action::CancelConstruction => {
+                drop(actor);
+                self.player_issue_command(Command::CancelConstruction(CancelConstructionCommand {
+                    structure: actor_id,
+                }));
             }
         }
     }
 
     fn player_issue_command(&self, command: Command) {
-        match self.core.issue_command(command, Team::Player) {
+        if self.core.match_result().is_some() {
+            self.hud
+                .borrow_mut()
+                .set_error_message("The match is over".to_owned());
+            return;
+        }
+        let recording = match &self.recording {
+            Some(recording) => recording,
+            None => {
+                self.hud
+                    .borrow_mut()
+                    .set_error_message("Can't give orders in a replay".to_owned());
+                return;
+            }
+        };
+        let queued = self.player_state.is_queueing_commands;
+        recording
+            .borrow_mut()
+            .record(self.tick, self.player_state.team, &command, queued);
+        let result = if queued {
+            self.core.queue_command(command, self.player_state.team)
+        } else {
+            self.core.issue_command(command, self.player_state.team)
+        };
+        match result {
             Ok(success) => {
-                if success.did_research_state_change {
-                    println!("Research state changed after issuing command. Updating HUD.");
+                if success.did_tech_state_change {
+                    println!("Tech state changed after issuing command. Updating HUD.");
                     self.update_hud_for_selection();
                 }
             }
             Err(error) => {
                 let message = match error {
-                    CommandError::NotEnoughResources => "Not enough resources".to_owned(),
+                    CommandError::NotEnoughResources(resource_type) => {
+                        format!("Not enough {}", resource_type)
+                    }
                     CommandError::NoPathFound => "Can't go there".to_owned(),
                     CommandError::NotCarryingResource => {
-                        "Not carrying any fuel to return".to_owned()
+                        "Not carrying any resources to return".to_owned()
                     }
                     CommandError::NotEnoughSpaceForStructure => {
                         "Not enough space for structure".to_owned()
                     }
-                    CommandError::EntityIsBusy => "Can't do that right now".to_owned(),
+                    CommandError::QueueIsFull => "The queue is full".to_owned(),
+                    CommandError::NothingToCancel => "Nothing to cancel".to_owned(),
+                    CommandError::UnknownEntity(_) | CommandError::EntityIsDead(_) => {
+                        "It's no longer there".to_owned()
+                    }
+                    CommandError::NotOwnedByTeam(_) => "That's not yours to command".to_owned(),
+                    CommandError::IncompatibleEntity(_) => "Can't do that".to_owned(),
+                    CommandError::InvalidTarget(_) => "Invalid target".to_owned(),
+                    CommandError::MissingPrerequisites => "Not unlocked yet".to_owned(),
+                    CommandError::AlreadyResearched => "Already researched".to_owned(),
+                    CommandError::SupplyBlocked => "Not enough supply".to_owned(),
                 };
                 self.hud.borrow_mut().set_error_message(message);
             }
@@ -365,54 +729,106 @@ impl Game {
         let world_pos = world_to_grid(world_pixel_coords);
         for entity in self.selected_player_entities() {
             let entity_ref = entity.borrow();
+            let entity_id = entity_ref.id;
             match &entity_ref.category {
                 EntityCategory::Unit(unit) => {
                     if unit.combat.is_some() {
                         if let Some(victim) = self.enemy_at_position(world_pixel_coords) {
                             drop(entity_ref);
-                            self._player_issue_attack(entity.borrow_mut(), victim.borrow());
+                            self._player_issue_attack(entity_id, victim.borrow().id);
+                            continue;
+                        }
+                    }
+                    let is_carrying_resource = unit
+                        .gathering
+                        .as_ref()
+                        .is_some_and(|gathering| gathering.is_carrying());
+                    if entity_ref.has_enabled_action(Action::Repair) && !is_carrying_resource {
+                        let damaged_structure = self
+                            .damaged_player_entity_at_position(world_pixel_coords)
+                            .filter(|entity| {
+                                matches!(entity.borrow().category, EntityCategory::Structure { .. })
+                            });
+                        if let Some(structure) = damaged_structure {
+                            drop(entity_ref);
+                            self._player_issue_repair(entity_id, structure.borrow().id);
                             continue;
                         }
                     }
                     if entity_ref.has_enabled_action(Action::GatherResource) {
                         if let Some(resource) = self.resource_at_position(world_pixel_coords) {
                             drop(entity_ref);
-                            self._player_issue_gather_resource(
-                                entity.borrow_mut(),
-                                resource.borrow(),
-                            );
+                            self._player_issue_gather_resource(entity_id, resource.borrow().id);
                             continue;
                         }
                         if let Some(structure) = self.player_structure_at_position(world_pos) {
                             drop(entity_ref);
                             self.player_issue_return_resource(
-                                entity.borrow_mut(),
-                                Some(structure.borrow()),
+                                entity_id,
+                                Some(structure.borrow().id),
                             );
                             continue;
                         }
                     }
                     drop(entity_ref);
-                    self._player_issue_movement(entity.borrow_mut(), world_pixel_coords);
+                    self._player_issue_movement(entity_id, world_pixel_coords);
                 }
                 EntityCategory::Structure { .. } => {
-                    println!("Structures have no right-click functionality yet")
+                    let trains_units = entity_ref
+                        .activity
+                        .as_ref()
+                        .is_some_and(|activity| activity.trains_units());
+                    drop(entity_ref);
+                    if trains_units {
+                        self.player_issue_set_rally_point(entity_id, world_pixel_coords);
+                    }
                 }
                 EntityCategory::Resource { .. } => {}
             }
         }
     }
 
-    fn player_issue_return_resource(
-        &self,
-        gatherer: RefMut<Entity>,
-        structure: Option<Ref<Entity>>,
-    ) {
-        if let Some(structure) = structure.as_ref() {
+    // The stance shown in the HUD is that of the first selected unit. Changing it puts all
+    // the selected units in the stance that comes after.
+    fn player_issue_all_selected_change_stance(&self) {
+        let stance = match self
+            .selected_player_entities()
+            .find_map(|e| e.borrow().stance())
+        {
+            Some(stance) => stance.next(),
+            None => return,
+        };
+        let units: Vec<EntityId> = self
+            .selected_player_entities()
+            .map(|entity| entity.borrow())
+            .filter(|entity| entity.has_enabled_action(Action::ChangeStance))
+            .map(|entity| entity.id)
+            .collect();
+        for unit in units {
+            self.player_issue_command(Command::SetStance(SetStanceCommand { unit, stance }));
+        }
+    }
+
+    fn player_issue_set_rally_point(&self, structure: EntityId, world_pixel_coords: [f32; 2]) {
+        let rally_point = if let Some(resource) = self.resource_at_position(world_pixel_coords) {
+            RallyPoint::Resource(resource.borrow().id)
+        } else if let Some(unit) = self.player_unit_at_position(world_pixel_coords) {
+            RallyPoint::Unit(unit.borrow().id)
+        } else {
+            RallyPoint::Position(world_to_grid(world_pixel_coords))
+        };
+        self.player_issue_command(Command::SetRallyPoint(SetRallyPointCommand {
+            structure,
+            rally_point,
+        }));
+    }
+
+    fn player_issue_return_resource(&self, gatherer: EntityId, structure: Option<EntityId>) {
+        if let Some(structure) = structure {
             self.player_state
                 .timed_entity_highlights
                 .borrow_mut()
-                .push(EntityHighlight::new(structure.id, HighlightType::Friendly));
+                .push(EntityHighlight::new(structure, HighlightType::Friendly));
         }
         self.player_issue_command(Command::ReturnResource(ReturnResourceCommand {
             gatherer,
@@ -426,11 +842,23 @@ impl Game {
         clicked_world_pos: [u32; 2],
         structure_type: EntityType,
     ) {
+        // Builders are used up by the structure, so a row of queued constructions is spread out
+        // over the selected builders, rather than put in one builder's queue
         let builder = self
             .selected_player_entities()
-            .next()
+            .min_by_key(|entity| {
+                let entity = entity.borrow();
+                match &entity.category {
+                    EntityCategory::Unit(unit) => {
+                        let is_busy = entity.state != EntityState::Idle;
+                        unit.command_queue.len() + is_busy as usize
+                    }
+                    _ => usize::MAX,
+                }
+            })
             .expect("Cannot issue construction without selected entity")
-            .borrow_mut();
+            .borrow()
+            .id;
         self.player_issue_command(Command::Construct(ConstructCommand {
             builder,
             structure_position: clicked_world_pos,
@@ -438,33 +866,43 @@ impl Game {
         }));
     }
 
+    // Clicking the ground rather than an enemy gives an attack-move order
     fn player_issue_all_selected_attack(&mut self, world_pixel_coords: [f32; 2]) {
         if let Some(victim) = self.enemy_at_position(world_pixel_coords) {
             for attacker in self.selected_player_entities() {
-                self._player_issue_attack(attacker.borrow_mut(), victim.borrow());
+                self._player_issue_attack(attacker.borrow().id, victim.borrow().id);
             }
         } else {
-            self.hud
+            self.player_state
+                .movement_command_indicator
                 .borrow_mut()
-                .set_error_message("Invalid attack target".to_owned());
+                .set(world_pixel_coords);
+            let destination = world_to_grid(world_pixel_coords);
+            for attacker in self.selected_player_entities() {
+                let unit = attacker.borrow().id;
+                self.player_issue_command(Command::AttackMove(AttackMoveCommand {
+                    unit,
+                    destination,
+                }));
+            }
         }
     }
 
-    fn _player_issue_attack(&self, attacker: RefMut<Entity>, victim: Ref<Entity>) {
+    fn _player_issue_attack(&self, attacker: EntityId, victim: EntityId) {
         self.player_state
             .timed_entity_highlights
             .borrow_mut()
-            .push(EntityHighlight::new(victim.id, HighlightType::Hostile));
+            .push(EntityHighlight::new(victim, HighlightType::Hostile));
         self.player_issue_command(Command::Attack(AttackCommand { attacker, victim }));
     }
 
     fn player_issue_all_selected_movement(&self, world_pixel_coords: [f32; 2]) {
         for entity in self.selected_player_entities() {
-            self._player_issue_movement(entity.borrow_mut(), world_pixel_coords);
+            self._player_issue_movement(entity.borrow().id, world_pixel_coords);
         }
     }
 
-    fn _player_issue_movement(&self, entity: RefMut<Entity>, world_pixel_coordinates: [f32; 2]) {
+    fn _player_issue_movement(&self, entity: EntityId, world_pixel_coordinates: [f32; 2]) {
         self.player_state
             .movement_command_indicator
             .borrow_mut()
@@ -476,10 +914,46 @@ impl Game {
         }));
     }
 
+    fn player_issue_all_selected_patrol(&self, world_pixel_coords: [f32; 2]) {
+        self.player_state
+            .movement_command_indicator
+            .borrow_mut()
+            .set(world_pixel_coords);
+        let destination = world_to_grid(world_pixel_coords);
+        for entity in self.selected_player_entities() {
+            let unit = entity.borrow().id;
+            self.player_issue_command(Command::Patrol(PatrolCommand { unit, destination }));
+        }
+    }
+
+    fn player_issue_all_selected_repair(&self, world_pixel_coords: [f32; 2]) {
+        if let Some(target) = self.damaged_player_entity_at_position(world_pixel_coords) {
+            let target_id = target.borrow().id;
+            for repairer in self.selected_player_entities() {
+                let repairer_id = repairer.borrow().id;
+                if repairer_id != target_id {
+                    self._player_issue_repair(repairer_id, target_id);
+                }
+            }
+        } else {
+            self.hud
+                .borrow_mut()
+                .set_error_message("Invalid repair target".to_owned());
+        }
+    }
+
+    fn _player_issue_repair(&self, repairer: EntityId, target: EntityId) {
+        self.player_state
+            .timed_entity_highlights
+            .borrow_mut()
+            .push(EntityHighlight::new(target, HighlightType::Friendly));
+        self.player_issue_command(Command::Repair(RepairCommand { repairer, target }));
+    }
+
     fn player_issue_all_selected_gather_resource(&self, world_pos: [f32; 2]) {
         if let Some(resource) = self.resource_at_position(world_pos) {
             for gatherer in self.selected_player_entities() {
-                self._player_issue_gather_resource(gatherer.borrow_mut(), resource.borrow());
+                self._player_issue_gather_resource(gatherer.borrow().id, resource.borrow().id);
             }
         } else {
             self.hud
@@ -488,11 +962,11 @@ impl Game {
         }
     }
 
-    fn _player_issue_gather_resource(&self, gatherer: RefMut<Entity>, resource: Ref<Entity>) {
+    fn _player_issue_gather_resource(&self, gatherer: EntityId, resource: EntityId) {
         self.player_state
             .timed_entity_highlights
             .borrow_mut()
-            .push(EntityHighlight::new(resource.id, HighlightType::Friendly));
+            .push(EntityHighlight::new(resource, HighlightType::Friendly));
         self.player_issue_command(Command::GatherResource(GatherResourceCommand {
             gatherer,
             resource,
@@ -509,6 +983,180 @@ impl Game {
             .map(world_to_grid)
     }
 
+    // Issue the commands of the AIs (or the replay), and step the simulation once
+    fn advance_simulation(&mut self) -> UpdateOutcome {
+        if let Some(viewer) = &mut self.replay_viewer {
+            for recorded in viewer.playback.rebalances_before_tick(self.tick) {
+                match definitions::rebalance(self.core.definitions(), &recorded.definitions) {
+                    Ok((definitions, _changes)) => {
+                        self.hud
+                            .borrow_mut()
+                            .set_definitions(Arc::clone(&definitions));
+                        self.core.rebalance(definitions);
+                    }
+                    Err(e) => eprintln!("ERROR: Failed to rebalance replay: {}", e),
+                }
+            }
+            for recorded in viewer.playback.commands_before_tick(self.tick) {
+                let command = recorded.command.clone();
+                let _ = if recorded.queued {
+                    self.core.queue_command(command, recorded.team)
+                } else {
+                    self.core.issue_command(command, recorded.team)
+                };
+            }
+        } else {
+            for ai in &mut self.enemy_team_ais {
+                if let Some(command) = ai.run(self.tick_duration, &self.core, &mut self.rng) {
+                    println!("[{:?}] Issuing AI command", ai.team());
+                    if let Some(recording) = &self.recording {
+                        recording
+                            .borrow_mut()
+                            .record(self.tick, ai.team(), &command, false);
+                    }
+                    let _ = self.core.issue_command(command, ai.team());
+                }
+            }
+        }
+
+        let outcome = self.core.update(self.tick_duration);
+        self.tick += 1;
+        outcome
+    }
+
+    fn tick(&mut self, ctx: &mut Context) {
+        let UpdateOutcome {
+            removed_entities,
+            finished_structures,
+            did_tech_state_change,
+            eliminated_teams,
+            messages,
+            ..
+        } = self.advance_simulation();
+
+        for team in eliminated_teams {
+            self.hud
+                .borrow_mut()
+                .set_error_message(format!("{} has been eliminated", team));
+        }
+        for message in messages {
+            self.hud.borrow_mut().set_error_message(message);
+        }
+
+        let num_selected_before = self.player_state.selected_entity_ids.len();
+        let core = &self.core;
+        let team = self.player_state.team;
+        self.player_state.selected_entity_ids.retain(|entity_id| {
+            !removed_entities.contains(entity_id)
+                && core.entities().iter().any(|(id, entity)| {
+                    id == entity_id && core.is_visible_to(&entity.borrow(), &team)
+                })
+        });
+        let mut should_update_hud = did_tech_state_change;
+        if num_selected_before != self.player_state.selected_entity_ids.len() {
+            // TODO: what if you still have some selected entity, but it doesn't
+            //       have any action corresponding to the cursor state?
+            self.set_player_cursor_state(ctx, CursorState::Default);
+            should_update_hud = true;
+        }
+        for selected_entity_id in &self.player_state.selected_entity_ids {
+            if finished_structures.contains(selected_entity_id) {
+                should_update_hud = true;
+                break;
+            }
+        }
+        if should_update_hud {
+            self.update_hud_for_selection();
+        }
+    }
+
+    // Re-simulate the replay up to the given tick. Seeking backwards starts over from the
+    // beginning of the match.
+    fn seek_replay(&mut self, ctx: &mut Context, target_tick: u64) {
+        let viewer = self.replay_viewer.as_mut().expect("Not watching a replay");
+        let target_tick = target_tick.min(viewer.playback.replay.num_ticks);
+        if target_tick < self.tick {
+            // It could be restored when the replay was loaded, so it can be restored again
+            self.core = viewer
+                .playback
+                .replay
+                .initial_state
+                .restore()
+                .expect("Restoring the start of the replay");
+            self.hud
+                .borrow_mut()
+                .set_definitions(Arc::clone(self.core.definitions()));
+            viewer.playback.rewind();
+            self.tick = 0;
+        }
+        while self.tick < target_tick {
+            self.advance_simulation();
+        }
+        self.unsimulated_time = Duration::ZERO;
+
+        // Selected entities may not exist at the new point in time
+        let core = &self.core;
+        self.player_state
+            .selected_entity_ids
+            .retain(|id| core.entities().iter().any(|(entity_id, _)| entity_id == id));
+        self.set_player_cursor_state(ctx, CursorState::Default);
+        self.update_hud_for_selection();
+    }
+
+    fn handle_replay_key(&mut self, ctx: &mut Context, keycode: KeyCode) -> bool {
+        let viewer = match &mut self.replay_viewer {
+            Some(viewer) => viewer,
+            None => return false,
+        };
+        let seek_step = (REPLAY_SEEK_STEP.as_secs_f32() / self.tick_duration.as_secs_f32()) as u64;
+        match keycode {
+            KeyCode::Space => viewer.is_paused = !viewer.is_paused,
+            KeyCode::PageUp => viewer.speed = (viewer.speed * 2).min(MAX_REPLAY_SPEED),
+            KeyCode::PageDown => viewer.speed = (viewer.speed / 2).max(1),
+            KeyCode::Comma => self.seek_replay(ctx, self.tick.saturating_sub(seek_step)),
+            KeyCode::Period => self.seek_replay(ctx, self.tick + seek_step),
+            KeyCode::Tab => self.view_next_team(),
+            _ => return false,
+        }
+        true
+    }
+
+    // Switch the camera and HUD over to the next team
+    fn view_next_team(&mut self) {
+        let teams: Vec<Team> = self
+            .core
+            .teams()
+            .into_iter()
+            .filter(|team| *team != Team::Neutral)
+            .collect();
+        let current = teams
+            .iter()
+            .position(|team| *team == self.player_state.team);
+        let next = match current {
+            Some(i) => teams[(i + 1) % teams.len()],
+            None => teams[0],
+        };
+        self.player_state.team = next;
+        println!("Now viewing {}", next);
+
+        let team_entity_position = self.core.entities().iter().find_map(|(_id, entity)| {
+            let entity = entity.borrow();
+            if entity.team == next {
+                Some(entity.world_pixel_position())
+            } else {
+                None
+            }
+        });
+        if let Some([x, y]) = team_entity_position {
+            let dimensions = self.core.dimensions();
+            self.set_camera_position(
+                x / (dimensions[0] as f32 * CELL_PIXEL_SIZE[0]),
+                y / (dimensions[1] as f32 * CELL_PIXEL_SIZE[1]),
+            );
+        }
+        self.set_selected_entities(vec![]);
+    }
+
     // Create a rect with non-negative width and height from two points
     fn rect_from_points(a: [f32; 2], b: [f32; 2]) -> Rect {
         let (x0, x1) = if a[0] < b[0] {
@@ -531,43 +1179,47 @@ impl EventHandler for Game {
         // let [x, y]: [f32; 2] = mouse_position(ctx);
         // graphics::set_window_title(ctx, &format!("{} ({}, {})", TITLE, x, y));
         let fps = ggez::timer::fps(ctx) as u32;
-        graphics::set_window_title(ctx, &format!("{} (fps={})", TITLE, fps));
+        let mut title = format!("{} (fps={})", TITLE, fps);
+        if let Some(viewer) = &self.replay_viewer {
+            title.push_str(&format!(
+                " | Replay {:.0}s / {:.0}s, speed x{}{}, viewing {:?}",
+                (self.tick_duration * self.tick as u32).as_secs_f32(),
+                (self.tick_duration * viewer.playback.replay.num_ticks as u32).as_secs_f32(),
+                viewer.speed,
+                if viewer.is_paused { ", paused" } else { "" },
+                self.player_state.team
+            ));
+        }
+        graphics::set_window_title(ctx, &title);
 
         let dt = ggez::timer::delta(ctx);
 
-        for ai in &mut self.enemy_team_ais {
-            if let Some(command) = ai.run(dt, &self.core, &mut self.rng) {
-                println!("[{:?}] Issuing AI command", ai.team());
-                let _ = self.core.issue_command(command, ai.team());
-            }
-        }
-
-        let UpdateOutcome {
-            removed_entities,
-            finished_structures,
-            did_research_state_change,
-        } = self.core.update(dt);
+        let speed = match &self.replay_viewer {
+            Some(viewer) if viewer.is_paused => 0,
+            Some(viewer) => viewer.speed,
+            None => 1,
+        };
 
-        let num_selected_before = self.player_state.selected_entity_ids.len();
-        self.player_state
-            .selected_entity_ids
-            .retain(|entity_id| !removed_entities.contains(entity_id));
-        let mut should_update_hud = did_research_state_change;
-        if num_selected_before != self.player_state.selected_entity_ids.len() {
-            // TODO: what if you still have some selected entity, but it doesn't
-            //       have any action corresponding to the cursor state?
-            self.set_player_cursor_state(ctx, CursorState::Default);
-            should_update_hud = true;
-        }
-        for selected_entity_id in &self.player_state.selected_entity_ids {
-            if finished_structures.contains(selected_entity_id) {
-                should_update_hud = true;
+        // The simulation always advances in fixed steps, to keep it deterministic
+        self.unsimulated_time = (self.unsimulated_time + dt * speed)
+            .min(self.tick_duration * MAX_TICKS_PER_FRAME * speed);
+        while self.unsimulated_time >= self.tick_duration {
+            if self.core.match_result().is_some() {
+                // The end screen is shown, and nothing more happens in the world
+                self.unsimulated_time = Duration::ZERO;
                 break;
             }
+            if let Some(viewer) = &mut self.replay_viewer {
+                if viewer.playback.is_finished(self.tick) {
+                    viewer.is_paused = true;
+                    self.unsimulated_time = Duration::ZERO;
+                    break;
+                }
+            }
+            self.unsimulated_time -= self.tick_duration;
+            self.tick(ctx);
         }
-        if should_update_hud {
-            self.update_hud_for_selection();
-        }
+        self.reload_definitions_if_changed(dt);
 
         self.player_state.update(ctx, dt);
 
@@ -577,8 +1229,7 @@ impl EventHandler for Game {
                 let hovered_entity =
                     if let Some(pixel_coords) = self.player_state.screen_to_world(mouse_pos) {
                         self.core
-                            .entities()
-                            .iter()
+                            .visible_entities(&self.player_state.team)
                             .find(|(_id, e)| e.borrow().pixel_rect().contains(pixel_coords))
                     } else {
                         None
@@ -609,6 +1260,14 @@ impl EventHandler for Game {
                         .map(|enemy| (enemy.borrow().id, HighlightType::Friendly));
                 }
             }
+
+            CursorState::SelectingRepairTarget => {
+                if let Some(world_pixel_coords) = self.player_state.screen_to_world(mouse_pos) {
+                    self.player_state.hovered_entity_highlight = self
+                        .damaged_player_entity_at_position(world_pixel_coords)
+                        .map(|target| (target.borrow().id, HighlightType::Friendly));
+                }
+            }
             _ => {}
         }
 
@@ -655,7 +1314,7 @@ impl EventHandler for Game {
                 }
             }
 
-            if ENTITY_VISIBILITY_RECT.contains(screen_coords) {
+            if ENTITY_VISIBILITY_RECT.contains(screen_coords) && self.is_visible(&entity) {
                 entities_to_draw.push((screen_coords, entity));
             }
         }
@@ -701,6 +1360,55 @@ impl EventHandler for Game {
             }
         }
 
+        let visibility = self.core.visibility(&self.player_state.team);
+        for projectile in self.core.projectiles() {
+            let is_in_sight = visibility.is_none_or(|visibility| {
+                visibility.get(&projectile.cell()) == Some(Visibility::Visible)
+            });
+            let screen_coords = self
+                .player_state
+                .world_to_screen(projectile.world_pixel_position());
+            if is_in_sight && ENTITY_VISIBILITY_RECT.contains(screen_coords) {
+                self.assets.draw_projectile(ctx, screen_coords)?;
+            }
+        }
+
+        if let Some(visibility) = visibility {
+            self.assets.draw_fog(
+                ctx,
+                WORLD_VIEWPORT.point().into(),
+                camera_pos_in_world,
+                visibility,
+            )?;
+        }
+
+        for structure in self.selected_player_entities() {
+            let structure = structure.borrow();
+            let rally_point = structure
+                .activity
+                .as_ref()
+                .and_then(|activity| activity.rally_point());
+            if let Some(target) = rally_point.and_then(|r| self.rally_point_world_position(r)) {
+                let from = self
+                    .player_state
+                    .world_to_screen(rect_center(structure.pixel_rect()));
+                let to = self.player_state.world_to_screen(target);
+                self.assets.draw_rally_point(ctx, from, to)?;
+            }
+        }
+
+        for unit in self.selected_player_entities() {
+            let unit = unit.borrow();
+            if matches!(&unit.category, EntityCategory::Unit(u) if !u.command_queue.is_empty()) {
+                let waypoints: Vec<[f32; 2]> = self
+                    .waypoints(&unit)
+                    .into_iter()
+                    .map(|waypoint| self.player_state.world_to_screen(waypoint))
+                    .collect();
+                self.assets.draw_waypoints(ctx, &waypoints)?;
+            }
+        }
+
         let mouse_position: [f32; 2] = mouse_position(ctx);
         match self.player_state.cursor_state() {
             CursorState::PlacingStructure(structure_type) => {
@@ -736,22 +1444,33 @@ impl EventHandler for Game {
 
         let player_resources = self
             .core
-            .team_state(&Team::Player)
-            .map(|team_state| team_state.borrow().resources);
+            .team_state(&self.player_state.team)
+            .map(|team_state| {
+                let resources = team_state.borrow().resources.clone();
+                (resources, self.core.supply(&self.player_state.team))
+            });
         self.hud.borrow_mut().draw(
             ctx,
             player_resources,
             selected_entities,
             &self.player_state,
             self.core.obstacle_grid(),
+            self.core.visibility(&self.player_state.team),
         )?;
 
+        if let Some(result) = self.core.match_result() {
+            self.hud
+                .borrow()
+                .draw_end_screen(ctx, result, self.player_state.team)?;
+        }
+
         graphics::present(ctx)?;
         Ok(())
     }
 
     fn mouse_button_down_event(&mut self, ctx: &mut Context, button: MouseButton, x: f32, y: f32) {
         let [x, y] = physical_to_logical(ctx, [x, y]);
+        self.player_state.is_queueing_commands = keyboard::is_mod_active(ctx, KeyMods::SHIFT);
         if let Some(clicked_world_pixel_coords) = self.player_state.screen_to_world([x, y]) {
             let clicked_world_pos = world_to_grid(clicked_world_pixel_coords);
             match self.player_state.cursor_state() {
@@ -776,7 +1495,10 @@ impl EventHandler for Game {
                         clicked_world_pos,
                         structure_type,
                     );
-                    self.set_player_cursor_state(ctx, CursorState::Default);
+                    // Holding Shift lets the player place several structures in a row
+                    if !self.player_state.is_queueing_commands {
+                        self.set_player_cursor_state(ctx, CursorState::Default);
+                    }
                 }
                 CursorState::SelectingAttackTarget => {
                     self.player_issue_all_selected_attack(clicked_world_pixel_coords);
@@ -786,6 +1508,17 @@ impl EventHandler for Game {
                     self.player_issue_all_selected_gather_resource(clicked_world_pixel_coords);
                     self.set_player_cursor_state(ctx, CursorState::Default);
                 }
+                CursorState::SelectingRepairTarget => {
+                    self.player_issue_all_selected_repair(clicked_world_pixel_coords);
+                    self.set_player_cursor_state(ctx, CursorState::Default);
+                }
+                CursorState::SelectingPatrolDestination => {
+                    self.player_issue_all_selected_patrol(clicked_world_pixel_coords);
+                    // Holding Shift lets the player add several points to the route
+                    if !self.player_state.is_queueing_commands {
+                        self.set_player_cursor_state(ctx, CursorState::Default);
+                    }
+                }
                 CursorState::DraggingSelectionArea(..) => {
                     panic!("How did we end up here? When we release button, this cursor action should have been removed.");
                 }
@@ -824,7 +1557,7 @@ impl EventHandler for Game {
 
                 for (id, entity) in self.core.entities() {
                     let entity = entity.borrow();
-                    if entity.team == Team::Player {
+                    if entity.team == self.player_state.team {
                         if entity.pixel_rect().overlaps(&selection_rect) {
                             player_entities.push(*id);
                             if player_entities.len() == MAX_NUM_SELECTED_ENTITIES {
@@ -833,6 +1566,7 @@ impl EventHandler for Game {
                         }
                     } else if non_player_entity.is_none()
                         && entity.pixel_rect().overlaps(&selection_rect)
+                        && self.is_visible(&entity)
                     {
                         non_player_entity = Some(*id);
                     }
@@ -863,15 +1597,27 @@ impl EventHandler for Game {
         }
     }
 
+    fn quit_event(&mut self, _ctx: &mut Context) -> bool {
+        self.save_recording();
+        false
+    }
+
     fn key_down_event(
         &mut self,
         ctx: &mut Context,
         keycode: KeyCode,
-        _keymods: KeyMods,
+        keymods: KeyMods,
         _repeat: bool,
     ) {
+        self.player_state.is_queueing_commands = keymods.contains(KeyMods::SHIFT);
         match keycode {
-            KeyCode::Escape => ggez::event::quit(ctx),
+            KeyCode::Escape => {
+                self.save_recording();
+                ggez::event::quit(ctx);
+            }
+            KeyCode::F5 if self.replay_viewer.is_none() => self.quicksave(),
+            KeyCode::F9 if self.replay_viewer.is_none() => self.quickload(ctx),
+            _ if self.handle_replay_key(ctx, keycode) => {}
             KeyCode::Key0 => {
                 if let Some(selected) = self.selected_entities().next() {
                     // Dump selected entity for debugging
@@ -891,6 +1637,27 @@ impl EventHandler for Game {
     }
 }
 
+// The actions that are shown in the HUD when the entity is selected
+fn entity_actions(entity: &Entity) -> [Option<Action>; NUM_ENTITY_ACTIONS] {
+    // Actions that are locked, or research that is already complete / in progress, are disabled
+    // by the core.
+    // TODO standardize how this sort of thing should work.
+    //      There are other situations where certain actions shouldn't be shown:
+    //      having a cursor action tied to some selected action (?), etc.
+    let mut actions = [None; NUM_ENTITY_ACTIONS];
+    if entity.construction_site.is_some() {
+        // Nothing else can be done with a structure until it's finished
+        actions[NUM_ENTITY_ACTIONS - 1] = Some(Action::CancelConstruction);
+    } else {
+        for (action, action_slot) in actions.iter_mut().zip(entity.action_slots) {
+            *action = action_slot
+                .filter(|slot| slot.enabled)
+                .map(|slot| slot.action);
+        }
+    }
+    actions
+}
+
 pub fn grid_to_world(grid_position: [u32; 2]) -> [f32; 2] {
     [
         grid_position[0] as f32 * CELL_PIXEL_SIZE[0],
@@ -898,6 +1665,15 @@ pub fn grid_to_world(grid_position: [u32; 2]) -> [f32; 2] {
     ]
 }
 
+fn cell_center(cell: [u32; 2]) -> [f32; 2] {
+    let [x, y] = grid_to_world(cell);
+    [x + CELL_PIXEL_SIZE[0] / 2.0, y + CELL_PIXEL_SIZE[1] / 2.0]
+}
+
+fn rect_center(rect: Rect) -> [f32; 2] {
+    [rect.x + rect.w / 2.0, rect.y + rect.h / 2.0]
+}
+
 fn world_to_grid(world_coordinates: [f32; 2]) -> [u32; 2] {
     let grid_x = world_coordinates[0] / CELL_PIXEL_SIZE[0];
     let grid_y = world_coordinates[1] / CELL_PIXEL_SIZE[1];
@@ -1,5 +1,8 @@
+use serde::{Deserialize, Serialize};
+
 use crate::core::ObstacleType;
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ObstacleGrid {
     grid: _Grid<ObstacleType>,
 }
@@ -48,6 +51,7 @@ impl ObstacleGrid {
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Grid<T> {
     grid: _Grid<T>,
 }
@@ -85,6 +89,7 @@ impl<T: std::fmt::Debug + PartialEq + Copy + Default> Grid<T> {
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 struct _Grid<T> {
     cells: Vec<T>,
     dimensions: [u32; 2],
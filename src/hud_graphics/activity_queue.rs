@@ -0,0 +1,108 @@
+use ggez::graphics::{Color, DrawMode, DrawParam, Drawable, Image, Mesh, MeshBuilder, Rect};
+use ggez::{Context, GameResult};
+
+use super::entity_portrait::PORTRAIT_DIMENSIONS;
+use super::PlayerInput;
+use crate::entities::MAX_ACTIVITY_QUEUE_LENGTH;
+use crate::game::COLOR_BG;
+
+const SLOT_SIZE: f32 = 28.0;
+const SLOT_MARGIN: f32 = 6.0;
+const PROGRESS_BAR_HEIGHT: f32 = 3.0;
+
+/// The trainings and research that a structure has lined up. Clicking a slot cancels it.
+pub struct ActivityQueue {
+    slots: [Rect; MAX_ACTIVITY_QUEUE_LENGTH],
+    slot_border: Mesh,
+    highlight: Mesh,
+    hovered_slot_index: Option<usize>,
+    num_shown_slots: usize,
+}
+
+impl ActivityQueue {
+    pub fn new(ctx: &mut Context, position_on_screen: [f32; 2]) -> GameResult<Self> {
+        let slots = [0, 1, 2, 3, 4].map(|i| {
+            Rect::new(
+                position_on_screen[0] + i as f32 * (SLOT_SIZE + SLOT_MARGIN),
+                position_on_screen[1],
+                SLOT_SIZE,
+                SLOT_SIZE,
+            )
+        });
+        let local_rect = Rect::new(0.0, 0.0, SLOT_SIZE, SLOT_SIZE);
+        let slot_border = MeshBuilder::new()
+            .rectangle(DrawMode::fill(), local_rect, COLOR_BG)?
+            .rectangle(
+                DrawMode::stroke(1.0),
+                local_rect,
+                Color::new(0.1, 0.1, 0.1, 1.0),
+            )?
+            .build(ctx)?;
+        let highlight = MeshBuilder::new()
+            .rectangle(
+                DrawMode::stroke(1.0),
+                local_rect,
+                Color::new(0.6, 0.6, 0.6, 1.0),
+            )?
+            .build(ctx)?;
+        Ok(Self {
+            slots,
+            slot_border,
+            highlight,
+            hovered_slot_index: None,
+            num_shown_slots: 0,
+        })
+    }
+
+    /// `progress` refers to the first item in the queue, which is the ongoing one
+    pub fn draw(&mut self, ctx: &mut Context, icons: &[&Image], progress: f32) -> GameResult {
+        self.num_shown_slots = icons.len();
+        let scale = SLOT_SIZE / PORTRAIT_DIMENSIONS[0];
+        for (i, icon) in icons.iter().enumerate() {
+            let slot = self.slots[i];
+            self.slot_border
+                .draw(ctx, DrawParam::new().dest(slot.point()))?;
+            icon.draw(
+                ctx,
+                DrawParam::new().dest(slot.point()).scale([scale, scale]),
+            )?;
+            if self.hovered_slot_index == Some(i) {
+                self.highlight
+                    .draw(ctx, DrawParam::new().dest(slot.point()))?;
+            }
+        }
+
+        if !icons.is_empty() {
+            let first = self.slots[0];
+            let progress_bar = Mesh::new_rectangle(
+                ctx,
+                DrawMode::fill(),
+                Rect::new(
+                    first.x,
+                    first.bottom() + 2.0,
+                    first.w * progress,
+                    PROGRESS_BAR_HEIGHT,
+                ),
+                Color::new(0.2, 0.8, 0.2, 1.0),
+            )?;
+            progress_bar.draw(ctx, DrawParam::new())?;
+        }
+        Ok(())
+    }
+
+    /// Should be called when the queue isn't drawn, so that it doesn't react to clicks
+    pub fn hide(&mut self) {
+        self.num_shown_slots = 0;
+    }
+
+    pub fn on_mouse_button_down(&self, x: f32, y: f32) -> Option<PlayerInput> {
+        self.slots[..self.num_shown_slots]
+            .iter()
+            .position(|slot| slot.contains([x, y]))
+            .map(PlayerInput::CancelActivity)
+    }
+
+    pub fn on_mouse_motion(&mut self, x: f32, y: f32) {
+        self.hovered_slot_index = self.slots.iter().position(|slot| slot.contains([x, y]));
+    }
+}
@@ -75,6 +75,8 @@ impl Button {
                     matches!(action, Action::Construct(s_type, _) if s_type == structure_type)
                 }
                 CursorState::SelectingResourceTarget => action == Action::GatherResource,
+                CursorState::SelectingPatrolDestination => action == Action::Patrol,
+                CursorState::SelectingRepairTarget => action == Action::Repair,
                 CursorState::DraggingSelectionArea(_) => false,
             };
 
@@ -114,10 +116,9 @@ impl Button {
     }
 
     pub fn on_click(&mut self) -> Option<Action> {
-        self.action.map(|action| {
+        self.action.inspect(|_action| {
             self.is_down = true;
             self.down_cooldown = Duration::from_millis(100);
-            action
         })
     }
 
@@ -131,6 +132,10 @@ impl Button {
         }
     }
 
+    pub fn set_graphics(&mut self, picture: Image) {
+        self.graphics = Some(picture);
+    }
+
     pub fn action(&self) -> Option<Action> {
         self.action
     }
@@ -0,0 +1,80 @@
+use ggez::graphics::{Color, DrawMode, DrawParam, Drawable, Mesh, MeshBuilder, Rect};
+use ggez::{Context, GameResult};
+
+use super::HUD_BORDER_COLOR;
+use crate::core::MatchResult;
+use crate::entities::Team;
+use crate::text::SharpFont;
+
+const COLUMNS: [&str; 6] = ["Trained", "Lost", "Killed", "Fuel", "Minerals", "Built"];
+
+pub struct EndScreen {
+    background: Mesh,
+    font: SharpFont,
+    position: [f32; 2],
+}
+
+impl EndScreen {
+    pub fn new(ctx: &mut Context, rect: Rect, font: SharpFont) -> GameResult<Self> {
+        let background = MeshBuilder::new()
+            .rectangle(DrawMode::fill(), rect, Color::new(0.1, 0.1, 0.15, 0.9))?
+            .rectangle(DrawMode::stroke(2.0), rect, HUD_BORDER_COLOR)?
+            .build(ctx)?;
+        Ok(Self {
+            background,
+            font,
+            position: [rect.x + 20.0, rect.y + 20.0],
+        })
+    }
+
+    pub fn draw(&self, ctx: &mut Context, result: &MatchResult, viewing_team: Team) -> GameResult {
+        self.background.draw(ctx, DrawParam::new())?;
+
+        let [x, mut y] = self.position;
+        let winners: Vec<String> = result.winners.iter().map(Team::to_string).collect();
+        let winners = winners.join(", ");
+        let title = if result.winners.is_empty() {
+            "Draw! Nobody is left standing".to_owned()
+        } else if result.winners.contains(&viewing_team) {
+            "Victory!".to_owned()
+        } else if result.stats.contains_key(&viewing_team) {
+            format!("Defeat! {} won the match", winners)
+        } else {
+            format!("{} won the match", winners)
+        };
+        self.font.text(20.0, title).draw(ctx, [x, y])?;
+        y += 40.0;
+
+        let column_width = 65.0;
+        let first_column_x = x + 90.0;
+        for (i, column) in COLUMNS.iter().enumerate() {
+            self.font
+                .text(14.0, *column)
+                .draw(ctx, [first_column_x + i as f32 * column_width, y])?;
+        }
+        y += 25.0;
+
+        for (team, stats) in &result.stats {
+            self.font.text(14.0, team.to_string()).draw(ctx, [x, y])?;
+            let values = [
+                stats.units_trained,
+                stats.units_lost,
+                stats.units_killed,
+                stats.fuel_gathered,
+                stats.minerals_gathered,
+                stats.structures_built,
+            ];
+            for (i, value) in values.iter().enumerate() {
+                self.font
+                    .text(14.0, value.to_string())
+                    .draw(ctx, [first_column_x + i as f32 * column_width, y])?;
+            }
+            y += 20.0;
+        }
+
+        self.font
+            .text(12.0, "Press Escape to quit")
+            .draw(ctx, [x, y + 15.0])?;
+        Ok(())
+    }
+}
@@ -5,7 +5,6 @@ use super::entity_portrait::{EntityPortrait, PORTRAIT_DIMENSIONS};
 use super::healthbar::Healthbar;
 use super::progress_bar::ProgressBar;
 use super::HUD_BORDER_COLOR;
-use crate::entities::Team;
 use crate::text::SharpFont;
 
 pub struct EntityHeader {
@@ -92,5 +91,4 @@ pub struct EntityHeaderContent<'a> {
     pub name: String,
     pub status: Option<String>,
     pub progress: Option<(f32, String)>,
-    pub team: Team,
 }
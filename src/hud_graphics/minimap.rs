@@ -4,21 +4,20 @@ use ggez::input::mouse::MouseButton;
 use ggez::{Context, GameResult};
 
 use super::HUD_BORDER_COLOR;
-use crate::core::ObstacleType;
-use crate::entities::Team;
+use crate::core::{ObstacleType, TeamSetup, Visibility};
 use crate::game::{CELL_PIXEL_SIZE, COLOR_BG, WORLD_VIEWPORT};
-use crate::grid::ObstacleGrid;
+use crate::grid::{Grid, ObstacleGrid};
 use crate::images;
 
 pub struct Minimap {
     container_border: Mesh,
     bg: Mesh,
     camera: Mesh,
-    player_entity_sprite_batch: SpriteBatch,
-    enemy_1_entity_sprite_batch: SpriteBatch,
-    enemy_2_entity_sprite_batch: SpriteBatch,
-    neutral_entity_sprite_batch: SpriteBatch,
+    // Each marker is tinted with the color of its team
+    entity_sprite_batch: SpriteBatch,
+    team_setup: TeamSetup,
     water_sprite_batch: SpriteBatch,
+    unexplored_sprite_batch: SpriteBatch,
     camera_scale: [f32; 2],
     rect: Rect,
     is_mouse_dragging: bool,
@@ -31,6 +30,7 @@ impl Minimap {
         position: [f32; 2],
         width: f32,
         world_dimensions: [u32; 2],
+        team_setup: &TeamSetup,
     ) -> GameResult<Self> {
         let aspect_ratio = world_dimensions[0] as f32 / world_dimensions[1] as f32;
         let container_h = width;
@@ -75,25 +75,18 @@ impl Minimap {
         ];
 
         let cell_rect = Rect::new(0.0, 0.0, cell_size[0], cell_size[1]);
-        let player_entity_sprite_batch =
-            sprite_batch(ctx, cell_rect, Color::new(0.5, 1.0, 0.5, 1.0))?;
-        let enemy_1_entity_sprite_batch =
-            sprite_batch(ctx, cell_rect, Color::new(0.8, 0.3, 0.3, 1.0))?;
-        let enemy_2_entity_sprite_batch =
-            sprite_batch(ctx, cell_rect, Color::new(1.0, 0.2, 1.0, 1.0))?;
-        let neutral_entity_sprite_batch =
-            sprite_batch(ctx, cell_rect, Color::new(0.5, 0.5, 0.5, 1.0))?;
+        let entity_sprite_batch = sprite_batch(ctx, cell_rect, Color::WHITE)?;
         let water_sprite_batch = sprite_batch(ctx, cell_rect, Color::new(0.5, 0.5, 1.0, 1.0))?;
+        let unexplored_sprite_batch = sprite_batch(ctx, cell_rect, Color::new(0.0, 0.0, 0.0, 1.0))?;
 
         Ok(Self {
             container_border,
             bg,
             camera,
-            player_entity_sprite_batch,
-            enemy_1_entity_sprite_batch,
-            enemy_2_entity_sprite_batch,
-            neutral_entity_sprite_batch,
+            entity_sprite_batch,
+            team_setup: team_setup.clone(),
             water_sprite_batch,
+            unexplored_sprite_batch,
             camera_scale,
             rect,
             is_mouse_dragging: false,
@@ -106,9 +99,10 @@ impl Minimap {
         ctx: &mut Context,
         camera_position_in_world: [f32; 2],
         grid: &ObstacleGrid,
+        visibility: Option<&Grid<Visibility>>,
     ) -> GameResult {
         self.bg.draw(ctx, DrawParam::default())?;
-        self.draw_entity_markers(ctx, grid)?;
+        self.draw_entity_markers(ctx, grid, visibility)?;
         self.camera.draw(
             ctx,
             DrawParam::default().dest([
@@ -122,46 +116,52 @@ impl Minimap {
         Ok(())
     }
 
-    fn draw_entity_markers(&mut self, ctx: &mut Context, grid: &ObstacleGrid) -> GameResult {
+    fn draw_entity_markers(
+        &mut self,
+        ctx: &mut Context,
+        grid: &ObstacleGrid,
+        visibility: Option<&Grid<Visibility>>,
+    ) -> GameResult {
         let [w, h] = grid.dimensions();
         for x in 0..w {
             for y in 0..h {
-                let sprite_batch = match grid.get(&[x, y]).unwrap() {
-                    ObstacleType::Entity(Team::Player) => {
-                        Some(&mut self.player_entity_sprite_batch)
-                    }
-                    ObstacleType::Entity(Team::Enemy1) => {
-                        Some(&mut self.enemy_1_entity_sprite_batch)
+                let cell_visibility = visibility
+                    .map(|visibility| visibility.get(&[x, y]).unwrap())
+                    .unwrap_or(Visibility::Visible);
+                let obstacle = match (grid.get(&[x, y]).unwrap(), cell_visibility) {
+                    (_, Visibility::Unexplored) => None,
+                    // Entities that are out of sight aren't shown, but terrain is remembered
+                    (ObstacleType::Entity(_), Visibility::Explored) => Some(ObstacleType::None),
+                    (obstacle, _) => Some(obstacle),
+                };
+                let pos = [
+                    (x as f32 / w as f32) * self.rect.w,
+                    (y as f32 / h as f32) * self.rect.h,
+                ];
+                let param = DrawParam::default().dest(pos);
+                match obstacle {
+                    None => {
+                        self.unexplored_sprite_batch.add(param);
                     }
-                    ObstacleType::Entity(Team::Enemy2) => {
-                        Some(&mut self.enemy_2_entity_sprite_batch)
+                    Some(ObstacleType::Entity(team)) => {
+                        let [r, g, b] = self.team_setup.color(team);
+                        self.entity_sprite_batch
+                            .add(param.color(Color::from_rgb(r, g, b)));
                     }
-                    ObstacleType::Entity(Team::Neutral) => {
-                        Some(&mut self.neutral_entity_sprite_batch)
+                    Some(ObstacleType::Water) => {
+                        self.water_sprite_batch.add(param);
                     }
-                    ObstacleType::Water => Some(&mut self.water_sprite_batch),
-                    ObstacleType::None => None,
-                };
-                if let Some(sprite_batch) = sprite_batch {
-                    let pos = [
-                        (x as f32 / w as f32) * self.rect.w,
-                        (y as f32 / h as f32) * self.rect.h,
-                    ];
-                    sprite_batch.add(DrawParam::default().dest(pos));
+                    Some(ObstacleType::None) => {}
                 }
             }
         }
         let param = DrawParam::default().dest(self.rect.point());
-        self.player_entity_sprite_batch.draw(ctx, param)?;
-        self.enemy_1_entity_sprite_batch.draw(ctx, param)?;
-        self.enemy_2_entity_sprite_batch.draw(ctx, param)?;
-        self.neutral_entity_sprite_batch.draw(ctx, param)?;
+        self.entity_sprite_batch.draw(ctx, param)?;
         self.water_sprite_batch.draw(ctx, param)?;
-        self.player_entity_sprite_batch.clear();
-        self.enemy_1_entity_sprite_batch.clear();
-        self.enemy_2_entity_sprite_batch.clear();
-        self.neutral_entity_sprite_batch.clear();
+        self.unexplored_sprite_batch.draw(ctx, param)?;
+        self.entity_sprite_batch.clear();
         self.water_sprite_batch.clear();
+        self.unexplored_sprite_batch.clear();
         Ok(())
     }
 
@@ -1,4 +1,6 @@
+mod activity_queue;
 mod button;
+mod end_screen;
 mod entity_header;
 pub mod entity_portrait;
 mod group_header;
@@ -7,24 +9,30 @@ mod minimap;
 mod progress_bar;
 
 use std::cell::Ref;
+use std::collections::BTreeMap;
 use std::convert::TryInto;
+use std::sync::Arc;
 use std::time::Duration;
 
-use ggez::graphics::{Color, Rect};
+use ggez::graphics::{Color, Image, Rect};
 use ggez::input::keyboard::KeyCode;
 use ggez::input::mouse::MouseButton;
 use ggez::{Context, GameResult};
 
+use self::activity_queue::ActivityQueue;
 use self::button::Button;
+use self::end_screen::EndScreen;
 use self::entity_header::{EntityHeader, EntityHeaderContent};
 use self::group_header::GroupHeader;
 use self::minimap::Minimap;
-use crate::data::{EntityType, HudAssets};
+use crate::core::{MatchResult, Supply, TeamSetup, Visibility};
+use crate::data::{HudAssets, ResourceType};
+use crate::definitions::EntityRegistry;
 use crate::entities::{
-    Action, ActivityTarget, Entity, EntityCategory, EntityState, Team, NUM_ENTITY_ACTIONS,
+    Action, Entity, EntityCategory, EntityState, Stance, Team, NUM_ENTITY_ACTIONS,
 };
-use crate::game::MAX_NUM_SELECTED_ENTITIES;
-use crate::grid::ObstacleGrid;
+use crate::game::{MAX_NUM_SELECTED_ENTITIES, WORLD_VIEWPORT};
+use crate::grid::{Grid, ObstacleGrid};
 use crate::player::{CursorState, PlayerState};
 use crate::text::{SharpFont, SharpText};
 
@@ -40,7 +48,9 @@ pub struct HudGraphics {
     error_message: ErrorMessage,
     tooltip: Tooltip,
     entity_header: EntityHeader,
+    activity_queue: ActivityQueue,
     group_header: GroupHeader,
+    end_screen: EndScreen,
     assets: HudAssets,
     num_selected_entities: usize,
     resources_position: [f32; 2],
@@ -53,27 +63,38 @@ impl HudGraphics {
         font: SharpFont,
         world_dimensions: [u32; 2],
         tooltip_position: [f32; 2],
+        team_setup: &TeamSetup,
+        definitions: Arc<EntityRegistry>,
     ) -> GameResult<Self> {
         let minimap_pos = position;
         let minimap_w = 195.0;
-        let minimap = Minimap::new(ctx, minimap_pos, minimap_w, world_dimensions)?;
+        let minimap = Minimap::new(ctx, minimap_pos, minimap_w, world_dimensions, team_setup)?;
 
-        let assets = HudAssets::new(ctx)?;
+        let assets = HudAssets::new(ctx, definitions)?;
 
         let header_pos = [position[0], position[1] + 200.0];
         let entity_header = EntityHeader::new(ctx, header_pos, font)?;
+        let activity_queue = ActivityQueue::new(ctx, [header_pos[0], header_pos[1] + 104.0])?;
         let group_header = GroupHeader::new(ctx, header_pos)?;
         let error_position = [tooltip_position[0] + 5.0, tooltip_position[1] - 30.0];
         let error_message = ErrorMessage::new(font, error_position);
         let tooltip = Tooltip::new(font, tooltip_position, &assets);
+        let end_screen_size = [480.0, 220.0];
+        let end_screen_rect = Rect::new(
+            WORLD_VIEWPORT.x + (WORLD_VIEWPORT.w - end_screen_size[0]) / 2.0,
+            WORLD_VIEWPORT.y + (WORLD_VIEWPORT.h - end_screen_size[1]) / 2.0,
+            end_screen_size[0],
+            end_screen_size[1],
+        );
+        let end_screen = EndScreen::new(ctx, end_screen_rect, font)?;
 
         let buttons_x = header_pos[0];
-        let buttons_y = header_pos[1] + 110.0;
+        let buttons_y = header_pos[1] + 140.0;
         let mut buttons = vec![];
-        let button_size = [55.0, 50.0];
-        let button_hor_margin = 15.0;
-        let button_vert_margin = 12.0;
-        let buttons_per_row = 3;
+        let button_size = [44.0, 44.0];
+        let button_hor_margin = 6.0;
+        let button_vert_margin = 6.0;
+        let buttons_per_row = 4;
         for i in 0..NUM_BUTTONS {
             let x = buttons_x + (i % buttons_per_row) as f32 * (button_size[0] + button_hor_margin);
             let y =
@@ -91,28 +112,37 @@ impl HudGraphics {
             error_message,
             tooltip,
             entity_header,
+            activity_queue,
             group_header,
+            end_screen,
             assets,
             num_selected_entities: 0,
-            resources_position: [600.0, 7.0],
+            resources_position: [460.0, 7.0],
         })
     }
 
     pub fn draw<'a>(
         &mut self,
         ctx: &mut Context,
-        player_resources: Option<u32>,
+        player_resources: Option<(BTreeMap<ResourceType, u32>, Supply)>,
         selected_entities: Vec<Ref<'a, Entity>>,
         player_state: &PlayerState,
         grid: &ObstacleGrid,
+        visibility: Option<&Grid<Visibility>>,
     ) -> GameResult {
         assert_eq!(selected_entities.len(), self.num_selected_entities);
 
         let cursor_state = player_state.cursor_state();
-
-        if let Some(player_resources) = player_resources {
+        self.activity_queue.hide();
+
+        if let Some((player_resources, supply)) = player_resources {
+            let mut amounts: Vec<String> = player_resources
+                .iter()
+                .map(|(resource_type, amount)| format!("{}: {}", resource_type.name(), amount))
+                .collect();
+            amounts.push(format!("Supply: {}/{}", supply.used, supply.provided));
             self.font
-                .text(15.0, format!("Fuel: {}", player_resources))
+                .text(15.0, amounts.join("   "))
                 .draw(ctx, self.resources_position)?;
         }
 
@@ -129,27 +159,29 @@ impl HudGraphics {
 
             let mut entity_status_text = None;
             let mut progress = None;
-            if entity.team == Team::Player {
+            if entity.team == player_state.team {
                 if let EntityCategory::Unit(unit) = &entity.category {
                     if let Some(gathering) = unit.gathering.as_ref() {
-                        if gathering.is_carrying() {
-                            entity_status_text = Some("[carrying fuel]".to_owned());
+                        if let Some(held) = gathering.held_resource() {
+                            entity_status_text =
+                                Some(format!("[carrying {} {}]", held.amount, held.resource_type));
                         }
                     }
                 }
-                if let EntityState::DoingActivity(target) = entity.state {
-                    let activity = entity.activity.as_ref().unwrap();
-                    let activity_progress = activity.progress(target).unwrap();
-                    let text = match target {
-                        ActivityTarget::Train(..) => "% Training".to_owned(),
-                        ActivityTarget::Research => "% Research".to_owned(),
-                    };
-                    progress = Some((activity_progress, text));
+                if let Some(activity) = entity.activity.as_ref() {
+                    let icons: Vec<&Image> = activity
+                        .queue()
+                        .map(|target| self.assets.activity_icon(target))
+                        .collect();
+                    let activity_progress = activity.progress().unwrap_or(0.0);
+                    self.activity_queue.draw(ctx, &icons, activity_progress)?;
                 }
             }
-            if entity.entity_type == EntityType::FuelRift {
-                let remaining = *entity.resource_remaining();
-                entity_status_text = Some(format!("[remaining fuel: {}]", remaining));
+            if let EntityCategory::Resource { remaining, config } = &entity.category {
+                entity_status_text = Some(format!(
+                    "[remaining {}: {}]",
+                    config.resource_type, remaining
+                ));
             }
             if let EntityState::UnderConstruction(remaining, total) = entity.state {
                 let construction_progress = (total - remaining).as_secs_f32() / total.as_secs_f32();
@@ -170,12 +202,16 @@ impl HudGraphics {
                     name: config.name.clone(),
                     status: entity_status_text,
                     progress,
-                    team: entity.team,
                 },
             )?;
         }
 
-        for (button_i, button) in self.buttons.iter().enumerate() {
+        // Shown on the stance button, which has no fixed icon
+        let stance = selected_entities.iter().find_map(|entity| entity.stance());
+        for (button_i, button) in self.buttons.iter_mut().enumerate() {
+            if let (Some(Action::ChangeStance), Some(stance)) = (button.action(), stance) {
+                button.set_graphics(self.assets.stance(stance).icon);
+            }
             let is_hovered = self.hovered_button_index == Some(button_i);
             let matches_entity_state = button
                 .action()
@@ -191,7 +227,12 @@ impl HudGraphics {
         let tooltip_text = match cursor_state {
             CursorState::Default => {
                 if let Some(index) = self.hovered_button_index {
-                    self.buttons[index].action().map(TooltipText::Action)
+                    match (self.buttons[index].action(), stance) {
+                        (Some(Action::ChangeStance), Some(stance)) => {
+                            Some(TooltipText::Stance(stance))
+                        }
+                        (action, _) => action.map(TooltipText::Action),
+                    }
                 } else {
                     None
                 }
@@ -202,17 +243,34 @@ impl HudGraphics {
             }
             CursorState::PlacingStructure(_) => Some(TooltipText::CursorPlaceStructure),
             CursorState::SelectingResourceTarget => Some(TooltipText::CursorSelectResource),
+            CursorState::SelectingPatrolDestination => {
+                Some(TooltipText::CursorSelectPatrolDestination)
+            }
+            CursorState::SelectingRepairTarget => Some(TooltipText::CursorSelectRepairTarget),
             CursorState::DraggingSelectionArea(_) => None,
         };
         self.error_message.draw(ctx)?;
         self.tooltip.draw(ctx, tooltip_text, &self.assets)?;
 
-        self.minimap
-            .draw(ctx, player_state.camera_position_in_world(), grid)?;
+        self.minimap.draw(
+            ctx,
+            player_state.camera_position_in_world(),
+            grid,
+            visibility,
+        )?;
 
         Ok(())
     }
 
+    pub fn draw_end_screen(
+        &self,
+        ctx: &mut Context,
+        result: &MatchResult,
+        viewing_team: Team,
+    ) -> GameResult {
+        self.end_screen.draw(ctx, result, viewing_team)
+    }
+
     pub fn on_mouse_button_down(
         &mut self,
         button: MouseButton,
@@ -233,6 +291,10 @@ impl HudGraphics {
             }
         }
 
+        if let Some(player_input) = self.activity_queue.on_mouse_button_down(x, y) {
+            return Some(player_input);
+        }
+
         self.minimap
             .on_mouse_button_down(button, x, y)
             .map(PlayerInput::SetCameraPositionRelativeToWorldDimension)
@@ -247,6 +309,7 @@ impl HudGraphics {
         if self.num_selected_entities > 1 {
             self.group_header.on_mouse_motion(x, y);
         }
+        self.activity_queue.on_mouse_motion(x, y);
 
         self.minimap
             .on_mouse_motion(x, y)
@@ -288,6 +351,11 @@ impl HudGraphics {
         }
     }
 
+    /// The entity definitions of the match, after they have been rebalanced
+    pub fn set_definitions(&mut self, definitions: Arc<EntityRegistry>) {
+        self.assets.set_definitions(definitions);
+    }
+
     pub fn set_num_selected_entities(&mut self, num: usize) {
         self.num_selected_entities = num;
     }
@@ -310,11 +378,21 @@ fn state_matches_action(state: EntityState, action: Action) -> bool {
             }
         }
         Action::Stop => state == EntityState::Idle,
-        Action::Move => state == EntityState::Moving,
+        Action::ChangeStance | Action::CancelConstruction => false,
+        Action::Move => matches!(state, EntityState::Moving | EntityState::Following(_)),
         Action::Attack => {
             matches!(
                 state,
-                EntityState::Attacking(_) | EntityState::MovingToAttackTarget(_)
+                EntityState::Attacking(_)
+                    | EntityState::MovingToAttackTarget(_)
+                    | EntityState::AttackMoving
+            )
+        }
+        Action::Patrol => state == EntityState::Patrolling,
+        Action::Repair => {
+            matches!(
+                state,
+                EntityState::Repairing(_) | EntityState::MovingToRepair(_)
             )
         }
         Action::GatherResource => {
@@ -381,6 +459,8 @@ struct Tooltip {
     text_select_movement_destination: SharpText,
     text_place_structure: SharpText,
     text_select_resource: SharpText,
+    text_select_patrol_destination: SharpText,
+    text_select_repair_target: SharpText,
 }
 
 impl Tooltip {
@@ -399,6 +479,8 @@ impl Tooltip {
             text_select_movement_destination: text("Select destination"),
             text_place_structure: text("Place structure"),
             text_select_resource: text("Select resource to gather"),
+            text_select_patrol_destination: text("Select patrol destination"),
+            text_select_repair_target: text("Select damaged entity to repair"),
         }
     }
 
@@ -420,6 +502,12 @@ impl Tooltip {
                         .text(TOOLTIP_FONT_SIZE, &config.text)
                         .draw(ctx, self.position)?;
                 }
+                TooltipText::Stance(stance) => {
+                    let config = assets.stance(stance);
+                    self.font
+                        .text(TOOLTIP_FONT_SIZE, &config.text)
+                        .draw(ctx, self.position)?;
+                }
                 TooltipText::CursorSelectAttackTarget => {
                     self.text_select_attack_target.draw(ctx, self.position)?
                 }
@@ -432,6 +520,12 @@ impl Tooltip {
                 TooltipText::CursorSelectResource => {
                     self.text_select_resource.draw(ctx, self.position)?
                 }
+                TooltipText::CursorSelectPatrolDestination => self
+                    .text_select_patrol_destination
+                    .draw(ctx, self.position)?,
+                TooltipText::CursorSelectRepairTarget => {
+                    self.text_select_repair_target.draw(ctx, self.position)?
+                }
             }
         };
         Ok(())
@@ -440,10 +534,13 @@ impl Tooltip {
 
 enum TooltipText {
     Action(Action),
+    Stance(Stance),
     CursorSelectAttackTarget,
     CursorSelectMovementDestination,
     CursorPlaceStructure,
     CursorSelectResource,
+    CursorSelectPatrolDestination,
+    CursorSelectRepairTarget,
 }
 
 #[derive(Debug)]
@@ -451,4 +548,5 @@ pub enum PlayerInput {
     UseEntityAction(Action),
     SetCameraPositionRelativeToWorldDimension([f32; 2]),
     LimitSelectionToIndex(usize),
+    CancelActivity(usize),
 }
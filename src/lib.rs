@@ -1,19 +1,28 @@
 extern crate ggez;
 extern crate rand;
 
+pub mod cli;
+pub mod definitions;
 pub mod game;
 pub mod map;
 pub mod map_editor;
+pub mod map_generator;
+pub mod simulation;
 
 mod assets;
 mod camera;
+mod command;
 mod core;
 mod data;
 mod entities;
 mod grid;
 mod hud_graphics;
 mod images;
+mod map_file;
 mod pathfind;
 mod player;
+mod replay;
+mod save;
 mod team_ai;
 mod text;
+mod trigger;
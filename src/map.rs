@@ -1,14 +1,19 @@
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
-use ggez::Context;
-use std::fs::OpenOptions;
-use std::io::{Read, Write};
+use ggez::{Context, GameError, GameResult};
+use std::collections::BTreeMap;
+use std::io::{self, Read};
 use std::path::Path;
 
-use crate::core::TeamResearchState;
-use crate::data::{self, create_entity, EntityType};
-use crate::entities::{Entity, Team};
+use crate::core::{TeamSetup, TechState, VictoryCondition};
+use crate::data::{self, EntityType};
+use crate::entities::{Entity, EntityIdAllocator, Team};
 use crate::grid::{CellRect, Grid};
+use crate::map_file::MapFile;
+use crate::map_generator::{self, GeneratorSettings};
+use crate::save;
+use crate::trigger::Trigger;
 
 #[derive(Debug, PartialEq)]
 pub enum MapType {
@@ -22,6 +27,25 @@ pub enum MapType {
 pub enum MapConfig {
     Type(MapType),
     FromFile(Box<dyn AsRef<Path>>),
+    /// Created from the seed of the match
+    Generated(GeneratorSettings),
+}
+
+impl MapConfig {
+    pub fn from_arg(arg: Option<&str>) -> Self {
+        match arg {
+            Some("loadtest") => MapConfig::Type(MapType::LoadTest),
+            Some("empty") => MapConfig::Type(MapType::Empty),
+            Some("spectator") => MapConfig::Type(MapType::Spectator),
+            Some("small") => MapConfig::Type(MapType::Small),
+            Some("generated") => MapConfig::Generated(GeneratorSettings::default()),
+            Some(filename) => {
+                let map_file_path = format!("/maps/{}", filename);
+                MapConfig::FromFile(Box::new(map_file_path))
+            }
+            None => MapConfig::Type(MapType::Medium),
+        }
+    }
 }
 
 pub struct WorldInitData {
@@ -29,17 +53,45 @@ pub struct WorldInitData {
     pub entities: Vec<Entity>,
     pub water_grid: Grid<bool>,
     pub tile_grid: Grid<TileId>,
+    pub victory_condition: VictoryCondition,
+    pub team_setup: TeamSetup,
+    /// Where each player's base is
+    pub start_locations: BTreeMap<Team, [u32; 2]>,
+    pub triggers: Vec<Trigger>,
 }
 
 impl WorldInitData {
-    pub fn load(ctx: &mut Context, config: MapConfig) -> Self {
+    pub fn load(ctx: &mut Context, config: MapConfig, seed: u64) -> GameResult<Self> {
+        match config {
+            MapConfig::Type(map_type) => Ok(Self::create_from_type(map_type, seed)),
+            MapConfig::FromFile(path) => Self::load_from_file(ctx, path.as_ref().as_ref()),
+            MapConfig::Generated(settings) => {
+                let map = map_generator::generate(&settings, seed)
+                    .map_err(GameError::ResourceLoadError)?;
+                Ok(Self::from_map(&map))
+            }
+        }
+    }
+
+    /// Same as `load()`, but doesn't need a ggez context. Map files are read directly from
+    /// the resources directory on disk.
+    pub fn load_headless(config: MapConfig, seed: u64) -> io::Result<Self> {
         match config {
-            MapConfig::Type(map_type) => Self::create_from_type(map_type),
-            MapConfig::FromFile(path) => Self::load_from_file(ctx, path.as_ref()),
+            MapConfig::Type(map_type) => Ok(Self::create_from_type(map_type, seed)),
+            MapConfig::FromFile(path) => {
+                let path: &Path = path.as_ref().as_ref();
+                let relative_path = path.strip_prefix("/").unwrap_or(path);
+                let contents = std::fs::read_to_string(Path::new("resources").join(relative_path))?;
+                Ok(Self::from_map(&MapFile::parse(&contents, path)?))
+            }
+            MapConfig::Generated(settings) => {
+                let map = map_generator::generate(&settings, seed).map_err(save::invalid_data)?;
+                Ok(Self::from_map(&map))
+            }
         }
     }
 
-    pub fn create_from_type(map_type: MapType) -> Self {
+    pub fn create_from_type(map_type: MapType, seed: u64) -> Self {
         let dimensions = match map_type {
             MapType::Empty => [30, 20],
             MapType::Small => [30, 20],
@@ -47,50 +99,58 @@ impl WorldInitData {
             MapType::LoadTest => [100, 100],
             MapType::Spectator => [25, 15],
         };
+        let victory_condition = match map_type {
+            MapType::Medium => VictoryCondition::DestroyAllStructures,
+            _ => VictoryCondition::Annihilation,
+        };
 
-        let mut rng = rand::thread_rng();
+        let mut rng = StdRng::seed_from_u64(seed);
 
         let water_grid = Grid::new(dimensions);
-        for x in 0..dimensions[0] {
-            for y in 0..dimensions[1] {
-                let water_cell = x % 4 < 2 && y % 3 < 2;
-                if water_cell && rng.gen_bool(0.4) {
-                    //water_grid.set([x, y], true);
-                }
-            }
-        }
         let tile_grid = create_tile_grid(&water_grid);
 
         let mut entities = vec![];
+        let mut entity_ids = EntityIdAllocator::new();
 
-        let research_state = TeamResearchState::NotStarted;
+        let tech = TechState::default();
 
         if map_type != MapType::Spectator {
             entities.push(data::create_entity(
-                EntityType::Engineer,
+                EntityType::named("Engineer"),
+                entity_ids.next(),
                 [5, 1],
-                Team::Player,
-                research_state,
+                Team::Player(0),
+                &tech,
             ));
             entities.push(data::create_entity(
-                EntityType::Enforcer,
+                EntityType::named("Enforcer"),
+                entity_ids.next(),
                 [8, 3],
-                Team::Player,
-                research_state,
+                Team::Player(0),
+                &tech,
             ));
             entities.push(data::create_entity(
-                EntityType::TechLab,
+                EntityType::named("TechLab"),
+                entity_ids.next(),
                 [1, 6],
-                Team::Player,
-                research_state,
+                Team::Player(0),
+                &tech,
             ));
         }
 
         entities.push(data::create_entity(
-            EntityType::FuelRift,
+            EntityType::named("FuelRift"),
+            entity_ids.next(),
             [6, 4],
             Team::Neutral,
-            research_state,
+            &tech,
+        ));
+        entities.push(data::create_entity(
+            EntityType::named("MineralDeposit"),
+            entity_ids.next(),
+            [6, 7],
+            Team::Neutral,
+            &tech,
         ));
 
         match map_type {
@@ -98,34 +158,39 @@ impl WorldInitData {
             MapType::Small => {}
             MapType::Medium => {
                 entities.push(data::create_entity(
-                    EntityType::Engineer,
+                    EntityType::named("Engineer"),
+                    entity_ids.next(),
                     [5, 2],
-                    Team::Enemy1,
-                    research_state,
+                    Team::Player(1),
+                    &tech,
                 ));
                 entities.push(data::create_entity(
-                    EntityType::Engineer,
+                    EntityType::named("Engineer"),
+                    entity_ids.next(),
                     [3, 0],
-                    Team::Enemy1,
-                    research_state,
+                    Team::Player(1),
+                    &tech,
                 ));
                 entities.push(data::create_entity(
-                    EntityType::Engineer,
+                    EntityType::named("Engineer"),
+                    entity_ids.next(),
                     [0, 4],
-                    Team::Enemy1,
-                    research_state,
+                    Team::Player(1),
+                    &tech,
                 ));
                 entities.push(data::create_entity(
-                    EntityType::Engineer,
+                    EntityType::named("Engineer"),
+                    entity_ids.next(),
                     [3, 4],
-                    Team::Enemy1,
-                    research_state,
+                    Team::Player(1),
+                    &tech,
                 ));
                 entities.push(data::create_entity(
-                    EntityType::TechLab,
+                    EntityType::named("TechLab"),
+                    entity_ids.next(),
                     [8, 4],
-                    Team::Enemy1,
-                    research_state,
+                    Team::Player(1),
+                    &tech,
                 ));
             }
             MapType::LoadTest => {
@@ -134,20 +199,21 @@ impl WorldInitData {
                     for x in 5..dimensions[0] {
                         if rng.gen_bool(0.2) {
                             let team = if rng.gen_bool(0.5) {
-                                Team::Player
+                                Team::Player(0)
                             } else {
-                                Team::Enemy1
+                                Team::Player(1)
                             };
                             let entity_type = if rng.gen_bool(0.5) {
-                                EntityType::Engineer
+                                EntityType::named("Engineer")
                             } else {
-                                EntityType::Enforcer
+                                EntityType::named("Enforcer")
                             };
                             entities.push(data::create_entity(
                                 entity_type,
+                                entity_ids.next(),
                                 [x, y],
                                 team,
-                                research_state,
+                                &tech,
                             ));
                         }
                     }
@@ -155,16 +221,18 @@ impl WorldInitData {
             }
             MapType::Spectator => {
                 entities.push(data::create_entity(
-                    EntityType::Engineer,
+                    EntityType::named("Engineer"),
+                    entity_ids.next(),
                     [3, 8],
-                    Team::Enemy1,
-                    research_state,
+                    Team::Player(1),
+                    &tech,
                 ));
                 entities.push(data::create_entity(
-                    EntityType::Engineer,
+                    EntityType::named("Engineer"),
+                    entity_ids.next(),
                     [11, 4],
-                    Team::Enemy2,
-                    research_state,
+                    Team::Player(2),
+                    &tech,
                 ));
             }
         };
@@ -186,126 +254,67 @@ impl WorldInitData {
             true
         });
 
+        // Players start out at their tech labs
+        let mut start_locations = BTreeMap::new();
+        for entity in &entities {
+            if entity.entity_type == EntityType::named("TechLab") {
+                start_locations
+                    .entry(entity.team)
+                    .or_insert(entity.position);
+            }
+        }
+
         Self {
             dimensions,
             entities,
             water_grid,
             tile_grid,
+            victory_condition,
+            team_setup: TeamSetup::default(),
+            start_locations,
+            triggers: vec![],
         }
     }
 
-    fn load_from_file(ctx: &mut Context, path: impl AsRef<Path>) -> Self {
-        let mut file = ggez::filesystem::open(ctx, path).unwrap();
-        let mut map = String::new();
-        file.read_to_string(&mut map).unwrap();
-        Self::load_from_file_contents(map)
+    fn load_from_file(ctx: &mut Context, path: &Path) -> GameResult<Self> {
+        let mut file = ggez::filesystem::open(ctx, path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let map = MapFile::parse(&contents, path)?;
+        Ok(Self::from_map(&map))
     }
 
-    pub fn load_from_file_contents(map: String) -> Self {
-        let rows: Vec<&str> = map.lines().collect();
-        let w = (rows[0].len() - 2) as u32;
-        let h = (rows.len() - 2) as u32;
-        for line in &rows {
-            assert_eq!(line.len() - 2, w as usize);
-        }
-
-        let mut entities = Vec::new();
-        let mut water_grid = Grid::new([w, h]);
-        let research_state = TeamResearchState::NotStarted;
-
-        for x in 0..w {
-            for y in 0..h {
-                let ch = rows[(y + 1) as usize].as_bytes()[(x + 1) as usize] as char;
-                match ch {
-                    'W' => {
-                        water_grid.set([x as u32, y as u32], true);
-                    }
-                    '1' => {
-                        entities.push(create_entity(
-                            EntityType::TechLab,
-                            [x, y],
-                            Team::Player,
-                            research_state,
-                        ));
-                    }
-                    '2' => {
-                        entities.push(create_entity(
-                            EntityType::TechLab,
-                            [x, y],
-                            Team::Enemy1,
-                            research_state,
-                        ));
-                    }
-                    'R' => {
-                        entities.push(create_entity(
-                            EntityType::FuelRift,
-                            [x, y],
-                            Team::Neutral,
-                            research_state,
-                        ));
-                    }
-                    _ => {}
-                }
-            }
-        }
-
+    pub fn from_map(map: &MapFile) -> Self {
+        let water_grid = map.water_grid();
         let tile_grid = create_tile_grid(&water_grid);
-
         Self {
-            dimensions: [w as u32, h as u32],
-            entities,
+            dimensions: map.dimensions,
+            entities: map.create_entities(),
             water_grid,
             tile_grid,
+            victory_condition: map.victory_condition,
+            team_setup: map.team_setup(),
+            start_locations: map
+                .players
+                .iter()
+                .map(|player| (player.team, player.start))
+                .collect(),
+            triggers: map.triggers.clone(),
         }
     }
+}
 
-    pub fn save_to_file(water_grid: &Grid<bool>, entities: &[Entity], filepath: &str) {
-        println!("Saving map to {:?} ...", filepath);
-        let mut file = OpenOptions::new().write(true).open(filepath).unwrap();
-
-        let mut content = String::new();
-        let [w, h] = water_grid.dimensions();
-
-        for _ in 0..w + 2 {
-            content.push('X');
-        }
-        content.push('\n');
-
+pub fn water_cells(water_grid: &Grid<bool>) -> Vec<[u32; 2]> {
+    let [w, h] = water_grid.dimensions();
+    let mut water_cells = vec![];
+    for x in 0..w {
         for y in 0..h {
-            content.push('X');
-            for x in 0..w {
-                if water_grid.get(&[x, y]).unwrap() {
-                    content.push('W');
-                } else if let Some(entity) =
-                    entities.iter().find(|entity| entity.position == [x, y])
-                {
-                    match (entity.entity_type, entity.team) {
-                        (EntityType::TechLab, Team::Player) => {
-                            content.push('1');
-                        }
-                        (EntityType::TechLab, Team::Enemy1) => {
-                            content.push('2');
-                        }
-                        (EntityType::FuelRift, Team::Neutral) => {
-                            content.push('R');
-                        }
-                        unhandled => panic!("Unhandled entity: {:?}", unhandled),
-                    }
-                } else {
-                    content.push(' ');
-                }
+            if water_grid.get(&[x, y]).unwrap() {
+                water_cells.push([x, y]);
             }
-            content.push_str("X\n");
         }
-
-        for _ in 0..w + 2 {
-            content.push('X');
-        }
-        content.push('\n');
-
-        file.write_all(content.as_bytes()).unwrap();
-        println!("Saved map");
     }
+    water_cells
 }
 
 pub fn create_tile_grid(water_grid: &Grid<bool>) -> Grid<TileId> {
@@ -422,8 +431,9 @@ pub fn create_tile_grid(water_grid: &Grid<bool>) -> Grid<TileId> {
     tile_grid
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
 pub enum TileId {
+    #[default]
     InvalidTile,
     Ground,
     WaterCenter,
@@ -440,9 +450,3 @@ pub enum TileId {
     WaterConcaveSW,
     WaterConcaveNW,
 }
-
-impl Default for TileId {
-    fn default() -> Self {
-        TileId::InvalidTile
-    }
-}
@@ -3,6 +3,8 @@ use crate::entities::Entity;
 use crate::game::{CELL_PIXEL_SIZE, WORLD_VIEWPORT};
 use crate::grid::Grid;
 use crate::map::{self, WorldInitData};
+use crate::map_file::{MapFile, Terrain};
+use crate::map_generator::{self, GeneratorSettings};
 
 use ggez;
 use ggez::conf::{NumSamples, WindowMode, WindowSetup};
@@ -10,12 +12,14 @@ use ggez::event::{self, EventHandler, KeyCode, KeyMods};
 use ggez::graphics::{Color, FilterMode, Rect};
 use ggez::input::mouse::MouseButton;
 use ggez::{graphics, Context, ContextBuilder, GameError, GameResult};
-use std::io::Read;
+use std::path::{Path, PathBuf};
 
 const COLOR_FG: Color = Color::new(0.3, 0.3, 0.4, 1.0);
 const GAME_SIZE: [f32; 2] = [800.0, 450.0];
 
-pub fn run(filepath: String) -> GameResult {
+/// With `generator`, the editor starts out with a generated map instead of loading the file, and
+/// pressing G generates a new one from the next seed. Saving writes to the file either way.
+pub fn run(filepath: String, generator: Option<(GeneratorSettings, u64)>) -> GameResult {
     const GAME_SCALE: f32 = 3.0;
     let window_setup = WindowSetup::default()
         .title("EDITOR")
@@ -33,38 +37,57 @@ pub fn run(filepath: String) -> GameResult {
     graphics::set_screen_coordinates(&mut ctx, Rect::new(0.0, 0.0, GAME_SIZE[0], GAME_SIZE[1]))
         .unwrap();
 
-    let mut file = std::fs::File::open(&filepath).unwrap();
-    let mut map_file_contents = String::new();
-    file.read_to_string(&mut map_file_contents).unwrap();
-
+    let map = match &generator {
+        Some((settings, seed)) => {
+            println!("Generating map with seed {}", seed);
+            map_generator::generate(settings, *seed).map_err(GameError::ResourceLoadError)?
+        }
+        None => {
+            let contents = std::fs::read_to_string(&filepath)?;
+            MapFile::parse(&contents, Path::new(&filepath))?
+        }
+    };
     let WorldInitData {
-        dimensions: _dimensions,
         entities,
         water_grid,
         tile_grid,
-    } = WorldInitData::load_from_file_contents(map_file_contents);
-
-    let assets = Assets::new(&mut ctx, [WORLD_VIEWPORT.w, WORLD_VIEWPORT.h], &tile_grid)?;
-
+        team_setup,
+        ..
+    } = WorldInitData::from_map(&map);
+
+    let assets = Assets::new(
+        &mut ctx,
+        [WORLD_VIEWPORT.w, WORLD_VIEWPORT.h],
+        &tile_grid,
+        &team_setup,
+    )?;
+
+    // Legacy maps are saved in the new format, next to the original
+    let filepath = Path::new(&filepath).with_extension("ron");
     let editor = Editor {
         filepath,
         assets,
+        map,
         water_grid,
         entities,
         left_mouse_current_cell: None,
         right_mouse_current_cell: None,
+        generator,
     };
 
     ggez::event::run(ctx, event_loop, editor)
 }
 
 struct Editor {
-    filepath: String,
+    filepath: PathBuf,
     assets: Assets,
+    // Everything but the terrain is kept as it was loaded
+    map: MapFile,
     water_grid: Grid<bool>,
     entities: Vec<Entity>,
     left_mouse_current_cell: Option<[u32; 2]>,
     right_mouse_current_cell: Option<[u32; 2]>,
+    generator: Option<(GeneratorSettings, u64)>,
 }
 
 impl EventHandler for Editor {
@@ -145,6 +168,8 @@ impl EventHandler for Editor {
             event::quit(ctx);
         } else if keycode == KeyCode::S {
             self.save();
+        } else if keycode == KeyCode::G {
+            self.regenerate(ctx);
         }
     }
 }
@@ -171,8 +196,30 @@ impl Editor {
             .unwrap();
     }
 
-    fn save(&self) {
-        WorldInitData::save_to_file(&self.water_grid, &self.entities, &self.filepath);
+    fn regenerate(&mut self, ctx: &mut Context) {
+        if let Some((settings, seed)) = &mut self.generator {
+            *seed += 1;
+            println!("Generating map with seed {}", seed);
+            match map_generator::generate(settings, *seed) {
+                Ok(map) => {
+                    let init_data = WorldInitData::from_map(&map);
+                    self.map = map;
+                    self.water_grid = init_data.water_grid;
+                    self.entities = init_data.entities;
+                    self.update_background_tiles(ctx);
+                }
+                Err(e) => println!("ERROR: Failed to generate map: {}", e),
+            }
+        }
+    }
+
+    fn save(&mut self) {
+        println!("Saving map to {:?} ...", self.filepath);
+        self.map.set_terrain(Terrain::Water, &self.water_grid);
+        match self.map.save_to_file(&self.filepath) {
+            Ok(()) => println!("Saved map"),
+            Err(e) => println!("ERROR: Failed to save map: {}", e),
+        }
     }
 }
 
@@ -0,0 +1,668 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::{Relation, TeamSetup, TechState, VictoryCondition};
+use crate::data::{self, EntityType};
+use crate::definitions;
+use crate::entities::{CategoryConfig, Entity, EntityIdAllocator, Team, MAX_PLAYERS};
+use crate::grid::{CellRect, Grid};
+use crate::save;
+use crate::trigger::{Trigger, TriggerAction, TriggerCondition};
+
+/// Bumped whenever maps change shape. Maps in the legacy `.txt` format have no version, and are
+/// converted when they are loaded.
+const MAP_FORMAT_VERSION: u32 = 1;
+
+// Used in terrain layers, for cells that are covered by the layer and cells that aren't
+const COVERED_CELL: char = '#';
+const UNCOVERED_CELL: char = '.';
+
+/// A map as it's stored on disk
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MapFile {
+    pub version: u32,
+    pub name: String,
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub description: String,
+    pub dimensions: [u32; 2],
+    pub players: Vec<PlayerSlot>,
+    /// Players that aren't listed here are hostile to each other
+    #[serde(default)]
+    pub relations: Vec<(Team, Team, Relation)>,
+    #[serde(default)]
+    pub victory_condition: VictoryCondition,
+    /// How much supply each team can have at most, if not the default
+    #[serde(default)]
+    pub max_supply: Option<u32>,
+    #[serde(default)]
+    pub terrain: Vec<TerrainLayer>,
+    #[serde(default)]
+    pub entities: Vec<MapEntity>,
+    #[serde(default)]
+    pub triggers: Vec<Trigger>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerSlot {
+    pub team: Team,
+    /// Where the player's base is. The camera starts out here.
+    pub start: [u32; 2],
+    /// Falls back to the default color of the team
+    #[serde(default)]
+    pub color: Option<[u8; 3]>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MapEntity {
+    pub entity_type: EntityType,
+    pub team: Team,
+    pub position: [u32; 2],
+    /// How much a resource holds, if it's not the capacity from the entity definitions
+    #[serde(default)]
+    pub resources: Option<u32>,
+}
+
+impl MapEntity {
+    pub fn cell_rect(&self) -> CellRect {
+        let size = match definitions::registry()
+            .get(self.entity_type)
+            .config
+            .category
+        {
+            CategoryConfig::StructureSize(size) => size,
+            CategoryConfig::Unit | CategoryConfig::Resource(_) => [1, 1],
+        };
+        CellRect {
+            position: self.position,
+            size,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Terrain {
+    Water,
+}
+
+/// One row of characters for each row of cells, where '#' marks the cells that are covered
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TerrainLayer {
+    pub terrain: Terrain,
+    pub rows: Vec<String>,
+}
+
+impl MapFile {
+    pub fn new(name: String, dimensions: [u32; 2]) -> Self {
+        Self {
+            version: MAP_FORMAT_VERSION,
+            name,
+            author: String::new(),
+            description: String::new(),
+            dimensions,
+            players: vec![],
+            relations: vec![],
+            victory_condition: VictoryCondition::default(),
+            max_supply: None,
+            terrain: vec![],
+            entities: vec![],
+            triggers: vec![],
+        }
+    }
+
+    /// Maps ending with `.txt` are assumed to be in the legacy format
+    pub fn parse(contents: &str, path: &Path) -> io::Result<Self> {
+        let invalid_map = |e| save::invalid_data(format!("Invalid map {:?}: {}", path, e));
+        let map = if path.extension().is_some_and(|extension| extension == "txt") {
+            let name = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            Self::from_legacy(contents, name).map_err(invalid_map)?
+        } else {
+            save::parse_ron(contents, MAP_FORMAT_VERSION)?
+        };
+        map.validate().map_err(invalid_map)?;
+        Ok(map)
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> io::Result<()> {
+        let config = ron::ser::PrettyConfig::new();
+        let contents = ron::ser::to_string_pretty(self, config).map_err(save::invalid_data)?;
+        std::fs::write(path, contents)
+    }
+
+    pub fn water_grid(&self) -> Grid<bool> {
+        let mut grid = Grid::new(self.dimensions);
+        for layer in &self.terrain {
+            if layer.terrain == Terrain::Water {
+                for (y, row) in layer.rows.iter().enumerate() {
+                    for (x, ch) in row.chars().enumerate() {
+                        if ch == COVERED_CELL {
+                            grid.set([x as u32, y as u32], true);
+                        }
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    pub fn set_terrain(&mut self, terrain: Terrain, grid: &Grid<bool>) {
+        let [w, h] = grid.dimensions();
+        let rows = (0..h)
+            .map(|y| {
+                (0..w)
+                    .map(|x| match grid.get(&[x, y]).unwrap() {
+                        true => COVERED_CELL,
+                        false => UNCOVERED_CELL,
+                    })
+                    .collect()
+            })
+            .collect();
+        self.terrain.retain(|layer| layer.terrain != terrain);
+        self.terrain.push(TerrainLayer { terrain, rows });
+    }
+
+    pub fn team_setup(&self) -> TeamSetup {
+        let mut team_setup = TeamSetup::default();
+        for player in &self.players {
+            if let Some(color) = player.color {
+                team_setup.set_color(player.team, color);
+            }
+        }
+        for (team, other_team, relation) in &self.relations {
+            team_setup.set_relation(*team, *other_team, *relation);
+        }
+        if let Some(max_supply) = self.max_supply {
+            team_setup.set_max_supply(max_supply);
+        }
+        team_setup
+    }
+
+    pub fn create_entities(&self) -> Vec<Entity> {
+        let mut entity_ids = EntityIdAllocator::new();
+        let tech = TechState::default();
+        self.entities
+            .iter()
+            .map(|map_entity| {
+                let mut entity = data::create_entity(
+                    map_entity.entity_type,
+                    entity_ids.next(),
+                    map_entity.position,
+                    map_entity.team,
+                    &tech,
+                );
+                if let Some(resources) = map_entity.resources {
+                    *entity.resource_remaining_mut() = resources;
+                }
+                entity
+            })
+            .collect()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let [w, h] = self.dimensions;
+        if w == 0 || h == 0 {
+            return Err(format!("Invalid dimensions {:?}", self.dimensions));
+        }
+        let is_within_map = |[x, y]: [u32; 2]| x < w && y < h;
+        let is_area_within_map = |area: CellRect| {
+            let [x, y] = area.position;
+            match (x.checked_add(area.size[0]), y.checked_add(area.size[1])) {
+                (Some(right), Some(bottom)) => right <= w && bottom <= h,
+                _ => false,
+            }
+        };
+
+        let mut players = HashSet::new();
+        for player in &self.players {
+            if !matches!(player.team, Team::Player(i) if i < MAX_PLAYERS) {
+                return Err(format!("{} can't be played", player.team));
+            }
+            if !players.insert(player.team) {
+                return Err(format!("{} is listed more than once", player.team));
+            }
+            if !is_within_map(player.start) {
+                return Err(format!("{} starts outside the map", player.team));
+            }
+        }
+        let is_known_team = |team: &Team| *team == Team::Neutral || players.contains(team);
+
+        for (team, other_team, _relation) in &self.relations {
+            if !players.contains(team) || !players.contains(other_team) {
+                return Err(format!(
+                    "Relation between {} and {} refers to a missing player",
+                    team, other_team
+                ));
+            }
+        }
+
+        for layer in &self.terrain {
+            let has_valid_size = layer.rows.len() == h as usize
+                && layer
+                    .rows
+                    .iter()
+                    .all(|row| row.chars().count() == w as usize);
+            if !has_valid_size {
+                return Err(format!(
+                    "{:?} layer should be {}x{} cells",
+                    layer.terrain, w, h
+                ));
+            }
+            let is_valid_cell = |ch| ch == COVERED_CELL || ch == UNCOVERED_CELL;
+            if !layer.rows.iter().all(|row| row.chars().all(is_valid_cell)) {
+                return Err(format!(
+                    "{:?} layer may only contain '{}' and '{}'",
+                    layer.terrain, COVERED_CELL, UNCOVERED_CELL
+                ));
+            }
+        }
+
+        let registry = definitions::registry();
+        let water = self.water_grid();
+        // The index of the entity that occupies each cell
+        let mut occupied: Grid<Option<usize>> = Grid::new(self.dimensions);
+        for (i, entity) in self.entities.iter().enumerate() {
+            let definition = registry.get(entity.entity_type);
+            let rect = entity.cell_rect();
+            if !is_area_within_map(rect) {
+                return Err(format!(
+                    "{} at {:?} is outside the map",
+                    definition.name, entity.position
+                ));
+            }
+            for x in rect.position[0]..rect.position[0] + rect.size[0] {
+                for y in rect.position[1]..rect.position[1] + rect.size[1] {
+                    if water.get(&[x, y]) == Some(true) {
+                        return Err(format!(
+                            "{} at {:?} is on water",
+                            definition.name, entity.position
+                        ));
+                    }
+                    if let Some(Some(other)) = occupied.get(&[x, y]) {
+                        let other = &self.entities[other];
+                        return Err(format!(
+                            "{} at {:?} overlaps {} at {:?}",
+                            registry.get(other.entity_type).name,
+                            other.position,
+                            definition.name,
+                            entity.position
+                        ));
+                    }
+                    occupied.set([x, y], Some(i));
+                }
+            }
+            if !is_known_team(&entity.team) {
+                return Err(format!(
+                    "{} at {:?} belongs to {}, which isn't one of the players",
+                    definition.name, entity.position, entity.team
+                ));
+            }
+            let is_resource = matches!(definition.config.category, CategoryConfig::Resource(_));
+            if entity.resources.is_some() && !is_resource {
+                return Err(format!(
+                    "{} at {:?} can't hold resources",
+                    definition.name, entity.position
+                ));
+            }
+        }
+
+        for trigger in &self.triggers {
+            if let Some(team) = trigger.teams().into_iter().find(|t| !is_known_team(t)) {
+                return Err(format!(
+                    "Trigger refers to {}, which isn't in the match",
+                    team
+                ));
+            }
+            if let TriggerCondition::UnitInArea { position, size, .. } = trigger.condition {
+                if !is_area_within_map(CellRect { position, size }) {
+                    return Err(format!("Trigger area at {:?} is outside the map", position));
+                }
+            }
+            for action in &trigger.actions {
+                if let TriggerAction::SpawnUnit {
+                    unit_type,
+                    position,
+                    ..
+                } = action
+                {
+                    let definition = registry.get(*unit_type);
+                    let name = &definition.name;
+                    if !matches!(definition.config.category, CategoryConfig::Unit) {
+                        return Err(format!("Trigger can't spawn {}, which isn't a unit", name));
+                    }
+                    if !is_within_map(*position) {
+                        return Err(format!(
+                            "Trigger spawns {} at {:?}, which is outside the map",
+                            name, position
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The legacy format is a grid of characters surrounded by a border, where 'W' is water,
+    // '1'-'8' is a player's tech lab, 'R' is a fuel rift and 'M' is a mineral deposit. It may be
+    // followed by lines like "victory: minerals 50", "relation: 1 2 allied" and
+    // "color: 3 200 120 40".
+    fn from_legacy(contents: &str, name: String) -> Result<Self, String> {
+        let mut rows: Vec<&str> = vec![];
+        let mut map_lines = vec![];
+        for line in contents.lines() {
+            if line.starts_with(VICTORY_CONDITION_PREFIX)
+                || line.starts_with(RELATION_PREFIX)
+                || line.starts_with(COLOR_PREFIX)
+            {
+                map_lines.push(line);
+            } else {
+                rows.push(line);
+            }
+        }
+        let width = rows.first().map_or(0, |row| row.len());
+        if rows.len() < 2 || width < 2 {
+            return Err("the grid must have a border around it".to_owned());
+        }
+        if let Some(y) = rows.iter().position(|row| row.len() != width) {
+            return Err(format!(
+                "row {} is {} characters wide, but the first row is {}",
+                y + 1,
+                rows[y].len(),
+                width
+            ));
+        }
+        let w = (width - 2) as u32;
+        let h = (rows.len() - 2) as u32;
+
+        let mut map = Self::new(name, [w, h]);
+        map.description = "Converted from the legacy map format".to_owned();
+        let mut water_grid = Grid::new([w, h]);
+        for y in 0..h {
+            for x in 0..w {
+                let ch = rows[(y + 1) as usize].as_bytes()[(x + 1) as usize] as char;
+                let (entity_type, team) = match ch {
+                    'W' => {
+                        water_grid.set([x, y], true);
+                        continue;
+                    }
+                    'R' => ("FuelRift", Team::Neutral),
+                    'M' => ("MineralDeposit", Team::Neutral),
+                    _ => match player_from_digit(ch) {
+                        Some(team) => ("TechLab", team),
+                        None => continue,
+                    },
+                };
+                map.entities.push(MapEntity {
+                    entity_type: EntityType::named(entity_type),
+                    team,
+                    position: [x, y],
+                    resources: None,
+                });
+                if team != Team::Neutral && !map.players.iter().any(|p| p.team == team) {
+                    map.players.push(PlayerSlot {
+                        team,
+                        start: [x, y],
+                        color: None,
+                    });
+                }
+            }
+        }
+        map.players.sort_by_key(|player| player.team);
+        map.set_terrain(Terrain::Water, &water_grid);
+
+        for line in map_lines {
+            if let Some(condition) = line.strip_prefix(VICTORY_CONDITION_PREFIX) {
+                map.victory_condition = condition
+                    .parse()
+                    .map_err(|_| format!("invalid victory condition: {:?}", condition))?;
+            } else if let Some(relation) = line.strip_prefix(RELATION_PREFIX) {
+                let relation = parse_relation(relation)
+                    .ok_or_else(|| format!("invalid relation: {:?}", relation))?;
+                map.relations.push(relation);
+            } else if let Some(color) = line.strip_prefix(COLOR_PREFIX) {
+                let (team, color) =
+                    parse_color(color).ok_or_else(|| format!("invalid color: {:?}", color))?;
+                if let Some(player) = map.players.iter_mut().find(|p| p.team == team) {
+                    player.color = Some(color);
+                }
+            }
+        }
+        Ok(map)
+    }
+}
+
+const VICTORY_CONDITION_PREFIX: &str = "victory:";
+const RELATION_PREFIX: &str = "relation:";
+const COLOR_PREFIX: &str = "color:";
+
+// Players are numbered from 1 in the legacy format
+fn player_from_digit(ch: char) -> Option<Team> {
+    let number = ch.to_digit(10)? as u8;
+    if (1..=MAX_PLAYERS).contains(&number) {
+        Some(Team::Player(number - 1))
+    } else {
+        None
+    }
+}
+
+fn parse_player(s: &str) -> Option<Team> {
+    match s.as_bytes() {
+        [digit] => player_from_digit(*digit as char),
+        _ => None,
+    }
+}
+
+fn parse_relation(s: &str) -> Option<(Team, Team, Relation)> {
+    match s.split_whitespace().collect::<Vec<&str>>()[..] {
+        [team, other_team, relation] => Some((
+            parse_player(team)?,
+            parse_player(other_team)?,
+            relation.parse().ok()?,
+        )),
+        _ => None,
+    }
+}
+
+fn parse_color(s: &str) -> Option<(Team, [u8; 3])> {
+    match s.split_whitespace().collect::<Vec<&str>>()[..] {
+        [team, r, g, b] => Some((
+            parse_player(team)?,
+            [r.parse().ok()?, g.parse().ok()?, b.parse().ok()?],
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LEGACY_MAP: &str = include_str!("../resources/maps/small.txt");
+
+    #[test]
+    fn map_files_are_valid() {
+        let contents = include_str!("../resources/maps/small.ron");
+        let map = MapFile::parse(contents, Path::new("small.ron")).unwrap();
+        assert_eq!(map.players.len(), 2);
+        assert_eq!(map.triggers.len(), 2);
+        assert_eq!(map.team_setup().max_supply(), data::DEFAULT_MAX_SUPPLY);
+
+        let contents = contents.replace("max_supply: None", "max_supply: Some(20)");
+        let map = MapFile::parse(&contents, Path::new("small.ron")).unwrap();
+        assert_eq!(map.team_setup().max_supply(), 20);
+    }
+
+    #[test]
+    fn legacy_maps_are_converted() {
+        let map = MapFile::parse(LEGACY_MAP, Path::new("small.txt")).unwrap();
+
+        assert_eq!(map.name, "small");
+        assert_eq!(map.dimensions, [36, 14]);
+        assert_eq!(map.players.len(), 1);
+        assert_eq!(map.players[0].team, Team::Player(0));
+        assert_eq!(map.players[0].start, [0, 0]);
+        let num_water_cells = LEGACY_MAP.chars().filter(|ch| *ch == 'W').count();
+        let water_grid = map.water_grid();
+        let num_converted_water_cells = (0..36)
+            .flat_map(|x| (0..14).map(move |y| [x, y]))
+            .filter(|cell| water_grid.get(cell).unwrap())
+            .count();
+        assert_eq!(num_converted_water_cells, num_water_cells);
+
+        // Converted maps can be saved in the new format, without losing anything
+        let saved = ron::to_string(&map).unwrap();
+        assert_eq!(MapFile::parse(&saved, Path::new("small.ron")).unwrap(), map);
+    }
+
+    #[test]
+    fn invalid_maps_are_rejected_with_clear_errors() {
+        let mut map = MapFile::new("test".to_owned(), [10, 10]);
+        map.players.push(PlayerSlot {
+            team: Team::Player(0),
+            start: [1, 1],
+            color: None,
+        });
+        map.entities.push(MapEntity {
+            entity_type: EntityType::named("FuelRift"),
+            team: Team::Neutral,
+            position: [5, 5],
+            resources: Some(100),
+        });
+        assert_eq!(map.validate(), Ok(()));
+        assert_eq!(*map.create_entities()[0].resource_remaining(), 100);
+
+        let mut invalid = map.clone();
+        invalid.entities[0].entity_type = EntityType::named("Enforcer");
+        assert_eq!(
+            invalid.validate(),
+            Err("Enforcer at [5, 5] can't hold resources".to_owned())
+        );
+
+        let mut invalid = map.clone();
+        invalid.entities[0].team = Team::Player(1);
+        assert_eq!(
+            invalid.validate(),
+            Err(
+                "FuelRift at [5, 5] belongs to Player 2, which isn't one of the players".to_owned()
+            )
+        );
+
+        let mut invalid = map.clone();
+        invalid.entities[0].entity_type = EntityType::named("TechLab");
+        invalid.entities[0].resources = None;
+        invalid.entities[0].position = [8, 8];
+        assert_eq!(
+            invalid.validate(),
+            Err("TechLab at [8, 8] is outside the map".to_owned())
+        );
+
+        let mut invalid = map.clone();
+        invalid.entities[0].position = [u32::MAX, 5];
+        assert_eq!(
+            invalid.validate(),
+            Err("FuelRift at [4294967295, 5] is outside the map".to_owned())
+        );
+
+        let mut invalid = map.clone();
+        invalid.entities.push(MapEntity {
+            entity_type: EntityType::named("TechLab"),
+            team: Team::Player(0),
+            position: [4, 4],
+            resources: None,
+        });
+        assert_eq!(
+            invalid.validate(),
+            Err("FuelRift at [5, 5] overlaps TechLab at [4, 4]".to_owned())
+        );
+
+        let mut invalid = map.clone();
+        let mut water = Grid::new([10, 10]);
+        water.set([5, 5], true);
+        invalid.set_terrain(Terrain::Water, &water);
+        assert_eq!(
+            invalid.validate(),
+            Err("FuelRift at [5, 5] is on water".to_owned())
+        );
+
+        let mut invalid = map.clone();
+        invalid.triggers.push(Trigger {
+            condition: TriggerCondition::Time(1.0),
+            actions: vec![TriggerAction::SpawnUnit {
+                unit_type: EntityType::named("TechLab"),
+                team: Team::Player(0),
+                position: [2, 2],
+            }],
+        });
+        assert_eq!(
+            invalid.validate(),
+            Err("Trigger can't spawn TechLab, which isn't a unit".to_owned())
+        );
+
+        let mut invalid = map.clone();
+        invalid.triggers.push(Trigger {
+            condition: TriggerCondition::Time(1.0),
+            actions: vec![TriggerAction::SpawnUnit {
+                unit_type: EntityType::named("Enforcer"),
+                team: Team::Player(0),
+                position: [35, 13],
+            }],
+        });
+        assert_eq!(
+            invalid.validate(),
+            Err("Trigger spawns Enforcer at [35, 13], which is outside the map".to_owned())
+        );
+
+        let mut invalid = map.clone();
+        invalid.triggers.push(Trigger {
+            condition: TriggerCondition::UnitInArea {
+                team: Team::Player(0),
+                position: [8, 8],
+                size: [3, 3],
+            },
+            actions: vec![],
+        });
+        assert_eq!(
+            invalid.validate(),
+            Err("Trigger area at [8, 8] is outside the map".to_owned())
+        );
+
+        let mut outdated = ron::to_string(&map).unwrap();
+        outdated = outdated.replace("version:1", "version:0");
+        let error = MapFile::parse(&outdated, Path::new("test.ron")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unsupported format version 0 (expected 1)"
+        );
+
+        let legacy_error = |contents| {
+            let error = MapFile::parse(contents, Path::new("test.txt")).unwrap_err();
+            error.to_string()
+        };
+        assert_eq!(
+            legacy_error(""),
+            r#"Invalid map "test.txt": the grid must have a border around it"#
+        );
+        assert_eq!(
+            legacy_error("XXXX\nX1 X\nXX\nXXXX"),
+            r#"Invalid map "test.txt": row 3 is 2 characters wide, but the first row is 4"#
+        );
+        assert_eq!(
+            legacy_error("XXX\nX1X\nXXX\nrelation: 1 9 allied"),
+            r#"Invalid map "test.txt": invalid relation: " 1 9 allied""#
+        );
+        assert_eq!(
+            legacy_error("XXXXXX\nX1R  X\nX    X\nX    X\nXXXXXX"),
+            r#"Invalid map "test.txt": TechLab at [0, 0] overlaps FuelRift at [1, 0]"#
+        );
+        assert_eq!(
+            legacy_error("XXXXXX\nX1   X\nXW   X\nX    X\nXXXXXX"),
+            r#"Invalid map "test.txt": TechLab at [0, 0] is on water"#
+        );
+    }
+}
@@ -0,0 +1,499 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::core::{ObstacleType, VictoryCondition};
+use crate::data::EntityType;
+use crate::entities::{Team, MAX_PLAYERS};
+use crate::grid::{Grid, ObstacleGrid};
+use crate::map_file::{MapEntity, MapFile, PlayerSlot, Terrain};
+use crate::pathfind::{self, Destination};
+
+// A layout that turns out to be unplayable is thrown away, and another one is tried
+const MAX_ATTEMPTS: u32 = 100;
+
+// How far from the edges of the map the bases are
+const BASE_MARGIN: f32 = 4.0;
+// No water is placed this close to a base, so that there's room to expand
+const BASE_CLEARANCE: f32 = 5.0;
+
+// Where things are placed, relative to the center of a player's tech lab. These are given for
+// the first player, and are turned around along with the base for the others. They are far
+// enough out that they don't end up on the tech lab when turned diagonally.
+const ENGINEER_OFFSETS: [[f32; 2]; 2] = [[3.0, -1.0], [3.0, 1.0]];
+const FUEL_RIFT_OFFSETS: [[f32; 2]; 2] = [[0.0, -3.5], [0.0, 3.5]];
+const MINERAL_DEPOSIT_OFFSET: [f32; 2] = [3.5, -3.0];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Symmetry {
+    /// Mirrored left to right, and also top to bottom if there are 4 players
+    Mirror,
+    /// Turned around the center of the map, once for each player
+    Rotational,
+}
+
+impl FromStr for Symmetry {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mirror" => Ok(Symmetry::Mirror),
+            "rotational" => Ok(Symmetry::Rotational),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratorSettings {
+    pub dimensions: [u32; 2],
+    pub num_players: u8,
+    pub symmetry: Symmetry,
+}
+
+impl GeneratorSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        let [w, h] = self.dimensions;
+        if w < 20 || h < 20 {
+            return Err("Maps need to be at least 20x20 cells".to_owned());
+        }
+        if !(2..=MAX_PLAYERS).contains(&self.num_players) {
+            return Err(format!("There can be 2 to {} players", MAX_PLAYERS));
+        }
+        if self.symmetry == Symmetry::Mirror && ![2, 4].contains(&self.num_players) {
+            return Err("Mirrored maps need 2 or 4 players. Try rotational symmetry.".to_owned());
+        }
+        Ok(())
+    }
+}
+
+impl Default for GeneratorSettings {
+    fn default() -> Self {
+        Self {
+            dimensions: [40, 30],
+            num_players: 2,
+            symmetry: Symmetry::Mirror,
+        }
+    }
+}
+
+/// Create a map where every player starts out the same way. Lakes and rivers are laid out at
+/// random, but with gaps so that every base can be reached from every other one.
+pub fn generate(settings: &GeneratorSettings, seed: u64) -> Result<MapFile, String> {
+    settings.validate()?;
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let layout = Layout::new(settings);
+    for attempt in 1..=MAX_ATTEMPTS {
+        let mut map = MapFile::new(
+            format!(
+                "Generated ({} players, seed {})",
+                settings.num_players, seed
+            ),
+            settings.dimensions,
+        );
+        map.author = "Map generator".to_owned();
+        map.description = format!("{:?} symmetry", settings.symmetry);
+        map.victory_condition = VictoryCondition::DestroyAllStructures;
+
+        let mut water_grid = layout.create_water(&mut rng);
+        map.entities = layout.create_entities(&mut rng);
+        for entity in &map.entities {
+            water_grid.set_area(entity.cell_rect(), false);
+        }
+        map.players = layout
+            .bases()
+            .into_iter()
+            .enumerate()
+            .map(|(i, position)| PlayerSlot {
+                team: Team::Player(i as u8),
+                start: position,
+                color: None,
+            })
+            .collect();
+        map.set_terrain(Terrain::Water, &water_grid);
+
+        if is_playable(&map, &water_grid) {
+            println!("Generated a map after {} attempt(s)", attempt);
+            return Ok(map);
+        }
+    }
+    Err(format!(
+        "Couldn't generate a playable map in {} attempts",
+        MAX_ATTEMPTS
+    ))
+}
+
+struct Layout {
+    dimensions: [u32; 2],
+    num_players: u8,
+    symmetry: Symmetry,
+}
+
+impl Layout {
+    fn new(settings: &GeneratorSettings) -> Self {
+        Self {
+            dimensions: settings.dimensions,
+            num_players: settings.num_players,
+            symmetry: settings.symmetry,
+        }
+    }
+
+    // The point, along with the corresponding points for the other players. The first one is
+    // for the first player, and so on.
+    fn images(&self, [x, y]: [f32; 2]) -> Vec<[f32; 2]> {
+        let [w, h] = self.dimensions;
+        let right = (w - 1) as f32;
+        let bottom = (h - 1) as f32;
+        match self.symmetry {
+            Symmetry::Mirror if self.num_players == 2 => vec![[x, y], [right - x, y]],
+            Symmetry::Mirror => vec![
+                [x, y],
+                [right - x, y],
+                [right - x, bottom - y],
+                [x, bottom - y],
+            ],
+            Symmetry::Rotational => {
+                // Turning around a circle keeps distances the same for everyone. On maps that
+                // aren't square, random things are only placed within the circle that fits in
+                // the map (see `random_point()`), so that they can be turned without ending up
+                // outside of it.
+                let center = [right / 2.0, bottom / 2.0];
+                let relative = [x - center[0], y - center[1]];
+                (0..self.num_players)
+                    .map(|i| {
+                        let angle = 2.0 * PI * i as f32 / self.num_players as f32;
+                        let (sin, cos) = angle.sin_cos();
+                        [
+                            center[0] + relative[0] * cos - relative[1] * sin,
+                            center[1] + relative[0] * sin + relative[1] * cos,
+                        ]
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    // The offset as seen from each player's base. Rotations are made in quarter turns, so that
+    // what is next to a base ends up the exact same number of cells away for every player.
+    fn turned(&self, [dx, dy]: [f32; 2]) -> Vec<[f32; 2]> {
+        match self.symmetry {
+            Symmetry::Mirror if self.num_players == 2 => vec![[dx, dy], [-dx, dy]],
+            Symmetry::Mirror => vec![[dx, dy], [-dx, dy], [-dx, -dy], [dx, -dy]],
+            Symmetry::Rotational => (0..self.num_players)
+                .map(|i| {
+                    let quarter_turns = (4.0 * i as f32 / self.num_players as f32).round() as u32;
+                    (0..quarter_turns).fold([dx, dy], |[dx, dy], _| [-dy, dx])
+                })
+                .collect(),
+        }
+    }
+
+    // Somewhere that can be turned around for every player without ending up outside the map
+    fn random_point(&self, rng: &mut StdRng) -> [f32; 2] {
+        let [w, h] = self.dimensions;
+        match self.symmetry {
+            Symmetry::Mirror => [rng.gen_range(0.0..w as f32), rng.gen_range(0.0..h as f32)],
+            Symmetry::Rotational => {
+                let center = [(w - 1) as f32 / 2.0, (h - 1) as f32 / 2.0];
+                let max_radius = center[0].min(center[1]);
+                // Uniformly spread over the area of the circle
+                let radius = max_radius * rng.gen_range(0.0f32..1.0).sqrt();
+                let angle = rng.gen_range(0.0..2.0 * PI);
+                [
+                    center[0] + radius * angle.cos(),
+                    center[1] + radius * angle.sin(),
+                ]
+            }
+        }
+    }
+
+    // Whether the images of the point are all within the map. Mirrored images are cut off by
+    // the edges in the same way for everyone, so they don't need to be.
+    fn is_turnable(&self, point: [f32; 2]) -> bool {
+        let [w, h] = self.dimensions;
+        match self.symmetry {
+            Symmetry::Mirror => true,
+            Symmetry::Rotational => self.images(point).into_iter().all(|[x, y]| {
+                (0.0..=(w - 1) as f32).contains(&x) && (0.0..=(h - 1) as f32).contains(&y)
+            }),
+        }
+    }
+
+    // Where the tech lab of the first player is centered
+    fn first_base(&self) -> [f32; 2] {
+        let [w, h] = self.dimensions;
+        let [right, bottom] = [(w - 1) as f32, (h - 1) as f32];
+        match self.symmetry {
+            Symmetry::Mirror if self.num_players == 2 => [BASE_MARGIN, bottom / 2.0],
+            Symmetry::Mirror => [BASE_MARGIN, BASE_MARGIN],
+            Symmetry::Rotational => {
+                // Left of the center, as far out as the shorter side of the map allows
+                let center = [right / 2.0, bottom / 2.0];
+                let radius = center[0].min(center[1]) - BASE_MARGIN;
+                [center[0] - radius, center[1]]
+            }
+        }
+    }
+
+    // The top left cell of each player's tech lab
+    fn bases(&self) -> Vec<[u32; 2]> {
+        self.cells(self.first_base())
+            .into_iter()
+            .map(|[x, y]| [x.saturating_sub(1), y.saturating_sub(1)])
+            .collect()
+    }
+
+    // The cell at the offset from the center of each player's tech lab
+    fn near_bases(&self, offset: [f32; 2]) -> Vec<[u32; 2]> {
+        let [w, h] = self.dimensions;
+        self.cells(self.first_base())
+            .into_iter()
+            .zip(self.turned(offset))
+            .map(|([x, y], [dx, dy])| {
+                [
+                    (x as f32 + dx.round()).clamp(0.0, (w - 1) as f32) as u32,
+                    (y as f32 + dy.round()).clamp(0.0, (h - 1) as f32) as u32,
+                ]
+            })
+            .collect()
+    }
+
+    fn cells(&self, point: [f32; 2]) -> Vec<[u32; 2]> {
+        let [w, h] = self.dimensions;
+        self.images(point)
+            .into_iter()
+            .map(|[x, y]| {
+                [
+                    (x.round().max(0.0) as u32).min(w - 1),
+                    (y.round().max(0.0) as u32).min(h - 1),
+                ]
+            })
+            .collect()
+    }
+
+    fn create_water(&self, rng: &mut StdRng) -> Grid<bool> {
+        let [w, h] = self.dimensions;
+        let mut water_grid = Grid::new(self.dimensions);
+
+        // Lakes are made up of a few overlapping circles
+        let num_lakes = rng.gen_range(2..=(w * h / (100 * self.num_players as u32)).max(2));
+        for _ in 0..num_lakes {
+            let [x, y] = self.random_point(rng);
+            for _ in 0..rng.gen_range(1..=3) {
+                let offset = [rng.gen_range(-2.0..2.0), rng.gen_range(-2.0..2.0)];
+                let radius = rng.gen_range(1.5..3.5);
+                for center in self.images([x + offset[0], y + offset[1]]) {
+                    paint_circle(&mut water_grid, center, radius, true);
+                }
+            }
+        }
+
+        // Rivers wind out from the middle of the map, with a gap to cross every few cells
+        let num_rivers = rng.gen_range(1..=2);
+        for _ in 0..num_rivers {
+            let [mut x, mut y] = [
+                rng.gen_range(w as f32 * 0.25..w as f32 * 0.75),
+                rng.gen_range(h as f32 * 0.25..h as f32 * 0.75),
+            ];
+            let mut direction: f32 = rng.gen_range(0.0..2.0 * PI);
+            let gap_interval = rng.gen_range(5..8);
+            for step in 0..rng.gen_range(12..30) {
+                if !self.is_turnable([x, y]) {
+                    break;
+                }
+                if step % gap_interval >= 3 {
+                    for center in self.images([x, y]) {
+                        paint_circle(&mut water_grid, center, 1.0, true);
+                    }
+                }
+                direction += rng.gen_range(-0.4..0.4);
+                x += direction.cos();
+                y += direction.sin();
+            }
+        }
+
+        for base in self.images(self.first_base()) {
+            paint_circle(&mut water_grid, base, BASE_CLEARANCE, false);
+        }
+        water_grid
+    }
+
+    fn create_entities(&self, rng: &mut StdRng) -> Vec<MapEntity> {
+        let mut entities = vec![];
+        let mut add = |entity_type: &str, cells: Vec<[u32; 2]>, is_neutral: bool| {
+            for (i, position) in cells.into_iter().enumerate() {
+                let team = if is_neutral {
+                    Team::Neutral
+                } else {
+                    Team::Player(i as u8)
+                };
+                entities.push(MapEntity {
+                    entity_type: EntityType::named(entity_type),
+                    team,
+                    position,
+                    resources: None,
+                });
+            }
+        };
+
+        add("TechLab", self.bases(), false);
+        for offset in ENGINEER_OFFSETS {
+            add("Engineer", self.near_bases(offset), false);
+        }
+        for offset in FUEL_RIFT_OFFSETS {
+            add("FuelRift", self.near_bases(offset), true);
+        }
+        add(
+            "MineralDeposit",
+            self.near_bases(MINERAL_DEPOSIT_OFFSET),
+            true,
+        );
+
+        // Further out, there's one more rift for each player to fight over
+        add("FuelRift", self.cells(self.random_point(rng)), true);
+
+        entities
+    }
+}
+
+fn paint_circle(grid: &mut Grid<bool>, [cx, cy]: [f32; 2], radius: f32, value: bool) {
+    let [w, h] = grid.dimensions();
+    let left = (cx - radius).floor().max(0.0) as u32;
+    let top = (cy - radius).floor().max(0.0) as u32;
+    let right = ((cx + radius).ceil().max(0.0) as u32).min(w - 1);
+    let bottom = ((cy + radius).ceil().max(0.0) as u32).min(h - 1);
+    for x in left..=right {
+        for y in top..=bottom {
+            let [dx, dy] = [x as f32 - cx, y as f32 - cy];
+            if dx * dx + dy * dy <= radius * radius {
+                grid.set([x, y], value);
+            }
+        }
+    }
+}
+
+// Nothing may overlap, and every base must be reachable from every other base
+fn is_playable(map: &MapFile, water_grid: &Grid<bool>) -> bool {
+    let [w, h] = map.dimensions;
+    let mut grid = ObstacleGrid::new(map.dimensions);
+    for x in 0..w {
+        for y in 0..h {
+            if water_grid.get(&[x, y]).unwrap() {
+                grid.set([x, y], ObstacleType::Water);
+            }
+        }
+    }
+    for entity in &map.entities {
+        let rect = entity.cell_rect();
+        for x in rect.position[0]..rect.position[0] + rect.size[0] {
+            for y in rect.position[1]..rect.position[1] + rect.size[1] {
+                if grid.get(&[x, y]) != Some(ObstacleType::None) {
+                    return false;
+                }
+            }
+        }
+        grid.set_area(rect, ObstacleType::Entity(entity.team));
+    }
+
+    let tech_labs: Vec<&MapEntity> = map
+        .entities
+        .iter()
+        .filter(|entity| entity.entity_type == EntityType::named("TechLab"))
+        .collect();
+    for (i, tech_lab) in tech_labs.iter().enumerate() {
+        let engineer = map
+            .entities
+            .iter()
+            .find(|entity| {
+                entity.team == tech_lab.team && entity.entity_type == EntityType::named("Engineer")
+            })
+            .unwrap();
+        for other in &tech_labs[i + 1..] {
+            let destination = Destination::AdjacentToEntity(other.cell_rect());
+            if !pathfind::is_reachable(engineer.position, destination, &grid) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn generated_maps_are_fair_and_connected() {
+        let setups = [
+            (2, Symmetry::Mirror),
+            (4, Symmetry::Mirror),
+            (3, Symmetry::Rotational),
+            (8, Symmetry::Rotational),
+        ];
+        for (num_players, symmetry) in setups {
+            let settings = GeneratorSettings {
+                dimensions: [40, 30],
+                num_players,
+                symmetry,
+            };
+            for seed in 0..5 {
+                let map = generate(&settings, seed).unwrap();
+                assert_eq!(map.players.len(), num_players as usize);
+                for player in &map.players {
+                    let num_entities = map.entities.iter().filter(|e| e.team == player.team);
+                    assert_eq!(num_entities.count(), 3, "{:?}", settings);
+                }
+                let rifts: Vec<&MapEntity> = map
+                    .entities
+                    .iter()
+                    .filter(|entity| entity.entity_type == EntityType::named("FuelRift"))
+                    .collect();
+                assert_eq!(rifts.len(), 3 * num_players as usize);
+
+                // The map isn't square, but every player has their rifts just as close. The ones
+                // next to the bases come first, one for each player at a time.
+                let tech_labs: Vec<&MapEntity> = map
+                    .entities
+                    .iter()
+                    .filter(|entity| entity.entity_type == EntityType::named("TechLab"))
+                    .collect();
+                let distances: Vec<Vec<u32>> = tech_labs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, tech_lab)| {
+                        let [x, y] = tech_lab.position;
+                        (0..FUEL_RIFT_OFFSETS.len())
+                            .map(|j| rifts[j * num_players as usize + i].position)
+                            .map(|[rift_x, rift_y]| {
+                                (x + 1).abs_diff(rift_x).pow(2) + (y + 1).abs_diff(rift_y).pow(2)
+                            })
+                            .collect()
+                    })
+                    .collect();
+                assert!(
+                    distances.iter().all(|d| *d == distances[0]),
+                    "{:?} seed {}: {:?}",
+                    settings,
+                    seed,
+                    distances
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_map() {
+        let settings = GeneratorSettings::default();
+        assert_eq!(generate(&settings, 4), generate(&settings, 4));
+        assert_ne!(generate(&settings, 4), generate(&settings, 5));
+
+        let mirrored_three_players = GeneratorSettings {
+            num_players: 3,
+            ..GeneratorSettings::default()
+        };
+        assert!(generate(&mirrored_three_players, 0).is_err());
+    }
+}
@@ -23,6 +23,11 @@ pub fn find_path(
     }
 }
 
+/// Unlike `find_path()`, this always searches properly, no matter how far away the destination is
+pub fn is_reachable(start: [u32; 2], destination: Destination, grid: &ObstacleGrid) -> bool {
+    a_star(start, destination.rect(), grid).is_some()
+}
+
 fn naive_path(start: [u32; 2], goal: [u32; 2]) -> Vec<[u32; 2]> {
     let [mut x, mut y] = start;
     let mut plan = Vec::new();
@@ -75,7 +80,7 @@ fn a_star(start: [u32; 2], destination: Rect, grid: &ObstacleGrid) -> Option<Vec
                         let neighbor = [neighbor[0] as u32, neighbor[1] as u32];
                         let is_free = grid
                             .get(&neighbor)
-                            .map_or(false, |obstacle| obstacle == ObstacleType::None);
+                            .is_some_and(|obstacle| obstacle == ObstacleType::None);
                         if is_free {
                             // println!("neighbor={:?}", neighbor);
 
@@ -233,8 +238,7 @@ struct RatedNode([u32; 2], f32);
 
 impl PartialOrd for RatedNode {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        // NOTE: Inverted in order to get a min-heap instead of max-heap
-        other.1.partial_cmp(&self.1)
+        Some(self.cmp(other))
     }
 }
 
@@ -271,7 +275,7 @@ mod test {
     #[test]
     fn path_going_around_obstacle() {
         let mut grid = ObstacleGrid::new([10, 10]);
-        grid.set([1, 0], ObstacleType::Entity(Team::Enemy1));
+        grid.set([1, 0], ObstacleType::Entity(Team::Player(1)));
         let path = find_path([0, 0], Destination::Point([2, 0]), &grid);
         let expected = vec![[2, 0], [1, 1]];
         assert_eq!(path, Some(expected));
@@ -280,8 +284,8 @@ mod test {
     #[test]
     fn impossible_path() {
         let mut grid = ObstacleGrid::new([10, 2]);
-        grid.set([2, 0], ObstacleType::Entity(Team::Enemy1));
-        grid.set([2, 1], ObstacleType::Entity(Team::Enemy1));
+        grid.set([2, 0], ObstacleType::Entity(Team::Player(1)));
+        grid.set([2, 1], ObstacleType::Entity(Team::Player(1)));
         let path = find_path([0, 0], Destination::Point([4, 0]), &grid);
         assert_eq!(path, None);
     }
@@ -289,12 +293,12 @@ mod test {
     #[test]
     fn zigzag_path() {
         let mut grid = ObstacleGrid::new([10, 4]);
-        grid.set([2, 0], ObstacleType::Entity(Team::Enemy1));
-        grid.set([2, 1], ObstacleType::Entity(Team::Enemy1));
-        grid.set([2, 2], ObstacleType::Entity(Team::Enemy1));
-        grid.set([4, 3], ObstacleType::Entity(Team::Enemy1));
-        grid.set([4, 2], ObstacleType::Entity(Team::Enemy1));
-        grid.set([4, 1], ObstacleType::Entity(Team::Enemy1));
+        grid.set([2, 0], ObstacleType::Entity(Team::Player(1)));
+        grid.set([2, 1], ObstacleType::Entity(Team::Player(1)));
+        grid.set([2, 2], ObstacleType::Entity(Team::Player(1)));
+        grid.set([4, 3], ObstacleType::Entity(Team::Player(1)));
+        grid.set([4, 2], ObstacleType::Entity(Team::Player(1)));
+        grid.set([4, 1], ObstacleType::Entity(Team::Player(1)));
         let start = [0, 0];
         let path = find_path(start, Destination::Point([6, 3]), &grid).unwrap();
         visualize_path(&grid, start, &path[..]);
@@ -319,12 +323,12 @@ mod test {
             position: [7, 3],
             size: [3, 2],
         };
-        grid.set([7, 3], ObstacleType::Entity(Team::Enemy1));
-        grid.set([8, 3], ObstacleType::Entity(Team::Enemy1));
-        grid.set([9, 3], ObstacleType::Entity(Team::Enemy1));
-        grid.set([7, 4], ObstacleType::Entity(Team::Enemy1));
-        grid.set([8, 4], ObstacleType::Entity(Team::Enemy1));
-        grid.set([9, 4], ObstacleType::Entity(Team::Enemy1));
+        grid.set([7, 3], ObstacleType::Entity(Team::Player(1)));
+        grid.set([8, 3], ObstacleType::Entity(Team::Player(1)));
+        grid.set([9, 3], ObstacleType::Entity(Team::Player(1)));
+        grid.set([7, 4], ObstacleType::Entity(Team::Player(1)));
+        grid.set([8, 4], ObstacleType::Entity(Team::Player(1)));
+        grid.set([9, 4], ObstacleType::Entity(Team::Player(1)));
 
         let start = [4, 4];
         let path = find_path(
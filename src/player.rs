@@ -6,7 +6,7 @@ use std::time::Duration;
 
 use crate::camera::Camera;
 use crate::data::EntityType;
-use crate::entities::EntityId;
+use crate::entities::{EntityId, Team};
 use crate::game::WORLD_VIEWPORT;
 
 #[derive(PartialEq, Copy, Clone)]
@@ -16,6 +16,8 @@ pub enum CursorState {
     SelectingMovementDestination,
     PlacingStructure(EntityType),
     SelectingResourceTarget,
+    SelectingPatrolDestination,
+    SelectingRepairTarget,
     DraggingSelectionArea([f32; 2]),
 }
 
@@ -78,28 +80,34 @@ impl EntityHighlight {
 
     pub fn is_visible(&self) -> bool {
         let blink_ms = 200;
-        (self.remaining.as_millis() / blink_ms) % 2 == 0
+        (self.remaining.as_millis() / blink_ms).is_multiple_of(2)
     }
 }
 
 pub struct PlayerState {
+    // The team that is controlled, and that is shown in the HUD
+    pub team: Team,
     pub selected_entity_ids: Vec<EntityId>,
     cursor_state: Cell<CursorState>,
     pub camera: RefCell<Camera>,
     pub movement_command_indicator: RefCell<MovementCommandIndicator>,
     pub timed_entity_highlights: RefCell<Vec<EntityHighlight>>,
     pub hovered_entity_highlight: Option<(EntityId, HighlightType)>,
+    // Shift is held, so commands are added to the end of the units' command queues
+    pub is_queueing_commands: bool,
 }
 
 impl PlayerState {
-    pub fn new(camera: Camera) -> Self {
+    pub fn new(camera: Camera, team: Team) -> Self {
         Self {
+            team,
             selected_entity_ids: vec![],
             cursor_state: Cell::new(CursorState::Default),
             camera: RefCell::new(camera),
             movement_command_indicator: RefCell::new(MovementCommandIndicator::new()),
             timed_entity_highlights: RefCell::new(vec![]),
             hovered_entity_highlight: None,
+            is_queueing_commands: false,
         }
     }
 
@@ -114,6 +122,10 @@ impl PlayerState {
             }
             CursorState::PlacingStructure(..) => mouse::set_cursor_type(ctx, CursorIcon::Grabbing),
             CursorState::SelectingResourceTarget => mouse::set_cursor_type(ctx, CursorIcon::Grab),
+            CursorState::SelectingPatrolDestination => {
+                mouse::set_cursor_type(ctx, CursorIcon::Move)
+            }
+            CursorState::SelectingRepairTarget => mouse::set_cursor_type(ctx, CursorIcon::Grab),
             CursorState::DraggingSelectionArea(..) => {
                 mouse::set_cursor_type(ctx, CursorIcon::Default)
             }
@@ -139,14 +151,8 @@ impl PlayerState {
     }
 
     pub fn screen_to_world_clamped(&self, coordinates: [f32; 2]) -> [f32; 2] {
-        let x = f32::min(
-            f32::max(0.0, coordinates[0] - WORLD_VIEWPORT.x),
-            WORLD_VIEWPORT.w,
-        );
-        let y = f32::min(
-            f32::max(0.0, coordinates[1] - WORLD_VIEWPORT.y),
-            WORLD_VIEWPORT.h,
-        );
+        let x = (coordinates[0] - WORLD_VIEWPORT.x).clamp(0.0, WORLD_VIEWPORT.w);
+        let y = (coordinates[1] - WORLD_VIEWPORT.y).clamp(0.0, WORLD_VIEWPORT.h);
 
         let camera_pos = self.camera.borrow().position_in_world;
         [x + camera_pos[0], y + camera_pos[1]]
@@ -0,0 +1,139 @@
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::command::Command;
+use crate::core::Core;
+use crate::entities::Team;
+use crate::grid::Grid;
+use crate::save::{self, SavedGame};
+use crate::team_ai::{AiRng, TeamAi};
+
+const REPLAY_FORMAT_VERSION: u32 = 1;
+
+pub const LAST_REPLAY_FILE: &str = "last_replay.ron";
+
+/// A recorded match: the state it started from and every command that was issued during it,
+/// by players and AIs alike, along with any rebalancing of the entity definitions. Since the
+/// simulation is deterministic, that is all that's needed to play the match again.
+#[derive(Serialize, Deserialize)]
+pub struct Replay {
+    version: u32,
+    pub tick_duration: Duration,
+    pub num_ticks: u64,
+    pub initial_state: SavedGame,
+    pub commands: Vec<RecordedCommand>,
+    pub rebalances: Vec<RecordedRebalance>,
+}
+
+/// A command that was issued right before the simulation ran tick number `tick`. Queued
+/// commands were added to the end of the unit's command queue, rather than replacing it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedCommand {
+    pub tick: u64,
+    pub team: Team,
+    pub command: Command,
+    pub queued: bool,
+}
+
+/// Entity definitions that were reloaded right before the simulation ran tick number `tick`,
+/// ahead of any commands for that tick
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedRebalance {
+    pub tick: u64,
+    pub definitions: String,
+}
+
+impl Replay {
+    pub fn new(
+        core: &Core,
+        water_grid: &Grid<bool>,
+        team_ais: &[TeamAi],
+        ai_rng: &AiRng,
+        tick_duration: Duration,
+    ) -> Self {
+        Self {
+            version: REPLAY_FORMAT_VERSION,
+            tick_duration,
+            num_ticks: 0,
+            initial_state: SavedGame::new(core, water_grid, team_ais, ai_rng),
+            commands: vec![],
+            rebalances: vec![],
+        }
+    }
+
+    pub fn record(&mut self, tick: u64, team: Team, command: &Command, queued: bool) {
+        self.commands.push(RecordedCommand {
+            tick,
+            team,
+            command: command.clone(),
+            queued,
+        });
+    }
+
+    pub fn record_rebalance(&mut self, tick: u64, definitions: &str) {
+        self.rebalances.push(RecordedRebalance {
+            tick,
+            definitions: definitions.to_owned(),
+        });
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> io::Result<()> {
+        save::write_ron_file(path, self)
+    }
+
+    pub fn load_from_file(path: &Path) -> io::Result<Self> {
+        save::read_ron_file(path, REPLAY_FORMAT_VERSION)
+    }
+}
+
+/// Feeds the commands of a replay back to the simulation, tick by tick
+pub struct ReplayPlayback {
+    pub replay: Replay,
+    next_command: usize,
+    next_rebalance: usize,
+}
+
+impl ReplayPlayback {
+    pub fn new(replay: Replay) -> Self {
+        Self {
+            replay,
+            next_command: 0,
+            next_rebalance: 0,
+        }
+    }
+
+    pub fn rewind(&mut self) {
+        self.next_command = 0;
+        self.next_rebalance = 0;
+    }
+
+    pub fn is_finished(&self, tick: u64) -> bool {
+        tick >= self.replay.num_ticks
+    }
+
+    /// The commands that should be issued before running the given tick. Ticks must be
+    /// visited in order, starting from 0 after a rewind.
+    pub fn commands_before_tick(&mut self, tick: u64) -> &[RecordedCommand] {
+        let start = self.next_command;
+        let commands = &self.replay.commands;
+        while self.next_command < commands.len() && commands[self.next_command].tick <= tick {
+            self.next_command += 1;
+        }
+        &commands[start..self.next_command]
+    }
+
+    /// The definitions that should be put in use before running the given tick, and before
+    /// issuing its commands. Like `commands_before_tick()`, ticks must be visited in order.
+    pub fn rebalances_before_tick(&mut self, tick: u64) -> &[RecordedRebalance] {
+        let start = self.next_rebalance;
+        let rebalances = &self.replay.rebalances;
+        while self.next_rebalance < rebalances.len() && rebalances[self.next_rebalance].tick <= tick
+        {
+            self.next_rebalance += 1;
+        }
+        &rebalances[start..self.next_rebalance]
+    }
+}
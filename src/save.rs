@@ -0,0 +1,121 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::core::Core;
+use crate::definitions;
+use crate::grid::Grid;
+use crate::team_ai::{AiRng, TeamAi};
+
+/// Bumped whenever the saved state changes shape, so that old save files are rejected with a
+/// clear error rather than being misread.
+const SAVE_FORMAT_VERSION: u32 = 1;
+
+pub const QUICKSAVE_FILE: &str = "quicksave.ron";
+
+/// A match in progress. Core holds everything that affects the outcome (entities with all their
+/// state, team resources and research, obstacle grid, what each team has explored), and the
+/// water grid is kept so that the terrain can be drawn again. The entity definitions are kept
+/// too, as they may have been rebalanced while the match was played. So are the computer
+/// players and their random number generator, which makes a loaded match play out exactly like
+/// the saved one would have.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavedGame {
+    pub core: Core,
+    pub water_grid: Grid<bool>,
+    pub definitions: String,
+    pub team_ais: Vec<TeamAi>,
+    pub ai_rng: AiRng,
+}
+
+impl SavedGame {
+    pub fn new(core: &Core, water_grid: &Grid<bool>, team_ais: &[TeamAi], ai_rng: &AiRng) -> Self {
+        Self {
+            core: core.clone(),
+            water_grid: water_grid.clone(),
+            definitions: core.definitions().source().to_owned(),
+            team_ais: team_ais.to_vec(),
+            ai_rng: ai_rng.clone(),
+        }
+    }
+
+    /// Return a core that is ready to continue the match with the saved entity definitions.
+    /// Fails if they differ from the ones that were read at startup by more than their balance
+    /// numbers.
+    pub fn restore(&self) -> io::Result<Core> {
+        let (definitions, _changes) =
+            definitions::rebalance(&definitions::registry(), &self.definitions)?;
+        let mut core = self.core.clone();
+        core.refresh_definitions(definitions);
+        Ok(core)
+    }
+}
+
+// The layout of SavedGame on disk, borrowing from the running game instead of owning the state.
+#[derive(Serialize)]
+struct SavedGameRef<'a> {
+    version: u32,
+    core: &'a Core,
+    water_grid: &'a Grid<bool>,
+    definitions: &'a str,
+    team_ais: &'a [TeamAi],
+    ai_rng: &'a AiRng,
+}
+
+pub fn save_to_file(
+    core: &Core,
+    water_grid: &Grid<bool>,
+    team_ais: &[TeamAi],
+    ai_rng: &AiRng,
+    path: &Path,
+) -> io::Result<()> {
+    let saved_game = SavedGameRef {
+        version: SAVE_FORMAT_VERSION,
+        core,
+        water_grid,
+        definitions: core.definitions().source(),
+        team_ais,
+        ai_rng,
+    };
+    write_ron_file(path, &saved_game)
+}
+
+pub fn load_from_file(path: &Path) -> io::Result<SavedGame> {
+    read_ron_file(path, SAVE_FORMAT_VERSION)
+}
+
+#[derive(Deserialize)]
+struct FileHeader {
+    version: u32,
+}
+
+/// Write `contents` as RON. It should have a `version` field, which is checked by
+/// `read_ron_file()`.
+pub fn write_ron_file(path: &Path, contents: &impl Serialize) -> io::Result<()> {
+    let contents = ron::to_string(contents).map_err(invalid_data)?;
+    fs::write(path, contents)
+}
+
+pub fn read_ron_file<T: DeserializeOwned>(path: &Path, expected_version: u32) -> io::Result<T> {
+    let contents = fs::read_to_string(path)?;
+    parse_ron(&contents, expected_version)
+}
+
+/// Like `read_ron_file()`, for contents that have already been read
+pub fn parse_ron<T: DeserializeOwned>(contents: &str, expected_version: u32) -> io::Result<T> {
+    let header: FileHeader = ron::from_str(contents).map_err(invalid_data)?;
+    if header.version != expected_version {
+        return Err(invalid_data(format!(
+            "Unsupported format version {} (expected {})",
+            header.version, expected_version
+        )));
+    }
+    ron::from_str(contents).map_err(invalid_data)
+}
+
+pub fn invalid_data(error: impl ToString) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, error.to_string())
+}
@@ -0,0 +1,498 @@
+use rand::SeedableRng;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::core::{Core, MatchResult, UpdateOutcome};
+use crate::data::ResourceType;
+use crate::definitions;
+use crate::entities::{EntityId, Team};
+use crate::grid::Grid;
+use crate::map::{self, WorldInitData};
+use crate::replay::{Replay, ReplayPlayback};
+use crate::save;
+use crate::team_ai::{self, AiRng, TeamAi};
+
+pub const DEFAULT_TICK_DURATION: Duration = Duration::from_millis(20);
+
+/// Besides the map and the issued commands, these are the only inputs to a match. Two runs
+/// with equal settings and equal commands end up in exactly the same state.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SimulationSettings {
+    pub seed: u64,
+    pub tick_duration: Duration,
+}
+
+impl SimulationSettings {
+    pub fn new(seed: Option<u64>, tick_duration: Duration) -> Self {
+        Self {
+            seed: seed.unwrap_or_else(rand::random),
+            tick_duration,
+        }
+    }
+
+    pub fn rng(&self) -> AiRng {
+        AiRng::seed_from_u64(self.seed)
+    }
+}
+
+/// Runs a match without any window or graphics. Every team (including the player's) is
+/// controlled by an AI, or the commands are taken from a replay. The game is stepped with a
+/// fixed tick length, and all issued commands are recorded.
+pub struct Simulation {
+    core: Core,
+    water_grid: Grid<bool>,
+    command_source: CommandSource,
+    rng: AiRng,
+    tick: u64,
+    tick_duration: Duration,
+    recording: Replay,
+}
+
+enum CommandSource {
+    TeamAis(Vec<TeamAi>),
+    Replay(Box<ReplayPlayback>),
+}
+
+impl Simulation {
+    pub fn new(world: WorldInitData, settings: SimulationSettings) -> Self {
+        let WorldInitData {
+            dimensions,
+            entities,
+            water_grid,
+            victory_condition,
+            team_setup,
+            triggers,
+            ..
+        } = world;
+
+        let teams: HashSet<Team> = entities.iter().map(|entity| entity.team).collect();
+        let team_ais = team_ai::create_team_ais(&teams, true);
+        let water_cells = map::water_cells(&water_grid);
+        let mut core = Core::new(
+            entities,
+            dimensions,
+            water_cells,
+            victory_condition,
+            team_setup,
+            settings.seed,
+        );
+        core.set_triggers(triggers);
+        let rng = settings.rng();
+        let recording = Replay::new(&core, &water_grid, &team_ais, &rng, settings.tick_duration);
+
+        Self {
+            core,
+            water_grid,
+            command_source: CommandSource::TeamAis(team_ais),
+            rng,
+            tick: 0,
+            tick_duration: settings.tick_duration,
+            recording,
+        }
+    }
+
+    /// Continue a saved match, with the AIs picking up where they left off
+    pub fn from_save_file(path: &Path, tick_duration: Duration) -> io::Result<Self> {
+        let saved_game = save::load_from_file(path)?;
+        let core = saved_game.restore()?;
+        let recording = Replay::new(
+            &core,
+            &saved_game.water_grid,
+            &saved_game.team_ais,
+            &saved_game.ai_rng,
+            tick_duration,
+        );
+        Ok(Self {
+            core,
+            water_grid: saved_game.water_grid,
+            command_source: CommandSource::TeamAis(saved_game.team_ais),
+            rng: saved_game.ai_rng,
+            tick: 0,
+            tick_duration,
+            recording,
+        })
+    }
+
+    /// Play back a recorded match. The seed doesn't matter, since no AI is involved.
+    pub fn from_replay_file(path: &Path) -> io::Result<Self> {
+        let replay = Replay::load_from_file(path)?;
+        let core = replay.initial_state.restore()?;
+        let initial_state = &replay.initial_state;
+        let recording = Replay::new(
+            &core,
+            &initial_state.water_grid,
+            &initial_state.team_ais,
+            &initial_state.ai_rng,
+            replay.tick_duration,
+        );
+        Ok(Self {
+            core,
+            water_grid: initial_state.water_grid.clone(),
+            rng: initial_state.ai_rng.clone(),
+            tick_duration: replay.tick_duration,
+            command_source: CommandSource::Replay(Box::new(ReplayPlayback::new(replay))),
+            tick: 0,
+            recording,
+        })
+    }
+
+    /// The length of the played back match, if this is a replay
+    pub fn replay_length(&self) -> Option<u64> {
+        match &self.command_source {
+            CommandSource::TeamAis(_) => None,
+            CommandSource::Replay(playback) => Some(playback.replay.num_ticks),
+        }
+    }
+
+    pub fn tick(&mut self) -> TickStats {
+        match &mut self.command_source {
+            CommandSource::TeamAis(team_ais) => {
+                for ai in team_ais {
+                    if let Some(command) = ai.run(self.tick_duration, &self.core, &mut self.rng) {
+                        self.recording.record(self.tick, ai.team(), &command, false);
+                        let _ = self.core.issue_command(command, ai.team());
+                    }
+                }
+            }
+            CommandSource::Replay(playback) => {
+                for recorded in playback.rebalances_before_tick(self.tick) {
+                    self.recording
+                        .record_rebalance(self.tick, &recorded.definitions);
+                    match definitions::rebalance(self.core.definitions(), &recorded.definitions) {
+                        Ok((definitions, _changes)) => self.core.rebalance(definitions),
+                        Err(e) => eprintln!("ERROR: Failed to rebalance replay: {}", e),
+                    }
+                }
+                for recorded in playback.commands_before_tick(self.tick) {
+                    self.recording.record(
+                        self.tick,
+                        recorded.team,
+                        &recorded.command,
+                        recorded.queued,
+                    );
+                    let command = recorded.command.clone();
+                    let _ = if recorded.queued {
+                        self.core.queue_command(command, recorded.team)
+                    } else {
+                        self.core.issue_command(command, recorded.team)
+                    };
+                }
+            }
+        }
+
+        let UpdateOutcome {
+            removed_entities,
+            eliminated_teams,
+            match_result,
+            messages,
+            ..
+        } = self.core.update(self.tick_duration);
+        self.tick += 1;
+
+        let teams = self
+            .core
+            .teams()
+            .into_iter()
+            .map(|team| {
+                let num_entities = self
+                    .core
+                    .entities()
+                    .iter()
+                    .filter(|(_id, entity)| entity.borrow().team == team)
+                    .count();
+                let resources = self
+                    .core
+                    .team_state_unchecked(&team)
+                    .borrow()
+                    .resources
+                    .clone();
+                TeamStats {
+                    team,
+                    num_entities,
+                    resources,
+                }
+            })
+            .collect();
+
+        TickStats {
+            tick: self.tick,
+            teams,
+            removed_entities,
+            eliminated_teams,
+            match_result,
+            messages,
+        }
+    }
+
+    /// Put other entity definitions in use before the next tick, like the game does when the
+    /// definitions file is edited. Only balance numbers may differ. The change is recorded.
+    pub fn rebalance(&mut self, definitions: &str) -> io::Result<Vec<String>> {
+        let (rebalanced, changes) = definitions::rebalance(self.core.definitions(), definitions)?;
+        if !changes.is_empty() {
+            self.core.rebalance(rebalanced);
+            self.recording.record_rebalance(self.tick, definitions);
+        }
+        Ok(changes)
+    }
+
+    /// Step the simulation `num_ticks` times, writing one line of stats per tick. Stops early
+    /// if the match ends, and returns the result in that case.
+    pub fn run(&mut self, num_ticks: u64, out: &mut impl Write) -> io::Result<Option<MatchResult>> {
+        for _ in 0..num_ticks {
+            let stats = self.tick();
+            writeln!(out, "{}", stats)?;
+            if stats.match_result.is_some() {
+                return Ok(stats.match_result);
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn match_result(&self) -> Option<&MatchResult> {
+        self.core.match_result()
+    }
+
+    /// Save the match as it is now, so that it can be continued with `from_save_file()`
+    pub fn save_game(&self, path: &Path) -> io::Result<()> {
+        let team_ais = match &self.command_source {
+            CommandSource::TeamAis(team_ais) => &team_ais[..],
+            CommandSource::Replay(_) => &[],
+        };
+        save::save_to_file(&self.core, &self.water_grid, team_ais, &self.rng, path)
+    }
+
+    /// Write everything that has been simulated so far to a replay file
+    pub fn save_replay(&mut self, path: &Path) -> io::Result<()> {
+        self.recording.num_ticks = self.tick;
+        self.recording.save_to_file(path)
+    }
+}
+
+#[derive(Debug)]
+pub struct TickStats {
+    pub tick: u64,
+    pub teams: Vec<TeamStats>,
+    pub removed_entities: Vec<EntityId>,
+    pub eliminated_teams: Vec<Team>,
+    /// Only set on the tick where the match ended
+    pub match_result: Option<MatchResult>,
+    /// Shown by map triggers
+    pub messages: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct TeamStats {
+    pub team: Team,
+    pub num_entities: usize,
+    pub resources: BTreeMap<ResourceType, u32>,
+}
+
+impl Display for TickStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "tick {}", self.tick)?;
+        for team_stats in &self.teams {
+            write!(
+                f,
+                " | {:?}: {} entities",
+                team_stats.team, team_stats.num_entities
+            )?;
+            for (resource_type, amount) in &team_stats.resources {
+                write!(f, ", {} {}", amount, resource_type)?;
+            }
+        }
+        write!(f, " | removed: {:?}", self.removed_entities)?;
+        if !self.eliminated_teams.is_empty() {
+            write!(f, " | eliminated: {:?}", self.eliminated_teams)?;
+        }
+        for message in &self.messages {
+            write!(f, " | message: {:?}", message)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::map::MapType;
+    use std::path::PathBuf;
+
+    // Named after the process too, so that test runs in parallel don't use each other's files
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rts-rs-{}-{}.ron", name, std::process::id()))
+    }
+
+    fn settings(seed: u64) -> SimulationSettings {
+        SimulationSettings::new(Some(seed), DEFAULT_TICK_DURATION)
+    }
+
+    fn dump_state(core: &Core) -> String {
+        let mut dump = String::new();
+        for team in core.teams() {
+            let team_state = core.team_state_unchecked(&team).borrow();
+            dump.push_str(&format!("{:?} {:?}\n", team, team_state.resources));
+        }
+        for (_id, entity) in core.entities() {
+            dump.push_str(&format!("{:?}\n", entity.borrow()));
+        }
+        dump
+    }
+
+    #[test]
+    fn ai_teams_build_bases_without_a_window() {
+        let world = WorldInitData::create_from_type(MapType::Spectator, 0);
+        let mut simulation = Simulation::new(world, settings(0));
+
+        let mut stats = None;
+        for _ in 0..3000 {
+            stats = Some(simulation.tick());
+        }
+
+        let stats = stats.unwrap();
+        assert_eq!(stats.tick, 3000);
+        for team in [Team::Player(1), Team::Player(2)] {
+            let team_stats = stats.teams.iter().find(|s| s.team == team).unwrap();
+            assert!(team_stats.num_entities > 1, "{:?}", team_stats);
+        }
+    }
+
+    #[test]
+    fn same_seed_gives_identical_matches() {
+        let mut a = Simulation::new(
+            WorldInitData::create_from_type(MapType::Medium, 7),
+            settings(7),
+        );
+        let mut b = Simulation::new(
+            WorldInitData::create_from_type(MapType::Medium, 7),
+            settings(7),
+        );
+        for _ in 0..5000 {
+            a.tick();
+            b.tick();
+        }
+        assert_eq!(dump_state(&a.core), dump_state(&b.core));
+    }
+
+    #[test]
+    fn saved_game_is_restored_exactly() {
+        let mut simulation = Simulation::new(
+            WorldInitData::create_from_type(MapType::Medium, 3),
+            settings(3),
+        );
+        for _ in 0..2000 {
+            simulation.tick();
+        }
+
+        let path = temp_path("saved-game-test");
+        simulation.save_game(&path).unwrap();
+        let saved_contents = std::fs::read_to_string(&path).unwrap();
+
+        let loaded = save::load_from_file(&path).unwrap();
+        assert_eq!(dump_state(&simulation.core), dump_state(&loaded.core));
+
+        let mut continued = Simulation::from_save_file(&path, DEFAULT_TICK_DURATION).unwrap();
+        continued.save_game(&path).unwrap();
+        assert_eq!(saved_contents, std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        // The AIs make the same decisions as they would have without saving
+        for _ in 0..3000 {
+            simulation.tick();
+            continued.tick();
+        }
+        assert_eq!(dump_state(&simulation.core), dump_state(&continued.core));
+    }
+
+    #[test]
+    fn replay_reproduces_recorded_match() {
+        let mut simulation = Simulation::new(
+            WorldInitData::create_from_type(MapType::Medium, 5),
+            settings(5),
+        );
+        for _ in 0..3000 {
+            simulation.tick();
+        }
+        let path = temp_path("replay-test");
+        simulation.save_replay(&path).unwrap();
+
+        let mut replayed = Simulation::from_replay_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(replayed.replay_length(), Some(3000));
+        for _ in 0..3000 {
+            replayed.tick();
+        }
+        assert_eq!(dump_state(&simulation.core), dump_state(&replayed.core));
+    }
+
+    #[test]
+    fn rebalancing_is_recorded_in_replays_and_saves() {
+        let original = definitions::registry().source().to_owned();
+        let rebalanced = original.replacen("duration: 14.0", "duration: 15.0", 1);
+        let mut simulation = Simulation::new(
+            WorldInitData::create_from_type(MapType::Medium, 5),
+            settings(5),
+        );
+        for _ in 0..100 {
+            simulation.tick();
+        }
+        assert_eq!(
+            simulation.rebalance(&rebalanced).unwrap(),
+            vec!["BattleAcademy Ranger training time 14s -> 15s".to_owned()]
+        );
+        // Other matches in the same process keep the numbers they started with
+        assert_eq!(definitions::registry().source(), original);
+        for _ in 0..100 {
+            simulation.tick();
+        }
+
+        let save_path = temp_path("rebalanced-game-test");
+        simulation.save_game(&save_path).unwrap();
+        let saved_game = save::load_from_file(&save_path).unwrap();
+        std::fs::remove_file(&save_path).unwrap();
+        assert_eq!(saved_game.definitions, rebalanced);
+
+        simulation.rebalance(&original).unwrap();
+        for _ in 0..100 {
+            simulation.tick();
+        }
+        let replay_path = temp_path("rebalanced-replay-test");
+        simulation.save_replay(&replay_path).unwrap();
+        let mut replayed = Simulation::from_replay_file(&replay_path).unwrap();
+        std::fs::remove_file(&replay_path).unwrap();
+        for _ in 0..300 {
+            replayed.tick();
+        }
+        let ticks: Vec<u64> = replayed
+            .recording
+            .rebalances
+            .iter()
+            .map(|r| r.tick)
+            .collect();
+        assert_eq!(ticks, vec![100, 200]);
+        assert_eq!(
+            replayed.recording.rebalances,
+            simulation.recording.rebalances
+        );
+        assert_eq!(dump_state(&simulation.core), dump_state(&replayed.core));
+    }
+
+    #[test]
+    fn match_ends_when_victory_condition_is_met() {
+        let world = WorldInitData::create_from_type(MapType::Medium, 1);
+        let mut simulation = Simulation::new(world, settings(1));
+
+        let result = simulation.run(60_000, &mut io::sink()).unwrap().unwrap();
+
+        assert_eq!(result.winners.len(), 1);
+        assert_eq!(simulation.match_result(), Some(&result));
+        assert_eq!(result.stats.len(), 2);
+        for (team, stats) in &result.stats {
+            let team_state = simulation.core.team_state_unchecked(team).borrow();
+            assert_eq!(team_state.is_eliminated, !result.winners.contains(team));
+            assert!(stats.units_trained > 0, "{:?}", stats);
+        }
+    }
+}
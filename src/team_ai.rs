@@ -1,41 +1,95 @@
-use rand::rngs::ThreadRng;
-use rand::Rng;
-use std::cell::{Ref, RefCell};
+use rand::{Error, Rng, RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::time::Duration;
 
-use crate::core::{
-    AttackCommand, Command, ConstructCommand, Core, GatherResourceCommand, StartActivityCommand,
+use crate::command::{
+    AttackCommand, AttackMoveCommand, Command, ConstructCommand, GatherResourceCommand,
+    StartActivityCommand,
 };
-use crate::data::EntityType;
-use crate::entities::{ActivityTarget, EntityState, Team};
+use crate::core::{Core, Visibility};
+use crate::data::{EntityType, ResearchTopic, ResourceType};
+use crate::entities::{ActivityTarget, Entity, EntityCategory, EntityId, EntityState, Team};
 
 use std::cmp;
 
+// How many trainings the AI lines up in each structure. Filling up the whole queue would tie up
+// fuel that is better spent on new structures.
+const QUEUE_LENGTH: usize = 2;
+
+/// Create an AI for each computer-controlled team. An AI attacks any team that it is hostile to.
+pub fn create_team_ais(teams: &HashSet<Team>, is_player_ai_controlled: bool) -> Vec<TeamAi> {
+    let mut teams: Vec<Team> = teams
+        .iter()
+        .copied()
+        .filter(|team| *team != Team::Neutral)
+        .filter(|team| *team != Team::HUMAN || is_player_ai_controlled)
+        .collect();
+    teams.sort();
+    teams.into_iter().map(TeamAi::new).collect()
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TeamAi {
     team: Team,
-    opponent: Team,
     timer_s: f32,
 }
 
-impl TeamAi {
-    pub fn new(team: Team, opponent: Team) -> Self {
+/// The random number generator that the AIs share. Unlike StdRng, its state can be saved along
+/// with the match, so that a loaded game plays out exactly like the saved one would have.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AiRng {
+    state: u64,
+}
+
+impl RngCore for AiRng {
+    // xorshift64*
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl SeedableRng for AiRng {
+    type Seed = [u8; 8];
+
+    fn from_seed(seed: Self::Seed) -> Self {
+        // The state must never be zero
         Self {
-            team,
-            opponent,
-            timer_s: 0.0,
+            state: u64::from_le_bytes(seed).max(1),
         }
     }
+}
+
+impl TeamAi {
+    pub fn new(team: Team) -> Self {
+        Self { team, timer_s: 0.0 }
+    }
 
     pub fn team(&self) -> Team {
         self.team
     }
 
-    pub fn run<'a>(
-        &mut self,
-        dt: Duration,
-        core: &'a Core,
-        rng: &mut ThreadRng,
-    ) -> Option<Command<'a>> {
+    pub fn run(&mut self, dt: Duration, core: &Core, rng: &mut AiRng) -> Option<Command> {
         self.timer_s -= dt.as_secs_f32();
         if self.timer_s <= 0.0 {
             self.timer_s = 1.0;
@@ -45,12 +99,12 @@ impl TeamAi {
         }
     }
 
-    fn act<'a>(&mut self, core: &'a Core, rng: &mut ThreadRng) -> Option<Command<'a>> {
+    fn act(&mut self, core: &Core, rng: &mut AiRng) -> Option<Command> {
         let entities = core.entities();
 
         let mut idle_workers = vec![];
-        let mut idle_bases = vec![];
-        let mut idle_military_buildings = vec![];
+        let mut available_bases = vec![];
+        let mut available_military_buildings = vec![];
         let mut idle_fighters = vec![];
         let mut has_base = false;
         let mut military_building_count = 0;
@@ -59,26 +113,38 @@ impl TeamAi {
         for (_id, entity) in entities {
             let entity_ref = entity.borrow();
             if entity_ref.team == self.team {
-                match (entity_ref.entity_type, entity_ref.state) {
-                    (EntityType::Engineer, state) => {
+                match (entity_ref.entity_type.name().as_str(), entity_ref.state) {
+                    ("Engineer", state) => {
                         worker_count += 1;
                         if state == EntityState::Idle {
                             idle_workers.push(entity);
                         }
                     }
-                    (EntityType::Enforcer, EntityState::Idle) => {
+                    ("Enforcer" | "Ranger", EntityState::Idle) => {
                         idle_fighters.push(entity);
                     }
-                    (EntityType::TechLab, state) => {
+                    ("TechLab", state) => {
                         has_base = true;
-                        if state == EntityState::Idle {
-                            idle_bases.push(entity);
+                        if !matches!(state, EntityState::UnderConstruction(..)) {
+                            let activity = entity_ref.activity.as_ref().unwrap();
+                            worker_count += activity
+                                .queue()
+                                .filter(|target| {
+                                    *target == ActivityTarget::Train(EntityType::named("Engineer"))
+                                })
+                                .count();
+                            if activity.queue_length() < QUEUE_LENGTH {
+                                available_bases.push(entity);
+                            }
                         }
                     }
-                    (EntityType::BattleAcademy, state) => {
+                    ("BattleAcademy", state) => {
                         military_building_count += 1;
-                        if state == EntityState::Idle {
-                            idle_military_buildings.push(entity);
+                        let activity = entity_ref.activity.as_ref().unwrap();
+                        if !matches!(state, EntityState::UnderConstruction(..))
+                            && activity.queue_length() < QUEUE_LENGTH
+                        {
+                            available_military_buildings.push(entity);
                         }
                     }
                     _ => {}
@@ -86,105 +152,207 @@ impl TeamAi {
             }
         }
 
-        if !has_base {
+        // Since the cost is paid up front, there is no point in ordering what we can't afford
+        let team_state = core.team_state_unchecked(&self.team).borrow();
+        let can_afford_construction = |worker: &&RefCell<Entity>, structure_type| {
+            let worker = worker.borrow();
+            let options = worker.unit().construction_options.as_ref().unwrap();
+            team_state.can_afford(&options.get(&structure_type).unwrap().cost)
+        };
+        let can_afford_activity = |structure: &&RefCell<Entity>, target: &ActivityTarget| {
+            let structure = structure.borrow();
+            team_state.can_afford(&structure.activity.as_ref().unwrap().config(target).cost)
+        };
+        let supply = core.supply(&self.team);
+        let has_supply_for = |entity_type| supply.has_room_for(core.supply_cost(entity_type));
+
+        if !has_base
+            && idle_workers
+                .last()
+                .is_some_and(|worker| can_afford_construction(worker, EntityType::named("TechLab")))
+        {
             if let Some(worker) = idle_workers.pop() {
-                let worker = worker.borrow_mut();
-                let structure_size = core.structure_size(&EntityType::TechLab);
+                let worker = worker.borrow();
+                let structure_size = core.structure_size(&EntityType::named("TechLab"));
                 if let Some(pos) =
                     find_free_position_for_structure(core, worker.position, *structure_size, rng)
                 {
                     return Some(Command::Construct(ConstructCommand {
-                        builder: worker,
+                        builder: worker.id,
                         structure_position: pos,
-                        structure_type: EntityType::TechLab,
+                        structure_type: EntityType::named("TechLab"),
                     }));
                 }
             }
         }
 
-        if military_building_count < 2 {
+        // More military buildings are only needed once they no longer provide enough supply
+        let wants_military_building = military_building_count < 2
+            || (military_building_count < 4 && !has_supply_for(EntityType::named("Enforcer")));
+        if wants_military_building
+            && idle_workers.last().is_some_and(|worker| {
+                can_afford_construction(worker, EntityType::named("BattleAcademy"))
+            })
+        {
             if let Some(worker) = idle_workers.pop() {
-                let worker = worker.borrow_mut();
-                let structure_size = core.structure_size(&EntityType::BattleAcademy);
+                let worker = worker.borrow();
+                let structure_size = core.structure_size(&EntityType::named("BattleAcademy"));
                 if let Some(pos) =
                     find_free_position_for_structure(core, worker.position, *structure_size, rng)
                 {
                     return Some(Command::Construct(ConstructCommand {
-                        builder: worker,
+                        builder: worker.id,
                         structure_position: pos,
-                        structure_type: EntityType::BattleAcademy,
+                        structure_type: EntityType::named("BattleAcademy"),
                     }));
                 }
             }
         }
 
         if !idle_workers.is_empty() {
-            if let Some(resource) =
-                entities
-                    .iter()
-                    .find_map(|(_id, e)| match RefCell::try_borrow(e) {
-                        Ok(e) if e.entity_type == EntityType::FuelRift => Some(e),
+            // Resources never move, so it's enough that they have been seen at some point
+            let visibility = core.visibility(&self.team);
+            let known_resources: Vec<(EntityId, ResourceType)> = entities
+                .iter()
+                .filter_map(|(id, e)| {
+                    let e = e.borrow();
+                    let is_known = visibility.is_none_or(|visibility| {
+                        visibility.get(&e.position) != Some(Visibility::Unexplored)
+                    });
+                    match &e.category {
+                        EntityCategory::Resource { config, .. } if is_known => {
+                            Some((*id, config.resource_type))
+                        }
                         _ => None,
-                    })
+                    }
+                })
+                .collect();
+            // Go for whatever we have the least of, if we know where to find it
+            let wanted = ResourceType::ALL
+                .into_iter()
+                .min_by_key(|resource_type| team_state.resource(*resource_type));
+            if let Some(resource) = known_resources
+                .iter()
+                .find(|(_id, resource_type)| Some(*resource_type) == wanted)
+                .or_else(|| known_resources.first())
+                .map(|(id, _resource_type)| *id)
             {
                 if let Some(worker) = idle_workers.pop() {
                     return Some(Command::GatherResource(GatherResourceCommand {
-                        gatherer: worker.borrow_mut(),
-                        resource: Ref::clone(&resource),
+                        gatherer: worker.borrow().id,
+                        resource,
                     }));
                 }
             }
         }
 
-        if worker_count < 3 {
-            if let Some(base) = idle_bases.into_iter().next() {
+        if worker_count < 3 && has_supply_for(EntityType::named("Engineer")) {
+            let target = ActivityTarget::Train(EntityType::named("Engineer"));
+            if let Some(base) = available_bases
+                .iter()
+                .find(|base| can_afford_activity(base, &target))
+            {
                 return Some(Command::StartActivity(StartActivityCommand {
-                    structure: base.borrow_mut(),
-                    target: ActivityTarget::Train(EntityType::Engineer),
+                    structure: base.borrow().id,
+                    target,
                 }));
             }
         }
 
-        if let Some(military_building) = idle_military_buildings.into_iter().next() {
-            return Some(Command::StartActivity(StartActivityCommand {
-                structure: military_building.borrow_mut(),
-                target: ActivityTarget::Train(EntityType::Enforcer),
-            }));
+        if !available_military_buildings.is_empty() {
+            let mut fighter_type = if rng.gen_bool(0.4) {
+                EntityType::named("Ranger")
+            } else {
+                EntityType::named("Enforcer")
+            };
+            if !core.is_unlocked(&self.team, fighter_type) {
+                // Work towards unlocking it, and make do with what's available in the meantime
+                let weapons = ResearchTopic::named("Weapons");
+                let research = ActivityTarget::Research(weapons);
+                if team_state.tech.can_start(weapons) {
+                    if let Some(base) = available_bases
+                        .iter()
+                        .find(|base| can_afford_activity(base, &research))
+                    {
+                        return Some(Command::StartActivity(StartActivityCommand {
+                            structure: base.borrow().id,
+                            target: research,
+                        }));
+                    }
+                }
+                fighter_type = EntityType::named("Enforcer");
+            }
+            let target = ActivityTarget::Train(fighter_type);
+            if let Some(military_building) = available_military_buildings
+                .iter()
+                .find(|building| can_afford_activity(building, &target))
+                .filter(|_| has_supply_for(fighter_type))
+            {
+                return Some(Command::StartActivity(StartActivityCommand {
+                    structure: military_building.borrow().id,
+                    target,
+                }));
+            }
         }
 
         if !idle_fighters.is_empty() {
             let mut victims = vec![];
-            for (_id, entity) in entities {
-                if let Ok(entity) = entity.try_borrow() {
-                    if entity.team == self.opponent {
-                        victims.push(entity);
-                        if victims.len() == idle_fighters.len() {
-                            // Have enough victims, one for each attacker
-                            break;
-                        }
+            for (id, entity) in core.visible_entities(&self.team) {
+                if core
+                    .team_setup()
+                    .are_hostile(self.team, entity.borrow().team)
+                {
+                    victims.push(*id);
+                    if victims.len() == idle_fighters.len() {
+                        // Have enough victims, one for each attacker
+                        break;
                     }
                 }
             }
 
-            for fighter in idle_fighters {
+            for fighter in &idle_fighters {
                 if let Some(victim) = victims.pop() {
                     return Some(Command::Attack(AttackCommand {
-                        attacker: fighter.borrow_mut(),
+                        attacker: fighter.borrow().id,
                         victim,
                     }));
                 }
             }
+
+            // No enemy in sight, so go looking for one
+            if let Some(destination) = self.find_scouting_destination(core, rng) {
+                return Some(Command::AttackMove(AttackMoveCommand {
+                    unit: idle_fighters[0].borrow().id,
+                    destination,
+                }));
+            }
         }
 
         None
     }
+
+    // Prefer parts of the map that haven't been explored yet
+    fn find_scouting_destination(&self, core: &Core, rng: &mut AiRng) -> Option<[u32; 2]> {
+        let visibility = core.visibility(&self.team)?;
+        let [w, h] = visibility.dimensions();
+        let mut fallback = None;
+        for _ in 0..20 {
+            let position = [rng.gen_range(0..w), rng.gen_range(0..h)];
+            match visibility.get(&position).unwrap() {
+                Visibility::Unexplored => return Some(position),
+                Visibility::Explored => fallback = fallback.or(Some(position)),
+                Visibility::Visible => {}
+            }
+        }
+        fallback
+    }
 }
 
 fn find_free_position_for_structure(
     core: &Core,
     worker_position: [u32; 2],
     structure_size: [u32; 2],
-    rng: &mut ThreadRng,
+    rng: &mut AiRng,
 ) -> Option<[u32; 2]> {
     let mut x = worker_position[0] as i32;
     let mut y = worker_position[1] as i32;
@@ -1,10 +1,10 @@
 use ggez::graphics::{DrawParam, Drawable, Font, Rect, Text};
 use ggez::{Context, GameResult};
 
-/// This module exists to avoid getting blurry text when scaling up game window. Images and meshes
-/// scale fine by default, but text becomes blurry.
-///
-/// To bypass the issue, we create the text using a larger size and then scale down when drawing it.
+// This module exists to avoid getting blurry text when scaling up game window. Images and meshes
+// scale fine by default, but text becomes blurry.
+//
+// To bypass the issue, we create the text using a larger size and then scale down when drawing it.
 
 const SCALING: f32 = 3.0;
 
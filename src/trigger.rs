@@ -0,0 +1,65 @@
+use serde::{Deserialize, Serialize};
+
+use crate::data::EntityType;
+use crate::entities::Team;
+
+/// A scripted event that is part of a map. A trigger fires once, as soon as its condition is met,
+/// and then carries out all of its actions.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trigger {
+    pub condition: TriggerCondition,
+    pub actions: Vec<TriggerAction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TriggerCondition {
+    /// This many seconds into the match
+    Time(f32),
+    /// Any unit of the team is within the area
+    UnitInArea {
+        team: Team,
+        position: [u32; 2],
+        size: [u32; 2],
+    },
+    /// The team has no entities of this type left
+    NoneLeft { team: Team, entity_type: EntityType },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TriggerAction {
+    /// Shown to anyone playing or watching the match
+    Message(String),
+    GiveResources {
+        team: Team,
+        #[serde(default)]
+        fuel: u32,
+        #[serde(default)]
+        minerals: u32,
+    },
+    /// A unit appears on a free cell next to the position
+    SpawnUnit {
+        unit_type: EntityType,
+        team: Team,
+        position: [u32; 2],
+    },
+}
+
+impl Trigger {
+    pub fn teams(&self) -> Vec<Team> {
+        let mut teams = vec![];
+        match &self.condition {
+            TriggerCondition::Time(_) => {}
+            TriggerCondition::UnitInArea { team, .. } | TriggerCondition::NoneLeft { team, .. } => {
+                teams.push(*team)
+            }
+        }
+        for action in &self.actions {
+            match action {
+                TriggerAction::Message(_) => {}
+                TriggerAction::GiveResources { team, .. }
+                | TriggerAction::SpawnUnit { team, .. } => teams.push(*team),
+            }
+        }
+        teams
+    }
+}